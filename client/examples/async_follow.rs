@@ -0,0 +1,15 @@
+use broadcast_client::{AsyncBroadcastClient, Backoff};
+
+/// Prints everything broadcast on the server from a tokio task.
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "localhost:9090".to_string());
+    let mut client = AsyncBroadcastClient::connect(&addr, Backoff::default()).await?;
+    loop {
+        let message = client.recv().await?;
+        match message.offset {
+            Some(offset) => println!("{:>8} {}", offset, message.text),
+            None => println!("         {}", message.text),
+        }
+    }
+}
@@ -0,0 +1,15 @@
+use broadcast_client::{Backoff, BroadcastClient};
+
+/// Prints everything broadcast on the server, surviving server restarts.
+fn main() -> std::io::Result<()> {
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "localhost:9090".to_string());
+    let mut client = BroadcastClient::connect(&addr, Backoff::default())?;
+    for message in client.messages() {
+        let message = message?;
+        match message.offset {
+            Some(offset) => println!("{:>8} {}", offset, message.text),
+            None => println!("         {}", message.text),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+/* C API of the broadcast-client crate, build it with `cargo build --release
+ * --features ffi` and link against libbroadcast_client.so.
+ *
+ * Functions returning int return -1 on failure, those returning a pointer
+ * NULL, and bb_last_error() says why. A client must only be used from one
+ * thread at a time. */
+#ifndef BROADCAST_CLIENT_H
+#define BROADCAST_CLIENT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct bb_client bb_client;
+
+#define BB_CONNECTED 0
+#define BB_RECONNECTING 1   /* value is the attempt */
+#define BB_GAP_DETECTED 2   /* value is how many messages were missed */
+
+/* offset is -1 for server notices, text is not NUL terminated and only valid
+ * during the call. */
+typedef void (*bb_message_cb)(void *user, int64_t offset, const char *text, size_t len);
+typedef void (*bb_status_cb)(void *user, int status, uint64_t value);
+
+/* Connects to "host:port", trying up to max_attempts times, 0 forever. The
+ * client reconnects and resumes on its own afterwards. */
+bb_client *bb_connect(const char *addr, uint32_t max_attempts);
+void bb_on_message(bb_client *client, bb_message_cb cb, void *user);
+void bb_on_status(bb_client *client, bb_status_cb cb, void *user);
+/* Sends one message, a trailing newline is optional. */
+int bb_send(bb_client *client, const char *text);
+/* Waits up to timeout_ms (-1 forever, 0 not at all) for a message and passes it
+ * to the message callback. Returns 1 if one arrived, 0 if not. */
+int bb_poll(bb_client *client, int timeout_ms);
+/* Offset of the last message received, -1 before the first. */
+int64_t bb_last_offset(const bb_client *client);
+void bb_close(bb_client *client);
+const char *bb_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
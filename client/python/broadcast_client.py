@@ -0,0 +1,102 @@
+"""Python access to the broadcast server over the C API of the broadcast-client
+crate, see include/broadcast_client.h. Build the library first with
+
+    cargo build --release --features ffi
+
+and point BROADCAST_CLIENT_LIB at libbroadcast_client.so if it is not in
+../target/release relative to this file.
+
+    with BroadcastClient("localhost:9090") as client:
+        client.send("hello")
+        for message in client:
+            print(message.offset, message.text)
+"""
+
+import ctypes
+import os
+from typing import Iterator, NamedTuple, Optional
+
+_DEFAULT_LIB = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "target", "release", "libbroadcast_client.so")
+
+_MESSAGE_CB = ctypes.CFUNCTYPE(None, ctypes.c_void_p, ctypes.c_int64, ctypes.POINTER(ctypes.c_char), ctypes.c_size_t)
+
+_lib = ctypes.CDLL(os.environ.get("BROADCAST_CLIENT_LIB", _DEFAULT_LIB))
+_lib.bb_connect.argtypes = [ctypes.c_char_p, ctypes.c_uint32]
+_lib.bb_connect.restype = ctypes.c_void_p
+_lib.bb_on_message.argtypes = [ctypes.c_void_p, _MESSAGE_CB, ctypes.c_void_p]
+_lib.bb_send.argtypes = [ctypes.c_void_p, ctypes.c_char_p]
+_lib.bb_poll.argtypes = [ctypes.c_void_p, ctypes.c_int]
+_lib.bb_last_offset.argtypes = [ctypes.c_void_p]
+_lib.bb_last_offset.restype = ctypes.c_int64
+_lib.bb_close.argtypes = [ctypes.c_void_p]
+_lib.bb_last_error.restype = ctypes.c_char_p
+
+
+class Message(NamedTuple):
+    """A line from the server, offset is None for server notices."""
+    offset: Optional[int]
+    text: str
+
+
+def _error() -> OSError:
+    return OSError((_lib.bb_last_error() or b"unknown error").decode(errors="replace"))
+
+
+class BroadcastClient:
+    """Connection that reconnects and resumes on its own, like the Rust one.
+
+    max_attempts of 0 keeps retrying forever.
+    """
+
+    def __init__(self, addr: str, max_attempts: int = 0):
+        self._received = []
+        # kept referenced, the library calls it as long as the client lives
+        self._callback = _MESSAGE_CB(self._on_message)
+        self._client = _lib.bb_connect(addr.encode(), max_attempts)
+        if not self._client:
+            raise _error()
+        _lib.bb_on_message(self._client, self._callback, None)
+
+    def _on_message(self, _user, offset, text, length):
+        self._received.append(Message(None if offset < 0 else offset, ctypes.string_at(text, length).decode(errors="replace")))
+
+    def send(self, text: str) -> None:
+        if _lib.bb_send(self._handle(), text.encode()) < 0:
+            raise _error()
+
+    def recv(self, timeout: Optional[float] = None) -> Optional[Message]:
+        """The next message, None if none arrived within timeout seconds."""
+        timeout_ms = -1 if timeout is None else int(timeout * 1000)
+        if _lib.bb_poll(self._handle(), timeout_ms) < 0:
+            raise _error()
+        return self._received.pop(0) if self._received else None
+
+    @property
+    def last_offset(self) -> Optional[int]:
+        offset = _lib.bb_last_offset(self._handle())
+        return None if offset < 0 else offset
+
+    def close(self) -> None:
+        if self._client:
+            _lib.bb_close(self._client)
+            self._client = None
+
+    def _handle(self):
+        if not self._client:
+            raise ValueError("client is closed")
+        return self._client
+
+    def __iter__(self) -> Iterator[Message]:
+        while True:
+            message = self.recv()
+            if message is not None:
+                yield message
+
+    def __enter__(self) -> "BroadcastClient":
+        return self
+
+    def __exit__(self, *exc) -> None:
+        self.close()
+
+    def __del__(self):
+        self.close()
@@ -0,0 +1,151 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+
+use tokio::io::unix::AsyncFd;
+
+use crate::{parse_line, pong_for, resume_command, Backoff, Cursor, Message, Status, StatusHook};
+
+/// `BroadcastClient` for tokio applications: the same reconnecting and resuming
+/// behavior, with the socket registered in the runtimes reactor through `AsyncFd`
+/// instead of blocking a thread.
+pub struct AsyncBroadcastClient {
+    addr: String,
+    backoff: Backoff,
+    conn: Option<AsyncFd<TcpStream>>,
+    pending: Vec<u8>, // received bytes not yet returned as messages
+    cursor: Cursor,
+    status: Option<StatusHook>,
+}
+
+impl AsyncBroadcastClient {
+    /// Connects to addr, retrying according to backoff.
+    pub async fn connect(addr: &str, backoff: Backoff) -> Result<AsyncBroadcastClient> {
+        let mut client = AsyncBroadcastClient {
+            addr: addr.to_string(),
+            backoff,
+            conn: None,
+            pending: Vec::new(),
+            cursor: Cursor::default(),
+            status: None,
+        };
+        client.reconnect().await?;
+        Ok(client)
+    }
+
+    /// Offset of the last message returned by recv.
+    pub fn last_offset(&self) -> Option<u64> {
+        self.cursor.offset
+    }
+
+    /// Calls f whenever the client reconnects or notices dropped messages.
+    pub fn on_status(&mut self, f: impl FnMut(&Status) + Send + 'static) {
+        self.status = Some(Box::new(f));
+    }
+
+    /// Sends a line to everyone else, reconnecting first if needed.
+    pub async fn send(&mut self, text: &str) -> Result<()> {
+        let line = format!("{}\n", text.trim_end_matches('\n'));
+        loop {
+            if let Some(conn) = &self.conn {
+                if write_all(conn, line.as_bytes()).await.is_ok() {
+                    return Ok(());
+                }
+            }
+            self.reconnect().await?;
+        }
+    }
+
+    /// Waits for the next line, reconnecting and resuming as needed.
+    ///
+    /// Only fails once the backoff gives up.
+    pub async fn recv(&mut self) -> Result<Message> {
+        loop {
+            while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line[..end]).into_owned();
+                if let Some(pong) = pong_for(&line) {
+                    if let Some(conn) = &self.conn {
+                        let _ = write_all(conn, pong.as_bytes()).await;
+                    }
+                    continue;
+                }
+                let message = parse_line(&mut self.cursor, &line);
+                if let Some(missed) = self.cursor.take_missed() {
+                    self.report(Status::GapDetected { missed });
+                }
+                if let Some(message) = message {
+                    return Ok(message);
+                }
+            }
+
+            let mut buf = [0; 4096];
+            let received = match &self.conn {
+                Some(conn) => {
+                    let mut guard = conn.readable().await?;
+                    match guard.try_io(|stream| stream.get_ref().read(&mut buf)) {
+                        Ok(result) => result,
+                        Err(_would_block) => continue,
+                    }
+                }
+                None => Ok(0),
+            };
+            match received {
+                Ok(n) if n > 0 => self.pending.extend_from_slice(&buf[..n]),
+                _ => self.reconnect().await?, // eof or error
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        self.conn = None;
+        self.pending.clear(); // at most the start of a line cut off by the disconnect
+        self.cursor.seq = 0;
+        let mut delay = self.backoff.initial;
+        let mut failures = 0;
+
+        loop {
+            match self.open().await {
+                Ok(conn) => {
+                    self.conn = Some(conn);
+                    self.report(Status::Connected);
+                    return Ok(());
+                }
+                Err(e) => {
+                    failures += 1;
+                    if self.backoff.attempts.is_some_and(|max| failures >= max) {
+                        return Err(Error::new(e.kind(), format!("giving up on {} -- {}", self.addr, e)));
+                    }
+                    self.report(Status::Reconnecting { attempt: failures, delay });
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.backoff.max);
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, status: Status) {
+        if let Some(f) = self.status.as_mut() {
+            f(&status);
+        }
+    }
+
+    async fn open(&self) -> Result<AsyncFd<TcpStream>> {
+        let stream = tokio::net::TcpStream::connect(&self.addr).await?.into_std()?;
+        let conn = AsyncFd::new(stream)?;
+        write_all(&conn, resume_command(self.cursor.offset).as_bytes()).await?;
+        Ok(conn)
+    }
+}
+
+async fn write_all(conn: &AsyncFd<TcpStream>, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let mut guard = conn.writable().await?;
+        match guard.try_io(|stream| stream.get_ref().write(buf)) {
+            Ok(Ok(0)) => return Err(Error::from(ErrorKind::WriteZero)),
+            Ok(Ok(n)) => buf = &buf[n..],
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! C API over `BroadcastClient`, declared in include/broadcast_client.h.
+//!
+//! Every function returns -1 or NULL on failure, `bb_last_error` says why.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+use std::time::Duration;
+
+use crate::{Backoff, BroadcastClient, Status};
+
+pub const BB_CONNECTED: c_int = 0;
+pub const BB_RECONNECTING: c_int = 1;
+pub const BB_GAP_DETECTED: c_int = 2;
+
+/// Called with every message, offset is -1 for server notices. text is only
+/// valid during the call and not NUL terminated, len says how long it is.
+pub type MessageCallback = extern "C" fn(user: *mut c_void, offset: i64, text: *const c_char, len: usize);
+
+/// Called on connection changes with one of the BB_ codes and the reconnect
+/// attempt or the number of missed messages.
+pub type StatusCallback = extern "C" fn(user: *mut c_void, status: c_int, value: u64);
+
+pub struct Client {
+    inner: BroadcastClient,
+    on_message: Option<(MessageCallback, UserData)>,
+}
+
+/// The callers user pointer, it is only ever used on the thread calling in.
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail<T>(e: impl ToString, failed: T) -> T {
+    let msg = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+    failed
+}
+
+/// Connects to addr (`host:port`), trying up to max_attempts times with backoff,
+/// 0 tries forever.
+///
+/// # Safety
+///
+/// addr has to be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bb_connect(addr: *const c_char, max_attempts: u32) -> *mut Client {
+    if addr.is_null() {
+        return fail("addr is NULL", ptr::null_mut());
+    }
+    let addr = match CStr::from_ptr(addr).to_str() {
+        Ok(addr) => addr,
+        Err(e) => return fail(e, ptr::null_mut()),
+    };
+    let backoff = Backoff { attempts: Some(max_attempts).filter(|&n| n > 0), ..Backoff::default() };
+    match BroadcastClient::connect(addr, backoff) {
+        Ok(inner) => Box::into_raw(Box::new(Client { inner, on_message: None })),
+        Err(e) => fail(e, ptr::null_mut()),
+    }
+}
+
+/// Sets the callback bb_poll hands messages to, user is passed back as is.
+///
+/// # Safety
+///
+/// client has to come from bb_connect and not be closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bb_on_message(client: *mut Client, callback: MessageCallback, user: *mut c_void) {
+    if let Some(client) = client.as_mut() {
+        client.on_message = Some((callback, UserData(user)));
+    }
+}
+
+/// Sets the callback for connection changes, run from within bb_poll and bb_send.
+///
+/// # Safety
+///
+/// client has to come from bb_connect and not be closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bb_on_status(client: *mut Client, callback: StatusCallback, user: *mut c_void) {
+    if let Some(client) = client.as_mut() {
+        let user = UserData(user);
+        client.inner.on_status(move |status| {
+            let user = user;
+            let (code, value) = match status {
+                Status::Connected => (BB_CONNECTED, 0),
+                Status::Reconnecting { attempt, .. } => (BB_RECONNECTING, *attempt as u64),
+                Status::GapDetected { missed } => (BB_GAP_DETECTED, *missed),
+            };
+            callback(user.0, code, value);
+        });
+    }
+}
+
+/// Sends text as one message, a trailing newline is optional.
+///
+/// Returns 0, or -1 once reconnecting gives up.
+///
+/// # Safety
+///
+/// client has to come from bb_connect and not be closed yet, text has to be a
+/// NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bb_send(client: *mut Client, text: *const c_char) -> c_int {
+    let (client, text) = match (client.as_mut(), text.is_null()) {
+        (Some(client), false) => (client, CStr::from_ptr(text)),
+        _ => return fail("client or text is NULL", -1),
+    };
+    match client.inner.send(&text.to_string_lossy()) {
+        Ok(()) => 0,
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Waits up to timeout_ms (-1 forever, 0 not at all) for a message and hands it
+/// to the message callback.
+///
+/// Returns 1 if a message arrived, 0 if none did, -1 once reconnecting gives up.
+///
+/// # Safety
+///
+/// client has to come from bb_connect and not be closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bb_poll(client: *mut Client, timeout_ms: c_int) -> c_int {
+    let client = match client.as_mut() {
+        Some(client) => client,
+        None => return fail("client is NULL", -1),
+    };
+    let received = match timeout_ms {
+        0 => client.inner.try_recv(),
+        t if t < 0 => client.inner.recv().map(Some),
+        t => client.inner.recv_timeout(Some(Duration::from_millis(t as u64))),
+    };
+    match received {
+        Ok(Some(message)) => {
+            if let Some((callback, user)) = client.on_message {
+                let offset = message.offset.map_or(-1, |o| o as i64);
+                callback(user.0, offset, message.text.as_ptr() as *const c_char, message.text.len());
+            }
+            1
+        }
+        Ok(None) => 0,
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Offset of the last message received, -1 before the first one.
+///
+/// # Safety
+///
+/// client has to come from bb_connect and not be closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bb_last_offset(client: *const Client) -> i64 {
+    client.as_ref().and_then(|c| c.inner.last_offset()).map_or(-1, |o| o as i64)
+}
+
+/// Disconnects and frees client.
+///
+/// # Safety
+///
+/// client has to come from bb_connect, it must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bb_close(client: *mut Client) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Why the last call on this thread failed, NULL if none did. Valid until the
+/// next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn bb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
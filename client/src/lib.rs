@@ -0,0 +1,360 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use spool::Spool;
+
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+pub use async_client::AsyncBroadcastClient;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod spool;
+
+/// How long to wait between reconnect attempts. The delay doubles after every
+/// failed attempt, up to max.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Give up after this many failed attempts in a row, None retries forever.
+    pub attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff { initial: Duration::from_millis(100), max: Duration::from_secs(30), attempts: None }
+    }
+}
+
+/// A line received from the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// Position in the servers stream, None for server notices (lines starting with `*`).
+    pub offset: Option<u64>,
+    pub text: String,
+}
+
+/// Connection state changes, see `BroadcastClient::on_status`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Connected,
+    /// The connection dropped or could not be made, retrying after delay.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The server dropped missed messages meant for this client, because it
+    /// did not keep up with them.
+    GapDetected { missed: u64 },
+}
+
+/// Where a connection is in the servers stream.
+#[derive(Default)]
+struct Cursor {
+    offset: Option<u64>,
+    /// Sequence number of the last message on this connection, they count up
+    /// from 1 on every connection.
+    seq: u64,
+    /// Messages skipped since the last call to take_missed.
+    missed: u64,
+}
+
+impl Cursor {
+    fn take_missed(&mut self) -> Option<u64> {
+        Some(std::mem::take(&mut self.missed)).filter(|&n| n > 0)
+    }
+}
+
+type StatusHook = Box<dyn FnMut(&Status) + Send>;
+
+/// Connection to the broadcast server that reconnects on its own.
+///
+/// After every reconnect the client sends `/resume <offset>` with the offset of
+/// the last message it returned, so the server replays what was missed and
+/// `recv` carries on as if the connection had never dropped. Messages the server
+/// no longer has are reported by a `* resume gap` notice, those it dropped while
+/// connected by a `Status::GapDetected`.
+pub struct BroadcastClient {
+    addr: String,
+    backoff: Backoff,
+    conn: Option<(BufReader<TcpStream>, TcpStream)>,
+    pending: Vec<u8>, // start of a line that has not been received completely
+    cursor: Cursor,
+    status: Option<StatusHook>,
+    spool: Option<Spool>,
+}
+
+impl BroadcastClient {
+    /// A client for addr that connects on first use.
+    pub fn new(addr: &str, backoff: Backoff) -> BroadcastClient {
+        BroadcastClient {
+            addr: addr.to_string(),
+            backoff,
+            conn: None,
+            pending: Vec::new(),
+            cursor: Cursor::default(),
+            status: None,
+            spool: None,
+        }
+    }
+
+    /// Connects to addr, retrying according to backoff.
+    pub fn connect(addr: &str, backoff: Backoff) -> Result<BroadcastClient> {
+        let mut client = BroadcastClient::new(addr, backoff);
+        client.reconnect()?;
+        Ok(client)
+    }
+
+    /// Calls f whenever the client connects or starts waiting to reconnect.
+    pub fn on_status(&mut self, f: impl FnMut(&Status) + Send + 'static) {
+        self.status = Some(Box::new(f));
+    }
+
+    /// Keeps lines send could not get out in the file at path, and sends them
+    /// first thing after the next connect, so they survive a flaky link and
+    /// restarts of the process alike. Lines an earlier process left there are
+    /// sent as soon as the client is connected.
+    ///
+    /// With a spool send, recv_timeout and try_recv don't wait for the
+    /// connection to come back, the latter two return None after one attempt to
+    /// connect. A line may be sent twice if the connection drops while it goes out.
+    pub fn spool_to(&mut self, path: &Path) -> Result<()> {
+        let spool = Spool::open(path)?;
+        let pending = !spool.is_empty();
+        self.spool = Some(spool);
+        if pending && self.conn.is_some() {
+            self.send_spooled();
+        }
+        Ok(())
+    }
+
+    /// Whether the client has a connection, as far as it knows.
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Offset of the last message returned by recv.
+    pub fn last_offset(&self) -> Option<u64> {
+        self.cursor.offset
+    }
+
+    /// Sends a line to everyone else, reconnecting first if needed. A line that was
+    /// being sent while the connection dropped is sent again.
+    ///
+    /// With a spool, see spool_to, the line goes through the spool and stays
+    /// there if the connection is down and one attempt to connect fails. Then
+    /// this only fails if the spool can't be written.
+    pub fn send(&mut self, text: &str) -> Result<()> {
+        let line = format!("{}\n", text.trim_end_matches('\n'));
+        if let Some(spool) = self.spool.as_mut() {
+            spool.push(&line)?;
+            self.send_spooled();
+            return Ok(());
+        }
+        loop {
+            if let Some((_, writer)) = self.conn.as_mut() {
+                if writer.write_all(line.as_bytes()).is_ok() {
+                    return Ok(());
+                }
+            }
+            self.reconnect()?;
+        }
+    }
+
+    /// Waits for the next line, reconnecting and resuming as needed. Latency
+    /// probes are answered here and never returned.
+    ///
+    /// Only fails once the backoff gives up.
+    pub fn recv(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.recv_timeout(None)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Like recv, but returns None if no complete line arrived within timeout.
+    /// Time spent reconnecting does not count.
+    pub fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        self.recv_with(timeout, false)
+    }
+
+    /// Like recv, but returns None right away unless a complete line has already
+    /// arrived, for polling from a loop that has other work to do. Reconnecting
+    /// still blocks.
+    pub fn try_recv(&mut self) -> Result<Option<Message>> {
+        let received = self.recv_with(None, true);
+        if let Some((reader, _)) = self.conn.as_mut() {
+            // the writer shares the socket, send must not see WouldBlock
+            reader.get_ref().set_nonblocking(false)?;
+        }
+        received
+    }
+
+    /// Messages as they arrive, see recv. Ends after the first error.
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages { client: self, failed: false }
+    }
+
+    fn recv_with(&mut self, timeout: Option<Duration>, nonblocking: bool) -> Result<Option<Message>> {
+        loop {
+            if let Some((reader, _)) = self.conn.as_mut() {
+                reader.get_ref().set_nonblocking(nonblocking)?;
+                reader.get_ref().set_read_timeout(timeout)?;
+                match reader.read_until(b'\n', &mut self.pending) {
+                    Ok(n) if n > 0 && self.pending.ends_with(b"\n") => {
+                        let line = std::mem::take(&mut self.pending);
+                        let line = String::from_utf8_lossy(&line[..line.len() - 1]).into_owned();
+                        if let Some(pong) = pong_for(&line) {
+                            if let Some((_, writer)) = self.conn.as_mut() {
+                                let _ = writer.write_all(pong.as_bytes());
+                            }
+                            continue;
+                        }
+                        let message = parse_line(&mut self.cursor, &line);
+                        if let Some(missed) = self.cursor.take_missed() {
+                            self.report(Status::GapDetected { missed });
+                        }
+                        if let Some(message) = message {
+                            return Ok(Some(message));
+                        }
+                        continue;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(None),
+                    _ => {} // eof, error, or a line cut off by the disconnect
+                }
+            }
+            if self.spool.is_some() && (timeout.is_some() || nonblocking) {
+                // spooling clients don't wait out an outage, see spool_to
+                if self.try_connect().is_err() {
+                    return Ok(None);
+                }
+                continue;
+            }
+            self.reconnect()?;
+        }
+    }
+
+    /// Writes out the spool, connecting once if there is no connection or it
+    /// dropped. What can't be written stays spooled for the next connect.
+    pub fn send_spooled(&mut self) {
+        let flushed = match (self.spool.as_mut(), self.conn.as_mut()) {
+            (Some(spool), Some((_, writer))) => spool.flush(writer).is_ok(),
+            _ => false,
+        };
+        if !flushed {
+            let _ = self.try_connect();
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let mut delay = self.backoff.initial;
+        let mut failures = 0;
+
+        loop {
+            match self.try_connect() {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    failures += 1;
+                    if self.backoff.attempts.is_some_and(|max| failures >= max) {
+                        return Err(Error::new(e.kind(), format!("giving up on {} -- {}", self.addr, e)));
+                    }
+                    self.report(Status::Reconnecting { attempt: failures, delay });
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(self.backoff.max);
+                }
+            }
+        }
+    }
+
+    /// One attempt to connect, sending whatever is spooled before anything else.
+    fn try_connect(&mut self) -> Result<()> {
+        self.conn = None;
+        self.pending.clear();
+        self.cursor.seq = 0;
+        let mut conn = self.open()?;
+        if let Some(spool) = self.spool.as_mut() {
+            spool.flush(&mut conn.1)?;
+        }
+        self.conn = Some(conn);
+        self.report(Status::Connected);
+        Ok(())
+    }
+
+    fn report(&mut self, status: Status) {
+        if let Some(f) = self.status.as_mut() {
+            f(&status);
+        }
+    }
+
+    fn open(&self) -> Result<(BufReader<TcpStream>, TcpStream)> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        stream.write_all(resume_command(self.cursor.offset).as_bytes())?;
+        let reader = stream.try_clone()?;
+        Ok((BufReader::new(reader), stream))
+    }
+}
+
+/// Iterator over received messages, see `BroadcastClient::messages`.
+pub struct Messages<'a> {
+    client: &'a mut BroadcastClient,
+    failed: bool,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Result<Message>> {
+        if self.failed {
+            return None;
+        }
+        let received = self.client.recv();
+        self.failed = received.is_err();
+        Some(received)
+    }
+}
+
+/// The commands asking the server for everything after last_offset, and to
+/// number messages on the connection.
+fn resume_command(last_offset: Option<u64>) -> String {
+    match last_offset {
+        Some(offset) => format!("/resume {}\n/seq\n", offset),
+        None => "/resume\n/seq\n".to_string(),
+    }
+}
+
+/// The answer to a latency probe, sent as soon as it is read so the server
+/// measures how far behind the client is. Probes only come after `/probe`.
+fn pong_for(line: &str) -> Option<String> {
+    line.strip_prefix("* probe ").map(|id| format!("/pong {}\n", id))
+}
+
+/// Parses a received line (without its newline), advancing the cursor.
+///
+/// Returns None for lines already seen and lines that only concern the connection.
+fn parse_line(cursor: &mut Cursor, line: &str) -> Option<Message> {
+    if let Some((tag, text)) = line.strip_prefix('@').and_then(|l| l.split_once(' ')) {
+        // `@<offset>:<seq>` live, replays only carry the offset
+        let (offset, seq) = match tag.split_once(':') {
+            Some((offset, seq)) => (offset, seq.parse::<u64>().ok()),
+            None => (tag, None),
+        };
+        if let Ok(offset) = offset.parse::<u64>() {
+            if let Some(seq) = seq {
+                cursor.missed += seq.saturating_sub(cursor.seq + 1);
+                cursor.seq = seq;
+            }
+            // replays may overlap with what was already seen
+            if cursor.offset.is_some_and(|last| offset <= last) {
+                return None;
+            }
+            cursor.offset = Some(offset);
+            return Some(Message { offset: Some(offset), text: text.to_string() });
+        }
+    }
+    if line.starts_with("* offsets on") || line.starts_with("* resumed after") || line.starts_with("* seq on") {
+        return None;
+    }
+    Some(Message { offset: None, text: line.to_string() })
+}
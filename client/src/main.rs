@@ -1,11 +1,16 @@
 use std::io::{Read, Write};
 use std::net::{TcpStream};
+use std::path::PathBuf;
 use std::thread;
 use std::time::{Duration};
 use rand::{thread_rng, Rng};
 
 use structopt::StructOpt;
 
+mod pipe;
+#[cfg(feature = "tui")]
+mod tui;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "socket_client")]
 struct Opt {
@@ -18,11 +23,47 @@ struct Opt {
     full_batch: u64,
     #[structopt(short="P", long, default_value = "2")]
     partial_batch: u64,
+    /// Chat interactively instead of sending test messages (needs the tui feature)
+    #[structopt(long)]
+    tui: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Broadcast lines from stdin and write broadcasts to stdout
+    Pipe {
+        /// Keep writing broadcasts after stdin is closed
+        #[structopt(long)]
+        keep_open: bool,
+        /// Keep lines that can't be sent in this file, to send them once connected
+        #[structopt(long, parse(from_os_str))]
+        spool: Option<PathBuf>,
+    },
 }
 
 fn main() {
     let opt = Opt::from_args();
 
+    if let Some(Command::Pipe { keep_open, spool }) = opt.command {
+        if let Err(e) = pipe::run(&format!("localhost:{}", opt.port), keep_open, spool.as_deref()) {
+            eprintln!("pipe failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if opt.tui {
+        #[cfg(feature = "tui")]
+        if let Err(e) = tui::run(&format!("localhost:{}", opt.port)) {
+            println!("tui failed: {}", e);
+        }
+        #[cfg(not(feature = "tui"))]
+        println!("built without the tui feature, try cargo run --features tui -- --tui");
+        return;
+    }
+
     let message = "Hello World, UIC CS463 was here!\n";
     let addr = format!("localhost:{}", opt.port);
 
@@ -0,0 +1,60 @@
+use std::io::{BufRead, Result, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use broadcast_client::{Backoff, BroadcastClient};
+
+/// How often a spooling pipe tries to connect while it is offline.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Broadcasts every line read from stdin and writes every broadcast to stdout,
+/// e.g. `uptime | client pipe` from cron or `client pipe --keep-open </dev/null | grep ERROR`.
+///
+/// Returns once stdin is closed and its lines are sent or spooled, unless
+/// keep_open is set.
+pub fn run(addr: &str, keep_open: bool, spool: Option<&Path>) -> Result<()> {
+    let (lines, input) = mpsc::channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let sent = line.map(|line| lines.send(line).is_ok());
+            if !sent.unwrap_or(false) {
+                return;
+            }
+        }
+    });
+
+    let mut client = BroadcastClient::new(addr, Backoff::default());
+    if let Some(path) = spool {
+        client.spool_to(path)?;
+    }
+    let mut stdout = std::io::stdout();
+    loop {
+        if spool.is_some() && !client.is_connected() {
+            // offline, spool stdin and try to connect now and then
+            match input.recv_timeout(RETRY_INTERVAL) {
+                Ok(line) => client.send(&line)?,
+                Err(RecvTimeoutError::Disconnected) if !keep_open => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(RETRY_INTERVAL);
+                    client.send_spooled();
+                }
+                Err(RecvTimeoutError::Timeout) => client.send_spooled(),
+            }
+            continue;
+        }
+        loop {
+            match input.try_recv() {
+                Ok(line) => client.send(&line)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) if keep_open => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        if let Some(message) = client.recv_timeout(Some(Duration::from_millis(50)))? {
+            writeln!(stdout, "{}", message.text)?;
+        }
+    }
+}
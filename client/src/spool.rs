@@ -0,0 +1,48 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Result, Write};
+use std::path::Path;
+
+/// Lines not sent yet, kept in a file so they outlive the process. Each line is
+/// synced to disk before it goes out, and the file is emptied once everything in
+/// it was written to a connection.
+pub(crate) struct Spool {
+    file: File,
+    lines: Vec<String>,
+}
+
+impl Spool {
+    /// Opens or creates the spool at path, with what an earlier process left.
+    pub(crate) fn open(path: &Path) -> Result<Spool> {
+        let file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut lines = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            lines.push(format!("{}\n", line?));
+        }
+        Ok(Spool { file, lines })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Adds a line, ending in a newline, to the end of the spool.
+    pub(crate) fn push(&mut self, line: &str) -> Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.lines.push(line.to_string());
+        Ok(())
+    }
+
+    /// Writes every line to writer and empties the spool. On error all of them
+    /// are kept, those written already are sent again next time.
+    pub(crate) fn flush(&mut self, writer: &mut impl Write) -> Result<()> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        writer.write_all(self.lines.concat().as_bytes())?;
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.lines.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeSet;
+use std::io::Result;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use broadcast_client::{Backoff, BroadcastClient, Message, Status};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Tabs};
+use ratatui::{DefaultTerminal, Frame};
+
+/// Name of the tab showing everything not sent to a room.
+const MAIN_TAB: &str = "main";
+
+enum NetEvent {
+    Message(Message),
+    Status(Status),
+    Failed(String),
+}
+
+struct Room {
+    name: String,
+    lines: Vec<String>,
+    unread: usize,
+}
+
+impl Room {
+    fn new(name: &str) -> Room {
+        Room { name: name.to_string(), lines: Vec::new(), unread: 0 }
+    }
+}
+
+/// Chat interface for `--tui`.
+///
+/// A line starting with `#room ` belongs to that rooms tab. `/join #room` opens a
+/// tab and joins the room on the server, `/part` closes the current one and leaves it.
+/// Messages are sent as `<nick> text` once a nick is set with `/nick`, which is
+/// also where Tab completion gets names from.
+///
+/// Keys: Enter sends, Tab completes a nick, Ctrl-N/Ctrl-P switch tabs,
+/// PageUp/PageDown scroll and Esc or Ctrl-C quits.
+struct App {
+    rooms: Vec<Room>,
+    active: usize,
+    input: String,
+    scroll: usize, // lines scrolled up from the bottom
+    status: String,
+    nick: Option<String>,
+    nicks: BTreeSet<String>,
+    outgoing: Sender<String>,
+    quit: bool,
+}
+
+pub fn run(addr: &str) -> Result<()> {
+    let (events, net) = mpsc::channel();
+    let (outgoing, lines) = mpsc::channel();
+    let addr = addr.to_string();
+    thread::spawn(move || network(&addr, events, lines));
+
+    let mut app = App {
+        rooms: vec![Room::new(MAIN_TAB)],
+        active: 0,
+        input: String::new(),
+        scroll: 0,
+        status: "connecting...".to_string(),
+        nick: None,
+        nicks: BTreeSet::new(),
+        outgoing,
+        quit: false,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, net);
+    ratatui::restore();
+    result
+}
+
+/// Owns the connection, forwarding what arrives to the interface and sending
+/// what the interface queued.
+fn network(addr: &str, events: Sender<NetEvent>, lines: Receiver<String>) {
+    let mut client = BroadcastClient::new(addr, Backoff::default());
+    let status = events.clone();
+    client.on_status(move |s| {
+        let _ = status.send(NetEvent::Status(s.clone()));
+    });
+
+    loop {
+        match client.recv_timeout(Some(Duration::from_millis(50))) {
+            Ok(Some(message)) => {
+                if events.send(NetEvent::Message(message)).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let _ = events.send(NetEvent::Failed(e.to_string()));
+                return;
+            }
+        }
+
+        loop {
+            match lines.try_recv() {
+                Ok(line) => {
+                    if let Err(e) = client.send(&line) {
+                        let _ = events.send(NetEvent::Failed(e.to_string()));
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal, net: Receiver<NetEvent>) -> Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if event::poll(Duration::from_millis(50))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.key(key);
+                    }
+                }
+            }
+            while let Ok(event) = net.try_recv() {
+                self.net_event(event);
+            }
+        }
+        Ok(())
+    }
+
+    fn net_event(&mut self, event: NetEvent) {
+        match event {
+            NetEvent::Message(message) => self.receive(&message.text),
+            NetEvent::Status(Status::Connected) => self.status = "connected".to_string(),
+            NetEvent::Status(Status::Reconnecting { attempt, delay }) => {
+                self.status = format!("disconnected, retry {} in {:?}", attempt, delay);
+            }
+            NetEvent::Status(Status::GapDetected { missed }) => self.receive(&format!("* missed {} messages", missed)),
+            NetEvent::Failed(e) => self.status = format!("connection failed: {}", e),
+        }
+    }
+
+    /// Files an incoming line under its room, opening a tab for rooms not seen yet.
+    fn receive(&mut self, text: &str) {
+        let (room, text) = match text.split_once(' ') {
+            Some((room, rest)) if room.starts_with('#') && room.len() > 1 => (room, rest),
+            _ => (MAIN_TAB, text),
+        };
+        if let Some(nick) = text.strip_prefix('<').and_then(|t| t.split_once('>')).map(|(n, _)| n) {
+            if !nick.is_empty() && !nick.contains(' ') {
+                self.nicks.insert(nick.to_string());
+            }
+        }
+
+        let i = self.room(room);
+        self.rooms[i].lines.push(text.to_string());
+        if i != self.active {
+            self.rooms[i].unread += 1;
+        } else if self.scroll > 0 {
+            self.scroll += 1; // keep the view where the reader left it
+        }
+    }
+
+    /// Index of the named room, opening it if needed.
+    fn room(&mut self, name: &str) -> usize {
+        match self.rooms.iter().position(|r| r.name == name) {
+            Some(i) => i,
+            None => {
+                self.rooms.push(Room::new(name));
+                self.rooms.len() - 1
+            }
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::Char('n') if ctrl => self.switch((self.active + 1) % self.rooms.len()),
+            KeyCode::Char('p') if ctrl => self.switch((self.active + self.rooms.len() - 1) % self.rooms.len()),
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Tab => self.complete(),
+            KeyCode::PageUp => {
+                let len = self.rooms[self.active].lines.len();
+                self.scroll = (self.scroll + 10).min(len.saturating_sub(1));
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Enter => self.submit(),
+            _ => {}
+        }
+    }
+
+    fn switch(&mut self, to: usize) {
+        self.active = to;
+        self.rooms[to].unread = 0;
+        self.scroll = 0;
+    }
+
+    /// Completes the word before the cursor to the first nick it is a prefix of.
+    fn complete(&mut self) {
+        let start = self.input.rfind(' ').map_or(0, |i| i + 1);
+        let word = &self.input[start..];
+        if word.is_empty() {
+            return;
+        }
+        if let Some(nick) = self.nicks.iter().find(|n| n.starts_with(word) && n.as_str() != word) {
+            let suffix = if start == 0 { ": " } else { " " };
+            let nick = format!("{}{}", nick, suffix);
+            self.input.replace_range(start.., &nick);
+        }
+    }
+
+    fn submit(&mut self) {
+        let input = std::mem::take(&mut self.input);
+        let line = input.trim();
+        if line.is_empty() {
+            return;
+        }
+        self.scroll = 0;
+
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next()) {
+            (Some("/join"), Some(room)) if room.starts_with('#') && room.len() > 1 => {
+                let i = self.room(room);
+                self.switch(i);
+            }
+            (Some("/part"), None) if self.active != 0 => {
+                let room = self.rooms.remove(self.active);
+                let _ = self.outgoing.send(format!("/part {}", room.name));
+                self.switch(self.active - 1);
+                return;
+            }
+            (Some("/nick"), Some(nick)) => self.nick = Some(nick.to_string()),
+            _ => {}
+        }
+        if line.starts_with('/') {
+            // server commands are passed on and answered in the main tab
+            let _ = self.outgoing.send(line.to_string());
+            return;
+        }
+
+        let text = match &self.nick {
+            Some(nick) => format!("<{}> {}", nick, line),
+            None => line.to_string(),
+        };
+        let room = &self.rooms[self.active];
+        let sent = if self.active == 0 { text.clone() } else { format!("{} {}", room.name, text) };
+        let _ = self.outgoing.send(sent);
+        // the server does not echo our own messages
+        self.rooms[self.active].lines.push(text);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [tabs, scrollback, input, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(1),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+
+        let titles = self.rooms.iter().map(|r| match r.unread {
+            0 => r.name.clone(),
+            n => format!("{} ({})", r.name, n),
+        });
+        let highlight = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+        frame.render_widget(Tabs::new(titles).select(self.active).highlight_style(highlight), tabs);
+
+        let room = &self.rooms[self.active];
+        let height = scrollback.height as usize;
+        let end = room.lines.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let lines: Vec<Line> = room.lines[start..end].iter().map(|l| Line::raw(l.as_str())).collect();
+        frame.render_widget(Paragraph::new(lines), scrollback);
+
+        let title = self.nick.as_deref().unwrap_or("no nick, set one with /nick");
+        let prompt = Paragraph::new(self.input.as_str()).block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(prompt, input);
+        frame.set_cursor_position((input.x + 1 + self.input.chars().count() as u16, input.y + 1));
+
+        let scrolled = if self.scroll > 0 { format!(" | scrolled up {} lines", self.scroll) } else { String::new() };
+        let bar = Paragraph::new(format!("{}{}", self.status, scrolled)).style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(bar, status);
+    }
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use epollserver::async_server::AsyncBroadcastServer;
+use epollserver::events::Event;
+
+/// Runs the broadcast server as a task of a tokio application, logging every
+/// message and connection and announcing the number of clients every ten seconds.
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    let port = std::env::args().nth(1).unwrap_or_else(|| "9090".to_string());
+    let mut server = AsyncBroadcastServer::bind(&format!("localhost:{}", port))?;
+    server.on_event(|event| match event {
+        Event::Connected { fd, peer } => println!("client {} connected from {}", fd, peer),
+        Event::Disconnected { fd, reason } => println!("client {} left ({})", fd, reason),
+        _ => {}
+    });
+    let mut ticks = tokio::time::interval(Duration::from_secs(10));
+
+    loop {
+        tokio::select! {
+            message = server.next_broadcast() => {
+                println!("broadcast: {}", String::from_utf8_lossy(&message?));
+            }
+            _ = ticks.tick() => {
+                let notice = format!("* {} clients connected", server.clients());
+                server.broadcast(notice.as_bytes());
+            }
+        }
+    }
+}
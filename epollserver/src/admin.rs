@@ -0,0 +1,530 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::bans::parse_duration;
+use crate::busypoll;
+use crate::commands::Level;
+use crate::rooms::{Qos, Rooms};
+use crate::logging::{self, LogLevel, Subsystem};
+use crate::mirror::Mirror;
+use crate::sys::Sys;
+use crate::{ClientState, EpollServer, Mute};
+
+const MAX_LINE: usize = 1024;
+
+const HELP: &str = "\
+commands:
+  kick <nick|fd>              disconnect a client
+  ban <nick|ip> [duration]    disconnect and refuse an address, e.g. `ban 10.0.0.5 2h`
+  unban <ip>                  lift a ban
+  mute <nick|fd> [shadow]     drop a clients messages, shadow mutes echo them back to it
+  unmute <nick|fd>            let a client talk again
+  op <nick|fd>                let a client run operator commands like /mute
+  deop <nick|fd>              take that back
+  bans                        list active bans
+  greylist                    list addresses refused for reconnecting too often
+  greylist add <ip> [duration]
+                              refuse an address for a while, --greylist-secs by default
+  greylist remove <ip>        let an address in again
+  greylist limit [n|off]      show or set how many connects a minute get an address greylisted
+  drain [duration]            stop accepting, flush clients for up to duration (10s), then exit
+  dump                        write a state snapshot to the dump file or stderr, like SIGUSR1
+  snapshot [path]             save bans, topics and history for --restore, to --snapshot-file by default
+  list [page]                 list connected clients with address, rooms, idle time and queue
+  topic <#room> [text]        set the retained topic of a room, clear it without text
+  rooms                       list rooms with their member count and topic
+  room create <room>          add a room that stays until deleted, rooms are [tenant/]#name
+  room delete <room>          close a room, telling its members
+  room lock|unlock <room>     make a room invite only, or open it again
+  room cap <room> <n|off>     limit how many members a room takes
+  room invite <room> <nick|fd>
+                              let a client join a locked room
+  room move <nick|fd> <#from> <#to>
+                              move a client into another room of its tenant
+  reload-script               recompile the --script hooks
+  reload-rules                read the [rule.<name>] sections of --config again
+  log-level [level]           show or set the log level: error, warn, info or debug
+  busy-poll [us|off]          show or set how long event loops spin before they wait
+  debug <subsystem> <on|off>  toggle debug output of framing, epoll, broadcast or all
+  reset-counters              zero the metrics counters and histograms
+  mirror [<#room> <percent>% | off]
+                              show, start or stop copying a sample of all messages into a room
+
+GET /healthz and GET /readyz answer HTTP probes of the event loop, listeners and drain.
+";
+
+/// Line based operator interface, only bound on localhost.
+pub struct AdminEndpoint {
+    listener: TcpListener,
+    conns: HashMap<i32, (TcpStream, Vec<u8>)>,
+}
+
+impl AdminEndpoint {
+    pub fn bind(port: u16) -> Result<AdminEndpoint> {
+        let listener = TcpListener::bind(format!("localhost:{}", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(AdminEndpoint { listener, conns: HashMap::new() })
+    }
+
+    pub fn listener_fd(&self) -> i32 {
+        self.listener.as_raw_fd()
+    }
+
+    pub fn owns(&self, fd: i32) -> bool {
+        fd == self.listener_fd() || self.conns.contains_key(&fd)
+    }
+
+    /// Accepts a new operator, or reads complete command lines from an existing one.
+    pub fn read_commands(&mut self, sys: &dyn Sys, fd: i32) -> Vec<String> {
+        if fd == self.listener_fd() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                let afd = stream.as_raw_fd();
+                if stream.set_nonblocking(true).is_ok() && sys.watch(afd).is_ok() {
+                    self.conns.insert(afd, (stream, Vec::new()));
+                }
+            }
+            return Vec::new();
+        }
+
+        let (stream, pending) = match self.conns.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return Vec::new(),
+        };
+
+        let mut buf = [0; 512];
+        let closed = match stream.read(&mut buf) {
+            Ok(0) => true,
+            Ok(n) => {
+                pending.extend_from_slice(&buf[..n]);
+                pending.len() > MAX_LINE && !pending.contains(&b'\n')
+            }
+            Err(e) => e.kind() != ErrorKind::WouldBlock,
+        };
+
+        let mut lines = Vec::new();
+        while let Some(i) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=i).collect();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+
+        if closed {
+            sys.unwatch(fd);
+            self.conns.remove(&fd);
+        }
+
+        lines
+    }
+
+    pub fn reply(&mut self, fd: i32, text: &str) {
+        if let Some((stream, _)) = self.conns.get_mut(&fd) {
+            let _ = stream.write_all(text.as_bytes());
+        }
+    }
+
+    /// Answers an HTTP request, see health.rs, and hangs up.
+    pub fn respond(&mut self, sys: &dyn Sys, fd: i32, status: u16, body: &str) {
+        if let Some((mut stream, _)) = self.conns.remove(&fd) {
+            let _ = stream.write_all(crate::health::response(status, body).as_bytes());
+            sys.unwatch(fd);
+        }
+    }
+}
+
+/// Runs one operator command and returns the reply text.
+pub fn execute(line: &str, epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) -> String {
+    let mut args = line.split_whitespace();
+    let reply = match (args.next(), args.next(), args.next()) {
+        (None, _, _) => return String::new(),
+        (Some("help"), _, _) => return HELP.to_string(),
+        (Some("kick"), Some(target), None) => match find_client(clients, target) {
+            Some(cfd) => {
+                kick(epserver, cfd, clients, "kicked");
+                Ok(format!("kicked {}", target))
+            }
+            None => Err(format!("no client {}", target)),
+        },
+        (Some("ban"), Some(target), duration) => ban(target, duration, epserver, clients),
+        (Some("unban"), Some(ip), None) => match ip.parse::<IpAddr>() {
+            Ok(ip) => match epserver.bans.unban(ip) {
+                Ok(true) => Ok(format!("unbanned {}", ip)),
+                Ok(false) => Err(format!("{} is not banned", ip)),
+                Err(e) => Err(format!("failed to save ban list -- {}", e)),
+            },
+            Err(_) => Err(format!("invalid address {}", ip)),
+        },
+        (Some("mute"), Some(target), shadow) if shadow.is_none() || shadow == Some("shadow") => {
+            let mute = if shadow.is_some() { Mute::Shadow } else { Mute::Muted };
+            set_mute(clients, target, mute)
+        }
+        (Some("unmute"), Some(target), None) => set_mute(clients, target, Mute::Off),
+        (Some("op"), Some(target), None) => set_level(clients, target, Level::Operator),
+        (Some("deop"), Some(target), None) => set_level(clients, target, Level::User),
+        (Some("reload-script"), None, None) => match epserver.scripts.as_mut() {
+            Some(scripts) => scripts.reload().map(|_| "reloaded script".to_string()).map_err(|e| e.to_string()),
+            None => Err("no script loaded".to_string()),
+        },
+        (Some("reload-rules"), None, None) => match epserver.rules.reload() {
+            Ok(()) => Ok(format!("reloaded {} rules", epserver.rules.len())),
+            Err(e) => Err(e.to_string()),
+        },
+        (Some("log-level"), None, None) => Ok(format!("log level is {}", logging::level().name())),
+        (Some("log-level"), Some(level), None) => level.parse::<LogLevel>().map(|level| {
+            logging::set_level(level);
+            format!("log level is {}", level.name())
+        }),
+        (Some("debug"), Some(sub), Some(state)) => set_debug(sub, state),
+        (Some("busy-poll"), spin, None) => busy_poll(spin),
+        (Some("reset-counters"), None, None) => {
+            crate::metrics::reset();
+            epserver.filters.reset_hits();
+            epserver.rules.reset_hits();
+            epserver.room_stats.reset();
+            for tenant in &epserver.tenants {
+                tenant.messages.set(0);
+            }
+            Ok("counters reset".to_string())
+        }
+        (Some("topic"), Some(room), _) => {
+            let text = line.splitn(3, char::is_whitespace).nth(2).map(str::trim).filter(|t| !t.is_empty());
+            if crate::rooms::valid_name(room) {
+                epserver.rooms.borrow_mut().set_topic(room, text.map(str::to_string));
+                Ok(format!("topic of {} {}", room, if text.is_some() { "set" } else { "cleared" }))
+            } else {
+                Err(format!("invalid room name {}", room))
+            }
+        }
+        (Some("greylist"), None, None) => {
+            let mut out = String::new();
+            for (ip, left) in epserver.greylist.listed(epserver.sys.now()) {
+                out.push_str(&format!("{} for {}s\n", ip, left.as_secs()));
+            }
+            return out;
+        }
+        (Some("greylist"), Some(_), _) => {
+            let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+            greylist(&args, epserver, clients)
+        }
+        (Some("mirror"), None, None) => Ok(epserver.mirror.as_ref().map_or_else(|| "not mirroring".to_string(), Mirror::describe)),
+        (Some("mirror"), Some("off"), None) => match epserver.mirror.take() {
+            Some(mirror) => Ok(format!("stopped {}", mirror.describe())),
+            None => Err("not mirroring".to_string()),
+        },
+        (Some("mirror"), Some(room), Some(percent)) if args.next().is_none() => {
+            match percent.strip_suffix('%').and_then(|p| p.parse::<f64>().ok()).filter(|p| *p > 0.0 && *p <= 100.0) {
+                Some(percent) if crate::rooms::valid_name(room) => {
+                    let mirror = Mirror::new(room, percent);
+                    let reply = mirror.describe();
+                    epserver.mirror = Some(mirror);
+                    Ok(reply)
+                }
+                Some(_) => Err(format!("invalid room name {}", room)),
+                None => Err("usage: mirror <#room> <percent>%, e.g. `mirror #debug 1%`".to_string()),
+            }
+        }
+        (Some("room"), Some(_), _) => {
+            let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+            room(&args, epserver, clients)
+        }
+        (Some("rooms"), None, None) => {
+            let mut out = String::new();
+            for (tenant, rooms) in epserver.namespaces() {
+                for room in rooms.borrow().iter() {
+                    let prefix = if tenant.is_empty() { String::new() } else { format!("{}/", tenant) };
+                    out.push_str(&format!("{}{} {} members", prefix, room.name, room.members.len()));
+                    if room.locked {
+                        out.push_str(", locked");
+                    }
+                    if let Some(cap) = room.cap {
+                        out.push_str(&format!(", cap {}", cap));
+                    }
+                    if let Some(topic) = &room.topic {
+                        out.push_str(&format!(", topic: {}", topic));
+                    }
+                    out.push('\n');
+                }
+            }
+            return out;
+        }
+        (Some("drain"), duration, None) => match duration.map(parse_duration).unwrap_or(Some(epserver.drain_timeout)) {
+            Some(timeout) => {
+                crate::drain::start(epserver, clients, timeout);
+                Ok(format!("draining {} clients", clients.len()))
+            }
+            None => Err("usage: drain [duration]".to_string()),
+        },
+        (Some("dump"), None, None) => match crate::dump::write(epserver, clients) {
+            Ok(target) => Ok(format!("wrote state to {}", target)),
+            Err(e) => Err(format!("failed to write state -- {}", e)),
+        },
+        (Some("snapshot"), path, None) => match path.map(PathBuf::from).or_else(|| epserver.snapshot_path.clone()) {
+            Some(path) => match crate::snapshot::save(epserver, &path) {
+                Ok(()) => Ok(format!("saved snapshot to {}", path.display())),
+                Err(e) => Err(format!("failed to save snapshot -- {}", e)),
+            },
+            None => Err("usage: snapshot <path>, or start with --snapshot-file".to_string()),
+        },
+        (Some("list"), page, None) => match page.map(str::parse::<usize>).unwrap_or(Ok(1)) {
+            Ok(page) => return crate::who::list(epserver, clients, None, page, |_| true),
+            Err(_) => Err("usage: list [page]".to_string()),
+        },
+        (Some("bans"), None, None) => {
+            let mut out = String::new();
+            for ban in epserver.bans.iter() {
+                match ban.until {
+                    Some(until) => {
+                        let left = until.duration_since(std::time::SystemTime::now()).unwrap_or_default();
+                        out.push_str(&format!("{} for {}s\n", ban.ip, left.as_secs()));
+                    }
+                    None => out.push_str(&format!("{} forever\n", ban.ip)),
+                }
+            }
+            return out;
+        }
+        _ => Err(format!("unknown command `{}`, try `help`", line)),
+    };
+
+    match reply {
+        Ok(msg) => format!("ok: {}\n", msg),
+        Err(msg) => format!("error: {}\n", msg),
+    }
+}
+
+/// Shows or sets the spin budget of every event loop, see busypoll.rs.
+fn busy_poll(spin: Option<&str>) -> std::result::Result<String, String> {
+    let us = match spin {
+        Some("off") => Some(0),
+        Some(us) => Some(us.parse::<u64>().map_err(|_| "usage: busy-poll [us|off]".to_string())?),
+        None => None,
+    };
+    if let Some(us) = us {
+        busypoll::SPIN_US.store(us, Ordering::Relaxed);
+    }
+    match busypoll::SPIN_US.load(Ordering::Relaxed) {
+        0 => Ok("busy poll is off".to_string()),
+        us => Ok(format!("busy poll for {}us", us)),
+    }
+}
+
+/// Bans target, an address or a client by nick or fd, and disconnects everyone
+/// connected from its address.
+pub(crate) fn ban(target: &str, duration: Option<&str>, epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) -> std::result::Result<String, String> {
+    let duration = match duration {
+        Some(d) => Some(parse_duration(d).ok_or(format!("invalid duration {}", d))?),
+        None => None,
+    };
+
+    let ip = match target.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => {
+            let cfd = find_client(clients, target).ok_or(format!("no client {}", target))?;
+            let peer = clients[&cfd].borrow().peer.ok_or(format!("{} has no address", target))?;
+            peer.ip()
+        }
+    };
+
+    epserver.bans.ban(ip, duration).map_err(|e| format!("failed to save ban list -- {}", e))?;
+
+    let banned: Vec<i32> = clients.iter()
+        .filter(|(_, c)| c.borrow().peer.is_some_and(|a| a.ip() == ip))
+        .map(|(cfd, _)| *cfd)
+        .collect();
+    for cfd in &banned {
+        kick(epserver, *cfd, clients, "banned");
+    }
+
+    Ok(format!("banned {} ({} disconnected)", ip, banned.len()))
+}
+
+fn greylist(args: &[&str], epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) -> std::result::Result<String, String> {
+    let now = epserver.sys.now();
+    let ip = |ip: &str| ip.parse::<IpAddr>().map_err(|_| format!("invalid address {}", ip));
+    match args {
+        ["add", addr] | ["add", addr, _] => {
+            let ip = ip(addr)?;
+            let duration = match args.get(2) {
+                Some(d) => Some(parse_duration(d).ok_or(format!("invalid duration {}", d))?),
+                None => None,
+            };
+            epserver.greylist.add(ip, duration, now);
+            let listed: Vec<i32> = clients.iter()
+                .filter(|(_, c)| c.borrow().peer.is_some_and(|a| a.ip() == ip))
+                .map(|(cfd, _)| *cfd)
+                .collect();
+            for cfd in &listed {
+                kick(epserver, *cfd, clients, "greylisted");
+            }
+            Ok(format!("greylisted {} ({} disconnected)", ip, listed.len()))
+        }
+        ["remove", addr] => {
+            let ip = ip(addr)?;
+            match epserver.greylist.remove(ip, now) {
+                true => Ok(format!("took {} off the greylist", ip)),
+                false => Err(format!("{} is not greylisted", ip)),
+            }
+        }
+        ["limit"] | ["limit", _] => {
+            match args.get(1) {
+                Some(&"off") => epserver.greylist.limit = None,
+                Some(n) => epserver.greylist.limit = Some(n.parse::<usize>().map_err(|_| "usage: greylist limit [n|off]".to_string())?),
+                None => {}
+            }
+            match epserver.greylist.limit {
+                Some(n) => Ok(format!("greylisting after {} connects a minute, for {}s", n, epserver.greylist.hold.as_secs())),
+                None => Ok("greylisting is off".to_string()),
+            }
+        }
+        _ => Err("usage: greylist [add <ip> [duration] | remove <ip> | limit [n|off]]".to_string()),
+    }
+}
+
+fn set_debug(sub: &str, state: &str) -> std::result::Result<String, String> {
+    let on = match state {
+        "on" => true,
+        "off" => false,
+        _ => return Err("usage: debug <subsystem|all> <on|off>".to_string()),
+    };
+    let subs = match sub {
+        "all" => logging::SUBSYSTEMS.to_vec(),
+        _ => vec![sub.parse::<Subsystem>()?],
+    };
+    for sub in &subs {
+        logging::set_debug(*sub, on);
+    }
+    let names: Vec<&str> = subs.iter().map(|s| s.name()).collect();
+    Ok(format!("debug output of {} {}", names.join(", "), state))
+}
+
+fn set_mute(clients: &HashMap<i32, RefCell<ClientState>>, target: &str, mute: Mute) -> std::result::Result<String, String> {
+    let cfd = find_client(clients, target).ok_or(format!("no client {}", target))?;
+    clients[&cfd].borrow_mut().mute = mute;
+    Ok(match mute {
+        Mute::Off => format!("unmuted {}", target),
+        Mute::Muted => format!("muted {}", target),
+        Mute::Shadow => format!("shadow muted {}", target),
+    })
+}
+
+fn set_level(clients: &HashMap<i32, RefCell<ClientState>>, target: &str, level: Level) -> std::result::Result<String, String> {
+    let cfd = find_client(clients, target).ok_or(format!("no client {}", target))?;
+    clients[&cfd].borrow_mut().level = level;
+    Ok(match level {
+        Level::Operator => format!("{} is an operator", target),
+        Level::User => format!("{} is a user", target),
+    })
+}
+
+/// The `room` commands, changes apply to routing right away.
+fn room(args: &[&str], epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> std::result::Result<String, String> {
+    match args {
+        ["create", spec] => {
+            let (rooms, name) = resolve_room(epserver, spec)?;
+            match rooms.borrow_mut().create(name) {
+                true => Ok(format!("created {}", spec)),
+                false => Ok(format!("{} exists, it is kept until deleted now", spec)),
+            }
+        }
+        ["delete", spec] => {
+            let (rooms, name) = resolve_room(epserver, spec)?;
+            let room = rooms.borrow_mut().delete(name).ok_or(format!("no room {}", spec))?;
+            for cfd in &room.members {
+                if let Some(client) = clients.get(cfd) {
+                    crate::notify(epserver, &mut client.borrow_mut(), format!("* {} was closed\n", name).as_bytes());
+                }
+            }
+            Ok(format!("deleted {} ({} members)", spec, room.members.len()))
+        }
+        [action @ ("lock" | "unlock"), spec] => {
+            let (rooms, name) = resolve_room(epserver, spec)?;
+            let mut rooms = rooms.borrow_mut();
+            let room = rooms.get_mut(name).ok_or(format!("no room {}", spec))?;
+            room.locked = *action == "lock";
+            Ok(format!("{} is {}", spec, if room.locked { "invite only" } else { "open" }))
+        }
+        ["cap", spec, cap] => {
+            let cap = match *cap {
+                "off" => None,
+                n => Some(n.parse::<usize>().map_err(|_| "usage: room cap <room> <n|off>".to_string())?),
+            };
+            let (rooms, name) = resolve_room(epserver, spec)?;
+            let mut rooms = rooms.borrow_mut();
+            let room = rooms.get_mut(name).ok_or(format!("no room {}", spec))?;
+            room.cap = cap;
+            // members past a new cap stay, it only stops new ones
+            Ok(match cap {
+                Some(cap) => format!("{} takes up to {} members, has {}", spec, cap, room.members.len()),
+                None => format!("{} takes any number of members", spec),
+            })
+        }
+        ["invite", spec, target] => {
+            let (rooms, name) = resolve_room(epserver, spec)?;
+            let cfd = find_client(clients, target).ok_or(format!("no client {}", target))?;
+            let mut client = clients[&cfd].borrow_mut();
+            if !std::ptr::eq(rooms, epserver.rooms_for(client.tenant)) {
+                return Err(format!("{} is in another tenant than {}", target, spec));
+            }
+            rooms.borrow_mut().get_mut(name).ok_or(format!("no room {}", spec))?.invited.insert(cfd);
+            crate::notify(epserver, &mut client, format!("* you are invited to {}\n", name).as_bytes());
+            Ok(format!("invited {} to {}", target, spec))
+        }
+        ["move", target, from, to] => {
+            let cfd = find_client(clients, target).ok_or(format!("no client {}", target))?;
+            let mut client = clients[&cfd].borrow_mut();
+            if !crate::rooms::valid_name(to) {
+                return Err(format!("invalid room name {}", to));
+            }
+            let mut rooms = epserver.rooms_for(client.tenant).borrow_mut();
+            let reliable = match rooms.get(from) {
+                Some(room) if room.members.contains(&cfd) => room.reliable.contains(&cfd),
+                _ => return Err(format!("{} is not in {}", target, from)),
+            };
+            rooms.part(from, cfd);
+            // operators may move clients past locks and caps
+            let qos = if reliable { Qos::Reliable } else { Qos::BestEffort };
+            let mut notice = format!("* moved from {} to {}\n", from, to);
+            if let Some(topic) = &rooms.join(to, cfd, client.nick.as_deref(), qos).topic {
+                notice.push_str(&format!("{} * topic: {}\n", to, topic));
+            }
+            drop(rooms);
+            crate::notify(epserver, &mut client, notice.as_bytes());
+            Ok(format!("moved {} from {} to {}", target, from, to))
+        }
+        _ => Err("unknown room command, try `help`".to_string()),
+    }
+}
+
+/// The rooms of the tenant a `[tenant/]#name` room is in, and its name.
+fn resolve_room<'a>(epserver: &'a EpollServer, spec: &'a str) -> std::result::Result<(&'a RefCell<Rooms>, &'a str), String> {
+    let (tenant, name) = match spec.split_once('/') {
+        Some((tenant, name)) => {
+            let t = epserver.tenants.iter().position(|t| t.name == tenant).ok_or(format!("no tenant {}", tenant))?;
+            (Some(t), name)
+        }
+        None => (None, spec),
+    };
+    if !crate::rooms::valid_name(name) {
+        return Err(format!("invalid room name {}", name));
+    }
+    Ok((epserver.rooms_for(tenant), name))
+}
+
+/// Looks a client up by nick, falling back to its fd.
+fn find_client(clients: &HashMap<i32, RefCell<ClientState>>, target: &str) -> Option<i32> {
+    let by_nick = clients.iter().find(|(_, c)| c.borrow().nick.as_deref() == Some(target));
+    match by_nick {
+        Some((cfd, _)) => Some(*cfd),
+        None => target.parse().ok().filter(|cfd| clients.contains_key(cfd)),
+    }
+}
+
+/// Tells a client why it is being dropped, then removes it.
+pub(crate) fn kick(epserver: &EpollServer, cfd: i32, clients: &mut HashMap<i32, RefCell<ClientState>>, reason: &str) {
+    if let Some(client) = clients.get(&cfd) {
+        crate::notify(epserver, &mut client.borrow_mut(), format!("* you have been {}\n", reason).as_bytes());
+    }
+    crate::remove_client(epserver, cfd, clients, reason);
+}
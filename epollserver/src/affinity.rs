@@ -0,0 +1,55 @@
+use std::io::{Error, Result};
+use std::str::FromStr;
+
+/// The cores `--pin-cpus` gives each event loop, one comma separated set per
+/// loop and `+` joining cores of one set, e.g. `2,3` or `2+10,3+11` to take the
+/// cores handling a NIC queues interrupts along. Ranges like `2-5` give one core
+/// each to four loops. Loops past the last set start over with the first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuSets(Vec<Vec<usize>>);
+
+impl FromStr for CpuSets {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CpuSets, String> {
+        let cpu = |s: &str| s.trim().parse::<usize>().ok().filter(|cpu| *cpu < libc::CPU_SETSIZE as usize)
+            .ok_or_else(|| format!("invalid cpu {:?}", s));
+        let mut sets = Vec::new();
+        for part in s.split(',') {
+            match part.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (cpu(first)?, cpu(last)?);
+                    if first > last {
+                        return Err(format!("invalid cpu range {:?}", part));
+                    }
+                    sets.extend((first..=last).map(|cpu| vec![cpu]));
+                }
+                None => sets.push(part.split('+').map(cpu).collect::<std::result::Result<_, _>>()?),
+            }
+        }
+        Ok(CpuSets(sets))
+    }
+}
+
+impl CpuSets {
+    /// The cores of the loop that is number worker.
+    pub fn of(&self, worker: usize) -> &[usize] {
+        &self.0[worker % self.0.len()]
+    }
+
+    /// Keeps the calling thread on the cores of the loop that is number worker,
+    /// so the scheduler doesn't move it and its warm caches elsewhere.
+    pub fn pin(&self, worker: usize) -> Result<()> {
+        let cpus = self.of(worker);
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for cpu in cpus {
+            unsafe { libc::CPU_SET(*cpu, &mut set) };
+        }
+        // 0 is the calling thread, not the whole process
+        if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } < 0 {
+            let e = Error::last_os_error();
+            return Err(Error::new(e.kind(), format!("cannot pin to cpus {:?} -- {}", cpus, e)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpListener;
+use std::os::fd::{IntoRawFd, RawFd};
+use std::rc::Rc;
+
+use tokio::io::unix::AsyncFd;
+
+use crate::events::Event;
+use crate::sys::Epoll;
+use crate::{poll_once, ClientState, EpollServer, MAX_EVENTS};
+
+/// The broadcast server driven by a tokio runtime instead of its own loop: the
+/// epoll fd is registered with the runtimes reactor, so nothing blocks in
+/// epoll_wait and no extra thread is needed.
+///
+/// The server is not Send, run it on a current thread runtime or a `LocalSet`.
+pub struct AsyncBroadcastServer {
+    epserver: EpollServer,
+    clients: HashMap<i32, RefCell<ClientState>>,
+    ready: AsyncFd<RawFd>,
+    next_offset: u64, // first history offset not yet handed out by next_broadcast
+    broadcasts: VecDeque<Vec<u8>>,
+}
+
+impl AsyncBroadcastServer {
+    /// Listens on addr with the default settings.
+    pub fn bind(addr: &str) -> Result<AsyncBroadcastServer> {
+        let listener = TcpListener::bind(addr)?;
+        let epserver = EpollServer::new(Rc::new(Epoll::new(MAX_EVENTS as usize)?), listener.into_raw_fd())?;
+        AsyncBroadcastServer::new(epserver)
+    }
+
+    /// Wraps a configured server, which has to run on real sockets.
+    pub fn new(epserver: EpollServer) -> Result<AsyncBroadcastServer> {
+        let fd = epserver.sys.event_fd()
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "server has no fd to wait on"))?;
+        let next_offset = epserver.history.borrow().next_offset();
+        Ok(AsyncBroadcastServer {
+            epserver,
+            clients: HashMap::new(),
+            ready: AsyncFd::new(fd)?,
+            next_offset,
+            broadcasts: VecDeque::new(),
+        })
+    }
+
+    /// Waits until something happens and handles it.
+    ///
+    /// Returns the number of events handled.
+    pub async fn turn(&mut self) -> Result<usize> {
+        loop {
+            let mut guard = self.ready.readable().await?;
+            let handled = poll_once(&mut self.epserver, &mut self.clients, 0)?;
+            // a full batch may have left events behind, and backlogged clients have
+            // messages buffered without the socket being readable, keep the fd marked ready then
+            if handled < MAX_EVENTS as usize && self.epserver.backlog.borrow().is_empty() {
+                guard.clear_ready();
+            }
+
+            self.collect_broadcasts();
+            if handled > 0 {
+                return Ok(handled);
+            }
+        }
+    }
+
+    /// Serves clients until polling fails or a drain finishes. Ticks and the drain
+    /// deadline are only checked when something happens, there is no timer behind them.
+    pub async fn run(&mut self) -> Result<()> {
+        while !self.epserver.is_stopped() {
+            self.turn().await?;
+        }
+        Ok(())
+    }
+
+    /// Serves clients until a message has been broadcast and returns it (without
+    /// its newline). Messages are only remembered while `--history` keeps them.
+    pub async fn next_broadcast(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(message) = self.broadcasts.pop_front() {
+                return Ok(message);
+            }
+            self.turn().await?;
+        }
+    }
+
+    /// Sends message to every connected client, it is not returned by next_broadcast.
+    ///
+    /// Returns total number of bytes written across all clients.
+    pub fn broadcast(&mut self, message: &[u8]) -> usize {
+        self.collect_broadcasts();
+        let mut line = message.to_vec();
+        line.push(b'\n');
+        let (bytes, _) = crate::broadcast(crate::Origin { fd: -1, tenant: None, meta: &crate::Metadata::new(), peer: None, label: None, forwarded: false }, &line, crate::Audience::Everyone, &self.epserver, &self.clients);
+        self.next_offset = self.epserver.history.borrow().next_offset();
+        bytes
+    }
+
+    /// Calls f with every connection and message event, see `EpollServer::on_event`.
+    pub fn on_event(&mut self, f: impl Fn(&Event) + 'static) {
+        self.epserver.on_event(f);
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn collect_broadcasts(&mut self) {
+        let history = self.epserver.history.borrow();
+        for (offset, message) in history.messages() {
+            if *offset >= self.next_offset {
+                self.broadcasts.push_back(message.clone());
+            }
+        }
+        self.next_offset = history.next_offset();
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::Result;
+
+use regex::bytes::Regex;
+
+use crate::commands::Level;
+use crate::config::Config;
+use crate::logging::info;
+use crate::rooms::{self, Qos};
+use crate::{ClientState, EpollServer, Metadata};
+
+struct Banner {
+    name: String,
+    pattern: Regex,
+    room: Option<String>,
+    level: Option<Level>,
+    tags: Metadata,
+}
+
+/// Patterns for the first line a client sends, for producers that can't run
+/// commands but announce themselves, configured with one `[banner.<name>]`
+/// section each:
+///
+/// ```text
+/// [banner.syslog]
+/// match = ^syslog:
+/// join = #logs
+/// tags = source=syslog
+/// ```
+///
+/// The first section whose `match` regex matches the line applies to the client:
+/// `join` puts it into a room that everything it sends goes to unless addressed
+/// to another one, `level = operator` promotes it and `tags = <key>=<value>,...`
+/// sets metadata as `/set` would. The line itself is a message like any other.
+#[derive(Default)]
+pub struct Banners {
+    banners: Vec<Banner>,
+}
+
+impl Banners {
+    pub fn from_config(config: &Config) -> Result<Banners> {
+        let mut banners = Vec::new();
+
+        for (name, section) in config.sections_with_prefix("banner") {
+            let pattern = match section.get("match") {
+                Some(entry) => Regex::new(&entry.value)
+                    .map_err(|e| config.error(entry.line, &format!("invalid regex -- {}", e)))?,
+                None => return Err(config.error(section.line, "banner is missing `match`")),
+            };
+            let room = match section.get("join") {
+                Some(entry) if rooms::valid_name(&entry.value) => Some(entry.value.clone()),
+                Some(entry) => return Err(config.error(entry.line, "join must be a room like `#logs`")),
+                None => None,
+            };
+            let level = match section.get("level").map(|e| (e.value.as_str(), e.line)) {
+                Some(("user", _)) => Some(Level::User),
+                Some(("operator", _)) => Some(Level::Operator),
+                Some((_, line)) => return Err(config.error(line, "level must be `user` or `operator`")),
+                None => None,
+            };
+            let mut tags = Metadata::new();
+            if let Some(entry) = section.get("tags") {
+                for tag in entry.value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                    let (key, value) = tag.split_once('=')
+                        .ok_or_else(|| config.error(entry.line, "tags must be `<key>=<value>,...`"))?;
+                    tags.insert(key.trim().to_string(), value.trim().to_string());
+                }
+            }
+
+            banners.push(Banner { name: name.to_string(), pattern, room, level, tags });
+        }
+
+        Ok(Banners { banners })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.banners.is_empty()
+    }
+}
+
+/// Applies the first banner matching line, the first the client sent (without
+/// its newline), if any does.
+pub(crate) fn apply(client: &mut ClientState, line: &[u8], epserver: &EpollServer) {
+    let Some(banner) = epserver.banners.banners.iter().find(|b| b.pattern.is_match(line)) else {
+        return;
+    };
+    info!("fd {} matched banner {}", client.fd, banner.name);
+
+    if let Some(name) = &banner.room {
+        epserver.rooms_for(client.tenant).borrow_mut().join(name, client.fd, client.nick.as_deref(), Qos::BestEffort);
+        client.room = Some(name.clone());
+    }
+    if let Some(level) = banner.level {
+        client.level = level;
+    }
+    for (key, value) in &banner.tags {
+        client.meta.insert(key.clone(), value.clone());
+    }
+}
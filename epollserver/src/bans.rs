@@ -0,0 +1,149 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct Ban {
+    pub ip: IpAddr,
+    pub until: Option<SystemTime>, // None bans forever
+}
+
+/// Banned addresses, written back to `path` (if any) on every change.
+pub struct BanList {
+    bans: Vec<Ban>,
+    path: Option<PathBuf>,
+}
+
+impl Default for BanList {
+    fn default() -> BanList {
+        BanList::new()
+    }
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList { bans: Vec::new(), path: None }
+    }
+
+    /// Loads bans from a file with one `ip [expiry unix seconds]` per line, a missing
+    /// file is treated as an empty list.
+    pub fn load(path: PathBuf) -> Result<BanList> {
+        let mut list = BanList { bans: Vec::new(), path: Some(path.clone()) };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(list),
+            Err(e) => return Err(e),
+        };
+
+        for (n, line) in text.lines().enumerate() {
+            let mut parts = line.split_whitespace();
+            let ip = match parts.next() {
+                Some(ip) => ip.parse::<IpAddr>(),
+                None => continue,
+            };
+            let until = parts.next().map(|secs| secs.parse::<u64>());
+
+            match (ip, until) {
+                (Ok(ip), None) => list.bans.push(Ban { ip, until: None }),
+                (Ok(ip), Some(Ok(secs))) => {
+                    list.bans.push(Ban { ip, until: Some(UNIX_EPOCH + Duration::from_secs(secs)) })
+                }
+                _ => {
+                    let errmsg = format!("{}:{}: invalid ban entry", path.display(), n + 1);
+                    return Err(Error::new(ErrorKind::InvalidData, errmsg));
+                }
+            }
+        }
+
+        list.prune();
+        Ok(list)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = SystemTime::now();
+        self.bans.iter().any(|b| b.ip == ip && b.until.is_none_or(|t| t > now))
+    }
+
+    /// Bans ip for duration (forever if None), replacing any existing ban on it.
+    pub fn ban(&mut self, ip: IpAddr, duration: Option<Duration>) -> Result<()> {
+        let until = match duration {
+            Some(d) => Some(SystemTime::now().checked_add(d).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "ban ends too far ahead"))?),
+            None => None,
+        };
+        self.bans.retain(|b| b.ip != ip);
+        self.bans.push(Ban { ip, until });
+        self.save()
+    }
+
+    /// Adds a ban as it was saved, replacing any existing ban on its address.
+    pub fn restore(&mut self, ban: Ban) -> Result<()> {
+        self.bans.retain(|b| b.ip != ban.ip);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Returns false if ip was not banned.
+    pub fn unban(&mut self, ip: IpAddr) -> Result<bool> {
+        let len = self.bans.len();
+        self.bans.retain(|b| b.ip != ip);
+        if self.bans.len() == len {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    pub fn iter(&mut self) -> impl Iterator<Item = &Ban> {
+        self.prune();
+        self.bans.iter()
+    }
+
+    fn prune(&mut self) {
+        let now = SystemTime::now();
+        self.bans.retain(|b| b.until.is_none_or(|t| t > now));
+    }
+
+    fn save(&mut self) -> Result<()> {
+        self.prune();
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut text = String::new();
+        for ban in &self.bans {
+            text.push_str(&ban.ip.to_string());
+            if let Some(until) = ban.until {
+                let secs = until.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                text.push_str(&format!(" {}", secs));
+            }
+            text.push('\n');
+        }
+
+        // write then rename so a crash never leaves a truncated list behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Longest duration parse_duration takes, so adding it to a point in time never
+/// overflows.
+const MAX_DURATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Parses durations like `90`, `90s`, `15m`, `2h` or `7d`, up to a century.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n: u64 = digits.parse().ok()?;
+    let secs = match unit {
+        "s" => Some(n),
+        "m" => n.checked_mul(60),
+        "h" => n.checked_mul(60 * 60),
+        "d" => n.checked_mul(60 * 60 * 24),
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs?)).filter(|d| *d <= MAX_DURATION)
+}
@@ -0,0 +1,46 @@
+use std::cell::Cell;
+use std::io::Result;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::sys::Sys;
+
+/// How long every event loop polls without blocking before it waits, in
+/// microseconds, 0 to always wait. Set with `--busy-poll-us` and the admin
+/// `busy-poll`.
+pub static SPIN_US: AtomicU64 = AtomicU64::new(0);
+pub static SPIN_HITS: AtomicUsize = AtomicUsize::new(0);
+pub static SPIN_MISSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Waits like Sys::wait, but first spins on it with a zero timeout for up to
+/// SPIN_US, so events arriving soon are handled without a wakeup. It only spins
+/// after a wait that found something: once a spin comes up dry the loop is
+/// taken to be idle, and blocks right away until there is work again.
+pub fn wait(sys: &dyn Sys, ready: &mut Vec<i32>, mut timeout_ms: i32, idle: &Cell<bool>) -> Result<()> {
+    let budget = Duration::from_micros(SPIN_US.load(Ordering::Relaxed));
+    if !budget.is_zero() && timeout_ms != 0 && !idle.get() {
+        let start = sys.now();
+        loop {
+            sys.wait(ready, 0)?;
+            if !ready.is_empty() {
+                SPIN_HITS.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            if sys.now().duration_since(start) >= budget {
+                break;
+            }
+            std::hint::spin_loop();
+        }
+        SPIN_MISSES.fetch_add(1, Ordering::Relaxed);
+        idle.set(true);
+        if timeout_ms > 0 {
+            let spent = sys.now().duration_since(start).as_millis().min(i32::MAX as u128) as i32;
+            timeout_ms = (timeout_ms - spent).max(0);
+        }
+    }
+    sys.wait(ready, timeout_ms)?;
+    if !ready.is_empty() {
+        idle.set(false);
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::cell::Cell;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::Config;
+use crate::logging::{info, warning};
+use crate::sys::Sys;
+
+/// How often each fault is injected, read from the `[chaos]` section:
+///
+/// ```text
+/// [chaos]
+/// seed = 42           # default: from the clock, printed at startup
+/// delay = 0.01        # stall a write for up to delay_ms
+/// delay_ms = 20
+/// short_write = 0.05  # write only part of the buffer
+/// eagain = 0.05       # fail a read or write with EAGAIN
+/// drop = 0.001        # reset the connection on a read or write
+/// ```
+///
+/// Probabilities are per call and default to the values above.
+pub struct ChaosConfig {
+    seed: u64,
+    delay: f64,
+    delay_ms: u64,
+    short_write: f64,
+    eagain: f64,
+    drop: f64,
+}
+
+impl ChaosConfig {
+    pub fn from_config(config: &Config) -> Result<ChaosConfig> {
+        let mut chaos = ChaosConfig {
+            seed: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(1, |d| d.as_nanos() as u64),
+            delay: 0.01,
+            delay_ms: 20,
+            short_write: 0.05,
+            eagain: 0.05,
+            drop: 0.001,
+        };
+
+        let section = match config.section("chaos") {
+            Some(section) => section,
+            None => return Ok(chaos),
+        };
+        for entry in &section.entries {
+            let invalid = || config.error(entry.line, &format!("invalid value for `{}`", entry.key));
+            let probability = || match entry.value.parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                _ => Err(config.error(entry.line, "probability must be between 0 and 1")),
+            };
+            match entry.key.as_str() {
+                "seed" => chaos.seed = entry.value.parse().map_err(|_| invalid())?,
+                "delay_ms" => chaos.delay_ms = entry.value.parse().map_err(|_| invalid())?,
+                "delay" => chaos.delay = probability()?,
+                "short_write" => chaos.short_write = probability()?,
+                "eagain" => chaos.eagain = probability()?,
+                "drop" => chaos.drop = probability()?,
+                key => return Err(config.error(entry.line, &format!("unknown chaos setting `{}`", key))),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+/// Fault injection for `--chaos`: wraps a `Sys` and randomly delays writes, cuts
+/// them short, fails reads and writes with EAGAIN and resets connections, so the
+/// queueing and reconnect paths get exercised. Never use this in production.
+pub struct Chaos {
+    inner: Box<dyn Sys>,
+    config: ChaosConfig,
+    rng: Cell<u64>,
+}
+
+impl Chaos {
+    pub fn new(inner: Box<dyn Sys>, config: ChaosConfig) -> Chaos {
+        warning!("chaos mode enabled, seed {}", config.seed);
+        let rng = Cell::new(config.seed.max(1));
+        Chaos { inner, config, rng }
+    }
+
+    /// xorshift64*, plenty for picking faults and reproducible from the seed.
+    fn next(&self) -> u64 {
+        let mut x = self.rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn chance(&self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// Resets the connection, the server sees the hangup on its next read.
+    fn drop_connection(&self, fd: i32) -> Error {
+        info!("chaos: dropping fd {}", fd);
+        unsafe { libc::shutdown(fd, libc::SHUT_RDWR); }
+        Error::from(ErrorKind::ConnectionReset)
+    }
+}
+
+impl Sys for Chaos {
+    fn wait(&self, ready: &mut Vec<i32>, timeout_ms: i32) -> Result<()> {
+        self.inner.wait(ready, timeout_ms)
+    }
+
+    fn watch(&self, fd: i32) -> Result<()> {
+        self.inner.watch(fd)
+    }
+
+    fn unwatch(&self, fd: i32) {
+        self.inner.unwatch(fd)
+    }
+
+    fn set_interest(&self, fd: i32, readable: bool, writable: bool) -> Result<()> {
+        self.inner.set_interest(fd, readable, writable)
+    }
+
+    fn accept(&self, listener: i32) -> Result<(i32, SocketAddr)> {
+        self.inner.accept(listener)
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize> {
+        if self.chance(self.config.drop) {
+            return Err(self.drop_connection(fd));
+        }
+        if self.chance(self.config.eagain) {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        self.inner.read(fd, buf)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        if self.chance(self.config.drop) {
+            return Err(self.drop_connection(fd));
+        }
+        if self.chance(self.config.eagain) {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        if self.chance(self.config.delay) {
+            let ms = self.next() % (self.config.delay_ms + 1);
+            std::thread::sleep(Duration::from_millis(ms));
+        }
+        if buf.len() > 1 && self.chance(self.config.short_write) {
+            let n = 1 + (self.next() % (buf.len() as u64 - 1)) as usize;
+            return self.inner.write(fd, &buf[..n]);
+        }
+        self.inner.write(fd, buf)
+    }
+
+    fn close(&self, fd: i32) {
+        self.inner.close(fd)
+    }
+
+    fn peer_addr(&self, fd: i32) -> Result<SocketAddr> {
+        self.inner.peer_addr(fd)
+    }
+
+    fn queued_bytes(&self, fd: i32) -> usize {
+        self.inner.queued_bytes(fd)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn event_fd(&self) -> Option<i32> {
+        self.inner.event_fd()
+    }
+}
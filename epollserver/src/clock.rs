@@ -0,0 +1,55 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How `--timestamps` stamps broadcast messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeFormat {
+    /// `2026-10-14T09:30:00.123Z`, always UTC.
+    Iso8601,
+    /// Milliseconds since the unix epoch.
+    Millis,
+}
+
+impl FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeFormat, String> {
+        match s {
+            "iso8601" => Ok(TimeFormat::Iso8601),
+            "millis" => Ok(TimeFormat::Millis),
+            _ => Err(format!("unknown time format {:?}, expected iso8601 or millis", s)),
+        }
+    }
+}
+
+/// Milliseconds since the unix epoch, 0 for clocks set before it.
+pub fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+pub fn format(t: SystemTime, format: TimeFormat) -> String {
+    let ms = millis(t);
+    match format {
+        TimeFormat::Millis => ms.to_string(),
+        TimeFormat::Iso8601 => {
+            let (secs, ms) = (ms / 1000, ms % 1000);
+            let (year, month, day) = civil_from_days((secs / 86400) as i64);
+            let rem = secs % 86400;
+            format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, rem / 3600, rem / 60 % 60, rem % 60, ms)
+        }
+    }
+}
+
+/// Year, month and day of days since 1970-01-01, from Howard Hinnants
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
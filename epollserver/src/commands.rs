@@ -0,0 +1,530 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use crate::bans::parse_duration;
+use crate::clock::{self, TimeFormat};
+use crate::rooms::{self, Qos};
+use crate::subscriptions;
+use crate::{answer_probe, notify, send, start_probing, ClientState, EpollServer, Mute};
+
+/// Who may run a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    User,
+    /// Clients promoted with the admin `op` command.
+    Operator,
+}
+
+/// A /kick or /ban, run once the wakeup is handled since the clients it
+/// disconnects may be borrowed until then, see EpollServer::sanctions.
+pub(crate) enum Sanction {
+    Kick(i32, String), // client and what the operator called it
+    Ban(IpAddr, Option<String>), // and for how long
+}
+
+/// What a command handler gets to work with.
+pub struct Invocation<'a> {
+    pub client: &'a mut ClientState,
+    /// Arguments as described by the commands usage, a trailing `...` argument
+    /// holds the rest of the line.
+    pub args: Vec<&'a str>,
+    pub epserver: &'a EpollServer,
+    /// Every client, the one running the command is borrowed already.
+    pub clients: &'a HashMap<i32, RefCell<ClientState>>,
+}
+
+impl Invocation<'_> {
+    /// Sends a line back to the client running the command.
+    pub fn reply(&mut self, text: &str) {
+        notify(self.epserver, self.client, format!("{}\n", text).as_bytes());
+    }
+}
+
+/// Runs a command, an Err is sent back to the client as `* <error>`.
+pub type Handler = fn(&mut Invocation) -> Result<(), String>;
+
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments like `<#room> [text...]`: `<required>`, `[optional]`, and a
+    /// trailing `...` for one taking the rest of the line.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub level: Level,
+    pub run: Handler,
+}
+
+/// Every line a client sends starting with `/` is looked up here instead of being
+/// broadcast. Commands are checked against the clients level and their usage
+/// before they run, and `/help` lists what the client may run.
+pub struct Commands {
+    commands: Vec<Command>,
+}
+
+impl Commands {
+    pub fn builtin() -> Commands {
+        let mut commands = Commands { commands: Vec::new() };
+        commands.register(Command { name: "help", usage: "[command]", help: "list commands or describe one", level: Level::User, run: help });
+        commands.register(Command { name: "auth", usage: "<token>", help: "unlock a connection from a listener that wants a token", level: Level::User, run: auth });
+        commands.register(Command { name: "nick", usage: "<name> [token]", help: "set the name others know you by, with its token if reserved", level: Level::User, run: nick });
+        commands.register(Command { name: "set", usage: "<key=value...>", help: "tell others about yourself, like team=infra, shown in /who", level: Level::User, run: set });
+        commands.register(Command { name: "unset", usage: "<key>", help: "forget something /set", level: Level::User, run: unset });
+        commands.register(Command { name: "resume", usage: "[offset]", help: "tag messages with offsets, and get those after offset again", level: Level::User, run: resume });
+        commands.register(Command { name: "seq", usage: "", help: "number messages on this connection so dropped ones show as gaps", level: Level::User, run: seq });
+        commands.register(Command { name: "join", usage: "<#room> [qos] [password]", help: "join a room, as best-effort or reliable, which never misses messages", level: Level::User, run: join });
+        commands.register(Command { name: "part", usage: "<#room>", help: "leave a room", level: Level::User, run: part });
+        commands.register(Command { name: "key", usage: "<#room> [password]", help: "make joining a room take a password, or stop that, as one of its ops", level: Level::User, run: key });
+        commands.register(Command { name: "allow", usage: "<#room> <nick>", help: "let only allowed nicks join a room, as one of its ops", level: Level::User, run: allow });
+        commands.register(Command { name: "disallow", usage: "<#room> <nick>", help: "take a nick off the allowed list of a room", level: Level::User, run: disallow });
+        commands.register(Command { name: "topic", usage: "<#room> [text...]", help: "show the topic of a room, or set it as one of its ops", level: Level::User, run: topic });
+        commands.register(Command { name: "time", usage: "", help: "show the server time, as iso8601 and epoch millis", level: Level::User, run: time });
+        commands.register(Command { name: "probe", usage: "[on|off]", help: "get `* probe <id>` lines to answer with /pong, measuring delivery latency", level: Level::User, run: probe });
+        commands.register(Command { name: "pong", usage: "<id>", help: "answer a probe", level: Level::User, run: pong });
+        commands.register(Command { name: "subscribe", usage: "<name>", help: "attach to a durable subscription, getting what was not acked again", level: Level::User, run: subscribe });
+        commands.register(Command { name: "unsubscribe", usage: "", help: "leave and forget the durable subscription", level: Level::User, run: unsubscribe });
+        commands.register(Command { name: "ack", usage: "<offset>", help: "acknowledge messages of the subscription up to offset", level: Level::User, run: ack });
+        commands.register(Command { name: "who", usage: "[page]", help: "list connected clients", level: Level::User, run: who });
+        commands.register(Command { name: "mute", usage: "<nick|fd>", help: "drop a clients messages", level: Level::Operator, run: mute });
+        commands.register(Command { name: "unmute", usage: "<nick|fd>", help: "let a client talk again", level: Level::Operator, run: unmute });
+        commands.register(Command { name: "kick", usage: "<nick|fd>", help: "disconnect a client", level: Level::Operator, run: kick });
+        commands.register(Command { name: "ban", usage: "<nick|ip> [duration]", help: "disconnect a client and refuse its address, for good or e.g. 2h", level: Level::Operator, run: ban });
+        commands
+    }
+
+    /// Adds a command, replacing any with the same name.
+    pub fn register(&mut self, command: Command) {
+        self.commands.retain(|c| c.name != command.name);
+        self.commands.push(command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|c| c.name == name)
+    }
+
+    /// Runs a command line (starting with `/`) for the client, which is sent any
+    /// error.
+    pub fn execute(&self, client: &mut ClientState, line: &str, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+        if let Err(error) = self.run(client, line.trim(), epserver, clients) {
+            notify(epserver, client, format!("* {}\n", error).as_bytes());
+        }
+    }
+
+    /// Runs a `REQ <id> <command>` line, the command with or without its `/`,
+    /// and answers with every line the command sent back framed as
+    ///
+    /// ```text
+    /// RSP <id> <line>
+    /// RSP <id> OK          or RSP <id> ERR <error>, always last
+    /// ```
+    ///
+    /// so client libraries can tell answers from broadcasts and match them up
+    /// with their requests, even with several in flight.
+    pub fn request(&self, client: &mut ClientState, line: &str, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+        let mut words = line.trim().splitn(3, char::is_whitespace).skip(1);
+        let (id, command) = match (words.next(), words.next().map(str::trim)) {
+            (Some(id), Some(command)) if !command.is_empty() => (id, command),
+            _ => return notify(epserver, client, b"* usage: REQ <id> <command>\n"),
+        };
+        let command = match command.starts_with('/') {
+            true => command.to_string(),
+            false => format!("/{}", command),
+        };
+        client.capturing = Some(Vec::new());
+        let result = self.run(client, &command, epserver, clients);
+        let captured = client.capturing.take().unwrap_or_default();
+        let mut answer = String::new();
+        for line in String::from_utf8_lossy(&captured).lines() {
+            answer.push_str(&format!("RSP {} {}\n", id, line));
+        }
+        match result {
+            Ok(()) => answer.push_str(&format!("RSP {} OK\n", id)),
+            Err(error) => answer.push_str(&format!("RSP {} ERR {}\n", id, error)),
+        }
+        notify(epserver, client, answer.as_bytes());
+    }
+
+    fn run(&self, client: &mut ClientState, line: &str, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> Result<(), String> {
+        let (name, rest) = line[1..].split_once(char::is_whitespace).unwrap_or((&line[1..], ""));
+        match self.get(name) {
+            None => Err(format!("unknown command /{}, try /help", name)),
+            Some(command) if command.level > client.level => Err(format!("/{} is for operators only", name)),
+            Some(command) => match parse_args(command.usage, rest) {
+                Some(args) => (command.run)(&mut Invocation { client, args, epserver, clients }),
+                None => Err(format!("usage: /{} {}", command.name, command.usage)),
+            },
+        }
+    }
+}
+
+/// Splits rest into the arguments usage describes.
+///
+/// Returns None if there are too few or too many.
+fn parse_args<'a>(usage: &str, rest: &'a str) -> Option<Vec<&'a str>> {
+    let mut args = Vec::new();
+    let mut rest = rest.trim();
+    for param in usage.split_whitespace() {
+        let required = param.starts_with('<');
+        if rest.is_empty() {
+            if required {
+                return None;
+            }
+            break;
+        }
+        if param.trim_end_matches([']', '>']).ends_with("...") {
+            args.push(rest);
+            rest = "";
+            break;
+        }
+        let (arg, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        args.push(arg);
+        rest = tail.trim_start();
+    }
+    if rest.is_empty() { Some(args) } else { None }
+}
+
+fn help(inv: &mut Invocation) -> Result<(), String> {
+    let commands = &inv.epserver.commands;
+    let text = match inv.args.first() {
+        Some(name) => {
+            let command = commands.get(name.trim_start_matches('/')).ok_or(format!("unknown command {}", name))?;
+            format!("* /{} {} -- {}", command.name, command.usage, command.help)
+        }
+        None => {
+            let mut text = String::from("* commands:");
+            for command in commands.commands.iter().filter(|c| c.level <= inv.client.level) {
+                text.push_str(&format!("\n*   /{} {} -- {}", command.name, command.usage, command.help));
+            }
+            text
+        }
+    };
+    inv.reply(&text);
+    Ok(())
+}
+
+fn nick(inv: &mut Invocation) -> Result<(), String> {
+    let (me, tenant) = (inv.client.fd, inv.client.tenant);
+    let taken = |name: &str| inv.clients.iter().filter(|(cfd, _)| **cfd != me).any(|(_, c)| {
+        let c = c.borrow();
+        c.tenant == tenant && c.nick.as_ref().is_some_and(|n| n.to_lowercase() == name.to_lowercase())
+    });
+    if let Some(refusal) = inv.epserver.nicks.refusal(inv.args[0], inv.args.get(1).copied(), taken) {
+        return Err(refusal);
+    }
+    let nick = inv.args[0].to_string();
+    inv.reply(&format!("* you are now known as {}", nick));
+    inv.client.nick = Some(nick);
+    Ok(())
+}
+
+/// Most metadata entries a client may set.
+const MAX_META: usize = 16;
+
+fn set(inv: &mut Invocation) -> Result<(), String> {
+    let mut pairs = Vec::new();
+    for pair in inv.args[0].split_whitespace() {
+        let (key, value) = pair.split_once('=').ok_or("usage: /set <key=value...>")?;
+        let valid_key = (1..=32).contains(&key.len())
+            && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.');
+        if !valid_key {
+            return Err(format!("invalid key {:?}, keys are up to 32 letters, digits, _, - or .", key));
+        }
+        if !(1..=128).contains(&value.len()) {
+            return Err(format!("the value of {} has to be 1 to 128 bytes", key));
+        }
+        pairs.push((key.to_string(), value.to_string()));
+    }
+    let added = pairs.iter().filter(|(k, _)| !inv.client.meta.contains_key(k)).count();
+    if inv.client.meta.len() + added > MAX_META {
+        return Err(format!("at most {} keys can be set", MAX_META));
+    }
+    let keys: Vec<String> = pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    inv.client.meta.extend(pairs);
+    inv.reply(&format!("* set {}", keys.join(" ")));
+    Ok(())
+}
+
+fn unset(inv: &mut Invocation) -> Result<(), String> {
+    let key = inv.args[0];
+    inv.client.meta.remove(key).ok_or(format!("{} is not set", key))?;
+    inv.reply(&format!("* unset {}", key));
+    Ok(())
+}
+
+fn resume(inv: &mut Invocation) -> Result<(), String> {
+    match inv.args.first() {
+        None => {
+            let next = inv.epserver.history_for(inv.client.tenant).borrow().next_offset();
+            send(inv.epserver, inv.client, format!("* offsets on, next is {}\n", next).as_bytes());
+        }
+        Some(offset) => {
+            let offset = offset.parse::<u64>().map_err(|_| "usage: /resume [offset]".to_string())?;
+            crate::resume(inv.client, offset, inv.epserver);
+        }
+    }
+    inv.client.offsets = true;
+    Ok(())
+}
+
+fn seq(inv: &mut Invocation) -> Result<(), String> {
+    if inv.client.seq.is_none() {
+        inv.client.seq = Some(0);
+    }
+    inv.client.offsets = true;
+    inv.reply(&format!("* seq on, next is {}", inv.client.seq.unwrap_or(0) + 1));
+    Ok(())
+}
+
+fn join(inv: &mut Invocation) -> Result<(), String> {
+    let room = inv.args[0];
+    if !rooms::valid_name(room) {
+        return Err("room names are # and up to 32 letters, digits, - or _".to_string());
+    }
+    // a lone argument that is no qos is the password
+    let (qos, password) = match inv.args[1..] {
+        [] => (Qos::BestEffort, None),
+        [arg] => match Qos::parse(arg) {
+            Some(qos) => (qos, None),
+            None => (Qos::BestEffort, Some(arg)),
+        },
+        [qos, password, ..] => (Qos::parse(qos).ok_or("usage: /join <#room> [best-effort|reliable] [password]")?, Some(password)),
+    };
+
+    let nick = inv.client.nick.as_deref();
+    let mut rooms = inv.epserver.rooms_for(inv.client.tenant).borrow_mut();
+    if let Some(refusal) = rooms.refusal(room, inv.client.fd, nick, password) {
+        return Err(format!("{} {}", room, refusal));
+    }
+    let joined = rooms.join(room, inv.client.fd, nick, qos);
+    let mut reply = match qos {
+        Qos::BestEffort => format!("* joined {}", room),
+        Qos::Reliable => format!("* joined {} (reliable)", room),
+    };
+    if let Some(topic) = &joined.topic {
+        reply.push_str(&format!("\n{} * topic: {}", room, topic));
+    }
+    // what was said lately, if the rooms policy keeps it
+    for message in &joined.recent {
+        reply.push('\n');
+        reply.push_str(&String::from_utf8_lossy(message));
+    }
+    drop(rooms);
+    inv.reply(&reply);
+    Ok(())
+}
+
+fn part(inv: &mut Invocation) -> Result<(), String> {
+    let room = inv.args[0];
+    if !inv.epserver.rooms_for(inv.client.tenant).borrow_mut().part(room, inv.client.fd) {
+        return Err(format!("you are not in {}", room));
+    }
+    if inv.client.room.as_deref() == Some(room) {
+        inv.client.room = None;
+    }
+    inv.reply(&format!("* left {}", room));
+    Ok(())
+}
+
+fn topic(inv: &mut Invocation) -> Result<(), String> {
+    let name = inv.args[0];
+    let mut rooms = inv.epserver.rooms_for(inv.client.tenant).borrow_mut();
+    let room = rooms.get(name).filter(|r| r.members.contains(&inv.client.fd))
+        .ok_or(format!("you are not in {}", name))?;
+    let reply = match inv.args.get(1) {
+        None => match &room.topic {
+            Some(topic) => format!("{} * topic: {}", name, topic),
+            None => format!("* {} has no topic", name),
+        },
+        Some(_) if !room.ops.contains(&inv.client.fd) => return Err(format!("only ops of {} can set its topic", name)),
+        Some(text) => {
+            rooms.set_topic(name, Some(text.to_string()));
+            format!("* topic of {} set", name)
+        }
+    };
+    drop(rooms);
+    inv.reply(&reply);
+    Ok(())
+}
+
+fn key(inv: &mut Invocation) -> Result<(), String> {
+    let name = inv.args[0];
+    let password = inv.args.get(1).map(|p| p.to_string());
+    let reply = match &password {
+        Some(_) => format!("* joining {} takes a password now", name),
+        None => format!("* {} takes no password anymore", name),
+    };
+    room_as_op(inv, name)?.password = password;
+    inv.reply(&reply);
+    Ok(())
+}
+
+fn allow(inv: &mut Invocation) -> Result<(), String> {
+    let (name, nick) = (inv.args[0], inv.args[1]);
+    room_as_op(inv, name)?.allowed.insert(nick.to_string());
+    inv.reply(&format!("* {} may join {}", nick, name));
+    Ok(())
+}
+
+fn disallow(inv: &mut Invocation) -> Result<(), String> {
+    let (name, nick) = (inv.args[0], inv.args[1]);
+    let mut room = room_as_op(inv, name)?;
+    if !room.allowed.remove(nick) {
+        return Err(format!("{} is not on the allowed list of {}", nick, name));
+    }
+    let open = room.allowed.is_empty();
+    drop(room);
+    inv.reply(&format!("* {} took {} off the allowed list{}", name, nick, if open { ", anyone may join now" } else { "" }));
+    Ok(())
+}
+
+/// The room name, for changing it, if the client is one of its ops.
+fn room_as_op<'a>(inv: &Invocation<'a>, name: &str) -> Result<std::cell::RefMut<'a, rooms::Room>, String> {
+    let fd = inv.client.fd;
+    let rooms = inv.epserver.rooms_for(inv.client.tenant).borrow_mut();
+    std::cell::RefMut::filter_map(rooms, |rooms| rooms.get_mut(name).filter(|r| r.ops.contains(&fd)))
+        .map_err(|_| format!("only ops of {} can change who may join it", name))
+}
+
+fn who(inv: &mut Invocation) -> Result<(), String> {
+    let page = match inv.args.first() {
+        Some(page) => page.parse().map_err(|_| "usage: /who [page]".to_string())?,
+        None => 1,
+    };
+    let tenant = inv.client.tenant;
+    let list = crate::who::list(inv.epserver, inv.clients, Some(inv.client), page, |c| c.authed && c.tenant == tenant);
+    let text: Vec<String> = list.lines().map(|l| format!("* {}", l)).collect();
+    inv.reply(&text.join("\n"));
+    Ok(())
+}
+
+fn time(inv: &mut Invocation) -> Result<(), String> {
+    let now = SystemTime::now();
+    inv.reply(&format!("* time {} {}", clock::format(now, TimeFormat::Iso8601), clock::millis(now)));
+    Ok(())
+}
+
+fn probe(inv: &mut Invocation) -> Result<(), String> {
+    match inv.args.first().copied() {
+        None | Some("on") => {
+            if !start_probing(inv.client, inv.epserver) {
+                return Err("probes are off on this server".to_string());
+            }
+            inv.reply("* probes on");
+        }
+        Some("off") => {
+            inv.client.probed = false;
+            inv.reply("* probes off");
+        }
+        Some(_) => return Err("usage: /probe [on|off]".to_string()),
+    }
+    Ok(())
+}
+
+fn pong(inv: &mut Invocation) -> Result<(), String> {
+    let id = inv.args[0].parse().map_err(|_| "usage: /pong <id>".to_string())?;
+    // answers are quiet, a reply would only add to what probes measure
+    answer_probe(inv.epserver, inv.client, id).ok_or_else(|| format!("no probe {} waiting for an answer", id))?;
+    Ok(())
+}
+
+fn subscribe(inv: &mut Invocation) -> Result<(), String> {
+    let name = inv.args[0];
+    if !subscriptions::valid_name(name) {
+        return Err("subscription names are up to 32 letters, digits, -, _ or .".to_string());
+    }
+    subscriptions::subscribe(inv.client, name, inv.epserver)
+}
+
+fn unsubscribe(inv: &mut Invocation) -> Result<(), String> {
+    let name = inv.client.subscription.take().ok_or("not subscribed")?;
+    inv.epserver.subscriptions.borrow_mut().remove(inv.client.tenant, &name);
+    inv.client.delivered = None;
+    inv.client.unacked_since = None;
+    inv.reply(&format!("* unsubscribed {}", name));
+    Ok(())
+}
+
+fn ack(inv: &mut Invocation) -> Result<(), String> {
+    let offset = inv.args[0].parse().map_err(|_| "usage: /ack <offset>".to_string())?;
+    // quiet like /pong, subscribers ack all the time
+    subscriptions::ack(inv.client, offset, inv.epserver)
+}
+
+fn auth(inv: &mut Invocation) -> Result<(), String> {
+    if inv.client.authed {
+        return Err("already authenticated".to_string());
+    }
+    let epserver = inv.epserver;
+    let policy = epserver.listener_policy(inv.client.listener).ok_or("wrong token")?;
+    let token = inv.args[0];
+    if policy.tenant.as_deref() == Some("*") {
+        let t = epserver.tenants.iter().position(|t| t.token.as_deref() == Some(token)).ok_or("wrong token")?;
+        if epserver.tenant_full(t, inv.clients) {
+            return Err(format!("tenant {} is full", epserver.tenants[t].name));
+        }
+        inv.client.tenant = Some(t);
+    } else if policy.token.as_deref() != Some(token) {
+        return Err("wrong token".to_string());
+    }
+    inv.client.authed = true;
+    inv.reply("* authenticated");
+    let notice = format!("* {} joined\n", crate::display_name(inv.client));
+    crate::announce(epserver, inv.clients, inv.client.tenant, &notice);
+    Ok(())
+}
+
+fn mute(inv: &mut Invocation) -> Result<(), String> {
+    set_mute(inv, Mute::Muted)
+}
+
+fn unmute(inv: &mut Invocation) -> Result<(), String> {
+    set_mute(inv, Mute::Off)
+}
+
+fn set_mute(inv: &mut Invocation, mute: Mute) -> Result<(), String> {
+    let target = inv.args[0];
+    let cfd = other_client(inv, target)?;
+    inv.clients[&cfd].borrow_mut().mute = mute;
+
+    let done = if mute == Mute::Off { "unmuted" } else { "muted" };
+    inv.reply(&format!("* {} {}", done, target));
+    Ok(())
+}
+
+fn kick(inv: &mut Invocation) -> Result<(), String> {
+    let target = inv.args[0];
+    let cfd = other_client(inv, target)?;
+    inv.epserver.sanctions.borrow_mut().push((inv.client.fd, Sanction::Kick(cfd, target.to_string())));
+    Ok(())
+}
+
+fn ban(inv: &mut Invocation) -> Result<(), String> {
+    let target = inv.args[0];
+    let ip = match target.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => {
+            let cfd = other_client(inv, target)?;
+            let peer = inv.clients[&cfd].borrow().peer;
+            peer.ok_or(format!("{} has no address", target))?.ip()
+        }
+    };
+    if inv.client.peer.is_some_and(|a| a.ip() == ip) {
+        return Err(format!("{} is your own address", ip));
+    }
+    let duration = inv.args.get(1).map(|d| d.to_string());
+    if let Some(d) = &duration {
+        parse_duration(d).ok_or(format!("invalid duration {}", d))?;
+    }
+    inv.epserver.sanctions.borrow_mut().push((inv.client.fd, Sanction::Ban(ip, duration)));
+    Ok(())
+}
+
+/// Looks up another client of the same tenant by fd or nick.
+fn other_client(inv: &Invocation, target: &str) -> Result<i32, String> {
+    let (me, tenant) = (inv.client.fd, inv.client.tenant);
+    let mut others = inv.clients.iter().filter(|(cfd, c)| **cfd != me && c.borrow().tenant == tenant);
+    match target.parse::<i32>() {
+        Ok(cfd) => others.find(|(c, _)| **c == cfd),
+        Err(_) => others.find(|(_, c)| c.borrow().nick.as_deref() == Some(target)),
+    }.map(|(cfd, _)| *cfd).ok_or(format!("no other client {}", target))
+}
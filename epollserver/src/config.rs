@@ -0,0 +1,92 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// A `--config` file made of `[section]` headers followed by `key = value` lines.
+/// Lines starting with `#` are comments. Keys may repeat and sections may appear
+/// more than once, everything keeps its file order.
+pub struct Config {
+    path: PathBuf,
+    sections: Vec<Section>,
+}
+
+pub struct Section {
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+impl Config {
+    /// An empty config, used when no file was given.
+    pub fn empty() -> Config {
+        Config { path: PathBuf::new(), sections: Vec::new() }
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        let mut config = Config { path: path.to_path_buf(), sections: Vec::new() };
+
+        for (i, raw) in text.lines().enumerate() {
+            let line = raw.trim();
+            let n = i + 1;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                config.sections.push(Section { name: name.trim().to_string(), line: n, entries: Vec::new() });
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some(kv) => kv,
+                None => return Err(config.error(n, "expected `key = value`")),
+            };
+            match config.sections.last_mut() {
+                Some(section) => section.entries.push(Entry {
+                    key: key.trim().to_string(),
+                    value: value.trim().to_string(),
+                    line: n,
+                }),
+                None => return Err(config.error(n, "entry outside of a [section]")),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The file this was read from, None for the empty config.
+    pub fn path(&self) -> Option<&Path> {
+        Some(self.path.as_path()).filter(|p| !p.as_os_str().is_empty())
+    }
+
+    /// Every section called `prefix.<something>`, paired with the part after the dot.
+    pub fn sections_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a Section)> {
+        self.sections.iter().filter_map(move |s| {
+            s.name.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('.')).map(|rest| (rest, s))
+        })
+    }
+
+    /// The last section called name, if any.
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().rev().find(|s| s.name == name)
+    }
+
+    /// An InvalidData error pointing at a line of the file.
+    pub fn error(&self, line: usize, msg: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", self.path.display(), line, msg))
+    }
+}
+
+impl Section {
+    /// The last value given for key, if any.
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().rev().find(|e| e.key == key)
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::logging::warning;
+
+/// Detaches from the terminal the classic way: fork, setsid, fork again so the
+/// daemon can never reacquire a controlling terminal. stdin is /dev/null and
+/// stdout and stderr go to log, or /dev/null without one. The working directory
+/// is kept so relative paths from the command line still work. Only the
+/// grandchild returns, call it before starting any threads.
+pub fn daemonize(log: Option<&Path>) -> Result<()> {
+    // open first so a bad path is still reported on the terminal
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let out = match log {
+        Some(path) => OpenOptions::new().create(true).append(true).mode(0o640).open(path)?,
+        None => null.try_clone()?,
+    };
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(os_error("setsid"));
+    }
+    fork_and_exit_parent()?;
+
+    let _ = std::io::stdout().flush();
+    unsafe {
+        if libc::dup2(null.as_raw_fd(), 0) < 0 || libc::dup2(out.as_raw_fd(), 1) < 0 || libc::dup2(out.as_raw_fd(), 2) < 0 {
+            return Err(os_error("dup2"));
+        }
+        libc::umask(0o027);
+    }
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> Result<()> {
+    match unsafe { libc::fork() } {
+        pid if pid < 0 => Err(os_error("fork")),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Holds the pid of the server in a file for init scripts, removed again on
+/// drop. Refuses to start when the file names a process that is still alive.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    pub fn create(path: PathBuf) -> Result<Pidfile> {
+        if let Ok(text) = fs::read_to_string(&path) {
+            if let Ok(pid) = text.trim().parse::<libc::pid_t>() {
+                if pid > 0 && unsafe { libc::kill(pid, 0) } == 0 {
+                    let errmsg = format!("{} says epollserver is already running as {}", path.display(), pid);
+                    return Err(Error::new(ErrorKind::AlreadyExists, errmsg));
+                }
+            }
+        }
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(Pidfile { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // after dropping privileges or a chroot this may no longer be possible
+        if let Err(e) = fs::remove_file(&self.path) {
+            warning!("cannot remove {} -- {}", self.path.display(), e);
+        }
+    }
+}
+
+fn os_error(call: &str) -> Error {
+    Error::other(format!("{} failed -- {}", call, Error::last_os_error()))
+}
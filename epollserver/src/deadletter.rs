@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use crate::clock::{self, TimeFormat};
+use crate::logging::warning;
+use crate::{send, ClientState, EpollServer};
+
+pub static DEAD_LETTERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Why a message was dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// A room took no more messages from the sender for now.
+    RateLimit,
+    /// Longer than a room takes.
+    TooLarge,
+    /// A recipients outbox was full.
+    FullQueue,
+    Invalid,
+    Duplicate,
+    Filter,
+    Script,
+    Plugin,
+    Rule,
+    /// Nobody else was there to get it.
+    NoRecipients,
+}
+
+impl Reason {
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::RateLimit => "rate-limit",
+            Reason::TooLarge => "too-large",
+            Reason::FullQueue => "full-queue",
+            Reason::Invalid => "invalid",
+            Reason::Duplicate => "duplicate",
+            Reason::Filter => "filter",
+            Reason::Script => "script",
+            Reason::Plugin => "plugin",
+            Reason::Rule => "rule",
+            Reason::NoRecipients => "no-recipients",
+        }
+    }
+}
+
+enum Target {
+    File(PathBuf, File),
+    Room(String),
+}
+
+/// Where dropped messages go instead of nowhere, given to `--dead-letter` as a
+/// file to append to or a `#room` of the senders tenant. Each one is annotated
+/// with why and who sent it, and in the file when:
+///
+/// ```text
+/// 2026-10-14T09:30:00.123Z full-queue fd=5 to=7 the message
+/// #audit full-queue fd=5 to=7 the message
+/// ```
+///
+/// They are collected while messages are handled and written out after each
+/// wakeup, straight to the rooms members without going through the broadcast
+/// path, so dead letters that can't be delivered are not diverted again.
+pub struct DeadLetters {
+    target: Target,
+    pending: RefCell<Vec<(Option<usize>, Vec<u8>)>>, // tenant, annotated line
+}
+
+impl DeadLetters {
+    pub fn open(spec: &str) -> Result<DeadLetters> {
+        let target = match spec.starts_with('#') {
+            true => Target::Room(spec.to_string()),
+            false => Target::File(PathBuf::from(spec), OpenOptions::new().create(true).append(true).open(spec)?),
+        };
+        Ok(DeadLetters { target, pending: RefCell::new(Vec::new()) })
+    }
+
+    /// Records a single message (without its newline) from fd that was dropped,
+    /// for recipient to if it was only dropped for one.
+    pub fn divert(&self, tenant: Option<usize>, from: i32, to: Option<i32>, reason: Reason, message: &[u8]) {
+        DEAD_LETTERS.fetch_add(1, Ordering::Relaxed);
+        let mut line = format!("{} fd={}", reason.name(), from).into_bytes();
+        if let Some(to) = to {
+            line.extend_from_slice(format!(" to={}", to).as_bytes());
+        }
+        line.push(b' ');
+        line.extend_from_slice(message);
+        line.push(b'\n');
+        self.pending.borrow_mut().push((tenant, line));
+    }
+}
+
+/// Writes out what was diverted since the last call.
+pub fn flush(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    let Some(dead) = &epserver.dead_letters else {
+        return;
+    };
+    let pending = dead.pending.take();
+    if pending.is_empty() {
+        return;
+    }
+    match &dead.target {
+        Target::File(path, file) => {
+            let stamp = clock::format(SystemTime::now(), TimeFormat::Iso8601);
+            let data = pending.into_iter().flat_map(|(_, line)| [stamp.as_bytes(), b" ", &line].concat()).collect::<Vec<u8>>();
+            if let Err(e) = (&*file).write_all(&data) {
+                warning!("cannot write dead letters to {} -- {}", path.display(), e);
+            }
+        }
+        Target::Room(name) => {
+            for (tenant, line) in pending {
+                let members = match epserver.rooms_for(tenant).borrow().get(name) {
+                    Some(room) => room.members.iter().copied().collect::<Vec<i32>>(),
+                    None => continue,
+                };
+                let line = [name.as_bytes(), b" ", &line].concat();
+                for fd in members {
+                    if let Some(client) = clients.get(&fd) {
+                        send(epserver, &mut client.borrow_mut(), &line);
+                    }
+                }
+            }
+        }
+    }
+}
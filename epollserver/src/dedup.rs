@@ -0,0 +1,101 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::bans::parse_duration;
+use crate::config::Config;
+
+pub static DUPLICATES_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Drops messages seen before within a sliding window, so a client that retries
+/// after a reconnect doesn't make everyone see the message twice. Enabled by a
+/// `[dedup]` section:
+///
+/// ```text
+/// [dedup]
+/// window = 30s       # how long a message is remembered
+/// by = id            # or `content` to also hash messages without an id
+/// ```
+///
+/// Clients tag a message with an id by starting it with `!<id> `, the tag is
+/// stripped before the message is broadcast. Ids and hashes are shared by every
+/// client of a tenant.
+pub struct Dedup {
+    window: Duration,
+    by_content: bool,
+    seen: HashMap<u64, Instant>,
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl Dedup {
+    /// None if the config has no `[dedup]` section.
+    pub fn from_config(config: &Config) -> Result<Option<Dedup>> {
+        let section = match config.section("dedup") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        let window = match section.get("window") {
+            Some(entry) => parse_duration(&entry.value)
+                .ok_or_else(|| config.error(entry.line, "window must look like 90, 90s, 15m, 2h or 7d"))?,
+            None => Duration::from_secs(30),
+        };
+        let by_content = match section.get("by").map(|e| (e.value.as_str(), e.line)) {
+            Some(("id", _)) | None => false,
+            Some(("content", _)) => true,
+            Some((_, line)) => return Err(config.error(line, "by must be `id` or `content`")),
+        };
+
+        Ok(Some(Dedup { window, by_content, seen: HashMap::new(), order: VecDeque::new() }))
+    }
+
+    /// Checks a single message (without its newline) from a client of tenant.
+    ///
+    /// Returns the message without its id tag, or None if it is a duplicate.
+    pub fn check<'a>(&mut self, tenant: Option<usize>, message: &'a [u8], now: Instant) -> Option<&'a [u8]> {
+        while let Some(&(at, key)) = self.order.front() {
+            if now.duration_since(at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            if self.seen.get(&key) == Some(&at) {
+                self.seen.remove(&key);
+            }
+        }
+
+        let (key, body) = match tagged(message) {
+            Some((id, body)) => (hash(tenant, b"id", id), body),
+            None if self.by_content => (hash(tenant, b"content", message), message),
+            None => return Some(message),
+        };
+
+        if self.seen.contains_key(&key) {
+            DUPLICATES_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.seen.insert(key, now);
+        self.order.push_back((now, key));
+        Some(body)
+    }
+}
+
+/// Splits `!<id> <body>` into id and body.
+fn tagged(message: &[u8]) -> Option<(&[u8], &[u8])> {
+    let rest = message.strip_prefix(b"!")?;
+    let space = rest.iter().position(|&b| b == b' ')?;
+    if space == 0 {
+        return None;
+    }
+    Some((&rest[..space], &rest[space + 1..]))
+}
+
+fn hash(tenant: Option<usize>, kind: &[u8], bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tenant.hash(&mut hasher);
+    kind.hash(&mut hasher);
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,208 @@
+use std::cell::{Cell, RefCell};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::io::Result;
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::logging::{info, warning};
+use crate::sys::{self, Sys};
+
+pub static DIALS: AtomicUsize = AtomicUsize::new(0);
+pub static DIAL_FAILURES: AtomicUsize = AtomicUsize::new(0);
+/// Dialed endpoints connected right now.
+pub static DIALED: AtomicUsize = AtomicUsize::new(0);
+
+/// Wait before dialing again after the first failure, doubled for every further
+/// one up to MAX_RETRY.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+/// How long connecting may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which way messages flow over a dialed connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// What the remote sends is broadcast, it gets nothing.
+    In,
+    /// The remote gets broadcasts, what it sends is ignored.
+    Out,
+    Both,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Direction, String> {
+        match s {
+            "in" => Ok(Direction::In),
+            "out" => Ok(Direction::Out),
+            "both" => Ok(Direction::Both),
+            _ => Err(format!("unknown direction {:?}, expected in, out or both", s)),
+        }
+    }
+}
+
+struct Dialer {
+    name: String,
+    addr: SocketAddr,
+    direction: Direction,
+    connecting: RefCell<Option<(TcpStream, Instant)>>, // and when it gives up
+    /// The client fd once connected.
+    client: Cell<Option<i32>>,
+    failures: Cell<u32>,
+    retry_at: Cell<Option<Instant>>,
+}
+
+/// A connection that went through, to be served like an accepted client.
+pub struct Dialed {
+    pub fd: i32,
+    pub name: String,
+    pub addr: SocketAddr,
+    pub direction: Direction,
+}
+
+/// Remote endpoints the server connects to itself, for systems that only take
+/// inbound connections. Each becomes a client named after its section once
+/// connected, and is dialed again whenever it disconnects:
+///
+/// ```text
+/// [dial.legacy]
+/// address = legacy.internal:7000
+/// direction = both   # in, out or both, see Direction
+/// ```
+///
+/// Only the first of several `--workers` dials, broadcasts of the others reach
+/// it over the shard bus.
+#[derive(Default)]
+pub struct Dialers {
+    dialers: Vec<Dialer>,
+}
+
+impl Dialers {
+    pub fn from_config(config: &Config) -> Result<Dialers> {
+        let mut dialers = Vec::new();
+        for (name, section) in config.sections_with_prefix("dial") {
+            let address = section.get("address").ok_or_else(|| config.error(section.line, "dial is missing `address`"))?;
+            let addr = address.value.to_socket_addrs()
+                .ok()
+                .and_then(|mut a| a.next())
+                .ok_or_else(|| config.error(address.line, &format!("{} does not resolve", address.value)))?;
+            let direction = match section.get("direction") {
+                Some(entry) => entry.value.parse().map_err(|e: String| config.error(entry.line, &e))?,
+                None => Direction::Both,
+            };
+            dialers.push(Dialer {
+                name: name.to_string(),
+                addr,
+                direction,
+                connecting: RefCell::new(None),
+                client: Cell::new(None),
+                failures: Cell::new(0),
+                retry_at: Cell::new(None),
+            });
+        }
+        Ok(Dialers { dialers })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dialers.is_empty()
+    }
+
+    /// Whether fd is a connection being made.
+    pub fn owns(&self, fd: i32) -> bool {
+        self.dialers.iter().any(|d| d.connecting_fd() == Some(fd))
+    }
+
+    /// Finishes connecting on fd, it became writable.
+    ///
+    /// Returns the connection if it went through.
+    pub fn handle_event(&self, sys: &dyn Sys, fd: i32, now: Instant) -> Option<Dialed> {
+        let dialer = self.dialers.iter().find(|d| d.connecting_fd() == Some(fd))?;
+        let (stream, _) = dialer.connecting.borrow_mut().take()?;
+        let connected = match stream.take_error() {
+            Ok(None) => sys.set_interest(fd, true, false),
+            Ok(Some(e)) | Err(e) => Err(e),
+        };
+        if let Err(e) = connected {
+            sys.unwatch(fd);
+            dialer.failed(now, &e.to_string());
+            return None;
+        }
+        info!("dialed {} at {}", dialer.name, dialer.addr);
+        dialer.failures.set(0);
+        DIALS.fetch_add(1, Ordering::Relaxed);
+        DIALED.fetch_add(1, Ordering::Relaxed);
+        let fd = stream.into_raw_fd();
+        dialer.client.set(Some(fd));
+        Some(Dialed { fd, name: dialer.name.clone(), addr: dialer.addr, direction: dialer.direction })
+    }
+
+    /// Dials again once the client on fd is gone, if it was a dialed one.
+    pub fn disconnected(&self, fd: i32, now: Instant) {
+        if let Some(dialer) = self.dialers.iter().find(|d| d.client.get() == Some(fd)) {
+            dialer.client.set(None);
+            DIALED.fetch_sub(1, Ordering::Relaxed);
+            info!("dialed {} disconnected, dialing again in {}s", dialer.name, RETRY_BACKOFF.as_secs());
+            dialer.retry_at.set(Some(now + RETRY_BACKOFF));
+        }
+    }
+
+    /// Dials what is due and gives up on connections taking too long, on every
+    /// wakeup.
+    pub fn poll(&self, sys: &dyn Sys, now: Instant) {
+        for dialer in &self.dialers {
+            let overdue = dialer.connecting.borrow().as_ref().is_some_and(|(_, deadline)| now >= *deadline);
+            if overdue {
+                if let Some((stream, _)) = dialer.connecting.borrow_mut().take() {
+                    sys.unwatch(stream.as_raw_fd());
+                }
+                dialer.failed(now, "timed out");
+            }
+            let idle = dialer.client.get().is_none() && dialer.connecting.borrow().is_none();
+            if idle && dialer.retry_at.get().is_none_or(|at| now >= at) {
+                dialer.start(sys, now);
+            }
+        }
+    }
+
+    /// When poll has something to do next.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.dialers.iter()
+            .flat_map(|d| [d.retry_at.get(), d.connecting.borrow().as_ref().map(|(_, deadline)| *deadline)])
+            .flatten()
+            .min()
+    }
+}
+
+impl Dialer {
+    fn connecting_fd(&self) -> Option<i32> {
+        self.connecting.borrow().as_ref().map(|(stream, _)| stream.as_raw_fd())
+    }
+
+    fn start(&self, sys: &dyn Sys, now: Instant) {
+        self.retry_at.set(None);
+        let stream = match sys::connect(self.addr) {
+            Ok(stream) => stream,
+            Err(e) => return self.failed(now, &e.to_string()),
+        };
+        let fd = stream.as_raw_fd();
+        if let Err(e) = sys.watch(fd).and_then(|_| sys.set_interest(fd, false, true)) {
+            return self.failed(now, &e.to_string());
+        }
+        *self.connecting.borrow_mut() = Some((stream, now + CONNECT_TIMEOUT));
+    }
+
+    fn failed(&self, now: Instant, why: &str) {
+        DIAL_FAILURES.fetch_add(1, Ordering::Relaxed);
+        let failures = self.failures.get() + 1;
+        self.failures.set(failures);
+        if failures == 1 {
+            warning!("dial {} to {} failed, retrying -- {}", self.name, self.addr, why);
+        }
+        let backoff = RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(failures - 1)).min(MAX_RETRY);
+        self.retry_at.set(Some(now + backoff));
+    }
+}
@@ -0,0 +1,51 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::logging::info;
+use crate::{notify, ClientState, EpollServer};
+
+/// Starts shutting down: new connections are refused, clients are told and not
+/// read from anymore, and what is queued for them gets until timeout to be written.
+/// The other `--workers` loops, if any, shut down too.
+pub fn start(epserver: &mut EpollServer, clients: &HashMap<i32, RefCell<ClientState>>, timeout: Duration) {
+    if epserver.draining.is_some() {
+        return;
+    }
+    info!("draining {} clients, {}s to flush", clients.len(), timeout.as_secs());
+    for (listener, _) in &epserver.listeners {
+        epserver.sys.unwatch(*listener);
+    }
+    epserver.draining = Some(epserver.sys.now() + timeout);
+    if let Some(shard) = &epserver.shard {
+        shard.drain_others();
+    }
+
+    for (cfd, client) in clients {
+        let mut client = client.borrow_mut();
+        notify(epserver, &mut client, b"* server is shutting down\n");
+        client.paused = true;
+        let _ = epserver.sys.set_interest(*cfd, false, !client.outbox.is_empty());
+    }
+}
+
+/// Closes every client once their outboxes are empty or the deadline passed.
+pub fn check(epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
+    let deadline = match epserver.draining {
+        Some(deadline) => deadline,
+        None => return,
+    };
+    let flushed = clients.values().all(|c| c.borrow().is_flushed());
+    if !flushed && epserver.sys.now() < deadline {
+        return;
+    }
+
+    let unflushed = clients.values().filter(|c| !c.borrow().is_flushed()).count();
+    for cfd in clients.keys() {
+        epserver.sys.unwatch(*cfd);
+        epserver.sys.close(*cfd);
+    }
+    info!("drained, closed {} clients ({} with unsent data)", clients.len(), unflushed);
+    clients.clear();
+    epserver.stopped = true;
+}
@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{Result, Write};
+
+use crate::{metrics, overload, ClientState, EpollServer};
+
+/// Writes a diagnostic snapshot to the servers dump file, or stderr without one.
+///
+/// Returns where it went.
+pub fn write(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> Result<String> {
+    let snapshot = render(epserver, clients);
+    match &epserver.dump_path {
+        Some(path) => {
+            OpenOptions::new().create(true).append(true).open(path)?.write_all(snapshot.as_bytes())?;
+            Ok(path.display().to_string())
+        }
+        None => {
+            std::io::stderr().write_all(snapshot.as_bytes())?;
+            Ok("stderr".to_string())
+        }
+    }
+}
+
+/// Everything needed to tell why a connection is stuck: per client buffer
+/// positions, queues and what epoll is asked to report, then the counters.
+pub fn render(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> String {
+    let mut out = String::new();
+    let uptime = epserver.sys.now().duration_since(epserver.started);
+    let _ = writeln!(out, "=== epollserver state, up {}s ===", uptime.as_secs());
+    let _ = writeln!(out, "listeners {:?}, draining {}, degraded {}", epserver.listeners, epserver.draining.is_some(), overload::degraded());
+    let _ = writeln!(out, "history next offset {}", epserver.history.borrow().next_offset());
+
+    let _ = writeln!(out, "clients ({}):", clients.len());
+    let mut fds: Vec<&i32> = clients.keys().collect();
+    fds.sort_unstable();
+    for fd in fds {
+        let client = match clients[fd].try_borrow() {
+            Ok(client) => client,
+            Err(_) => {
+                let _ = writeln!(out, "  {} (busy)", fd);
+                continue;
+            }
+        };
+        let events = match (client.paused, client.outbox.is_empty()) {
+            (false, true) => "IN",
+            (false, false) => "IN|OUT",
+            (true, true) => "-",
+            (true, false) => "OUT",
+        };
+        let _ = writeln!(
+            out,
+            "  {} nick={} peer={} off={} needle={} queued={} coalesced={} events={} mute={:?} level={:?} tenant={} read_only={} authed={} offsets={} acks={} probe={} backed_up={} residence={} slow={} meta={:?} sub={} delivered={} unacked={} holding={:?}",
+            fd,
+            client.nick.as_deref().unwrap_or("-"),
+            crate::peer_name(&client),
+            client.off,
+            client.needle,
+            client.outbox.len(),
+            client.coalesced.len(),
+            events,
+            client.mute,
+            client.level,
+            client.tenant.map_or("-", |t| epserver.tenants[t].name.as_str()),
+            client.read_only,
+            client.authed,
+            client.offsets,
+            client.acks,
+            client.probe_latency.map_or("-".to_string(), |l| format!("{:?}", l)),
+            client.backed_up_since.map_or("-".to_string(), |t| format!("{:?}", epserver.sys.now().saturating_duration_since(t))),
+            client.last_residence.map_or("-".to_string(), |r| format!("{:?}", r)),
+            client.slow,
+            client.meta,
+            client.subscription.as_deref().unwrap_or("-"),
+            client.delivered.map_or("-".to_string(), |d| d.to_string()),
+            client.unacked_since.map_or("-".to_string(), |t| format!("{:?}", epserver.sys.now().saturating_duration_since(t))),
+            client.holding
+        );
+    }
+
+    let _ = writeln!(out, "rooms:");
+    for (tenant, rooms) in epserver.namespaces() {
+        for room in rooms.borrow().iter() {
+            let prefix = if tenant.is_empty() { String::new() } else { format!("{}/", tenant) };
+            let _ = writeln!(
+                out,
+                "  {}{} members={:?} ops={:?} reliable={:?} pinned={} locked={} invited={:?} cap={}",
+                prefix, room.name, room.members, room.ops, room.reliable, room.pinned, room.locked, room.invited,
+                room.cap.map_or("-".to_string(), |c| c.to_string())
+            );
+        }
+    }
+
+    let _ = writeln!(out, "counters:");
+    for line in metrics::render().lines().filter(|l| !l.starts_with('#')) {
+        let _ = writeln!(out, "  {}", line);
+    }
+    out
+}
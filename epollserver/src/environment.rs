@@ -0,0 +1,78 @@
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+
+use structopt::clap::{App, ErrorKind as ClapError};
+
+/// Prefix of the environment variables options can be given in.
+pub const PREFIX: &str = "EPOLLBROADCAST_";
+
+/// Every command line option can also be given as an environment variable, named
+/// after its long form in upper case with `_` for `-` and the prefix in front,
+/// like `EPOLLBROADCAST_MAX_QUEUE_BYTES=65536` for `--max-queue-bytes 65536`.
+/// Flags take `1`, `true`, `yes` or `on` to be set, and `0`, `false`, `no`, `off`
+/// or nothing to be left off. Options that can be repeated take comma separated
+/// values. Options given on the command line win over the environment.
+///
+/// Returns args with the options from the environment in front.
+pub fn merge(app: App, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let mut vars: Vec<(String, String)> = std::env::vars().filter(|(k, _)| k.starts_with(PREFIX)).collect();
+    if vars.is_empty() {
+        return Ok(args);
+    }
+    vars.sort();
+
+    let given = app.clone().get_matches_from(&args);
+    let mut merged = args[..1].to_vec();
+    for (var, value) in vars {
+        let name = var[PREFIX.len()..].to_lowercase();
+        let long = format!("--{}", name.replace('_', "-"));
+        if given.occurrences_of(&name) > 0 {
+            continue;
+        }
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("{}: {}", var, msg));
+
+        match kind(&app, &long) {
+            Kind::Unknown => return Err(invalid("no such option")),
+            Kind::Flag => match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => merged.push(long.into()),
+                "0" | "false" | "no" | "off" | "" => {}
+                _ => return Err(invalid("expected true or false")),
+            },
+            Kind::Single => {
+                merged.push(long.into());
+                merged.push(value.into());
+            }
+            Kind::Repeated => {
+                for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                    merged.push(long.clone().into());
+                    merged.push(part.into());
+                }
+            }
+        }
+    }
+    merged.extend_from_slice(&args[1..]);
+    Ok(merged)
+}
+
+enum Kind {
+    Unknown,
+    Flag,
+    Single,
+    Repeated,
+}
+
+/// What kind of option long is, found out by how the parser takes to it: a flag
+/// leaves a value after it unexpected, an option takes it.
+fn kind(app: &App, long: &str) -> Kind {
+    let unknown = |args: &[&str]| matches!(app.clone().get_matches_from_safe(args), Err(e) if e.kind == ClapError::UnknownArgument);
+    if unknown(&["", long]) {
+        return Kind::Unknown;
+    }
+    if unknown(&["", long, "0"]) {
+        return Kind::Flag;
+    }
+    match app.clone().get_matches_from_safe(["", long, "0", long, "0"]) {
+        Err(e) if e.kind == ClapError::UnexpectedMultipleUsage => Kind::Single,
+        _ => Kind::Repeated,
+    }
+}
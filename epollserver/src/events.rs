@@ -0,0 +1,18 @@
+use std::net::SocketAddr;
+
+/// What happened to connections and messages, handed to the callback set with
+/// `EpollServer::on_event` so embedders can keep their own books.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A client was accepted and is now served.
+    Connected { fd: i32, peer: SocketAddr },
+    /// A client sent bytes worth of messages, before filters or mutes had a say.
+    MessageReceived { from: i32, bytes: usize },
+    /// A line got history offset seq and reached recipients clients.
+    Broadcasted { seq: u64, recipients: usize },
+    /// A client is gone, reason is what the others were told, e.g. `quit`.
+    Disconnected { fd: i32, reason: String },
+}
+
+/// Callback receiving every event, see `EpollServer::on_event`.
+pub type EventHandler = Box<dyn Fn(&Event)>;
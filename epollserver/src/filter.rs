@@ -0,0 +1,130 @@
+use std::fmt::Write as _;
+use std::io::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use regex::bytes::Regex;
+
+use crate::config::Config;
+use crate::Metadata;
+
+enum Action {
+    Drop,
+    Replace(Vec<u8>),
+}
+
+struct Rule {
+    name: String,
+    pattern: Regex,
+    from: Option<(String, String)>, // only applies to senders with this metadata
+    action: Action,
+    hits: AtomicUsize,
+}
+
+/// Ordered regex rules applied to every complete message before it is broadcast,
+/// configured with one `[filter.<name>]` section per rule:
+///
+/// ```text
+/// [filter.swearing]
+/// match = (?i)\b(darn|heck)\b
+/// action = replace
+/// replacement = ****
+/// ```
+///
+/// A rule with `from = <key>=<value>` only applies to messages from clients that
+/// set that metadata.
+pub struct FilterChain {
+    rules: Vec<Rule>,
+}
+
+impl FilterChain {
+    pub fn from_config(config: &Config) -> Result<FilterChain> {
+        let mut rules = Vec::new();
+
+        for (name, section) in config.sections_with_prefix("filter") {
+            let pattern = match section.get("match") {
+                Some(entry) => Regex::new(&entry.value)
+                    .map_err(|e| config.error(entry.line, &format!("invalid regex -- {}", e)))?,
+                None => return Err(config.error(section.line, "filter is missing `match`")),
+            };
+
+            let action = match section.get("action").map(|e| (e.value.as_str(), e.line)) {
+                Some(("drop", _)) | None => Action::Drop,
+                Some(("replace", line)) => match section.get("replacement") {
+                    Some(entry) => Action::Replace(entry.value.clone().into_bytes()),
+                    None => return Err(config.error(line, "replace filter is missing `replacement`")),
+                },
+                Some((_, line)) => return Err(config.error(line, "action must be `drop` or `replace`")),
+            };
+
+            let from = match section.get("from") {
+                Some(entry) => match entry.value.split_once('=') {
+                    Some((key, value)) => Some((key.trim().to_string(), value.trim().to_string())),
+                    None => return Err(config.error(entry.line, "from must be `<key>=<value>`")),
+                },
+                None => None,
+            };
+
+            rules.push(Rule { name: name.to_string(), pattern, from, action, hits: AtomicUsize::new(0) });
+        }
+
+        Ok(FilterChain { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Runs a single message (without its newline) through every rule in order,
+    /// meta being what its sender set.
+    ///
+    /// Returns None if a rule dropped the message.
+    pub fn apply(&self, message: &[u8], meta: &Metadata) -> Option<Vec<u8>> {
+        let mut message = message.to_vec();
+
+        for rule in &self.rules {
+            if rule.from.as_ref().is_some_and(|(key, value)| meta.get(key) != Some(value)) {
+                continue;
+            }
+            if !rule.pattern.is_match(&message) {
+                continue;
+            }
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+
+            match &rule.action {
+                Action::Drop => return None,
+                Action::Replace(with) => {
+                    message = rule.pattern.replace_all(&message, with.as_slice()).into_owned();
+                }
+            }
+        }
+
+        Some(message)
+    }
+
+    /// Per rule hit counters in prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        if self.rules.is_empty() {
+            return out;
+        }
+
+        let _ = writeln!(out, "# HELP epollbroadcast_filter_hits_total Messages matched by each filter rule.");
+        let _ = writeln!(out, "# TYPE epollbroadcast_filter_hits_total counter");
+        for rule in &self.rules {
+            let _ = writeln!(
+                out,
+                "epollbroadcast_filter_hits_total{{rule=\"{}\"}} {}",
+                rule.name,
+                rule.hits.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+
+    /// Zeroes every rule's hit counter, for the admin `reset-counters`.
+    pub fn reset_hits(&self) {
+        for rule in &self.rules {
+            rule.hits.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub static GREYLISTED: AtomicUsize = AtomicUsize::new(0);
+pub static GREYLIST_REFUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// How far back connects count towards the limit.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Addresses that reconnect more than `limit` times a minute, like clients stuck
+/// in a reconnect loop, get refused for a while instead of taking up the accept
+/// path. Set with `--greylist-reconnects`, and seen and edited through the admin
+/// `greylist`. Unlike bans, the greylist is not saved.
+pub struct Greylist {
+    /// Connects a minute an address may make, None to track nothing.
+    pub limit: Option<usize>,
+    /// How long an address over the limit is refused.
+    pub hold: Duration,
+    connects: HashMap<IpAddr, VecDeque<Instant>>, // within the last WINDOW
+    listed: HashMap<IpAddr, Instant>, // refused until
+}
+
+impl Greylist {
+    pub fn new(limit: Option<usize>, hold: Duration) -> Greylist {
+        Greylist { limit, hold, connects: HashMap::new(), listed: HashMap::new() }
+    }
+
+    /// Counts a connect from ip, returns whether it is to be refused. Connects
+    /// of a listed address don't count, so it gets back in once hold passed.
+    pub fn connect(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.is_listed(ip, now) {
+            GREYLIST_REFUSED.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        let Some(limit) = self.limit else { return false };
+        let connects = self.connects.entry(ip).or_default();
+        connects.push_back(now);
+        while connects.front().is_some_and(|t| now.duration_since(*t) > WINDOW) {
+            connects.pop_front();
+        }
+        if connects.len() <= limit {
+            return false;
+        }
+        self.connects.remove(&ip);
+        self.listed.insert(ip, now + self.hold);
+        GREYLISTED.fetch_add(1, Ordering::Relaxed);
+        GREYLIST_REFUSED.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn is_listed(&self, ip: IpAddr, now: Instant) -> bool {
+        self.listed.get(&ip).is_some_and(|until| *until > now)
+    }
+
+    /// Lists ip for duration, or hold if None.
+    pub fn add(&mut self, ip: IpAddr, duration: Option<Duration>, now: Instant) {
+        self.listed.insert(ip, now + duration.unwrap_or(self.hold));
+    }
+
+    /// Forgets ip and its connects, returns false if it was not listed.
+    pub fn remove(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.connects.remove(&ip);
+        self.listed.remove(&ip).is_some_and(|until| until > now)
+    }
+
+    /// Listed addresses with how long they are refused for.
+    pub fn listed(&mut self, now: Instant) -> Vec<(IpAddr, Duration)> {
+        self.prune(now);
+        let mut listed: Vec<_> = self.listed.iter().map(|(ip, until)| (*ip, until.duration_since(now))).collect();
+        listed.sort();
+        listed
+    }
+
+    /// Forgets expired listings and addresses that went quiet, called every tick.
+    pub fn prune(&mut self, now: Instant) {
+        self.listed.retain(|_, until| *until > now);
+        self.connects.retain(|_, connects| connects.back().is_some_and(|t| now.duration_since(*t) <= WINDOW));
+    }
+}
@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Error, Result, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::logging::{info, warning};
+use crate::{notify, peer_name, remove_client, ClientState, EpollServer};
+
+pub static HANDOFFS: AtomicUsize = AtomicUsize::new(0);
+pub static HANDOFFS_FAILED: AtomicUsize = AtomicUsize::new(0);
+
+/// How long a helper may take to accept a connection, longer and it stays here.
+const HELPER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client a `handoff` rule matched, waiting for the end of the wakeup to be
+/// passed on.
+pub struct Handoff {
+    pub rule: String,
+    pub socket: PathBuf,
+    /// What the client sent from the matching message on.
+    pub data: Vec<u8>,
+}
+
+/// Passes every client a rule matched to its helper, see Rules. The helper gets
+/// the connection itself over its unix socket, with one line
+///
+/// ```text
+/// HANDOFF <rule> peer=<address> nick=<nick>
+/// ```
+///
+/// and then what the client sent from the matching message on, the connection
+/// still nonblocking. The client leaves the broadcast as if it disconnected,
+/// anything still queued for it is dropped. Clients whose helper can't be
+/// reached stay and are told, what they sent from the message on is lost.
+pub(crate) fn complete(epserver: &EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
+    for fd in epserver.handoffs.take() {
+        let Some(client) = clients.get(&fd) else { continue };
+        let mut client = client.borrow_mut();
+        let Some(handoff) = client.handoff.take() else { continue };
+        let header = format!("HANDOFF {} peer={} nick={}\n", handoff.rule, peer_name(&client), client.nick.as_deref().unwrap_or("-"));
+        match send(&handoff.socket, fd, &[header.as_bytes(), &handoff.data].concat()) {
+            Ok(()) => {
+                HANDOFFS.fetch_add(1, Ordering::Relaxed);
+                info!("handed fd {} off to {} ({})", fd, handoff.socket.display(), handoff.rule);
+                drop(client);
+                remove_client(epserver, fd, clients, "handed off");
+            }
+            Err(e) => {
+                HANDOFFS_FAILED.fetch_add(1, Ordering::Relaxed);
+                warning!("failed to hand fd {} off to {} -- {}", fd, handoff.socket.display(), e);
+                notify(epserver, &mut client, b"* handoff failed, still here\n");
+                let writable = !client.outbox.is_empty();
+                let _ = epserver.sys.set_interest(fd, true, writable);
+            }
+        }
+    }
+}
+
+/// Sends fd with data to the helper listening on socket, in one message as far
+/// as the socket takes it.
+fn send(socket: &Path, fd: i32, data: &[u8]) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_write_timeout(Some(HELPER_TIMEOUT))?;
+
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<i32>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<i32>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut i32, fd);
+        libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    stream.write_all(&data[sent as usize..])
+}
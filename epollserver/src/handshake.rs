@@ -0,0 +1,92 @@
+use std::fmt::Write as _;
+
+use crate::rooms::Rate;
+
+/// Newest protocol version the server speaks. v1 is the raw line protocol, v2
+/// adds the optional `HELLO` greeting, v3 the `LIMITS` line after its answer.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Features a client can ask for in its greeting. Anything else it asks for,
+/// like `msgpack` or `zstd`, is left out of the answer and so not in use.
+pub const CAPABILITIES: [&str; 4] = ["resume", "seq", "probe", "req"];
+
+/// What both sides agreed on after a `HELLO v<version> [caps=a,b]` line.
+#[derive(Debug, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    pub caps: Vec<&'static str>,
+}
+
+impl Hello {
+    /// Parses a greeting (without its newline), None if it isn't one. Only the
+    /// first line of a connection is looked at, old clients that start with
+    /// anything else just speak v1.
+    pub fn negotiate(line: &str) -> Option<std::result::Result<Hello, String>> {
+        let mut words = line.strip_prefix("HELLO ")?.split_whitespace();
+        let version = match words.next().and_then(|v| v.strip_prefix('v')).and_then(|v| v.parse::<u32>().ok()) {
+            Some(version) if version > 0 => version.min(PROTOCOL_VERSION),
+            _ => return Some(Err("usage: HELLO v<version> [caps=a,b]".to_string())),
+        };
+        let mut caps = Vec::new();
+        for word in words {
+            let wanted = match word.strip_prefix("caps=") {
+                Some(wanted) => wanted,
+                None => return Some(Err(format!("unknown HELLO option {}", word))),
+            };
+            for cap in wanted.split(',') {
+                if let Some(cap) = CAPABILITIES.iter().find(|c| **c == cap) {
+                    if !caps.contains(cap) {
+                        caps.push(*cap);
+                    }
+                }
+            }
+        }
+        Some(Ok(Hello { version, caps }))
+    }
+
+    /// The greeting sent back.
+    pub fn reply(&self) -> String {
+        format!("HELLO v{} caps={}\n", self.version, self.caps.join(","))
+    }
+}
+
+/// What the server enforces on a connection, told to clients that greet with v3
+/// or later right after the answer, so they can stay within it rather than find
+/// out by being cut off:
+///
+/// ```text
+/// LIMITS max_message=<bytes> overflow=<policy> max_queue=<bytes> delivery=<mode> read_only=<bool> [rate.#<room>=<rate>] [max_message.#<room>=<bytes>]
+/// ```
+///
+/// Room entries only appear for rooms with such limits. Keys clients don't know
+/// are to be ignored, later versions may add some.
+#[derive(Debug, PartialEq)]
+pub struct Limits {
+    /// Longest line, without its newline.
+    pub max_message: usize,
+    /// What happens to longer ones: `message`, `discard` or `disconnect`.
+    pub overflow: &'static str,
+    /// Bytes queued for the client before it misses messages or is disconnected.
+    pub max_queue: usize,
+    pub delivery: &'static str,
+    pub read_only: bool,
+    /// Rooms with their rate and longest message, if limited.
+    pub rooms: Vec<(String, Option<Rate>, Option<usize>)>,
+}
+
+impl Limits {
+    pub fn line(&self) -> String {
+        let mut line = format!("LIMITS max_message={} overflow={} max_queue={} delivery={} read_only={}",
+            self.max_message, self.overflow, self.max_queue, self.delivery, self.read_only);
+        for (room, rate, max_message) in &self.rooms {
+            if let Some(rate) = rate {
+                let _ = write!(line, " rate.{}={}", room, rate);
+            }
+            if let Some(max) = max_message {
+                let _ = write!(line, " max_message.{}={}", room, max);
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
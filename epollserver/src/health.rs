@@ -0,0 +1,78 @@
+use std::fmt::Write as _;
+
+use crate::EpollServer;
+
+/// Answers the HTTP probes served on the admin port, for orchestrators that gate
+/// traffic on them:
+///
+/// * `/healthz` -- the event loop is live, its last tick ran on time
+/// * `/readyz` -- also every listener is accepting and the server is not draining
+///
+/// Both answer 200 or 503 with one `name: state` line per check, anything else 404.
+pub fn probe(path: &str, epserver: &EpollServer) -> (u16, String) {
+    let ready = match path {
+        "/healthz" => false,
+        "/readyz" => true,
+        _ => return (404, "not found\n".to_string()),
+    };
+
+    let mut body = String::new();
+    let mut ok = true;
+
+    // next_tick is moved on by every tick, a loop stuck somewhere falls behind it
+    let overdue = epserver.sys.now().saturating_duration_since(epserver.next_tick);
+    let live = overdue <= epserver.tick * 2;
+    ok &= live;
+    match live {
+        true => body.push_str("loop: ok\n"),
+        false => {
+            let _ = writeln!(body, "loop: tick overdue by {}ms", overdue.as_millis());
+        }
+    }
+
+    if ready {
+        let listening = epserver.listeners.iter().filter(|(fd, _)| accepting(*fd)).count();
+        let all = listening == epserver.listeners.len() && listening > 0;
+        ok &= all;
+        let _ = writeln!(body, "listeners: {}/{} accepting", listening, epserver.listeners.len());
+
+        let draining = epserver.draining.is_some() || epserver.stopped;
+        ok &= !draining;
+        body.push_str(if draining { "drain: draining\n" } else { "drain: no\n" });
+    }
+
+    (if ok { 200 } else { 503 }, body)
+}
+
+/// Whether fd is a socket listening for connections.
+fn accepting(fd: i32) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rc = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN, (&mut value as *mut libc::c_int).cast(), &mut len) };
+    rc == 0 && value != 0
+}
+
+/// A whole HTTP response, for connections closed right after it.
+pub fn response(status: u16, body: &str) -> String {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        501 => "Not Implemented",
+        _ => "Service Unavailable",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body
+    )
+}
+
+/// The path of an HTTP GET request line, like `GET /healthz HTTP/1.1`.
+pub fn request_path(line: &str) -> Option<&str> {
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("GET"), Some(path), Some(version)) if version.starts_with("HTTP/") => {
+            Some(path.split('?').next().unwrap_or(path))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The most recent broadcast messages, kept so reconnecting clients can `/resume`.
+///
+/// Every message gets an offset one higher than the previous, starting at first.
+pub struct History {
+    next: u64,
+    capacity: usize,
+    messages: VecDeque<(u64, Vec<u8>)>,
+}
+
+/// The current time in microseconds. Starting from it keeps offsets growing across
+/// restarts, so a client resuming from before a restart is told about the gap.
+pub fn first_offset_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+}
+
+impl History {
+    pub fn new(capacity: usize, first: u64) -> History {
+        History { next: first, capacity, messages: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Stores a message (without its newline), returns its offset.
+    pub fn push(&mut self, message: &[u8]) -> u64 {
+        let offset = self.next;
+        self.push_at(offset, message);
+        offset
+    }
+
+    /// Stores a message with the offset it already has, which must be past every
+    /// retained one.
+    pub fn push_at(&mut self, offset: u64, message: &[u8]) {
+        self.next = self.next.max(offset + 1);
+        if self.capacity > 0 {
+            if self.messages.len() == self.capacity {
+                self.messages.pop_front();
+            }
+            self.messages.push_back((offset, message.to_vec()));
+        }
+    }
+
+    /// Fills the history from a file of newline separated messages, oldest first,
+    /// giving them offsets as if they had just been broadcast. A line starting
+    /// with `@<offset> `, the way clients with offsets on receive it, keeps that
+    /// offset instead, those have to increase through the file.
+    ///
+    /// Returns the number of messages loaded.
+    pub fn preload(&mut self, path: &Path) -> Result<usize> {
+        let data = fs::read(path)?;
+        let mut last = None;
+        let mut loaded = 0;
+        for (n, line) in data.split(|&b| b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let tagged = line.strip_prefix(b"@").and_then(|l| {
+                let space = l.iter().position(|&b| b == b' ')?;
+                // live lines may carry a sequence number, `@<offset>:<seq> `
+                let tag = std::str::from_utf8(&l[..space]).ok()?;
+                let offset = tag.split(':').next()?.parse::<u64>().ok()?;
+                Some((offset, &l[space + 1..]))
+            });
+            let (offset, message) = match tagged {
+                Some((offset, _)) if last.is_some_and(|last| offset <= last) => {
+                    let errmsg = format!("line {}: offset {} is not after {}", n + 1, offset, last.unwrap_or(0));
+                    return Err(Error::new(ErrorKind::InvalidData, errmsg));
+                }
+                Some(tagged) => tagged,
+                None => (last.map_or(self.next, |last| last + 1), line),
+            };
+            self.push_at(offset, message);
+            last = Some(offset);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Makes sure no message gets an offset below next.
+    pub fn advance(&mut self, next: u64) {
+        self.next = self.next.max(next);
+    }
+
+    /// Offset the next message will get.
+    pub fn next_offset(&self) -> u64 {
+        self.next
+    }
+
+    /// Every retained message with its offset, oldest first.
+    pub fn messages(&self) -> impl Iterator<Item = &(u64, Vec<u8>)> {
+        self.messages.iter()
+    }
+
+    /// The retained messages after offset, and the first offset after it that was
+    /// not retained anymore if any were lost.
+    pub fn since(&self, offset: u64) -> (Option<u64>, impl Iterator<Item = &(u64, Vec<u8>)>) {
+        let oldest = self.messages.front().map_or(self.next, |(o, _)| *o);
+        let gap = if offset.saturating_add(1) < oldest { Some(offset + 1) } else { None };
+        (gap, self.messages.iter().filter(move |(o, _)| *o > offset))
+    }
+}
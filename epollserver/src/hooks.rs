@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::io::{Result, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use regex::bytes::Regex;
+
+use crate::bans::parse_duration;
+use crate::config::Config;
+use crate::logging::{info, warning};
+use crate::rooms::{Bucket, Rate};
+
+pub static HOOK_RUNS: AtomicUsize = AtomicUsize::new(0);
+pub static HOOK_SKIPPED: AtomicUsize = AtomicUsize::new(0);
+pub static HOOK_TIMEOUTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Commands running at once across all hooks, matches past it are skipped.
+pub const MAX_CHILDREN: usize = 32;
+
+struct Hook {
+    name: String,
+    pattern: Regex,
+    command: String,
+    rate: Option<Rate>,
+    bucket: RefCell<Option<Bucket>>,
+    timeout: Duration,
+    max_running: usize,
+}
+
+struct Running {
+    hook: usize,
+    child: Child,
+    started: Instant,
+    killed: bool,
+}
+
+/// Commands run for broadcast messages matching a pattern, like paging someone on
+/// alerts, configured with one `[hook.<name>]` section each:
+///
+/// ```text
+/// [hook.pager]
+/// match = ^ALERT
+/// command = /usr/local/bin/page --team oncall
+/// rate = 5/m
+/// timeout = 10s
+/// max_running = 2
+/// ```
+///
+/// The command runs with `/bin/sh -c`, the message on its stdin, its output
+/// discarded and errors going to the servers stderr. Nothing waits for it:
+/// finished ones are reaped on every tick and before starting more, and ones running longer than `timeout` (10s by default) are killed
+/// together with anything they started. Matches past the hooks `rate`, its
+/// `max_running` (1 by default) or MAX_CHILDREN in all are skipped and counted.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    running: RefCell<Vec<Running>>,
+}
+
+impl Hooks {
+    pub fn from_config(config: &Config) -> Result<Hooks> {
+        let mut hooks = Vec::new();
+
+        for (name, section) in config.sections_with_prefix("hook") {
+            let pattern = match section.get("match") {
+                Some(entry) => Regex::new(&entry.value)
+                    .map_err(|e| config.error(entry.line, &format!("invalid regex -- {}", e)))?,
+                None => return Err(config.error(section.line, "hook is missing `match`")),
+            };
+            let command = section.get("command").map(|e| e.value.clone())
+                .ok_or_else(|| config.error(section.line, "hook is missing `command`"))?;
+            let rate = match section.get("rate") {
+                Some(entry) => Some(entry.value.parse().map_err(|e: String| config.error(entry.line, &e))?),
+                None => None,
+            };
+            let timeout = match section.get("timeout") {
+                Some(entry) => parse_duration(&entry.value).filter(|t| !t.is_zero())
+                    .ok_or_else(|| config.error(entry.line, "timeout must be a duration like 10s or 2m"))?,
+                None => Duration::from_secs(10),
+            };
+            let max_running = match section.get("max_running") {
+                Some(entry) => entry.value.parse().ok().filter(|n| *n > 0)
+                    .ok_or_else(|| config.error(entry.line, "max_running must be a positive number"))?,
+                None => 1,
+            };
+
+            hooks.push(Hook { name: name.to_string(), pattern, command, rate, bucket: RefCell::new(None), timeout, max_running });
+        }
+
+        Ok(Hooks { hooks, running: RefCell::new(Vec::new()) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Starts the command of every hook matching a single message (without its
+    /// newline) that is within its limits.
+    pub fn fire(&self, message: &[u8], now: Instant) {
+        let mut reaped = false;
+        for (i, hook) in self.hooks.iter().enumerate().filter(|(_, h)| h.pattern.is_match(message)) {
+            if !reaped {
+                // counts the running ones right, not as of the last tick
+                self.reap(now);
+                reaped = true;
+            }
+            let mut running = self.running.borrow_mut();
+            let mine = running.iter().filter(|r| r.hook == i).count();
+            let allowed = match hook.rate {
+                Some(rate) => hook.bucket.borrow_mut().get_or_insert_with(|| Bucket::full(rate, now)).take(rate, now),
+                None => true,
+            };
+            if !allowed || mine >= hook.max_running || running.len() >= MAX_CHILDREN {
+                HOOK_SKIPPED.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            match spawn(&hook.command, message) {
+                Ok(child) => {
+                    HOOK_RUNS.fetch_add(1, Ordering::Relaxed);
+                    running.push(Running { hook: i, child, started: now, killed: false });
+                }
+                Err(e) => {
+                    HOOK_SKIPPED.fetch_add(1, Ordering::Relaxed);
+                    warning!("hook {} failed to start -- {}", hook.name, e);
+                }
+            }
+        }
+    }
+
+    /// Collects commands that finished, killing those past their timeout.
+    pub fn reap(&self, now: Instant) {
+        self.running.borrow_mut().retain_mut(|running| {
+            let hook = &self.hooks[running.hook];
+            match running.child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() && !running.killed {
+                        info!("hook {} exited with {}", hook.name, status);
+                    }
+                    false
+                }
+                Ok(None) if !running.killed && now.saturating_duration_since(running.started) > hook.timeout => {
+                    warning!("hook {} ran longer than {}s, killed", hook.name, hook.timeout.as_secs());
+                    HOOK_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                    // its own process group, so whatever the shell started goes too
+                    unsafe { libc::kill(-(running.child.id() as libc::pid_t), libc::SIGKILL) };
+                    running.killed = true;
+                    true
+                }
+                Ok(None) => true,
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+/// Runs command with message and a newline written to its stdin, as far as the
+/// pipe takes it without blocking.
+fn spawn(command: &str, message: &[u8]) -> Result<Child> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let fd = stdin.as_raw_fd();
+        unsafe { libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK) };
+        let _ = stdin.write_all(&[message, b"\n"].concat());
+    }
+    Ok(child)
+}
@@ -0,0 +1,1937 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+
+mod admin;
+pub mod affinity;
+pub mod banners;
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod bans;
+pub mod busypoll;
+pub mod chaos;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod daemon;
+pub mod deadletter;
+pub mod dedup;
+pub mod dial;
+mod drain;
+mod dump;
+pub mod environment;
+pub mod events;
+pub mod filter;
+pub mod greylist;
+pub mod handoff;
+pub mod handshake;
+mod health;
+pub mod history;
+pub mod hooks;
+pub mod listener;
+pub mod logging;
+mod metrics;
+pub mod mirror;
+pub mod multicast;
+pub mod nicks;
+pub mod numa;
+mod outbox;
+pub mod overload;
+pub mod plugin;
+pub mod poll;
+pub mod privileges;
+pub mod probe;
+pub mod record;
+pub mod rooms;
+pub mod roomstats;
+pub mod rules;
+pub mod sanitize;
+pub mod sandbox;
+pub mod scripting;
+pub mod signals;
+pub mod sim;
+pub mod sink;
+pub mod slow;
+pub mod shards;
+pub mod sniff;
+pub mod snapshot;
+pub mod source;
+pub mod subscriptions;
+pub mod sys;
+mod tail;
+pub mod tenant;
+pub mod throttle;
+pub mod trace;
+pub mod webhooks;
+mod who;
+
+use admin::AdminEndpoint;
+use banners::Banners;
+use bans::BanList;
+use commands::{Commands, Level, Sanction};
+use clock::TimeFormat;
+use config::Config;
+use deadletter::{DeadLetters, Reason};
+use dedup::Dedup;
+use dial::{Dialers, Direction};
+use events::{Event, EventHandler};
+use filter::FilterChain;
+use greylist::Greylist;
+use handoff::Handoff;
+use nicks::Nicks;
+use handshake::{Hello, Limits};
+use throttle::AcceptThrottle;
+use history::History;
+use hooks::Hooks;
+use listener::{Delivery, Policy};
+use logging::{debug, error, info};
+use mirror::Mirror;
+use metrics::{MetricsEndpoint, INBOUND_MESSAGE_BYTES, OUTBOUND_MESSAGE_BYTES, TOTAL_BYTES_SENT, WAIT_ERRORS, WAIT_INTERRUPTED};
+use multicast::Multicast;
+use outbox::{Lane, Outbox};
+use overload::OverloadMonitor;
+use probe::Probes;
+use slow::SlowConsumers;
+use source::{Backpressure, Sources};
+use shards::Shard;
+use sniff::Sniffed;
+use subscriptions::Subscriptions;
+use plugin::Plugin;
+use rooms::{Bucket, Qos, Rate, Room, RoomPolicy, Rooms};
+use roomstats::RoomStats;
+use rules::{Rules, Target};
+use sanitize::Sanitizer;
+use scripting::ScriptHooks;
+use signals::Signals;
+use sink::Sink;
+use sys::Sys;
+use trace::Tracer;
+use tenant::Tenant;
+use webhooks::Webhooks;
+
+pub const MAX_EVENTS: i32 = 256;
+const BUFFER_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mute {
+    Off,
+    Muted,  // messages are dropped and the client is told so
+    Shadow, // messages are dropped, but echoed back so the client doesn't notice
+}
+
+/// What a client said about itself with `/set key=value`, like its team. Shown in
+/// `/who`, passed to sinks with its messages and matched by filters.
+pub type Metadata = BTreeMap<String, String>;
+
+pub struct ClientState {
+    off: usize, // index after last u8 in buf if buf has no \n
+    needle: usize, // index after last \n in buf
+    buf: Box<[u8; BUFFER_SIZE + 1]>, // one spare byte to terminate an overflowing message
+    fd: i32,
+    nick: Option<String>,
+    mute: Mute,
+    offsets: bool, // prefix every message sent to this client with `@<offset> `
+    seq: Option<u64>, // last per connection number given out, `@<offset>:<seq> ` once on
+    coalesced: Vec<u8>, // data held back to be written with what follows, see Coalesce
+    outbox: Outbox,
+    acks: u64, // `?` messages acknowledged so far
+    paused: bool, // not read from until reliable subscribers catch up
+    holding: BTreeSet<i32>, // publishers paused until this clients outbox drains
+    level: Level,
+    last_active: Instant, // when the client last sent something, or connected
+    read_only: bool, // accepted on a subscriber listener
+    listener: i32, // accepted on, its policy decides what `/auth` wants
+    authed: bool, // false until `/auth` succeeds, if the listener wants it
+    tenant: Option<usize>, // index into EpollServer::tenants, None for the default
+    discarding: bool, // dropping input up to the next newline, see Overflow::Discard
+    backlogged: bool, // complete messages left in buf after using up the message budget
+    delivery: Delivery,
+    lagging: bool, // fell behind under at-least-once, gets nothing more and is disconnected
+    greeted: bool, // past the first line, the only one that may be a `HELLO`
+    requests: bool, // lines `REQ <id> <command>` are requests, see Commands::request
+    capturing: Option<Vec<u8>>, // what is sent to the client while it runs a request
+    probed: bool, // sent `* probe <id>` lines to answer with `/pong <id>`
+    probe_latency: Option<Duration>, // of the last probe answered
+    probe_next: u64, // lowest probe id it may still answer, keeps it to probes it got
+    backed_up_since: Option<Instant>, // when the outbox last stopped being empty
+    last_residence: Option<Duration>, // how long it stayed that way the last time
+    slow: bool, // backed up for longer than SlowConsumers allow
+    room_buckets: HashMap<String, Bucket>, // what is left of rate limited rooms' rates
+    meta: Metadata,
+    subscription: Option<String>, // attached to with `/subscribe`, see Subscriptions
+    delivered: Option<u64>, // last offset sent to it while subscribed
+    unacked_since: Option<Instant>, // when it got the oldest message it has not acked
+    peer: Option<SocketAddr>, // accepted from, None for clients not on a socket
+    room: Option<String>, // messages not addressed to a room go here, see Banners
+    handoff: Option<Handoff>, // matched a handoff rule, passed on at the end of the wakeup
+    sniffed: bool, // known to speak the line protocol, see sniff.rs
+    proxied: bool, // may still start with a PROXY protocol header
+    direction: Direction, // In for sources and dialed clients that only send, see Dialers
+    label: Option<String>, // what a source sends goes out as `[<label>] <line>`, see Sources
+    backpressure: Option<Backpressure>, // of a source, applied here while dropping or sampling
+    shed: u64, // lines a source sent while overloaded, for sampling
+}
+
+impl ClientState {
+    /// Whether everything sent to the client has been written.
+    fn is_flushed(&self) -> bool {
+        self.outbox.is_empty() && self.coalesced.is_empty()
+    }
+
+    pub fn with_fd(fd: i32) -> ClientState {
+        ClientState {
+            off: 0,
+            needle: 0,
+            buf: Box::new([0; BUFFER_SIZE + 1]),
+            fd,
+            nick: None,
+            mute: Mute::Off,
+            offsets: false,
+            seq: None,
+            coalesced: Vec::new(),
+            outbox: Outbox::new(),
+            acks: 0,
+            paused: false,
+            holding: BTreeSet::new(),
+            level: Level::User,
+            last_active: Instant::now(),
+            read_only: false,
+            listener: -1,
+            authed: true,
+            tenant: None,
+            discarding: false,
+            backlogged: false,
+            delivery: Delivery::AtMostOnce,
+            lagging: false,
+            greeted: false,
+            requests: false,
+            capturing: None,
+            probed: false,
+            probe_latency: None,
+            probe_next: 0,
+            backed_up_since: None,
+            last_residence: None,
+            slow: false,
+            room_buckets: HashMap::new(),
+            meta: Metadata::new(),
+            subscription: None,
+            delivered: None,
+            unacked_since: None,
+            peer: None,
+            room: None,
+            handoff: None,
+            sniffed: true,
+            proxied: false,
+            direction: Direction::Both,
+            label: None,
+            backpressure: None,
+            shed: 0,
+        }
+    }
+}
+
+/// What happens when a client fills its buffer without sending a newline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Broadcast the buffer as if it ended in a newline.
+    Message,
+    /// Drop it and everything up to the next newline.
+    Discard,
+    /// Tell the client and disconnect it.
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Overflow, String> {
+        match s {
+            "message" => Ok(Overflow::Message),
+            "discard" => Ok(Overflow::Discard),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!("unknown overflow policy {:?}, expected message, discard or disconnect", s)),
+        }
+    }
+}
+
+/// Holds small broadcasts back per client, so a chatty publisher costs one
+/// write for several messages instead of one each. Only used while nothing is
+/// queued for the client anyway.
+#[derive(Clone, Copy, Debug)]
+pub struct Coalesce {
+    /// How long the first message held back may wait, at millisecond granularity.
+    pub delay: Duration,
+    /// Written right away once this many bytes are held back.
+    pub bytes: usize,
+}
+
+pub struct EpollServer {
+    sys: Rc<dyn Sys>,
+    listeners: Vec<(i32, Policy)>,
+    metrics: Option<MetricsEndpoint>,
+    admin: Option<AdminEndpoint>,
+    pub bans: BanList,
+    pub greylist: Greylist,
+    pub overload: OverloadMonitor,
+    pub filters: FilterChain,
+    pub rules: Rules,
+    pub banners: Banners,
+    pub hooks: Hooks,
+    pub webhooks: Webhooks,
+    pub dialers: Dialers,
+    pub sources: Sources,
+    /// Where dropped messages are diverted to, if anywhere.
+    pub dead_letters: Option<DeadLetters>,
+    /// Copies a sample of all messages into a room, see the admin `mirror`.
+    pub mirror: Option<Mirror>,
+    pub scripts: Option<ScriptHooks>,
+    pub plugin: Option<Plugin>,
+    pub history: RefCell<History>,
+    pub dedup: Option<RefCell<Dedup>>,
+    pub rooms: RefCell<Rooms>,
+    /// Counters per room, see `--room-metrics`.
+    pub room_stats: RoomStats,
+    /// Namespaces besides the default one, see tenant.rs.
+    pub tenants: Vec<Tenant>,
+    pub commands: Commands,
+    pub nicks: Nicks,
+    /// Tell everyone when a client connects or leaves.
+    pub presence: bool,
+    /// How long SIGTERM gives clients to receive what is queued for them.
+    pub drain_timeout: Duration,
+    /// Where SIGUSR1 and the admin `dump` write state snapshots, stderr if None.
+    pub dump_path: Option<PathBuf>,
+    /// Where the admin `snapshot` persists bans, topics and history by default.
+    pub snapshot_path: Option<PathBuf>,
+    signals: Option<Signals>,
+    started: Instant,
+    /// How often periodic work runs, whether or not anything else happens.
+    pub tick: Duration,
+    next_tick: Instant,
+    draining: Option<Instant>, // deadline for flushing outboxes once shutting down
+    stopped: bool,
+    pub multicast: Option<Multicast>,
+    sinks: Vec<Sink>,
+    /// Messages for a client are dropped while this many bytes wait in its outbox.
+    pub max_queue_bytes: usize,
+    /// Prefix every broadcast message with the time the server relayed it.
+    pub timestamps: Option<TimeFormat>,
+    /// Prefix every broadcast message with the address of its sender.
+    pub attribute_peer: bool,
+    pub coalesce: Option<Coalesce>,
+    /// What to do with clients that send more than fits in a line.
+    pub overflow: Overflow,
+    pub sanitizer: Sanitizer,
+    /// Most complete messages handled for a client per wakeup, the rest wait
+    /// until every other ready client had its turn.
+    pub message_budget: Option<usize>,
+    /// Write the messages of one read that go to the same clients together instead
+    /// of one write per message. Hooks, filters and numbering see them one by one
+    /// either way.
+    pub batch: bool,
+    /// How messages are delivered on listeners that don't say.
+    pub delivery: Delivery,
+    pub slow_consumers: Option<SlowConsumers>,
+    pub subscriptions: RefCell<Subscriptions>,
+    pub tracer: Option<Tracer>,
+    /// How long a subscriber may leave messages unacknowledged before it is
+    /// disconnected to get them redelivered.
+    pub ack_timeout: Duration,
+    /// Limits on rooms by name, see RoomPolicy.
+    pub room_policies: BTreeMap<String, RoomPolicy>,
+    lagging: RefCell<Vec<i32>>, // clients to disconnect for falling behind
+    sanctions: RefCell<Vec<(i32, Sanction)>>, // operator /kick and /ban, and who ran them
+    handoffs: RefCell<Vec<i32>>, // clients to pass to a helper process
+    backlog: RefCell<Vec<i32>>, // clients with messages left over from the last wakeup
+    coalescing: RefCell<Vec<i32>>, // clients holding data back
+    coalesce_due: Cell<Option<Instant>>, // when the oldest of it has to go out
+    events: Option<EventHandler>,
+    probes: Option<RefCell<Probes>>,
+    /// Which of the `--workers` loops this is, if there are several.
+    pub shard: Option<Shard>,
+    spun_dry: Cell<bool>, // the last busy poll found nothing, wait right away
+    throttle: AcceptThrottle,
+}
+
+impl EpollServer {
+    pub fn new(sys: Rc<dyn Sys>, listener: i32) -> Result<EpollServer> {
+        if let Err(e) = sys.watch(listener) {
+            let errmsg = format!("failed to watch server fd {} -- {}", listener, e);
+            return Err(Error::other(errmsg));
+        }
+
+        let started = sys.now();
+        Ok(
+            EpollServer {
+                sys,
+                listeners: vec![(listener, Policy::default())],
+                metrics: None,
+                admin: None,
+                bans: BanList::new(),
+                greylist: Greylist::new(None, Duration::from_secs(300)),
+                overload: OverloadMonitor::new(Duration::from_millis(50), 1 << 20),
+                filters: FilterChain::from_config(&Config::empty())?,
+                rules: Rules::from_config(&Config::empty())?,
+                banners: Banners::default(),
+                hooks: Hooks::default(),
+                webhooks: Webhooks::default(),
+                dialers: Dialers::default(),
+                sources: Sources::default(),
+                dead_letters: None,
+                mirror: None,
+                scripts: None,
+                plugin: None,
+                history: RefCell::new(History::new(1024, 0)),
+                dedup: None,
+                rooms: RefCell::new(Rooms::default()),
+                room_stats: RoomStats::new(100),
+                tenants: Vec::new(),
+                commands: Commands::builtin(),
+                nicks: Nicks::default(),
+                presence: true,
+                drain_timeout: Duration::from_secs(10),
+                dump_path: None,
+                snapshot_path: None,
+                signals: None,
+                started,
+                tick: Duration::from_secs(1),
+                next_tick: started,
+                draining: None,
+                stopped: false,
+                multicast: None,
+                sinks: Vec::new(),
+                max_queue_bytes: 1 << 20,
+                timestamps: None,
+                attribute_peer: false,
+                coalesce: None,
+                overflow: Overflow::Disconnect,
+                sanitizer: Sanitizer::default(),
+                message_budget: None,
+                batch: true,
+                delivery: Delivery::AtMostOnce,
+                slow_consumers: None,
+                subscriptions: RefCell::new(Subscriptions::default()),
+                tracer: None,
+                ack_timeout: Duration::from_secs(30),
+                room_policies: BTreeMap::new(),
+                lagging: RefCell::new(Vec::new()),
+                sanctions: RefCell::new(Vec::new()),
+                handoffs: RefCell::new(Vec::new()),
+                backlog: RefCell::new(Vec::new()),
+                coalescing: RefCell::new(Vec::new()),
+                coalesce_due: Cell::new(None),
+                events: None,
+                probes: None,
+                shard: None,
+                spun_dry: Cell::new(false),
+                throttle: AcceptThrottle::new(None, started),
+            }
+        )
+    }
+
+    /// Accepts clients on another listening socket as well, they all join the
+    /// same rooms and broadcasts but are held to policy.
+    pub fn add_listener(&mut self, listener: i32, policy: Policy) -> Result<()> {
+        if let Err(e) = self.sys.watch(listener) {
+            let errmsg = format!("failed to watch server fd {} -- {}", listener, e);
+            return Err(Error::other(errmsg));
+        }
+        self.listeners.push((listener, policy));
+        Ok(())
+    }
+
+    /// The rooms of tenant, the servers own for the default one.
+    pub fn rooms_for(&self, tenant: Option<usize>) -> &RefCell<Rooms> {
+        match tenant {
+            Some(t) => &self.tenants[t].rooms,
+            None => &self.rooms,
+        }
+    }
+
+    pub fn history_for(&self, tenant: Option<usize>) -> &RefCell<History> {
+        match tenant {
+            Some(t) => &self.tenants[t].history,
+            None => &self.history,
+        }
+    }
+
+    /// The name of tenant, empty for the default one.
+    fn tenant_name(&self, tenant: Option<usize>) -> &str {
+        tenant.map_or("", |t| self.tenants[t].name.as_str())
+    }
+
+    /// Every tenants rooms paired with its name, the default tenants with "".
+    fn namespaces(&self) -> impl Iterator<Item = (&str, &RefCell<Rooms>)> {
+        namespaces(&self.rooms, &self.tenants)
+    }
+
+    /// Whether tenant has as many clients as it may. A client borrowed by the
+    /// caller is not counted.
+    fn tenant_full(&self, tenant: usize, clients: &HashMap<i32, RefCell<ClientState>>) -> bool {
+        let max = match self.tenants[tenant].max_clients {
+            Some(max) => max,
+            None => return false,
+        };
+        let count = clients.values().filter_map(|c| c.try_borrow().ok()).filter(|c| c.tenant == Some(tenant)).count();
+        count >= max
+    }
+
+    fn listener_policy(&self, listener: i32) -> Option<&Policy> {
+        self.listeners.iter().find(|(l, _)| *l == listener).map(|(_, policy)| policy)
+    }
+
+    /// Replaces the policy of a listener, for clients accepted from now on.
+    pub fn set_policy(&mut self, listener: i32, policy: Policy) {
+        if let Some(entry) = self.listeners.iter_mut().find(|(l, _)| *l == listener) {
+            entry.1 = policy;
+        }
+    }
+
+    /// Calls f with every connection and message event, from the event loop.
+    pub fn on_event(&mut self, f: impl Fn(&Event) + 'static) {
+        self.events = Some(Box::new(f));
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(f) = &self.events {
+            f(&event);
+        }
+    }
+
+    /// Starts serving metrics, the endpoint shares the servers event loop.
+    pub fn serve_metrics(&mut self, port: u16) -> Result<()> {
+        let endpoint = MetricsEndpoint::bind(port)?;
+        self.sys.watch(endpoint.listener_fd())?;
+        self.metrics = Some(endpoint);
+        Ok(())
+    }
+
+    /// Forwards every broadcast to sink, flushing it from the servers event loop.
+    pub fn add_sink(&mut self, sink: Sink) -> Result<()> {
+        self.sys.watch(sink.fd())?;
+        self.sys.set_interest(sink.fd(), false, false)?;
+        self.sinks.push(sink);
+        Ok(())
+    }
+
+    /// Drains and stops on SIGTERM and dumps state on SIGUSR1, signals are handled
+    /// on the servers event loop.
+    pub fn handle_signals(&mut self) -> Result<()> {
+        let signals = Signals::install(&[libc::SIGTERM, libc::SIGUSR1])?;
+        self.sys.watch(signals.fd())?;
+        self.signals = Some(signals);
+        Ok(())
+    }
+
+    /// Sends opted in clients a probe every interval and tracks how long they take
+    /// to answer, see Probes.
+    pub fn probe_every(&mut self, interval: Duration) {
+        self.probes = Some(RefCell::new(Probes::new(interval, self.sys.now())));
+    }
+
+    /// Accepts at most rate connections on all listeners together, see
+    /// AcceptThrottle.
+    pub fn throttle_accepts(&mut self, rate: Rate) {
+        self.throttle = AcceptThrottle::new(Some(rate), self.sys.now());
+    }
+
+    /// How long polling may block before the next tick or drain deadline is due.
+    pub fn timeout_ms(&self) -> i32 {
+        if !self.backlog.borrow().is_empty() {
+            return 0;
+        }
+        let probe_due = self.probes.as_ref().map(|p| p.borrow().next_due());
+        let due = [self.draining, self.coalesce_due.get(), probe_due, self.webhooks.next_due(), self.dialers.next_due(), self.sources.next_due(self.sys.now()), self.throttle.resume_due()].into_iter().flatten().fold(self.next_tick, Instant::min);
+        let wait = due.saturating_duration_since(self.sys.now());
+        // round up, waking a little early would just poll again
+        wait.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+    }
+
+    /// Whether a drain finished and every client has been closed.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Makes this one of several event loops, forwarding broadcasts to the others.
+    pub fn join_shards(&mut self, shard: Shard) -> Result<()> {
+        self.sys.watch(shard.fd())?;
+        self.shard = Some(shard);
+        Ok(())
+    }
+
+    /// Starts accepting operator connections on the servers event loop.
+    pub fn serve_admin(&mut self, port: u16) -> Result<()> {
+        let endpoint = AdminEndpoint::bind(port)?;
+        self.sys.watch(endpoint.listener_fd())?;
+        self.admin = Some(endpoint);
+        Ok(())
+    }
+}
+
+/// EpollServer::namespaces, for callers that borrow other fields of the server
+/// meanwhile.
+fn namespaces<'a>(rooms: &'a RefCell<Rooms>, tenants: &'a [Tenant]) -> impl Iterator<Item = (&'a str, &'a RefCell<Rooms>)> {
+    std::iter::once(("", rooms)).chain(tenants.iter().map(|t| (t.name.as_str(), &t.rooms)))
+}
+
+/// Sends orators complete messages to every client connected. Lines starting with `/`
+/// are run as commands instead, lines starting with `?` are broadcast without the
+/// `?` and acknowledged with `ACK <seq> <recipients>`, and a first line `HELLO ...`
+/// is answered, see handshake.rs. Clients that asked for `req` in theirs send
+/// commands as `REQ <id> <command>` too. Past the message budget the rest stays in the
+/// buffer and the orator goes on the backlog.
+///
+/// Returns total number of bytes sent or queued across all clients.
+fn broadcast_message(orator: &mut ClientState, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> usize {
+    let mut bytes = 0;
+    let mut start = 0; // first byte not yet broadcast or handled
+    let mut line = 0;
+    let mut budget = epserver.message_budget.unwrap_or(usize::MAX);
+
+    while line < orator.needle {
+        if budget == 0 {
+            orator.needle = line;
+            orator.backlogged = true;
+            epserver.backlog.borrow_mut().push(orator.fd);
+            break;
+        }
+        budget -= 1;
+        let end = match orator.buf[line..orator.needle].iter().position(|&b| b == b'\n') {
+            Some(i) => line + i + 1,
+            None => orator.needle,
+        };
+
+        if !orator.greeted {
+            orator.greeted = true;
+            let first = String::from_utf8_lossy(&orator.buf[line..end]).into_owned();
+            if let Some(hello) = Hello::negotiate(first.trim_end()) {
+                greet(orator, hello, epserver);
+                start = end;
+                line = end;
+                continue;
+            }
+            // banners don't get around authentication
+            if orator.authed && !epserver.banners.is_empty() {
+                banners::apply(orator, first.trim_end().as_bytes(), epserver);
+            }
+        }
+
+        if !orator.authed {
+            // nothing but /auth until it succeeds
+            if orator.buf[line..end].starts_with(b"/auth") {
+                let command = String::from_utf8_lossy(&orator.buf[line..end]).into_owned();
+                epserver.commands.execute(orator, &command, epserver, clients);
+            } else {
+                notify(epserver, orator, b"* authenticate first with /auth <token>\n");
+            }
+            start = end;
+        } else if orator.buf[line] == b'/' {
+            bytes += relay(orator, start..line, epserver, clients).0;
+            if orator.handoff.is_some() {
+                start = line;
+                break;
+            }
+            let command = String::from_utf8_lossy(&orator.buf[line..end]).into_owned();
+            epserver.commands.execute(orator, &command, epserver, clients);
+            start = end;
+        } else if orator.requests && orator.buf[line..end].starts_with(b"REQ ") {
+            bytes += relay(orator, start..line, epserver, clients).0;
+            if orator.handoff.is_some() {
+                start = line;
+                break;
+            }
+            let request = String::from_utf8_lossy(&orator.buf[line..end]).into_owned();
+            epserver.commands.request(orator, &request, epserver, clients);
+            start = end;
+        } else if orator.buf[line] == b'?' {
+            bytes += relay(orator, start..line, epserver, clients).0;
+            if orator.handoff.is_some() {
+                start = line;
+                break;
+            }
+            let (sent, recipients) = relay(orator, line + 1..end, epserver, clients);
+            if orator.handoff.is_some() {
+                start = end;
+                break;
+            }
+            bytes += sent;
+            orator.acks += 1;
+            let ack = format!("ACK {} {}\n", orator.acks, recipients);
+            notify(epserver, orator, ack.as_bytes());
+            start = end;
+        }
+        line = end;
+    }
+    if orator.handoff.is_none() {
+        bytes += relay(orator, start..orator.needle, epserver, clients).0;
+        start = orator.needle;
+    }
+    if let Some(handoff) = orator.handoff.as_mut() {
+        // the rest goes to the helper too, partial lines included
+        handoff.data.extend_from_slice(&orator.buf[start..orator.off]);
+        orator.needle = orator.off;
+    }
+
+    consume_message(orator);
+    bytes
+}
+
+/// Answers a `HELLO` and turns on what was agreed on.
+fn greet(client: &mut ClientState, hello: std::result::Result<Hello, String>, epserver: &EpollServer) {
+    match hello {
+        Ok(hello) => {
+            if hello.caps.contains(&"resume") {
+                client.offsets = true;
+            }
+            if hello.caps.contains(&"seq") {
+                client.offsets = true;
+                client.seq = Some(client.seq.unwrap_or(0));
+            }
+            if hello.caps.contains(&"probe") {
+                start_probing(client, epserver);
+            }
+            if hello.caps.contains(&"req") {
+                client.requests = true;
+            }
+            notify(epserver, client, hello.reply().as_bytes());
+            if hello.version >= 3 {
+                notify(epserver, client, limits(client, epserver).line().as_bytes());
+            }
+        }
+        Err(e) => notify(epserver, client, format!("* {}\n", e).as_bytes()),
+    }
+}
+
+/// The limits check_message, send and the room policies hold client to.
+fn limits(client: &ClientState, epserver: &EpollServer) -> Limits {
+    let rooms = epserver.room_policies.iter()
+        .filter(|(_, policy)| policy.rate.is_some() || policy.max_message_bytes.is_some())
+        .map(|(name, policy)| (name.clone(), policy.rate, policy.max_message_bytes))
+        .collect();
+    Limits {
+        // a line has to fit the read buffer along with its newline
+        max_message: BUFFER_SIZE - 1,
+        overflow: match epserver.overflow {
+            Overflow::Message => "message",
+            Overflow::Discard => "discard",
+            Overflow::Disconnect => "disconnect",
+        },
+        max_queue: epserver.max_queue_bytes,
+        delivery: match client.delivery {
+            Delivery::AtMostOnce => "at-most-once",
+            Delivery::AtLeastOnce => "at-least-once",
+        },
+        read_only: client.read_only,
+        rooms,
+    }
+}
+
+/// Sends the client every retained message after offset, telling it about any it
+/// can't get anymore. While the server is overloaded nothing is replayed, so a
+/// storm of reconnects doesn't make it worse, the client is told to try again.
+fn resume(client: &mut ClientState, offset: u64, epserver: &EpollServer) {
+    if overload::degraded() {
+        notify(epserver, client, format!("* resume deferred, overloaded, /resume {} again later\n", offset).as_bytes());
+        return;
+    }
+    let history = epserver.history_for(client.tenant).borrow();
+    let (gap, messages) = history.since(offset);
+    let mut out = Vec::new();
+    if let Some(first) = gap {
+        out.extend_from_slice(format!("* resume gap, messages from {} on are gone\n", first).as_bytes());
+    }
+    for (at, message) in messages {
+        out.extend_from_slice(format!("@{} ", at).as_bytes());
+        out.extend_from_slice(message);
+        out.push(b'\n');
+    }
+    out.extend_from_slice(format!("* resumed after {}\n", offset).as_bytes());
+    drop(history);
+    send(epserver, client, &out);
+}
+
+/// Broadcasts part of the orators buffer unless the orator has been muted, running
+/// each message through the filter chain first. Messages addressed to a room only go
+/// to its members.
+///
+/// Returns total number of bytes sent or queued across all clients, and the number
+/// of clients that got all of it.
+fn relay(orator: &mut ClientState, range: std::ops::Range<usize>, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> (usize, usize) {
+    if range.is_empty() {
+        return (0, 0);
+    }
+    if let Some(backpressure) = orator.backpressure.filter(|b| *b != Backpressure::Pause && overload::degraded()) {
+        return shed(orator, range, backpressure, epserver, clients);
+    }
+    let Some(tracer) = epserver.tracer.as_ref().filter(|t| t.begin(orator.fd, range.len())) else {
+        return relay_lines(orator, range, epserver, clients);
+    };
+    let (bytes, recipients) = relay_lines(orator, range, epserver, clients);
+    let waiting = clients.iter()
+        .filter(|(_, c)| c.try_borrow().is_ok_and(|c| !c.outbox.is_empty()))
+        .map(|(fd, _)| *fd)
+        .collect();
+    tracer.fanned_out(recipients, bytes, waiting);
+    (bytes, recipients)
+}
+
+/// Relays the lines of a source that backpressure keeps, dropping the others.
+fn shed(orator: &mut ClientState, range: std::ops::Range<usize>, backpressure: Backpressure, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> (usize, usize) {
+    let (mut bytes, mut recipients) = (0, 0);
+    let mut start = range.start;
+    while start < range.end {
+        let end = orator.buf[start..range.end].iter().position(|&b| b == b'\n').map_or(range.end, |i| start + i + 1);
+        let keep = match backpressure {
+            Backpressure::Sample(n) => orator.shed.is_multiple_of(n),
+            _ => false,
+        };
+        orator.shed += 1;
+        if keep {
+            let (b, r) = relay_lines(orator, start..end, epserver, clients);
+            bytes += b;
+            recipients = r;
+        } else {
+            source::SHED.fetch_add(1, Ordering::Relaxed);
+        }
+        start = end;
+    }
+    (bytes, recipients)
+}
+
+/// Relays a non-empty part of the orators buffer, see relay.
+fn relay_lines(orator: &mut ClientState, range: std::ops::Range<usize>, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> (usize, usize) {
+    for line in orator.buf[range.clone()].split_inclusive(|&b| b == b'\n') {
+        INBOUND_MESSAGE_BYTES.observe(line.len() as u64);
+        epserver.emit(Event::MessageReceived { from: orator.fd, bytes: line.len() });
+    }
+
+    if orator.direction == Direction::Out {
+        return (0, 0);
+    }
+    if orator.read_only {
+        notify(epserver, orator, b"* this connection is read-only, message dropped\n");
+        return (0, 0);
+    }
+    match orator.mute {
+        Mute::Off => {}
+        Mute::Muted => {
+            notify(epserver, orator, b"* you are muted, message dropped\n");
+            return (0, 0);
+        }
+        Mute::Shadow => {
+            let echo = orator.buf[range].to_vec();
+            send(epserver, orator, &echo);
+            // pretend everyone got it, acks included
+            return (0, clients.len() - 1);
+        }
+    }
+
+    let rooms = epserver.rooms_for(orator.tenant);
+    let plain = epserver.dedup.is_none() && epserver.filters.is_empty() && rooms.borrow().is_empty() && epserver.rules.is_empty() && epserver.sanitizer.is_off();
+    if plain && epserver.batch && epserver.scripts.is_none() && epserver.plugin.is_none() {
+        return broadcast(Origin::of(orator), &orator.buf[range], Audience::Everyone, epserver, clients);
+    }
+
+    let mut processed = Vec::with_capacity(range.len());
+    let mut replies = Vec::new();
+    for line in orator.buf[range].split_inclusive(|&b| b == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        if let Some(out) = process_message(orator, text, epserver, &mut replies) {
+            processed.extend_from_slice(&out);
+            processed.push(b'\n');
+        }
+    }
+
+    if let Some(tracer) = &epserver.tracer {
+        tracer.filtered();
+    }
+    for reply in replies {
+        notify(epserver, orator, format!("{}\n", reply).as_bytes());
+    }
+    if rooms.borrow().is_empty() && epserver.rules.is_empty() && epserver.batch {
+        return broadcast(Origin::of(orator), &processed, Audience::Everyone, epserver, clients);
+    }
+    route(orator, &processed, epserver, clients)
+}
+
+/// Broadcasts messages one by one, those addressed to a room only to its members,
+/// after the routing rules had their say.
+///
+/// Returns total number of bytes sent or queued across all clients, and the number
+/// of clients that got the last message.
+fn route(orator: &mut ClientState, messages: &[u8], epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> (usize, usize) {
+    let (mut bytes, mut recipients) = (0, 0);
+    let mut at = 0;
+    for line in messages.split_inclusive(|&b| b == b'\n') {
+        let start = at;
+        at += line.len();
+        let rooms = epserver.rooms_for(orator.tenant).borrow();
+        let defaulted;
+        let line = match orator.room.as_ref().filter(|_| rooms.addressed(line).is_none()) {
+            Some(name) => {
+                defaulted = [name.as_bytes(), b" ", line].concat();
+                &defaulted[..]
+            }
+            None => line,
+        };
+        let room = rooms.addressed(line);
+        if let Some(room) = room.filter(|room| !room.members.contains(&orator.fd)) {
+            let reply = format!("* you are not in {}\n", room.name);
+            drop(rooms);
+            notify(epserver, orator, reply.as_bytes());
+            recipients = 0;
+            continue;
+        }
+
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        let skip = room.map_or(0, |room| room.name.len() + 1);
+        let mut verdict = match epserver.rules.is_empty() {
+            true => None,
+            false => Some(epserver.rules.evaluate(orator.nick.as_deref(), &orator.meta, room.map(|r| r.name.as_str()), &text[skip..])),
+        };
+        if let Some((rule, socket)) = verdict.as_mut().and_then(|v| v.handoff.take()) {
+            drop(rooms);
+            hand_off(orator, Handoff { rule, socket, data: messages[start..].to_vec() }, epserver);
+            return (bytes, 0);
+        }
+        let body = verdict.as_ref().and_then(|v| v.body.as_deref()).unwrap_or(&text[skip..]);
+        let rewritten;
+        let line = match verdict.as_ref().is_some_and(|v| v.body.is_some()) {
+            true => {
+                rewritten = [&text[..skip], body, b"\n"].concat();
+                &rewritten[..]
+            }
+            false => line,
+        };
+
+        let (mut sent, mut got) = (0, 0);
+        if verdict.as_ref().is_none_or(|v| v.original) {
+            (sent, got) = match room {
+                Some(room) => match breaks_policy(orator, room, line, epserver) {
+                    Some((reason, reply)) => {
+                        epserver.room_stats.dropped(epserver.tenant_name(orator.tenant), &room.name, 1);
+                        drop(rooms);
+                        notify(epserver, orator, reply.as_bytes());
+                        dead_letter(epserver, orator.tenant, orator.fd, None, reason, line.strip_suffix(b"\n").unwrap_or(line));
+                        (0, 0)
+                    }
+                    None => {
+                        let sent = broadcast(Origin::of(orator), line, Audience::Room(room), epserver, clients);
+                        let (name, keep) = (room.name.clone(), epserver.room_policies.get(&room.name).map_or(0, |p| p.history));
+                        drop(rooms);
+                        let text = line.strip_suffix(b"\n").unwrap_or(line);
+                        epserver.rooms_for(orator.tenant).borrow_mut().remember(&name, text, keep);
+                        sent
+                    }
+                },
+                None => {
+                    drop(rooms);
+                    broadcast(Origin::of(orator), line, Audience::Everyone, epserver, clients)
+                }
+            };
+        } else {
+            drop(rooms);
+            if verdict.as_ref().is_some_and(|v| v.targets.is_empty()) {
+                dead_letter(epserver, orator.tenant, orator.fd, None, Reason::Rule, text);
+            }
+        }
+        for target in verdict.iter().flat_map(|v| &v.targets) {
+            let (more, to) = deliver_to(target, orator, line, body, epserver, clients);
+            sent += more;
+            got += to;
+        }
+        bytes += sent;
+        recipients = got;
+    }
+    (bytes, recipients)
+}
+
+/// Stops reading from the orator, to pass it on once the wakeup is over.
+fn hand_off(orator: &mut ClientState, handoff: Handoff, epserver: &EpollServer) {
+    orator.handoff = Some(handoff);
+    epserver.handoffs.borrow_mut().push(orator.fd);
+    let _ = epserver.sys.set_interest(orator.fd, false, false);
+}
+
+/// Sends a message a rule routed or copied to target: rooms of the orators tenant
+/// get the body under their own name, tagged clients the line as it was sent.
+fn deliver_to(target: &Target, orator: &ClientState, line: &[u8], body: &[u8], epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> (usize, usize) {
+    match target {
+        Target::Room(name) => {
+            let rooms = epserver.rooms_for(orator.tenant).borrow();
+            let Some(room) = rooms.get(name) else {
+                debug!(Broadcast, "fd {} rule target {} does not exist", orator.fd, name);
+                dead_letter(epserver, orator.tenant, orator.fd, None, Reason::NoRecipients, body);
+                return (0, 0);
+            };
+            let line = [name.as_bytes(), b" ", body, b"\n"].concat();
+            broadcast(Origin::of(orator), &line, Audience::Room(room), epserver, clients)
+        }
+        Target::Tagged(key, value) => broadcast(Origin::of(orator), line, Audience::Tagged(key, value), epserver, clients),
+    }
+}
+
+/// Why a message (with its newline) to room breaks the rooms policy, if it does,
+/// using up one of the orators messages if the room is rate limited.
+fn breaks_policy(orator: &mut ClientState, room: &Room, line: &[u8], epserver: &EpollServer) -> Option<(Reason, String)> {
+    let policy = epserver.room_policies.get(&room.name)?;
+    let text = line.strip_suffix(b"\n").unwrap_or(line);
+    let len = text.len().saturating_sub(room.name.len() + 1);
+    if let Some(max) = policy.max_message_bytes.filter(|max| len > *max) {
+        return Some((Reason::TooLarge, format!("* {} takes messages up to {} bytes, dropped\n", room.name, max)));
+    }
+    if let Some(rate) = policy.rate {
+        let now = epserver.sys.now();
+        let bucket = orator.room_buckets.entry(room.name.clone()).or_insert_with(|| Bucket::full(rate, now));
+        if !bucket.take(rate, now) {
+            return Some((Reason::RateLimit, format!("* {} takes {} messages per member, dropped\n", room.name, rate)));
+        }
+    }
+    None
+}
+
+/// Runs a single message (without its newline) through the sanitizer,
+/// deduplication, the filter chain, the script hooks and the wasm plugin,
+/// appending whatever the script replies to the orator to replies.
+///
+/// Returns None if the message was dropped.
+fn process_message(orator: &ClientState, message: &[u8], epserver: &EpollServer, replies: &mut Vec<String>) -> Option<Vec<u8>> {
+    let dropped = |reason| {
+        dead_letter(epserver, orator.tenant, orator.fd, None, reason, message);
+        None
+    };
+    let sanitized = match epserver.sanitizer.apply(message) {
+        Ok(message) => message,
+        Err(why) => {
+            replies.push(format!("* {}, dropped", why));
+            return dropped(Reason::Invalid);
+        }
+    };
+    let unique = match &epserver.dedup {
+        Some(dedup) => match dedup.borrow_mut().check(orator.tenant, &sanitized, epserver.sys.now()) {
+            Some(unique) => unique,
+            None => return dropped(Reason::Duplicate),
+        },
+        None => &sanitized,
+    };
+    let Some(mut out) = epserver.filters.apply(unique, &orator.meta) else {
+        return dropped(Reason::Filter);
+    };
+
+    if let Some(scripts) = &epserver.scripts {
+        let (scripted, mut r) = scripts.on_message(orator.fd, orator.nick.as_deref(), &out);
+        replies.append(&mut r);
+        match scripted {
+            Some(scripted) => out = scripted,
+            None => return dropped(Reason::Script),
+        }
+    }
+
+    match &epserver.plugin {
+        Some(plugin) => plugin.process_message(&out).or_else(|| dropped(Reason::Plugin)),
+        None => Some(out),
+    }
+}
+
+/// Diverts a single message (without its newline) from fd, dropped for reason,
+/// to the dead letters if there are any.
+fn dead_letter(epserver: &EpollServer, tenant: Option<usize>, from: i32, to: Option<i32>, reason: Reason, message: &[u8]) {
+    if let Some(dead) = &epserver.dead_letters {
+        dead.divert(tenant, from, to, reason, message);
+    }
+}
+
+/// Who besides the orator gets a broadcast, always within the orators tenant.
+#[derive(Clone, Copy)]
+enum Audience<'a> {
+    Everyone,
+    Room(&'a Room),
+    /// Clients whose metadata has the key set to the value.
+    Tagged(&'a str, &'a str),
+}
+
+impl Audience<'_> {
+    fn room(&self) -> Option<&Room> {
+        match self {
+            Audience::Room(room) => Some(room),
+            _ => None,
+        }
+    }
+}
+
+/// Who a broadcast is from, -1 and no tenant or address for the server itself.
+#[derive(Clone, Copy)]
+struct Origin<'a> {
+    fd: i32,
+    tenant: Option<usize>,
+    meta: &'a Metadata,
+    peer: Option<SocketAddr>,
+    /// Of the source it came from, if tagged.
+    label: Option<&'a str>,
+    /// Another shard broadcast it already, see shards.rs.
+    forwarded: bool,
+}
+
+impl<'a> Origin<'a> {
+    fn of(client: &'a ClientState) -> Origin<'a> {
+        Origin { fd: client.fd, tenant: client.tenant, meta: &client.meta, peer: client.peer, label: client.label.as_deref(), forwarded: false }
+    }
+}
+
+/// Sends newline terminated messages to every client of the origins tenant but the
+/// orator, or only to the given audience, recording them in the tenants history.
+/// Sinks get the orators metadata and address with them.
+/// Reliable members get messages queued however full their outbox is, and hold the
+/// orator until it drains.
+///
+/// Returns total number of bytes sent or queued across all clients, and the number
+/// of clients that got all of it.
+fn broadcast(origin: Origin, message: &[u8], audience: Audience, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> (usize, usize) {
+    if message.is_empty() {
+        return (0, 0);
+    }
+    let (ofd, tenant) = (origin.fd, origin.tenant);
+    let room = audience.room();
+    if let Some(mirror) = epserver.mirror.as_ref().filter(|_| !origin.forwarded && !overload::degraded()) {
+        mirror.offer(&origin, message, epserver);
+    }
+
+    let (mut bytes, mut recipients) = (0, 0);
+
+    // stamped before anything else sees it, so history and sinks keep the time too
+    let attributed;
+    let attribution = match origin.label {
+        Some(label) => Some(label.to_string()),
+        None => origin.peer.filter(|_| epserver.attribute_peer).map(|peer| peer.to_string()),
+    };
+    let message = match attribution.filter(|_| !origin.forwarded) {
+        Some(attribution) => {
+            let prefix = format!("[{}] ", attribution);
+            attributed = message.split_inclusive(|&b| b == b'\n')
+                .flat_map(|line| [prefix.as_bytes(), line])
+                .flatten()
+                .copied()
+                .collect::<Vec<u8>>();
+            &attributed[..]
+        }
+        None => message,
+    };
+    let stamped;
+    let message = match epserver.timestamps.filter(|_| !origin.forwarded) {
+        Some(format) => {
+            let stamp = clock::format(SystemTime::now(), format);
+            stamped = message.split_inclusive(|&b| b == b'\n')
+                .flat_map(|line| [stamp.as_bytes(), b" ", line])
+                .flatten()
+                .copied()
+                .collect::<Vec<u8>>();
+            &stamped[..]
+        }
+        None => message,
+    };
+
+    let with_peer;
+    let meta = match origin.peer.filter(|_| !epserver.sinks.is_empty()) {
+        Some(peer) => {
+            with_peer = origin.meta.iter().map(|(k, v)| (k.clone(), v.clone())).chain([("peer".to_string(), peer.to_string())]).collect::<Metadata>();
+            &with_peer
+        }
+        None => origin.meta,
+    };
+
+    if let Some(shard) = epserver.shard.as_ref().filter(|_| !origin.forwarded) {
+        shard.forward(tenant, &audience, message);
+    }
+
+    let mut history = epserver.history_for(tenant).borrow_mut();
+    let mut tagged = Vec::with_capacity(message.len() + 24);
+    let mut line_offsets = Vec::new();
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        // the shard a forwarded one came from did this already
+        if !origin.forwarded {
+            if let Some(multicast) = epserver.multicast.as_ref().filter(|_| matches!(audience, Audience::Everyone) && tenant.is_none()) {
+                multicast.send(text);
+            }
+            for sink in &epserver.sinks {
+                sink.send(&*epserver.sys, text, meta);
+            }
+            if !epserver.hooks.is_empty() {
+                epserver.hooks.fire(text, epserver.sys.now());
+            }
+            if !epserver.webhooks.is_empty() {
+                epserver.webhooks.offer(text);
+            }
+        }
+        let offset = history.push(text);
+        if let Some(t) = tenant {
+            let messages = &epserver.tenants[t].messages;
+            messages.set(messages.get() + 1);
+        }
+        tagged.extend_from_slice(format!("@{} ", offset).as_bytes());
+        tagged.extend_from_slice(line);
+        line_offsets.push(offset);
+    }
+
+    for (cfd, client) in clients.iter() {
+        // ensure we don't borrow the orator a second time
+        // (the mutable borrow occurs in handle_client())
+        if *cfd != ofd && room.is_none_or(|r| r.members.contains(cfd)) {
+            let mut client = client.borrow_mut();
+            if !client.authed || !client.sniffed || client.tenant != tenant || client.direction == Direction::In {
+                continue;
+            }
+            if let Audience::Tagged(key, value) = audience {
+                if client.meta.get(key).map(String::as_str) != Some(value) {
+                    continue;
+                }
+            }
+            let headers;
+            let parts: Vec<&[u8]> = match client.seq {
+                // numbered even if dropped below, that is the gap the client sees
+                Some(seq) => {
+                    headers = number(&line_offsets, seq);
+                    client.seq = Some(seq + line_offsets.len() as u64);
+                    headers.iter().map(String::as_bytes).zip(message.split_inclusive(|&b| b == b'\n'))
+                        .flat_map(|(header, line)| [header, line])
+                        .collect()
+                }
+                None if client.offsets => vec![&tagged],
+                None => vec![message],
+            };
+            let len = parts.iter().map(|p| p.len()).sum::<usize>();
+            let sent = match room.is_some_and(|r| r.reliable.contains(cfd)) {
+                true => {
+                    let sent = deliver_parts(epserver, &mut client, &parts, Lane::Data);
+                    if ofd >= 0 && client.outbox.len() > epserver.max_queue_bytes {
+                        client.holding.insert(ofd);
+                    }
+                    sent
+                }
+                false => send_parts(epserver, &mut client, &parts),
+            };
+            if sent {
+                for line in message.split_inclusive(|&b| b == b'\n') {
+                    OUTBOUND_MESSAGE_BYTES.observe(line.len() as u64);
+                }
+                bytes += len;
+                recipients += 1;
+                if client.subscription.is_some() {
+                    client.delivered = line_offsets.last().copied();
+                    client.unacked_since.get_or_insert(epserver.sys.now());
+                }
+            } else {
+                if let Some(room) = room {
+                    epserver.room_stats.dropped(epserver.tenant_name(tenant), &room.name, line_offsets.len());
+                }
+                if ofd >= 0 {
+                    for line in message.split_inclusive(|&b| b == b'\n') {
+                        dead_letter(epserver, tenant, ofd, Some(*cfd), Reason::FullQueue, line.strip_suffix(b"\n").unwrap_or(line));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(room) = room {
+        epserver.room_stats.sent(epserver.tenant_name(tenant), &room.name, line_offsets.len(), bytes);
+    }
+    if recipients == 0 && ofd >= 0 {
+        for line in message.split_inclusive(|&b| b == b'\n') {
+            dead_letter(epserver, tenant, ofd, None, Reason::NoRecipients, line.strip_suffix(b"\n").unwrap_or(line));
+        }
+    }
+    for offset in &line_offsets {
+        epserver.emit(Event::Broadcasted { seq: *offset, recipients });
+    }
+    debug!(Broadcast, "fd {} broadcast {} lines to {} clients, {} bytes", ofd, line_offsets.len(), recipients, bytes);
+
+    (bytes, recipients)
+}
+
+/// The `@<offset>:<seq> ` tag for each line, with sequence numbers counting on
+/// from seq. They are written in front of the shared lines with writev, instead
+/// of copying every line into a buffer for each client.
+fn number(offsets: &[u64], seq: u64) -> Vec<String> {
+    offsets.iter().enumerate().map(|(i, offset)| format!("@{}:{} ", offset, seq + 1 + i as u64)).collect()
+}
+
+/// Writes data to the client, queueing whatever its socket can't take right now.
+///
+/// Returns false if data was dropped because the clients outbox is full.
+fn send(epserver: &EpollServer, client: &mut ClientState, data: &[u8]) -> bool {
+    send_parts(epserver, client, &[data])
+}
+
+/// Like send, for data given in parts that are written as one.
+fn send_parts(epserver: &EpollServer, client: &mut ClientState, parts: &[&[u8]]) -> bool {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>();
+    if client.lagging {
+        return false;
+    }
+    if !client.outbox.is_empty() && client.outbox.len() + len > epserver.max_queue_bytes {
+        if client.delivery == Delivery::AtLeastOnce || client.subscription.is_some() {
+            // a gap it doesn't know about would break the guarantee, make it reconnect
+            client.lagging = true;
+            epserver.lagging.borrow_mut().push(client.fd);
+        }
+        return false;
+    }
+    deliver_parts(epserver, client, parts, Lane::Data)
+}
+
+/// Sends an ack, command reply or server notice, which goes ahead of queued data
+/// and is never dropped.
+fn notify(epserver: &EpollServer, client: &mut ClientState, data: &[u8]) {
+    deliver(epserver, client, data, Lane::Control);
+}
+
+/// Like send, but queues data however full the clients outbox is.
+///
+/// Returns false if the socket failed.
+fn deliver(epserver: &EpollServer, client: &mut ClientState, data: &[u8], lane: Lane) -> bool {
+    deliver_parts(epserver, client, &[data], lane)
+}
+
+fn deliver_parts(epserver: &EpollServer, client: &mut ClientState, parts: &[&[u8]], lane: Lane) -> bool {
+    if client.direction == Direction::In {
+        return true; // gets nothing, a named pipe would read it back as a message
+    }
+    if let Some(captured) = client.capturing.as_mut() {
+        // answers a request, which frames and sends it once the command finished
+        for part in parts {
+            captured.extend_from_slice(part);
+        }
+        return true;
+    }
+    if let Some(coalesce) = epserver.coalesce.filter(|_| lane == Lane::Data) {
+        if client.outbox.is_empty() {
+            if client.coalesced.is_empty() {
+                let mut coalescing = epserver.coalescing.borrow_mut();
+                if coalescing.is_empty() {
+                    epserver.coalesce_due.set(Some(epserver.sys.now() + coalesce.delay));
+                }
+                coalescing.push(client.fd);
+            }
+            for part in parts {
+                client.coalesced.extend_from_slice(part);
+            }
+            return client.coalesced.len() < coalesce.bytes || flush_coalesced(epserver, client);
+        }
+        // whatever was held back stays ahead of data
+        if !flush_coalesced(epserver, client) {
+            return false;
+        }
+    }
+    write_or_queue(epserver, client, parts, lane)
+}
+
+/// Writes what deliver held back for the client.
+///
+/// Returns false if the socket failed.
+fn flush_coalesced(epserver: &EpollServer, client: &mut ClientState) -> bool {
+    if client.coalesced.is_empty() {
+        return true;
+    }
+    let held = std::mem::take(&mut client.coalesced);
+    write_or_queue(epserver, client, &[&held], Lane::Data)
+}
+
+/// Writes out everything held back once the oldest of it is due.
+fn flush_all_coalesced(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    if epserver.coalesce_due.get().is_none_or(|due| epserver.sys.now() < due) {
+        return;
+    }
+    epserver.coalesce_due.set(None);
+    let fds = std::mem::take(&mut *epserver.coalescing.borrow_mut());
+    for fd in fds {
+        if let Some(client) = clients.get(&fd) {
+            flush_coalesced(epserver, &mut client.borrow_mut());
+        }
+    }
+}
+
+fn write_or_queue(epserver: &EpollServer, client: &mut ClientState, parts: &[&[u8]], lane: Lane) -> bool {
+    if !client.outbox.is_empty() {
+        client.outbox.push_parts(lane, parts, 0);
+        return true;
+    }
+
+    let written = match epserver.sys.writev(client.fd, parts) {
+        Ok(n) => n,
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => 0,
+        Err(_) => return false, // the hangup shows up on the next read
+    };
+    if written < parts.iter().map(|p| p.len()).sum::<usize>() {
+        client.outbox.push_parts(lane, parts, written);
+        client.backed_up_since = Some(epserver.sys.now());
+        let _ = epserver.sys.set_interest(client.fd, !client.paused, true);
+    }
+    true
+}
+
+/// Writes what is queued for a client once its socket can take more.
+fn flush_client(cfd: i32, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> Result<()> {
+    let mut client = match clients.get(&cfd) {
+        Some(c) => c.borrow_mut(),
+        None => return Err(Error::from(ErrorKind::InvalidInput)),
+    };
+    if client.outbox.is_empty() {
+        return Ok(());
+    }
+
+    client.outbox.flush(&*epserver.sys, cfd)?;
+    if client.outbox.is_empty() {
+        slow::caught_up(&mut client, epserver);
+        if let Some(tracer) = &epserver.tracer {
+            tracer.drained(cfd);
+        }
+        epserver.sys.set_interest(cfd, !client.paused, false)?;
+    }
+    if client.outbox.len() <= epserver.max_queue_bytes / 2 && !client.holding.is_empty() {
+        let held = std::mem::take(&mut client.holding);
+        drop(client);
+        release(held, epserver, clients);
+    }
+    Ok(())
+}
+
+/// Stops reading from a publisher while a reliable subscriber is holding it.
+fn pause_if_held(publisher: &mut ClientState, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    if publisher.paused {
+        return;
+    }
+    let held = clients.iter().any(|(cfd, c)| *cfd != publisher.fd && c.borrow().holding.contains(&publisher.fd));
+    if held {
+        publisher.paused = true;
+        let _ = epserver.sys.set_interest(publisher.fd, false, !publisher.outbox.is_empty());
+    }
+}
+
+/// Resumes reading from publishers nobody is holding anymore.
+fn release(publishers: BTreeSet<i32>, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    for pfd in publishers {
+        let held = clients.values().any(|c| c.borrow().holding.contains(&pfd));
+        if let Some(publisher) = clients.get(&pfd).filter(|_| !held && epserver.draining.is_none()) {
+            let mut publisher = publisher.borrow_mut();
+            publisher.paused = false;
+            if publisher.backlogged {
+                epserver.backlog.borrow_mut().push(pfd);
+            }
+            let _ = epserver.sys.set_interest(pfd, true, !publisher.outbox.is_empty());
+        }
+    }
+}
+
+/// Drops the broadcast part of the orators buffer.
+fn consume_message(orator: &mut ClientState) {
+    // if there are left over bytes past the needle, shift them to the 
+    // beginning of the buffer for next read, this way writes always start at index 0
+    if orator.needle < orator.off {
+        orator.off -= orator.needle;
+        for i in 0..orator.off {
+            orator.buf[i] = orator.buf[orator.needle];
+            orator.needle += 1;
+        }
+    } else {
+        orator.off = 0;
+    }
+    orator.needle = 0;
+}
+
+/// Checks clients buffer after reading for a newline and adjusts offset and needle,
+/// applying the overflow policy once the buffer is full without one.
+/// 
+/// Returns true if message should be broadcasted, InvalidData if the client has
+/// to be disconnected.
+fn check_message(client: &mut ClientState, bytes: usize, epserver: &EpollServer) -> Result<bool> {
+    if client.discarding {
+        match client.buf[..bytes].iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                client.buf.copy_within(i + 1..bytes, 0);
+                client.discarding = false;
+                return check_message(client, bytes - i - 1, epserver);
+            }
+            None => return Ok(false),
+        }
+    }
+
+    for i in (0..client.off + bytes).rev() {
+        if client.buf[i] == b'\n' {
+            client.needle = i + 1;
+            break;
+        }
+    }
+
+    client.off += bytes;
+    if client.needle == 0 && client.off == BUFFER_SIZE {
+        match epserver.overflow {
+            Overflow::Message => {
+                client.buf[BUFFER_SIZE] = b'\n';
+                client.off += 1;
+                client.needle = client.off;
+            }
+            Overflow::Discard => {
+                client.off = 0;
+                client.discarding = true;
+                notify(epserver, client, b"* message too long, dropped\n");
+            }
+            Overflow::Disconnect => {
+                notify(epserver, client, b"* message too long\n");
+                return Err(Error::from(ErrorKind::InvalidData));
+            }
+        }
+    }
+    debug!(Framing, "fd {} read {} bytes, {} buffered, {} in complete lines", client.fd, bytes, client.off, client.needle);
+    Ok(client.needle > 0)
+}
+
+fn handle_client(cfd: i32, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> Result<()> {
+    let mut client = match clients.get(&cfd) {
+        Some(c) => c.borrow_mut(),
+        None => return Err(Error::from(ErrorKind::InvalidInput)),
+    };
+    if client.paused && !client.outbox.is_empty() {
+        return Ok(()); // woken for write space, reading waits for the subscribers
+    }
+    if client.backlogged {
+        // what is buffered goes first, the socket keeps reporting readable meanwhile
+        return take_backlog(&mut client, epserver, clients);
+    }
+    
+    let off = client.off;
+    match epserver.sys.read(cfd, &mut client.buf[off..BUFFER_SIZE]) {
+        Ok(bytes) => {
+            if bytes == 0 { 
+                return Err(Error::from(ErrorKind::ConnectionAborted)); 
+            }
+            client.last_active = epserver.sys.now();
+            if let Some(tracer) = &epserver.tracer {
+                tracer.read();
+            }
+            if !client.sniffed {
+                // held in the buffer until it is clear what they are
+                client.off += bytes;
+                match sniff::sniff(&mut client, epserver) {
+                    Sniffed::Wait => return Ok(()),
+                    Sniffed::Close(why) => return Err(Error::new(ErrorKind::ConnectionAborted, why)),
+                    Sniffed::Line => {
+                        client.sniffed = true;
+                        welcome(&mut client, epserver, clients);
+                        if check_message(&mut client, 0, epserver)? {
+                            take_messages(&mut client, epserver, clients);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+
+            if check_message(&mut client, bytes, epserver)? {
+                take_messages(&mut client, epserver, clients);
+            }
+
+            Ok(())
+        },
+        Err(e) => {
+            match e.kind() {
+                ErrorKind::WouldBlock => Ok(()),
+                _ => Err(e)
+            }
+        }
+    }
+}
+
+/// Broadcasts the complete messages in the clients buffer.
+fn take_messages(client: &mut ClientState, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    let sent = broadcast_message(client, epserver, clients);
+    if !epserver.rooms_for(client.tenant).borrow().is_empty() {
+        pause_if_held(client, epserver, clients);
+    }
+    TOTAL_BYTES_SENT.fetch_add(sent, Ordering::Relaxed);
+    if !overload::degraded() {
+        info!("sent {:?} bytes", TOTAL_BYTES_SENT);
+    }
+}
+
+/// Gives a backlogged client its next share of the message budget, unless it is
+/// paused, release puts it back on the backlog then.
+fn take_backlog(client: &mut ClientState, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) -> Result<()> {
+    if client.paused {
+        return Ok(());
+    }
+    client.backlogged = false;
+    if check_message(client, 0, epserver)? {
+        take_messages(client, epserver, clients);
+    }
+    Ok(())
+}
+
+/// Disconnects a client, telling the others why it left, e.g. `quit` or `kicked`.
+fn remove_client(epserver: &EpollServer, cfd: i32, clients: &mut HashMap<i32, RefCell<ClientState>>, reason: &str) {
+    epserver.sys.unwatch(cfd);
+    let mut peer = None;
+    if let Some(client) = clients.remove(&cfd) {
+        let client = client.into_inner();
+        peer = client.peer;
+        epserver.rooms_for(client.tenant).borrow_mut().part_all(cfd);
+        if let Some(name) = &client.subscription {
+            epserver.subscriptions.borrow_mut().detach(client.tenant, name);
+        }
+        if let Some(tracer) = &epserver.tracer {
+            tracer.drained(cfd);
+        }
+        if let Some(scripts) = &epserver.scripts {
+            scripts.on_disconnect(cfd, client.nick.as_deref());
+        }
+        epserver.sys.close(cfd);
+        if client.authed && client.sniffed {
+            announce(epserver, clients, client.tenant, &format!("* {} left ({})\n", display_name(&client), reason));
+        }
+        release(client.holding, epserver, clients);
+        epserver.dialers.disconnected(cfd, epserver.sys.now());
+        epserver.sources.closed(&*epserver.sys, cfd, epserver.sys.now());
+        epserver.emit(Event::Disconnected { fd: cfd, reason: reason.to_string() });
+    }
+    match peer {
+        Some(peer) => info!("removed client {} from {} ({})", cfd, peer, reason),
+        None => info!("removed client {} ({})", cfd, reason),
+    }
+}
+
+/// The address a client connected from, `-` if it didn't.
+pub(crate) fn peer_name(client: &ClientState) -> String {
+    client.peer.map_or_else(|| "-".to_string(), |a| a.to_string())
+}
+
+/// The nick of a client, or `client <fd>` until it picks one.
+fn display_name(client: &ClientState) -> String {
+    match &client.nick {
+        Some(nick) => nick.clone(),
+        None => format!("client {}", client.fd),
+    }
+}
+
+/// Sends a presence notice to every client of tenant, unless disabled. A client
+/// borrowed by the caller is skipped.
+fn announce(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>, tenant: Option<usize>, notice: &str) {
+    if epserver.presence {
+        for client in clients.values() {
+            if let Ok(mut client) = client.try_borrow_mut() {
+                if client.authed && client.sniffed && client.tenant == tenant && client.direction != Direction::In {
+                    send(epserver, &mut client, notice.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+fn accept_client(epserver: &mut EpollServer, listener: i32) -> Result<(i32, SocketAddr)> {
+    let (fd, addr) = epserver.sys.accept(listener)?;
+    if epserver.bans.is_banned(addr.ip()) {
+        info!("refused banned client {}", addr);
+        epserver.sys.close(fd);
+        return Err(Error::from(ErrorKind::PermissionDenied));
+    }
+    if epserver.greylist.connect(addr.ip(), epserver.sys.now()) {
+        info!("refused greylisted client {}", addr);
+        epserver.sys.close(fd);
+        return Err(Error::from(ErrorKind::PermissionDenied));
+    }
+
+    info!("accepted a client (fd = {}) from {}", fd, addr);
+
+    if let Err(e) = epserver.sys.watch(fd) {
+        error!("failed to add client to epoll");
+        epserver.sys.close(fd);
+        return Err(e);
+    }
+
+    Ok((fd, addr))
+}
+
+fn handle_event(fd: i32, epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
+    if let Some(metrics) = epserver.metrics.as_mut().filter(|m| m.owns(fd)) {
+        let mut counts = vec![0; epserver.tenants.len()];
+        for t in clients.values().filter_map(|c| c.borrow().tenant) {
+            counts[t] += 1;
+        }
+        let tenants = &epserver.tenants;
+        metrics.handle_event(&*epserver.sys, fd, || {
+            let probes = epserver.probes.as_ref().map(|p| p.borrow().render_metrics()).unwrap_or_default();
+            let per_client = who::render_metrics(clients);
+            let per_room = epserver.room_stats.render_metrics(namespaces(&epserver.rooms, tenants));
+            let shards = epserver.shard.as_ref().map(shards::render_metrics).unwrap_or_default();
+            format!("{}{}{}{}{}{}{}{}", epserver.filters.render_metrics(), epserver.rules.render_metrics(), tenant::render_metrics(tenants, &counts), probes, per_client, per_room, sniff::render_metrics(), shards)
+        });
+    } else if epserver.admin.as_ref().is_some_and(|a| a.owns(fd)) {
+        let commands = epserver.admin.as_mut().map(|a| a.read_commands(&*epserver.sys, fd)).unwrap_or_default();
+        for line in commands {
+            if let Some(path) = health::request_path(&line) {
+                let (status, body) = health::probe(path, epserver);
+                if let Some(admin) = epserver.admin.as_mut() {
+                    admin.respond(&*epserver.sys, fd, status, &body);
+                }
+                break;
+            }
+            let reply = admin::execute(&line, epserver, clients);
+            if let Some(admin) = epserver.admin.as_mut() {
+                admin.reply(fd, &reply);
+            }
+        }
+    } else if let Some(sink) = epserver.sinks.iter().find(|s| s.fd() == fd) {
+        sink.flush(&*epserver.sys);
+    } else if epserver.shard.as_ref().is_some_and(|s| s.fd() == fd) {
+        shards::deliver(epserver, clients);
+    } else if epserver.webhooks.owns(fd) {
+        epserver.webhooks.handle_event(&*epserver.sys, fd, epserver.sys.now());
+    } else if epserver.sources.owns(fd) {
+        epserver.sources.handle_event(fd);
+    } else if epserver.dialers.owns(fd) {
+        if let Some(dialed) = epserver.dialers.handle_event(&*epserver.sys, fd, epserver.sys.now()) {
+            let mut client = ClientState::with_fd(dialed.fd);
+            client.peer = Some(dialed.addr);
+            client.nick = Some(dialed.name);
+            client.direction = dialed.direction;
+            serve(epserver, clients, client);
+            epserver.emit(Event::Connected { fd: dialed.fd, peer: dialed.addr });
+        }
+    } else if epserver.signals.as_ref().is_some_and(|s| s.fd() == fd) {
+        let received = epserver.signals.as_ref().map(Signals::read).unwrap_or_default();
+        if received.contains(&libc::SIGUSR1) {
+            if let Err(e) = dump::write(epserver, clients) {
+                error!("failed to write state dump -- {}", e);
+            }
+        }
+        if received.contains(&libc::SIGTERM) {
+            drain::start(epserver, clients, epserver.drain_timeout);
+        }
+    } else if epserver.listener_policy(fd).is_some() && !epserver.throttle.admit(epserver.sys.now()) {
+        throttle::pause(epserver);
+    } else if let Some(policy) = epserver.listener_policy(fd).cloned() {
+        if let Ok((cfd, peer)) = accept_client(epserver, fd) {
+            let mut client = ClientState::with_fd(cfd);
+            client.peer = Some(peer);
+            client.last_active = epserver.sys.now();
+            client.listener = fd;
+            client.read_only = policy.read_only;
+            client.authed = !policy.needs_auth();
+            client.delivery = policy.delivery.unwrap_or(epserver.delivery);
+            client.tenant = policy.tenant.as_deref().and_then(|name| epserver.tenants.iter().position(|t| t.name == name));
+            if let Some(full) = client.tenant.filter(|t| epserver.tenant_full(*t, clients)) {
+                let notice = format!("* tenant {} is full\n", epserver.tenants[full].name);
+                let _ = epserver.sys.write(cfd, notice.as_bytes());
+                epserver.sys.unwatch(cfd);
+                epserver.sys.close(cfd);
+                return;
+            }
+            client.nick = epserver.nicks.guest();
+            client.sniffed = !policy.sniff;
+            client.proxied = policy.proxy_protocol;
+            if client.sniffed {
+                welcome(&mut client, epserver, clients);
+            }
+            clients.insert(cfd, RefCell::new(client));
+            epserver.emit(Event::Connected { fd: cfd, peer });
+        }
+    } else {
+        let handled = flush_client(fd, epserver, clients).and_then(|_| handle_client(fd, epserver, clients));
+        if let Err(e) = handled {
+            match e.kind() {
+                ErrorKind::InvalidInput => {}
+                ErrorKind::ConnectionAborted => remove_client(epserver, fd, clients, "quit"),
+                ErrorKind::InvalidData => remove_client(epserver, fd, clients, "message too long"),
+                _ => remove_client(epserver, fd, clients, "error"),
+            }
+        }
+    }
+}
+
+/// Serves a client on an fd the server opened itself, like a dialed one.
+fn serve(epserver: &EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>, mut client: ClientState) {
+    client.last_active = epserver.sys.now();
+    client.delivery = epserver.delivery;
+    welcome(&mut client, epserver, clients);
+    clients.insert(client.fd, RefCell::new(client));
+}
+
+/// Greets a client that turned out to speak the line protocol, and tells the
+/// others it is there.
+fn welcome(client: &mut ClientState, epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    if !client.authed {
+        notify(epserver, client, b"* authenticate with /auth <token>\n");
+    }
+    if client.delivery == Delivery::AtLeastOnce {
+        client.offsets = true;
+        let next = epserver.history_for(client.tenant).borrow().next_offset();
+        notify(epserver, client, format!("* at-least-once delivery, offsets on, next is {}\n", next).as_bytes());
+    }
+    if let Some(scripts) = &epserver.scripts {
+        for reply in scripts.on_connect(client.fd, &peer_name(client)) {
+            notify(epserver, client, format!("{}\n", reply).as_bytes());
+        }
+    }
+    if client.authed {
+        announce(epserver, clients, client.tenant, &format!("* {} joined\n", display_name(client)));
+    }
+}
+
+/// Waits up to timeout_ms for events and handles them. A wait interrupted by a
+/// signal counts as one where nothing happened.
+///
+/// Returns the number of events handled.
+pub fn poll_once(epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>, timeout_ms: i32) -> Result<usize> {
+    let mut ready = Vec::new();
+    if let Err(e) = busypoll::wait(&*epserver.sys, &mut ready, timeout_ms, &epserver.spun_dry) {
+        if e.kind() != ErrorKind::Interrupted {
+            WAIT_ERRORS.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        // a signal cut the wait short, nothing is ready but ticks and deadlines still run
+        WAIT_INTERRUPTED.fetch_add(1, Ordering::Relaxed);
+        ready.clear();
+    }
+    debug!(Epoll, "wait reported {} fds {:?}", ready.len(), ready);
+
+    let start = epserver.sys.now();
+    let backlog = epserver.backlog.take();
+    for fd in &ready {
+        handle_event(*fd, epserver, clients);
+    }
+    handoff::complete(epserver, clients);
+    throttle::check(epserver);
+    for fd in epserver.lagging.take() {
+        if let Some(client) = clients.get(&fd) {
+            notify(epserver, &mut client.borrow_mut(), b"* too far behind, reconnect and /resume\n");
+            remove_client(epserver, fd, clients, "too far behind");
+        }
+    }
+    for (operator, sanction) in epserver.sanctions.take() {
+        let reply = match sanction {
+            Sanction::Kick(cfd, target) if clients.contains_key(&cfd) => {
+                admin::kick(epserver, cfd, clients, "kicked");
+                Ok(format!("kicked {}", target))
+            }
+            Sanction::Kick(_, target) => Err(format!("{} is gone already", target)),
+            Sanction::Ban(ip, duration) => admin::ban(&ip.to_string(), duration.as_deref(), epserver, clients),
+        };
+        if let Some(client) = clients.get(&operator) {
+            notify(epserver, &mut client.borrow_mut(), format!("* {}\n", reply.unwrap_or_else(|e| e)).as_bytes());
+        }
+    }
+    for fd in backlog.into_iter().filter(|fd| !ready.contains(fd)) {
+        let taken = match clients.get(&fd) {
+            Some(client) if client.borrow().backlogged => take_backlog(&mut client.borrow_mut(), epserver, clients),
+            _ => Ok(()),
+        };
+        if taken.is_err() {
+            remove_client(epserver, fd, clients, "message too long");
+        }
+    }
+    deadletter::flush(epserver, clients);
+    mirror::flush(epserver, clients);
+    if !epserver.webhooks.is_empty() {
+        epserver.webhooks.poll(&*epserver.sys, epserver.sys.now());
+    }
+    if !epserver.dialers.is_empty() {
+        epserver.dialers.poll(&*epserver.sys, epserver.sys.now());
+    }
+    for opened in epserver.sources.poll(&*epserver.sys, epserver.sys.now(), overload::degraded()) {
+        let mut client = ClientState::with_fd(opened.fd);
+        client.nick = Some(opened.label.clone());
+        client.direction = Direction::In;
+        client.meta.insert("source".to_string(), opened.label.clone());
+        if let Some(room) = opened.room {
+            epserver.rooms_for(None).borrow_mut().join(&room, opened.fd, Some(&opened.label), Qos::BestEffort);
+            client.room = Some(room);
+        }
+        client.label = opened.tag.then_some(opened.label);
+        client.backpressure = opened.backpressure;
+        serve(epserver, clients, client);
+    }
+    let lag = epserver.sys.now().duration_since(start);
+    epserver.overload.update(lag, &*epserver.sys, clients);
+    flush_all_coalesced(epserver, clients);
+    if epserver.sys.now() >= epserver.next_tick {
+        on_tick(epserver, clients);
+    }
+    send_probes(epserver, clients);
+    drain::check(epserver, clients);
+
+    Ok(ready.len())
+}
+
+/// Sends a probe to every client that wants them, if one is due.
+fn send_probes(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    let Some(probes) = &epserver.probes else { return };
+    let Some(id) = probes.borrow_mut().due(epserver.sys.now()) else { return };
+    let line = format!("* probe {}\n", id);
+    for client in clients.values() {
+        let mut client = client.borrow_mut();
+        if client.probed {
+            // queued behind messages, so the answer says how far behind the client is
+            send(epserver, &mut client, line.as_bytes());
+        }
+    }
+}
+
+/// Records a clients answer to probe id.
+///
+/// Returns the latency, None for probes not sent or long forgotten.
+fn answer_probe(epserver: &EpollServer, client: &mut ClientState, id: u64) -> Option<Duration> {
+    if !client.probed || id < client.probe_next {
+        return None;
+    }
+    let latency = epserver.probes.as_ref()?.borrow_mut().answer(id, epserver.sys.now())?;
+    client.probe_latency = Some(latency);
+    client.probe_next = id + 1;
+    Some(latency)
+}
+
+/// Starts sending the client probes, if the server sends any.
+fn start_probing(client: &mut ClientState, epserver: &EpollServer) -> bool {
+    let Some(probes) = &epserver.probes else { return false };
+    if !client.probed {
+        client.probed = true;
+        client.probe_next = probes.borrow().next_id();
+    }
+    true
+}
+
+/// Periodic work, run every `tick` by poll_once.
+fn on_tick(epserver: &mut EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
+    epserver.next_tick = epserver.sys.now() + epserver.tick;
+    if let Some(scripts) = &epserver.scripts {
+        scripts.on_tick();
+    }
+    slow::check(epserver, clients);
+    sniff::check(epserver, clients);
+    epserver.greylist.prune(epserver.sys.now());
+    if let Some(shard) = &epserver.shard {
+        shard.count(clients.len());
+    }
+    subscriptions::check(epserver, clients);
+    epserver.hooks.reap(epserver.sys.now());
+    if let Some(tracer) = &epserver.tracer {
+        tracer.export();
+    }
+    drain::check(epserver, clients);
+}
+
+/// Serves clients until a drain finishes, or polling fails.
+pub fn await_clients(mut epserver: EpollServer) -> Result<()> {
+    let mut clients: HashMap<i32, RefCell<ClientState>> = HashMap::new();
+
+    while !epserver.is_stopped() {
+        let timeout = epserver.timeout_ms();
+        if let Err(e) = poll_once(&mut epserver, &mut clients, timeout) {
+            error!("epoll_wait error: {} (errno {})", e, e.raw_os_error().unwrap_or(0));
+            return Err(e);
+        }
+    }
+    Ok(())
+}
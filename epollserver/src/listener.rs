@@ -0,0 +1,121 @@
+use std::io::Result;
+use std::str::FromStr;
+
+use crate::config::Config;
+
+/// What a client can count on getting. Both keep messages in order and never
+/// deliver one twice on the same connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Delivery {
+    /// Fire and forget: messages for a client whose queue is full are dropped
+    /// and the connection stays up, so it silently misses them.
+    #[default]
+    AtMostOnce,
+    /// Clients get history offsets from the start, and one that falls too far
+    /// behind is disconnected instead of losing messages. Reconnecting with
+    /// `/resume <last offset>` fills the gap from history, so nothing is lost as
+    /// long as the client comes back within `--history` messages. Anything it
+    /// got but did not process may come again after the resume.
+    AtLeastOnce,
+}
+
+impl FromStr for Delivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Delivery, String> {
+        match s {
+            "at-most-once" => Ok(Delivery::AtMostOnce),
+            "at-least-once" => Ok(Delivery::AtLeastOnce),
+            _ => Err(format!("unknown delivery {:?}, expected at-most-once or at-least-once", s)),
+        }
+    }
+}
+
+/// What clients accepted on a listener may do.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Subscribers only receive, what they send is dropped. Commands still work.
+    pub read_only: bool,
+    /// Clients have to send `/auth <token>` before anything else.
+    pub token: Option<String>,
+    /// The tenant clients belong to, `*` to have `/auth` pick it by token.
+    pub tenant: Option<String>,
+    /// How messages are delivered, `--delivery` if None.
+    pub delivery: Option<Delivery>,
+    /// Tell line clients from TLS and HTTP by what they send first, see sniff.rs.
+    pub sniff: bool,
+    /// Take the client address from a PROXY protocol header, for listeners
+    /// behind a load balancer. Implies sniff.
+    pub proxy_protocol: bool,
+}
+
+impl Policy {
+    /// Whether clients have to `/auth` first.
+    pub fn needs_auth(&self) -> bool {
+        self.token.is_some() || self.tenant.as_deref() == Some("*")
+    }
+}
+
+/// A listener from the config file, one `[listener.<name>]` section each:
+///
+/// ```text
+/// [listener.subscribers]
+/// bind = 0.0.0.0:9091
+/// role = subscriber   # or publisher, the default
+/// token = s3cret      # optional
+/// tenant = acme       # optional, see tenant.rs
+/// delivery = at-least-once   # optional, see Delivery
+/// sniff = true        # optional, see Policy::sniff
+/// proxy_protocol = true   # optional, only behind a proxy that always sends it
+/// ```
+pub struct ListenerConfig {
+    pub name: String,
+    pub bind: String,
+    pub policy: Policy,
+}
+
+impl ListenerConfig {
+    pub fn from_config(config: &Config) -> Result<Vec<ListenerConfig>> {
+        let mut listeners = Vec::new();
+        for (name, section) in config.sections_with_prefix("listener") {
+            let bind = match section.get("bind") {
+                Some(entry) => entry.value.clone(),
+                None => return Err(config.error(section.line, "listener is missing `bind`")),
+            };
+            let read_only = match section.get("role").map(|e| (e.value.as_str(), e.line)) {
+                None | Some(("publisher", _)) => false,
+                Some(("subscriber", _)) => true,
+                Some((_, line)) => return Err(config.error(line, "role must be `publisher` or `subscriber`")),
+            };
+            let token = match section.get("token") {
+                Some(entry) if entry.value.is_empty() => return Err(config.error(entry.line, "token is empty")),
+                Some(entry) => Some(entry.value.clone()),
+                None => None,
+            };
+            let tenant = match section.get("tenant") {
+                Some(entry) if entry.value == "*" && token.is_some() => {
+                    return Err(config.error(entry.line, "`tenant = *` takes tenant tokens, drop `token`"))
+                }
+                Some(entry) if entry.value != "*" && config.section(&format!("tenant.{}", entry.value)).is_none() => {
+                    return Err(config.error(entry.line, &format!("no [tenant.{}] section", entry.value)))
+                }
+                Some(entry) => Some(entry.value.clone()),
+                None => None,
+            };
+            let delivery = match section.get("delivery") {
+                Some(entry) => Some(entry.value.parse().map_err(|e: String| config.error(entry.line, &e))?),
+                None => None,
+            };
+            let flag = |key: &str| match section.get(key).map(|e| (e.value.as_str(), e.line)) {
+                None | Some(("false", _)) => Ok(false),
+                Some(("true", _)) => Ok(true),
+                Some((_, line)) => Err(config.error(line, &format!("{} must be `true` or `false`", key))),
+            };
+            let proxy_protocol = flag("proxy_protocol")?;
+            let sniff = flag("sniff")? || proxy_protocol;
+            let policy = Policy { read_only, token, tenant, delivery, sniff, proxy_protocol };
+            listeners.push(ListenerConfig { name: name.to_string(), bind, policy });
+        }
+        Ok(listeners)
+    }
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// How much the server prints, from --log-level or the admin `log-level`.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogLevel, String> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level {:?}, expected error, warn, info or debug", s)),
+        }
+    }
+}
+
+/// Parts of the server whose debug output can be turned on on its own, without
+/// the flood from everything else at `debug`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Subsystem {
+    /// Splitting client input into lines.
+    Framing,
+    /// What each wait reports.
+    Epoll,
+    /// Who each message goes to.
+    Broadcast,
+}
+
+pub const SUBSYSTEMS: [Subsystem; 3] = [Subsystem::Framing, Subsystem::Epoll, Subsystem::Broadcast];
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static DEBUG: [AtomicBool; 3] = [const { AtomicBool::new(false) }; 3];
+
+impl Subsystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Framing => "framing",
+            Subsystem::Epoll => "epoll",
+            Subsystem::Broadcast => "broadcast",
+        }
+    }
+
+    fn flag(self) -> &'static AtomicBool {
+        &DEBUG[self as usize]
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Subsystem, String> {
+        SUBSYSTEMS.into_iter().find(|sub| sub.name() == s)
+            .ok_or_else(|| format!("unknown subsystem {:?}, expected framing, epoll or broadcast", s))
+    }
+}
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        _ => LogLevel::Debug,
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level <= self::level()
+}
+
+/// Whether sub prints debug output, either toggled on its own or at level debug.
+pub fn debugging(sub: Subsystem) -> bool {
+    enabled(LogLevel::Debug) || sub.flag().load(Ordering::Relaxed)
+}
+
+pub fn set_debug(sub: Subsystem, on: bool) {
+    sub.flag().store(on, Ordering::Relaxed);
+}
+
+/// Prints at the given level, for main.rs which can't use the macros below:
+/// warnings and errors go to stderr, the rest to stdout.
+pub fn log(level: LogLevel, args: std::fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    if level <= LogLevel::Warn {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+/// Prints to stderr at level error or above.
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints to stderr at level warn or above.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints to stdout at level info or above.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints to stdout when debugging the given subsystem.
+macro_rules! debug {
+    ($sub:ident, $($arg:tt)*) => {
+        if $crate::logging::debugging($crate::logging::Subsystem::$sub) {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
@@ -1,246 +1,564 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream};
-use std::io::{Error, ErrorKind, Read, Result, Write};
-use std::os::fd::AsRawFd;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::IntoRawFd;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
-const MAX_EVENTS: i32 = 256;
-const BUFFER_SIZE: usize = 256;
-
-static TOTAL_BYTES_SENT: AtomicUsize = AtomicUsize::new(0);
+use epollserver::affinity::CpuSets;
+use epollserver::banners::Banners;
+use epollserver::bans::BanList;
+use epollserver::busypoll;
+use epollserver::chaos::{Chaos, ChaosConfig};
+use epollserver::clock::TimeFormat;
+use epollserver::config::Config;
+use epollserver::daemon::{self, Pidfile};
+use epollserver::deadletter::DeadLetters;
+use epollserver::dedup::Dedup;
+use epollserver::dial::Dialers;
+use epollserver::environment;
+use epollserver::filter::FilterChain;
+use epollserver::greylist::Greylist;
+use epollserver::history::{self, History};
+use epollserver::hooks::Hooks;
+use epollserver::listener::{Delivery, ListenerConfig, Policy};
+use epollserver::logging::{self, LogLevel};
+use epollserver::multicast::Multicast;
+use epollserver::nicks::Nicks;
+use epollserver::numa;
+use epollserver::overload::OverloadMonitor;
+use epollserver::plugin::Plugin;
+use epollserver::poll::{Backend, Poll};
+use epollserver::privileges;
+use epollserver::record::{self, Recorder};
+use epollserver::sandbox::{self, Sandbox};
+use epollserver::shards::{self, Shard};
+use epollserver::rooms::{Rate, RoomPolicy};
+use epollserver::roomstats::RoomStats;
+use epollserver::rules::Rules;
+use epollserver::sanitize::{Sanitizer, Utf8Policy};
+use epollserver::scripting::ScriptHooks;
+use epollserver::sim::{self, SimNet};
+use epollserver::sink::Sink;
+use epollserver::slow::{SlowConsumers, SlowPolicy};
+use epollserver::snapshot;
+use epollserver::source::{Device, Sources};
+use epollserver::sys::{self, Epoll, Sys};
+use epollserver::tenant::Tenant;
+use epollserver::trace::Tracer;
+use epollserver::webhooks::Webhooks;
+use epollserver::{await_clients, Coalesce, EpollServer, Overflow, MAX_EVENTS};
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "epollserver")]
+#[structopt(
+    name = "epollserver",
+    after_help = "Options can also be given as EPOLLBROADCAST_<OPTION> environment variables, like \
+                  EPOLLBROADCAST_MAX_QUEUE_BYTES=65536, flags as 1 or 0 and repeated options comma \
+                  separated. The command line wins."
+)]
 struct Opt {
+    /// Listen on localhost at this port unless --bind or [listener.*] sections are given
     #[structopt(short, long, default_value = "9090")]
-    port: u16
-}
-
-struct ClientState {
-    off: usize, // index after last u8 in buf if buf has no \n
-    needle: usize, // index after last \n in buf
-    buf: Box<[u8; BUFFER_SIZE]>,
-    stream: TcpStream,
+    port: u16,
+    /// Listen on this host:port, can be repeated, e.g. 0.0.0.0:9090 and [::]:9090
+    #[structopt(long)]
+    bind: Vec<String>,
+    /// Answer HTTP probes and close TLS handshakes on the --bind or --port listeners,
+    /// telling them from line clients by what they send first
+    #[structopt(long)]
+    sniff: bool,
+    /// Take client addresses from PROXY protocol headers on the --bind or --port
+    /// listeners, only for when they are behind a proxy
+    #[structopt(long)]
+    proxy_protocol: bool,
+    /// Read message filters and other settings from this file
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Serve prometheus metrics over http on this localhost port
+    #[structopt(long)]
+    metrics_port: Option<u16>,
+    /// Accept operator commands (kick, ban, ...) on this localhost port
+    #[structopt(long)]
+    admin_port: Option<u16>,
+    /// Persist bans to this file and load them at startup
+    #[structopt(long, parse(from_os_str))]
+    ban_file: Option<PathBuf>,
+    /// Run on_connect/on_message/on_disconnect hooks from this rhai script
+    #[structopt(long, parse(from_os_str))]
+    script: Option<PathBuf>,
+    /// Pass every message through this sandboxed wasm plugin
+    #[structopt(long, parse(from_os_str))]
+    plugin: Option<PathBuf>,
+    /// Refuse addresses that connect more than this many times a minute, for
+    /// --greylist-secs
+    #[structopt(long)]
+    greylist_reconnects: Option<usize>,
+    /// How long greylisted addresses are refused
+    #[structopt(long, default_value = "300")]
+    greylist_secs: u64,
+    /// Accept at most this many connections per s, m or h on all listeners of an
+    /// event loop, like 200/s. Storms using it up pause the listeners for a while
+    #[structopt(long)]
+    max_accept_rate: Option<Rate>,
+    /// Degrade when handling one batch of events takes longer than this
+    #[structopt(long, default_value = "50")]
+    overload_lag_ms: u64,
+    /// Degrade when more than this many bytes wait in client send queues
+    #[structopt(long, default_value = "1048576")]
+    overload_queue_bytes: usize,
+    /// Run this scenario against a simulated network instead of listening
+    #[structopt(long, parse(from_os_str))]
+    simulate: Option<PathBuf>,
+    /// Write everything clients send, with timestamps, to this file
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+    /// Feed a file written by --record through the server instead of listening
+    #[structopt(long, parse(from_os_str), conflicts_with = "simulate")]
+    replay: Option<PathBuf>,
+    /// How many times faster than recorded to replay, 0 for no pauses at all
+    #[structopt(long, default_value = "1")]
+    replay_speed: f64,
+    /// Inject random delays, short writes, EAGAIN and dropped connections, see [chaos] in the config
+    #[structopt(long)]
+    chaos: bool,
+    /// Wait for sockets with epoll or poll
+    #[structopt(long, default_value = "epoll")]
+    backend: Backend,
+    /// How many recent messages to keep for clients that /resume after reconnecting
+    #[structopt(long, default_value = "1024")]
+    history: usize,
+    /// Where the admin `snapshot` saves bans, room topics and history if not given a path
+    #[structopt(long)]
+    snapshot_file: Option<PathBuf>,
+    /// Start from a snapshot the admin `snapshot` saved, before any --preload
+    #[structopt(long)]
+    restore: Option<PathBuf>,
+    /// Fill the history from this file of newline separated messages at startup, so
+    /// clients resuming right after a restart get recent context
+    #[structopt(long)]
+    preload: Option<PathBuf>,
+    /// Drop messages for a client while this many bytes wait to be written to it
+    #[structopt(long, default_value = "1048576")]
+    max_queue_bytes: usize,
+    /// When a client sends a line longer than the buffer: broadcast what fits as a
+    /// message, discard the rest of the line, or disconnect the client
+    #[structopt(long, default_value = "disconnect")]
+    overflow_policy: Overflow,
+    /// Handle at most this many messages of one client per wakeup before moving on to the next
+    #[structopt(long)]
+    max_messages_per_event: Option<usize>,
+    /// at-most-once drops messages for slow clients, at-least-once disconnects them
+    /// so they /resume, for listeners without a delivery setting
+    #[structopt(long, default_value = "at-most-once")]
+    delivery: Delivery,
+    /// Count clients whose outbox stays backed up this long as slow consumers, and
+    /// log and mark them
+    #[structopt(long)]
+    slow_consumer_ms: Option<u64>,
+    /// Besides warning about slow consumers, drop what is queued for them or disconnect them
+    #[structopt(long, default_value = "warn")]
+    slow_consumer_policy: SlowPolicy,
+    /// Append messages dropped by limits, filters, rules or full queues to this file,
+    /// or send them to this #room, annotated with why
+    #[structopt(long)]
+    dead_letter: Option<String>,
+    /// Export trace spans of sampled messages to this OpenTelemetry collector,
+    /// http://host[:4318]
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+    /// Share of messages traced for --otlp-endpoint, from 0 to 1
+    #[structopt(long, default_value = "0.01")]
+    trace_sample: f64,
+    /// Write each message to clients on its own, not together with others from the same read
+    #[structopt(long)]
+    no_batch: bool,
+    /// Drop (reject) or repair (replace) messages that are not valid UTF-8
+    #[structopt(long)]
+    require_utf8: Option<Utf8Policy>,
+    /// Remove control characters and ANSI escape sequences from messages
+    #[structopt(long)]
+    strip_control: bool,
+    /// Send clients that ask with /probe a probe this often, timing their answers in metrics
+    #[structopt(long)]
+    probe_interval_ms: Option<u64>,
+    /// Hold small broadcasts back up to this many microseconds to write them together
+    #[structopt(long)]
+    coalesce_us: Option<u64>,
+    /// Write held back broadcasts right away once this many bytes are waiting
+    #[structopt(long, default_value = "4096")]
+    coalesce_bytes: usize,
+    /// Prefix every broadcast message with the server time, as iso8601 or millis
+    #[structopt(long)]
+    timestamps: Option<TimeFormat>,
+    /// Prefix every broadcast message with the address of its sender, like [10.0.0.5:41000]
+    #[structopt(long)]
+    attribute_peer: bool,
+    /// Also send every message as a UDP datagram to this multicast group, e.g. 239.1.2.3:9091
+    #[structopt(long)]
+    multicast_group: Option<SocketAddr>,
+    /// How many router hops multicast datagrams may cross
+    #[structopt(long, default_value = "1")]
+    multicast_ttl: u32,
+    /// Forward every message to syslog://host:port or gelf://host:port, can be repeated
+    #[structopt(long)]
+    sink: Vec<String>,
+    /// Broadcast the lines of a serial port, e.g. /dev/ttyUSB0@115200, can be repeated
+    #[structopt(long)]
+    serial: Vec<String>,
+    /// Broadcast the lines written to this named pipe as from a client named pipe, can be repeated
+    #[structopt(long)]
+    fifo: Vec<PathBuf>,
+    /// Follow this file like tail -F and broadcast the lines appended to it, can be repeated
+    #[structopt(long)]
+    tail: Vec<PathBuf>,
+    /// Give this many rooms metrics of their own, the others count as room="other", 0 for none
+    #[structopt(long, default_value = "100")]
+    room_metrics: usize,
+    /// Name clients guest-<n> until they pick a nick, instead of client <fd>
+    #[structopt(long)]
+    guest_nicks: bool,
+    /// Don't tell clients when someone connects or leaves
+    #[structopt(long)]
+    no_presence: bool,
+    /// On SIGTERM, give clients this many seconds to receive what is queued for them
+    #[structopt(long, default_value = "10")]
+    drain_timeout: u64,
+    /// Disconnect /subscribe clients that leave messages unacknowledged for this many
+    /// seconds, so they reconnect and get them redelivered
+    #[structopt(long, default_value = "30")]
+    ack_timeout: u64,
+    /// Append SIGUSR1 and admin `dump` state snapshots to this file instead of stderr
+    #[structopt(long, parse(from_os_str))]
+    dump_file: Option<PathBuf>,
+    /// Run periodic work (script on_tick, drain deadlines) this often
+    #[structopt(long, default_value = "1000")]
+    tick_ms: u64,
+    /// Switch to this user once listening, e.g. after binding a port below 1024 as root
+    #[structopt(long)]
+    user: Option<String>,
+    /// Switch to this group once listening, defaults to the users primary group
+    #[structopt(long)]
+    group: Option<String>,
+    /// Confine the server to this directory once listening
+    #[structopt(long, parse(from_os_str))]
+    chroot: Option<PathBuf>,
+    /// Once listening, restrict syscalls (seccomp), file access (landlock) or all
+    #[structopt(long)]
+    sandbox: Option<Sandbox>,
+    /// Print errors, warnings, info (the default) or debug output of everything
+    #[structopt(long, default_value = "info")]
+    log_level: LogLevel,
+    /// Detach from the terminal once listening
+    #[structopt(long)]
+    daemon: bool,
+    /// Write the server pid here, removed again on exit
+    #[structopt(long, parse(from_os_str))]
+    pidfile: Option<PathBuf>,
+    /// Where stdout and stderr go with --daemon, discarded without one
+    #[structopt(long, parse(from_os_str), requires = "daemon")]
+    log_file: Option<PathBuf>,
+    /// Run this many event loops on threads of their own, each keeping the clients
+    /// it accepts. Broadcasts reach every loop, rooms, history, presence and
+    /// operator commands stay within one; metrics and admin are served by the first
+    #[structopt(long, default_value = "1")]
+    workers: usize,
+    /// Keep each event loop on these cores, one set per worker like 2,3,4 or 2-4,
+    /// cores of a set joined with +, like 2+10 for a core and its NIC interrupts.
+    /// On machines with several memory nodes loops allocate from their cores node
+    #[structopt(long)]
+    pin_cpus: Option<CpuSets>,
+    /// Poll without blocking for up to this many microseconds after handling events,
+    /// before waiting for more, trading CPU for wakeup latency
+    #[structopt(long, default_value = "0")]
+    busy_poll_us: u64,
 }
 
-impl ClientState {
-    pub fn with_stream(stream: TcpStream) -> ClientState {
-        ClientState {
-            off: 0,
-            needle: 0,
-            buf: Box::new([0; BUFFER_SIZE]),
-            stream,
-        }
+fn main() -> Result<()> {
+    let opt = Opt::from_iter(environment::merge(Opt::clap(), std::env::args_os().collect())?);
+    logging::set_level(opt.log_level);
+    busypoll::SPIN_US.store(opt.busy_poll_us, Ordering::Relaxed);
+    let config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::empty(),
+    };
+    let simulated = opt.simulate.is_some() || opt.replay.is_some();
+    if opt.workers > 1 && (simulated || opt.record.is_some()) {
+        return Err(Error::new(ErrorKind::InvalidInput, "--workers can't be used to --simulate, --record or --replay"));
     }
-
-    /// Mutably borrow the clients tcp stream and buffer together for reading.
-    pub fn borrow_reader_mut(&mut self) -> (&mut TcpStream, &mut [u8]) {
-        (&mut self.stream, &mut *self.buf)
+    let sim = if simulated { Some(Rc::new(SimNet::new())) } else { None };
+    let listeners = if simulated { Vec::new() } else { bind(&opt, &config)? };
+    let listening = listeners.iter().map(|(l, _)| l.local_addr().map(|a| a.to_string())).collect::<Result<Vec<_>>>()?;
+    let mut per_worker = Vec::new();
+    for _ in 1..opt.workers {
+        per_worker.push(listeners.iter().map(|(l, policy)| Ok((l.try_clone()?, policy.clone()))).collect::<Result<Vec<_>>>()?);
     }
-}
-
-struct EpollServer {
-    epfd: i32,
-    events: Vec<libc::epoll_event>,
-    listener: TcpListener,
-}
-
-impl EpollServer {
-    pub fn new(listener: TcpListener, max_events: usize) -> Result<EpollServer> {
-        let sockfd = listener.as_raw_fd();
-
-        unsafe {
-            let epfd = libc::epoll_create1(0);
-
-            if epfd >= 0 {
-                let mut e = libc::epoll_event {
-                    events: libc::EPOLLIN as u32,
-                    u64: sockfd as u64
-                };
-                
-                if libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, sockfd, &mut e) == 0 {
-                    return Ok(
-                        EpollServer {
-                            epfd,
-                            events: Vec::with_capacity(max_events),
-                            listener
-                        }
-                    );
-                } else {
-                    let errmsg = format!("epoll_ctl failed to add server fd {} -- {}", sockfd, Error::last_os_error());
-                    return Err(Error::other(errmsg));
-                }
-            }
-        }
-
-        let errmsg = format!("epoll_create1 failed -- {}", Error::last_os_error());
-        Err(Error::other(errmsg))
+    let mut shards = shards::create(if opt.workers > 1 { opt.workers } else { 0 })?.into_iter();
+    let mut epserver = match &sim {
+        Some(net) => EpollServer::new(net.clone(), sim::SIM_LISTENER)?,
+        None => open(&opt, &config, listeners)?,
+    };
+    if let Some(first) = shards.next() {
+        epserver.join_shards(first)?;
     }
-}
-
-/// Attempts to write orators buffer to every client connected, does not try again
-/// if write fails.
-///
-/// Returns total number of bytes written across all clients.
-fn broadcast_message(orator: &mut ClientState, clients: &HashMap<i32, RefCell<ClientState>>) -> usize {
-    let ofd = orator.stream.as_raw_fd();
-    let message = &orator.buf[0..orator.needle];
-    let mut bytes = 0;
-
-    for (cfd, client) in clients.iter() {
-        // ensure we don't mutably borrow the orator a second time
-        // (first mutable borrow occurs in handle_client())
-        if *cfd != ofd {
-            match client.borrow_mut().stream.write(message) {
-                Ok(n) => bytes += n,
-                Err(_) => /* eprintln!("write error (fd = {}): {e}", *cfd) */ {},
-            }
-        }
+    configure(&mut epserver, &opt, &config, simulated)?;
+    if opt.sandbox.is_some() && !epserver.hooks.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "[hook.*] commands can't run once --sandbox forbids starting them"));
     }
-
-    // if there are left over bytes past the needle, shift them to the 
-    // beginning of the buffer for next read, this way writes always start at index 0
-    if orator.needle < orator.off {
-        orator.off -= orator.needle;
-        for i in 0..orator.off {
-            orator.buf[i] = orator.buf[orator.needle];
-            orator.needle += 1;
-        }
-    } else {
-        orator.off = 0;
+    if opt.sandbox.is_some() && epserver.rules.hands_off() {
+        return Err(Error::new(ErrorKind::InvalidInput, "handoff rules can't reach their helpers once --sandbox forbids connecting"));
     }
-    orator.needle = 0;
-
-    bytes
-}
-
-/// Checks clients buffer after reading for a newline and adjusts offset and needle.
-/// 
-/// Returns true if message should be broadcasted.
-fn check_message(client: &mut ClientState, bytes: usize) -> bool {
-    for i in (0..client.off + bytes).rev() {
-        if client.buf[i] == b'\n' {
-            client.needle = i + 1;
-            break;
-        }
+    if opt.sandbox.is_some() && !epserver.webhooks.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "[webhook.*] endpoints can't be reached once --sandbox forbids connecting"));
     }
-
-    client.off += bytes;
-    if client.needle > 0 {
-        return true;
+    if opt.sandbox.is_some() && !epserver.dialers.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "[dial.*] endpoints can't be reached once --sandbox forbids connecting"));
+    }
+    if opt.sandbox.is_some() && opt.workers > 1 {
+        return Err(Error::new(ErrorKind::InvalidInput, "--sandbox only confines one thread, it can't be used with --workers"));
+    }
+    let mut sandboxed = sandbox::Paths::default();
+    sandboxed.write.extend(opt.ban_file.iter().chain(&opt.dump_file).chain(&opt.snapshot_file).chain(&opt.pidfile).cloned());
+    sandboxed.read.extend(opt.script.iter().chain(&opt.config).cloned());
+    sandboxed.read.extend(epserver.sources.paths().map(Path::to_path_buf));
+    if let Some(port) = opt.metrics_port {
+        epserver.serve_metrics(port)?;
+    }
+    if let Some(port) = opt.admin_port {
+        epserver.serve_admin(port)?;
     }
 
-    false
-}
+    if let (Some(net), Some(session)) = (&sim, &opt.replay) {
+        return record::replay(session, opt.replay_speed, net.clone(), epserver);
+    }
+    if let (Some(net), Some(scenario)) = (sim, &opt.simulate) {
+        return sim::run_scenario(scenario, net, epserver);
+    }
 
-fn handle_client(cfd: i32, clients: &HashMap<i32, RefCell<ClientState>>) -> Result<()> {
-    let mut client = match clients.get(&cfd) {
-        Some(c) => c.borrow_mut(),
-        None => return Err(Error::from(ErrorKind::InvalidInput)),
+    if opt.daemon {
+        println!("epoll server listening on {}, detaching", listening.join(", "));
+        daemon::daemonize(opt.log_file.as_deref())?;
+    }
+    let _pidfile = match &opt.pidfile {
+        Some(path) => Some(Pidfile::create(path.clone())?),
+        None => None,
     };
-    
-    let off = client.off;
-    let (stream, buf) = client.borrow_reader_mut();
-    match stream.read(&mut buf[off..BUFFER_SIZE]) {
-        Ok(bytes) => {
-            if bytes == 0 { 
-                return Err(Error::from(ErrorKind::ConnectionAborted)); 
-            }
-
-            if check_message(&mut client, bytes) {
-                let sent = broadcast_message(&mut client, clients);
-                TOTAL_BYTES_SENT.fetch_add(sent, Ordering::Relaxed);
-                println!("sent {:?} bytes", TOTAL_BYTES_SENT);
-            }
-
-            Ok(())
-        },
-        Err(e) => {
-            match e.kind() {
-                ErrorKind::WouldBlock => Ok(()),
-                _ => Err(e)
-            }
+    // before any worker starts, so signals are blocked for all of them
+    epserver.handle_signals()?;
+    let (opt, config) = (Arc::new(opt), Arc::new(config));
+    let mut workers = Vec::new();
+    for (listeners, shard) in per_worker.into_iter().zip(shards) {
+        let (ready, started) = mpsc::channel();
+        let (go, wait) = mpsc::channel();
+        let (opt, config) = (opt.clone(), config.clone());
+        let handle = std::thread::spawn(move || worker(&opt, &config, listeners, shard, ready, wait));
+        started.recv().map_err(|_| Error::other("a worker failed to start"))??;
+        workers.push((go, handle));
+    }
+    // only now, the others would start out on the same cores
+    if let Some(cpus) = &opt.pin_cpus {
+        let node = place(cpus, 0)?;
+        if let Some(shard) = epserver.shard.as_mut() {
+            shard.node = node;
         }
     }
+    privileges::drop_privileges(opt.user.as_deref(), opt.group.as_deref(), opt.chroot.as_deref())?;
+    if let Some(mode) = opt.sandbox {
+        sandbox::enter(mode, &sandboxed)?;
+    }
+    for (go, _) in &workers {
+        let _ = go.send(());
+    }
+    println!("epoll server listening on {}...\n", listening.join(", "));
+    await_clients(epserver)?;
+    // the others drain along with the first
+    for (_, handle) in workers {
+        let _ = handle.join();
+    }
+    Ok(())
 }
 
-fn remove_client(epfd: i32, cfd: i32, clients: &mut HashMap<i32, RefCell<ClientState>>) {
-    unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_DEL, cfd, std::ptr::null_mut()); }
-    clients.remove(&cfd);
-    println!("removed client {}", cfd);
-}
-
-fn accept_client(epfd: i32, listener: &TcpListener) -> Result<TcpStream> {
-    let (stream, _) = match listener.accept() {
-        Ok(s) => s,
-        Err(e) => return Err(e)
+/// Creates the server on the listeners, picking its backend.
+fn open(opt: &Opt, config: &Config, listeners: Vec<(TcpListener, Policy)>) -> Result<EpollServer> {
+    let mut sys: Box<dyn Sys> = match opt.backend {
+        Backend::Epoll => Box::new(Epoll::new(MAX_EVENTS as usize)?),
+        Backend::Poll => Box::new(Poll::new()),
     };
-
-    if let Err(e) = stream.set_nonblocking(true) {
-        return Err(e);
+    if opt.chaos {
+        sys = Box::new(Chaos::new(sys, ChaosConfig::from_config(config)?));
     }
-    let fd = stream.as_raw_fd();
-    println!("accepted a client (fd = {})", fd);
-
-    let mut e = libc::epoll_event {
-        events: libc::EPOLLIN as u32,
-        u64: fd as u64
-    };
-
-    let ret = unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, fd, &mut e) };
-    if ret < 0 {
-        eprintln!("failed to add client to epoll");
-        return Err(Error::last_os_error());
+    if let Some(path) = &opt.record {
+        sys = Box::new(Recorder::create(sys, path)?);
     }
-
-    Ok(stream)
+    let mut listeners = listeners.into_iter();
+    let (first, policy) = listeners.next().ok_or_else(|| Error::other("nothing to listen on"))?;
+    let first = first.into_raw_fd();
+    let mut epserver = EpollServer::new(Rc::from(sys), first)?;
+    epserver.set_policy(first, policy);
+    for (listener, policy) in listeners {
+        epserver.add_listener(listener.into_raw_fd(), policy)?;
+    }
+    Ok(epserver)
 }
 
-fn handle_event(event: &libc::epoll_event, epserver: &EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
-    if event.u64 == epserver.listener.as_raw_fd() as u64 {
-        if let Ok(stream) = accept_client(epserver.epfd, &epserver.listener) {
-            clients.insert(stream.as_raw_fd(), RefCell::new(ClientState::with_stream(stream)));
+/// Applies the options and config to a server, the same for every worker. Only
+/// the first says what it restored or preloaded.
+fn configure(epserver: &mut EpollServer, opt: &Opt, config: &Config, simulated: bool) -> Result<()> {
+    let first = epserver.shard.as_ref().is_none_or(|s| s.id == 0);
+    // simulations get stable offsets so scenarios can expect them
+    let first_offset = if simulated { 0 } else { history::first_offset_now() };
+    epserver.history = RefCell::new(History::new(opt.history, first_offset));
+    epserver.tenants = Tenant::from_config(config, opt.history, first_offset)?;
+    epserver.max_queue_bytes = opt.max_queue_bytes;
+    epserver.timestamps = opt.timestamps;
+    epserver.attribute_peer = opt.attribute_peer;
+    epserver.coalesce = opt.coalesce_us.map(|us| Coalesce { delay: Duration::from_micros(us), bytes: opt.coalesce_bytes });
+    epserver.overflow = opt.overflow_policy;
+    epserver.batch = !opt.no_batch;
+    epserver.delivery = opt.delivery;
+    epserver.slow_consumers = opt.slow_consumer_ms
+        .map(|ms| SlowConsumers { threshold: Duration::from_millis(ms), policy: opt.slow_consumer_policy });
+    epserver.message_budget = opt.max_messages_per_event.map(|n| n.max(1));
+    epserver.sanitizer = Sanitizer { utf8: opt.require_utf8, strip_control: opt.strip_control };
+    epserver.presence = !opt.no_presence;
+    if let Some(ms) = opt.probe_interval_ms {
+        epserver.probe_every(Duration::from_millis(ms.max(1)));
+    }
+    epserver.drain_timeout = Duration::from_secs(opt.drain_timeout);
+    epserver.ack_timeout = Duration::from_secs(opt.ack_timeout.max(1));
+    epserver.dump_path = opt.dump_file.clone();
+    epserver.snapshot_path = opt.snapshot_file.clone();
+    epserver.tick = Duration::from_millis(opt.tick_ms.max(1));
+    epserver.greylist = Greylist::new(opt.greylist_reconnects, Duration::from_secs(opt.greylist_secs));
+    if let Some(rate) = opt.max_accept_rate {
+        epserver.throttle_accepts(rate);
+    }
+    epserver.overload = OverloadMonitor::new(Duration::from_millis(opt.overload_lag_ms), opt.overload_queue_bytes);
+    epserver.filters = FilterChain::from_config(config)?;
+    epserver.rules = Rules::from_config(config)?;
+    epserver.nicks = Nicks::from_config(config)?;
+    epserver.nicks.guests = opt.guest_nicks;
+    epserver.banners = Banners::from_config(config)?;
+    epserver.hooks = Hooks::from_config(config)?;
+    epserver.webhooks = Webhooks::from_config(config)?;
+    let dialers = Dialers::from_config(config)?;
+    if first && !simulated {
+        epserver.dialers = dialers;
+        epserver.sources = Sources::from_config(config)?;
+        for spec in &opt.serial {
+            epserver.sources.add(Device::serial(spec)?)?;
+        }
+        for path in &opt.fifo {
+            epserver.sources.add(Device::Fifo(path.clone()))?;
+        }
+        for path in &opt.tail {
+            epserver.sources.add(Device::Tail(path.clone()))?;
+        }
+    }
+    epserver.room_policies = RoomPolicy::from_config(config)?;
+    epserver.room_stats = RoomStats::new(opt.room_metrics);
+    epserver.dedup = Dedup::from_config(config)?.map(RefCell::new);
+    if let Some(group) = opt.multicast_group {
+        epserver.multicast = Some(Multicast::new(group, opt.multicast_ttl)?);
+    }
+    for url in &opt.sink {
+        epserver.add_sink(Sink::open(url)?)?;
+    }
+    if let Some(endpoint) = &opt.otlp_endpoint {
+        epserver.tracer = Some(Tracer::new(endpoint, opt.trace_sample)?);
+    }
+    if let Some(spec) = &opt.dead_letter {
+        let dead = DeadLetters::open(spec).map_err(|e| Error::new(e.kind(), format!("cannot open dead letters {} -- {}", spec, e)))?;
+        epserver.dead_letters = Some(dead);
+    }
+    if let Some(path) = &opt.script {
+        epserver.scripts = Some(ScriptHooks::load(path.clone())?);
+    }
+    if let Some(path) = &opt.plugin {
+        epserver.plugin = Some(Plugin::load(path.clone())?);
+    }
+    if let Some(path) = &opt.ban_file {
+        epserver.bans = BanList::load(path.clone())?;
+    }
+    if let Some(path) = &opt.restore {
+        let restored = snapshot::restore(epserver, path)
+            .map_err(|e| Error::new(e.kind(), format!("cannot restore {} -- {}", path.display(), e)))?;
+        if first {
+            println!("restored {} entries from {}", restored, path.display());
         }
-    } else {
-        if let Err(e) = handle_client(event.u64 as i32, clients) {
-            if e.kind() != ErrorKind::InvalidInput {
-                remove_client(epserver.epfd, event.u64 as i32, clients)
-            }
+    }
+    if let Some(path) = &opt.preload {
+        let loaded = epserver.history.borrow_mut().preload(path)
+            .map_err(|e| Error::new(e.kind(), format!("cannot preload {} -- {}", path.display(), e)))?;
+        if first {
+            println!("preloaded {} messages from {}", loaded, path.display());
         }
     }
+    Ok(())
 }
 
-fn await_clients(mut epserver: EpollServer) {
-    let events = epserver.events.as_mut_ptr();
-    let mut clients: HashMap<i32, RefCell<ClientState>> = HashMap::new();
-
-    loop {
-        let ready = unsafe { libc::epoll_wait(epserver.epfd, events, MAX_EVENTS, -1) };
-        if ready < 0 {
-            eprintln!("epoll_wait error: {}", Error::last_os_error());
-            match Error::last_os_error().kind() {
-                ErrorKind::Interrupted => continue,
-                _ => break,
-            }
-        }
+/// Pins loop number id to its cores and, on machines with more than one memory
+/// node, has it allocate from the node of those cores.
+///
+/// Returns that node, None if the cores span several.
+fn place(cpus: &CpuSets, id: usize) -> Result<Option<usize>> {
+    cpus.pin(id)?;
+    let node = numa::node_of_all(cpus.of(id)).filter(|_| numa::nodes() > 1);
+    if node.is_some() {
+        numa::allocate_locally()?;
+    }
+    Ok(node)
+}
 
-        for i in 0..ready as isize {
-            if let Some(event) = unsafe { events.offset(i).as_ref() } {
-                handle_event(event, &epserver, &mut clients);
-            }
+/// Runs one of the --workers loops past the first, once go says the server is
+/// ready. A worker that fails to poll takes the whole server down.
+fn worker(opt: &Opt, config: &Config, listeners: Vec<(TcpListener, Policy)>, mut shard: Shard, ready: Sender<Result<()>>, go: Receiver<()>) {
+    let id = shard.id;
+    // before the server allocates anything
+    let placed = opt.pin_cpus.as_ref().map_or(Ok(None), |cpus| place(cpus, id));
+    let built = placed.and_then(|node| {
+        shard.node = node;
+        let mut epserver = open(opt, config, listeners)?;
+        epserver.join_shards(shard)?;
+        configure(&mut epserver, opt, config, false)?;
+        Ok(epserver)
+    });
+    let epserver = match built {
+        Ok(epserver) => epserver,
+        Err(e) => {
+            let _ = ready.send(Err(Error::new(e.kind(), format!("worker {} -- {}", id, e))));
+            return;
         }
+    };
+    let _ = ready.send(Ok(()));
+    if go.recv().is_err() {
+        return;
+    }
+    if let Err(e) = await_clients(epserver) {
+        logging::log(LogLevel::Error, format_args!("worker {} stopped -- {}", id, e));
+        std::process::exit(1);
     }
 }
 
-fn main() -> Result<()> {
-    let opt = Opt::from_args();
-    let addr = format!("localhost:{}", opt.port);
-    let listener = TcpListener::bind(addr)?;
-    let epserver = EpollServer::new(listener, MAX_EVENTS as usize)?;
-    println!("epoll server listening on port {}...\n", opt.port);
-    await_clients(epserver);
-
-    Err(Error::last_os_error())
-}
\ No newline at end of file
+/// Binds every --bind address and [listener.*] section, or localhost:port
+/// without any.
+fn bind(opt: &Opt, config: &Config) -> Result<Vec<(TcpListener, Policy)>> {
+    let default = Policy { sniff: opt.sniff || opt.proxy_protocol, proxy_protocol: opt.proxy_protocol, ..Policy::default() };
+    let mut wanted: Vec<(String, Policy)> = opt.bind.iter().map(|addr| (addr.clone(), default.clone())).collect();
+    for listener in ListenerConfig::from_config(config)? {
+        wanted.push((listener.bind, listener.policy));
+    }
+    if wanted.is_empty() {
+        return Ok(vec![(TcpListener::bind(format!("localhost:{}", opt.port))?, default)]);
+    }
+    wanted.into_iter().map(|(addr, policy)| {
+        let resolved = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} does not resolve", addr)))?;
+        let listener = sys::listen(resolved).map_err(|e| Error::new(e.kind(), format!("cannot listen on {} -- {}", addr, e)))?;
+        Ok((listener, policy))
+    }).collect()
+}
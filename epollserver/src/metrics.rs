@@ -0,0 +1,331 @@
+#[cfg(feature = "metrics-http")]
+use std::collections::HashMap;
+use std::fmt::Write as _;
+#[cfg(feature = "metrics-http")]
+use std::io::{ErrorKind, Read, Write};
+use std::io::Result;
+#[cfg(feature = "metrics-http")]
+use std::net::{TcpListener, TcpStream};
+#[cfg(feature = "metrics-http")]
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{busypoll, deadletter, dedup, dial, greylist, handoff, throttle, hooks, mirror, multicast, overload, sink, slow, sniff, source, subscriptions, trace, webhooks};
+use crate::sys::Sys;
+
+/// Number of power-of-two buckets, the last upper bound is 2^(HISTOGRAM_BUCKETS - 1).
+const HISTOGRAM_BUCKETS: usize = 17;
+#[cfg(feature = "metrics-http")]
+const MAX_REQUEST_SIZE: usize = 4096;
+
+pub static TOTAL_BYTES_SENT: AtomicUsize = AtomicUsize::new(0);
+pub static WAIT_INTERRUPTED: AtomicUsize = AtomicUsize::new(0);
+pub static WAIT_ERRORS: AtomicUsize = AtomicUsize::new(0);
+pub static INBOUND_MESSAGE_BYTES: Histogram = Histogram::new();
+pub static OUTBOUND_MESSAGE_BYTES: Histogram = Histogram::new();
+pub static QUEUE_RESIDENCE_MS: Histogram = Histogram::new();
+
+/// Histogram with exponentially growing buckets, bucket i counts values <= 2^i.
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        // smallest i such that value <= 2^i, values past the last bound only land in +Inf
+        let i = if value <= 1 { 0 } else { (64 - (value - 1).leading_zeros()) as usize };
+        if i < HISTOGRAM_BUCKETS {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+    }
+
+    /// Appends the histogram in prometheus text format, buckets are cumulative.
+    pub fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, 1u64 << i, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Renders every metric the server tracks in prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    render_value(&mut out, "epollbroadcast_sent_bytes_total", "counter",
+        "Bytes written to clients by broadcasts.", TOTAL_BYTES_SENT.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_wait_interrupted_total", "counter",
+        "Waits for events a signal cut short.", WAIT_INTERRUPTED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_wait_errors_total", "counter",
+        "Waits for events that failed for any other reason, each one stops the server.", WAIT_ERRORS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_degraded", "gauge",
+        "Whether non-essential features are disabled due to overload.", overload::degraded() as usize);
+    render_value(&mut out, "epollbroadcast_degraded_transitions_total", "counter",
+        "Times the server entered or left degraded mode.", overload::TRANSITIONS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_queued_bytes", "gauge",
+        "Bytes waiting in client send queues at the last sample.", overload::QUEUED_BYTES.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_duplicates_dropped_total", "counter",
+        "Messages dropped as duplicates of one seen within the dedup window.", dedup::DUPLICATES_DROPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_multicast_dropped_total", "counter",
+        "Multicast datagrams the socket refused.", multicast::DATAGRAMS_DROPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_sink_dropped_total", "counter",
+        "Messages a --sink could not forward.", sink::SINK_DROPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_slow_consumers_total", "counter",
+        "Times a client was backed up long enough to count as a slow consumer.", slow::SLOW_CONSUMERS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_redelivered_total", "counter",
+        "Messages sent again to clients attaching to a durable subscription.", subscriptions::REDELIVERED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_trace_spans_dropped_total", "counter",
+        "Trace spans, or requests of them, the otlp collector did not get.", trace::SPANS_DROPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_dead_letters_total", "counter",
+        "Dropped messages diverted to --dead-letter.", deadletter::DEAD_LETTERS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_hook_runs_total", "counter",
+        "Commands started by [hook.*] sections.", hooks::HOOK_RUNS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_hook_skipped_total", "counter",
+        "Hook matches not run for their rate, running limit or a failure to start.", hooks::HOOK_SKIPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_hook_timeouts_total", "counter",
+        "Hook commands killed for running past their timeout.", hooks::HOOK_TIMEOUTS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_webhook_sent_total", "counter",
+        "Messages a webhook endpoint took.", webhooks::WEBHOOK_SENT.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_webhook_retries_total", "counter",
+        "Webhook requests that failed and were tried again.", webhooks::WEBHOOK_RETRIES.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_webhook_dropped_total", "counter",
+        "Messages dropped after their webhook requests kept failing or the queue overflowed.", webhooks::WEBHOOK_DROPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_webhook_skipped_total", "counter",
+        "Messages not queued for webhooks while the server was overloaded.", webhooks::WEBHOOK_SKIPPED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_accept_pauses_total", "counter",
+        "Times an accept storm used up --max-accept-rate and the listeners were paused.", throttle::PAUSES.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_accept_paused_ms_total", "counter",
+        "Time listeners spent paused by --max-accept-rate.", throttle::PAUSED_MS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_accept_paused", "gauge",
+        "Event loops whose listeners are paused right now.", throttle::PAUSED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_greylisted_total", "counter",
+        "Addresses greylisted for reconnecting more than --greylist-reconnects times a minute.", greylist::GREYLISTED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_greylist_refused_total", "counter",
+        "Connections refused because their address was greylisted.", greylist::GREYLIST_REFUSED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_dials_total", "counter",
+        "Connections made to [dial.<name>] endpoints.", dial::DIALS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_dial_failures_total", "counter",
+        "Attempts to reach a [dial.<name>] endpoint that failed or timed out.", dial::DIAL_FAILURES.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_dialed", "gauge",
+        "Dialed endpoints connected right now.", dial::DIALED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_source_shed_total", "counter",
+        "Lines of sources dropped by their backpressure while the server was overloaded.", source::SHED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_sources_paused", "gauge",
+        "Sources not read from while the server is overloaded.", source::PAUSED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_mirrored_total", "counter",
+        "Messages copied into the room of the admin `mirror`.", mirror::MIRRORED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_handoffs_total", "counter",
+        "Clients passed to a helper process by a handoff rule.", handoff::HANDOFFS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_handoffs_failed_total", "counter",
+        "Handoffs whose helper could not be reached, the clients stayed.", handoff::HANDOFFS_FAILED.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_busy_poll_hits_total", "counter",
+        "Busy polls that found events before their spin budget ran out.", busypoll::SPIN_HITS.load(Ordering::Relaxed));
+    render_value(&mut out, "epollbroadcast_busy_poll_misses_total", "counter",
+        "Busy polls that spun their whole budget and went on to wait.", busypoll::SPIN_MISSES.load(Ordering::Relaxed));
+
+    INBOUND_MESSAGE_BYTES.render(
+        "epollbroadcast_inbound_message_bytes",
+        "Size of messages received from clients.",
+        &mut out,
+    );
+    OUTBOUND_MESSAGE_BYTES.render(
+        "epollbroadcast_outbound_message_bytes",
+        "Size of messages written to each recipient.",
+        &mut out,
+    );
+    QUEUE_RESIDENCE_MS.render(
+        "epollbroadcast_queue_residence_ms",
+        "How long data waited in a client outbox before it drained.",
+        &mut out,
+    );
+
+    out
+}
+
+/// Zeroes every counter and histogram rendered above, gauges keep their value.
+pub fn reset() {
+    for counter in [&TOTAL_BYTES_SENT, &WAIT_INTERRUPTED, &WAIT_ERRORS, &overload::TRANSITIONS, &dedup::DUPLICATES_DROPPED,
+        &multicast::DATAGRAMS_DROPPED, &sink::SINK_DROPPED, &slow::SLOW_CONSUMERS, &deadletter::DEAD_LETTERS, &subscriptions::REDELIVERED, &trace::SPANS_DROPPED,
+        &hooks::HOOK_RUNS, &hooks::HOOK_SKIPPED, &hooks::HOOK_TIMEOUTS, &webhooks::WEBHOOK_SENT, &webhooks::WEBHOOK_RETRIES,
+        &webhooks::WEBHOOK_DROPPED, &webhooks::WEBHOOK_SKIPPED, &sniff::SNIFFED_TLS, &sniff::SNIFFED_HTTP, &sniff::SNIFFED_PROXY, &busypoll::SPIN_HITS,
+        &busypoll::SPIN_MISSES, &handoff::HANDOFFS, &handoff::HANDOFFS_FAILED,
+        &throttle::PAUSES, &throttle::PAUSED_MS, &greylist::GREYLISTED, &greylist::GREYLIST_REFUSED,
+        &dial::DIALS, &dial::DIAL_FAILURES, &source::SHED, &mirror::MIRRORED] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    INBOUND_MESSAGE_BYTES.reset();
+    OUTBOUND_MESSAGE_BYTES.reset();
+    QUEUE_RESIDENCE_MS.reset();
+}
+
+/// Appends a single counter or gauge in prometheus text format.
+fn render_value(out: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Minimal http endpoint serving `render()` to any GET request, one response per connection.
+#[cfg(feature = "metrics-http")]
+pub struct MetricsEndpoint {
+    listener: TcpListener,
+    conns: HashMap<i32, Scrape>,
+}
+
+/// A scrape connection, what came of its request and, once it is answered, the
+/// part of the response the socket did not take yet.
+#[cfg(feature = "metrics-http")]
+struct Scrape {
+    stream: TcpStream,
+    request: Vec<u8>,
+    unsent: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "metrics-http")]
+impl MetricsEndpoint {
+    pub fn bind(port: u16) -> Result<MetricsEndpoint> {
+        let listener = TcpListener::bind(format!("localhost:{}", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(MetricsEndpoint { listener, conns: HashMap::new() })
+    }
+
+    pub fn listener_fd(&self) -> i32 {
+        self.listener.as_raw_fd()
+    }
+
+    pub fn owns(&self, fd: i32) -> bool {
+        fd == self.listener_fd() || self.conns.contains_key(&fd)
+    }
+
+    /// Handles readiness on the listener or one of the scrape connections, extra
+    /// renders metrics owned by other parts of the server.
+    pub fn handle_event(&mut self, sys: &dyn Sys, fd: i32, extra: impl FnOnce() -> String) {
+        if fd == self.listener_fd() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                let cfd = stream.as_raw_fd();
+                if stream.set_nonblocking(true).is_ok() && sys.watch(cfd).is_ok() {
+                    self.conns.insert(cfd, Scrape { stream, request: Vec::new(), unsent: None });
+                }
+            }
+            return;
+        }
+
+        let Some(scrape) = self.conns.get_mut(&fd) else { return };
+        let done = match scrape.unsent {
+            Some(_) => write_response(scrape),
+            None => serve_request(scrape, extra),
+        };
+
+        if done {
+            sys.unwatch(fd);
+            self.conns.remove(&fd);
+        } else if scrape.unsent.is_some() {
+            // the rest goes out as the socket becomes writable
+            let _ = sys.set_interest(fd, false, true);
+        }
+    }
+}
+
+/// Reads what is available of the request and answers once the headers are complete.
+///
+/// Returns true when the connection should be closed.
+#[cfg(feature = "metrics-http")]
+fn serve_request(scrape: &mut Scrape, extra: impl FnOnce() -> String) -> bool {
+    let req = &mut scrape.request;
+    let mut buf = [0; 512];
+    match scrape.stream.read(&mut buf) {
+        Ok(0) => return true,
+        Ok(n) => req.extend_from_slice(&buf[..n]),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+        Err(_) => return true,
+    }
+
+    if !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        return req.len() > MAX_REQUEST_SIZE;
+    }
+
+    let response = if req.starts_with(b"GET ") {
+        let mut body = render();
+        body.push_str(&extra());
+        format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.0 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_string()
+    };
+    scrape.unsent = Some(response.into_bytes());
+    write_response(scrape)
+}
+
+/// Writes as much of the response as the socket takes.
+///
+/// Returns true once all of it is written or the connection failed.
+#[cfg(feature = "metrics-http")]
+fn write_response(scrape: &mut Scrape) -> bool {
+    let Some(unsent) = scrape.unsent.as_mut() else { return true };
+    while !unsent.is_empty() {
+        match scrape.stream.write(unsent) {
+            Ok(0) => return true,
+            Ok(n) => {
+                unsent.drain(..n);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+            Err(_) => return true,
+        }
+    }
+    true
+}
+
+/// Stand-in when built without the `metrics-http` feature, `--metrics-port` then
+/// fails at startup. The counters themselves still show up in state dumps.
+#[cfg(not(feature = "metrics-http"))]
+pub struct MetricsEndpoint;
+
+#[cfg(not(feature = "metrics-http"))]
+impl MetricsEndpoint {
+    pub fn bind(port: u16) -> Result<MetricsEndpoint> {
+        let errmsg = format!("cannot serve metrics on port {}, built without the metrics-http feature", port);
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, errmsg))
+    }
+
+    pub fn listener_fd(&self) -> i32 {
+        -1
+    }
+
+    pub fn owns(&self, _fd: i32) -> bool {
+        false
+    }
+
+    pub fn handle_event(&mut self, _sys: &dyn Sys, _fd: i32, _extra: impl FnOnce() -> String) {}
+}
@@ -0,0 +1,80 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{send, ClientState, EpollServer, Origin};
+
+pub static MIRRORED: AtomicUsize = AtomicUsize::new(0);
+
+/// Copies a sample of all traffic into a room, to see what goes through without
+/// subscribing to everything. Toggled with the admin `mirror`. Every copy says
+/// who sent the message and what metadata they have:
+///
+/// ```text
+/// #debug fd=5 peer=10.0.0.5:41000 tenant=- bytes=14 source=gps the message
+/// ```
+///
+/// Like dead letters, copies go straight to the rooms members after each wakeup
+/// and are not mirrored again. None are made while the server is overloaded.
+pub struct Mirror {
+    room: String,
+    percent: f64,
+    credit: Cell<f64>, // percent added up per message, one is copied per 100
+    pending: RefCell<Vec<(Option<usize>, Vec<u8>)>>, // tenant, annotated line
+}
+
+impl Mirror {
+    /// Mirrors percent of the messages into room, a `#name` in every tenant.
+    pub fn new(room: &str, percent: f64) -> Mirror {
+        Mirror { room: room.to_string(), percent, credit: Cell::new(0.0), pending: RefCell::new(Vec::new()) }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("mirroring {}% into {}", self.percent, self.room)
+    }
+
+    /// Copies the lines of message that fall into the sample.
+    pub(crate) fn offer(&self, origin: &Origin, message: &[u8], epserver: &EpollServer) {
+        for line in message.split_inclusive(|&b| b == b'\n') {
+            if line.starts_with(self.room.as_bytes()) && line.get(self.room.len()) == Some(&b' ') {
+                continue; // already in the room
+            }
+            let credit = self.credit.get() + self.percent;
+            if credit < 100.0 {
+                self.credit.set(credit);
+                continue;
+            }
+            self.credit.set(credit - 100.0);
+            MIRRORED.fetch_add(1, Ordering::Relaxed);
+            let peer = origin.peer.map_or_else(|| "-".to_string(), |p| p.to_string());
+            let tenant = origin.tenant.map_or("-", |t| epserver.tenants[t].name.as_str());
+            let text = line.strip_suffix(b"\n").unwrap_or(line);
+            let mut annotated = format!("{} fd={} peer={} tenant={} bytes={}", self.room, origin.fd, peer, tenant, text.len()).into_bytes();
+            for (key, value) in origin.meta {
+                annotated.extend_from_slice(format!(" {}={}", key, value).as_bytes());
+            }
+            annotated.push(b' ');
+            annotated.extend_from_slice(text);
+            annotated.push(b'\n');
+            self.pending.borrow_mut().push((origin.tenant, annotated));
+        }
+    }
+}
+
+/// Writes out what was copied since the last call.
+pub(crate) fn flush(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    let Some(mirror) = &epserver.mirror else {
+        return;
+    };
+    for (tenant, line) in mirror.pending.take() {
+        let members = match epserver.rooms_for(tenant).borrow().get(&mirror.room) {
+            Some(room) => room.members.iter().copied().collect::<Vec<i32>>(),
+            None => continue,
+        };
+        for fd in members {
+            if let Some(client) = clients.get(&fd) {
+                send(epserver, &mut client.borrow_mut(), &line);
+            }
+        }
+    }
+}
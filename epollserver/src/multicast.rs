@@ -0,0 +1,39 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub static DATAGRAMS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Also sends every message that goes to everyone (room messages don't) as one UDP
+/// datagram to a multicast group, so LAN consumers can listen without connecting.
+/// Datagrams the socket can't take right away are dropped, never queued.
+pub struct Multicast {
+    socket: UdpSocket,
+}
+
+impl Multicast {
+    /// Sends to group, an address like `239.1.2.3:9091`, reaching ttl hops.
+    pub fn new(group: SocketAddr, ttl: u32) -> Result<Multicast> {
+        if !group.ip().is_multicast() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a multicast address", group.ip())));
+        }
+        let socket = match group {
+            SocketAddr::V4(_) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.set_multicast_ttl_v4(ttl)?;
+                socket
+            }
+            SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+        };
+        socket.set_nonblocking(true)?;
+        socket.connect(group)?;
+        Ok(Multicast { socket })
+    }
+
+    /// Sends a single message (without its newline).
+    pub fn send(&self, message: &[u8]) {
+        if self.socket.send(message).is_err() {
+            DATAGRAMS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
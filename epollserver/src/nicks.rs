@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::Config;
+
+/// Counts up across every `--workers` loop, so no two guests share a name.
+static NEXT_GUEST: AtomicU64 = AtomicU64::new(1);
+
+/// Nicks starting with this are handed out by the server only.
+pub const GUEST_PREFIX: &str = "guest-";
+
+/// Who may go by which name, so the nick in front of a message says who sent
+/// it. A nick is only ever held by one client of a tenant, not counting case.
+/// Nicks may be reserved for whoever knows a token, with one `[nick.<name>]`
+/// section each:
+///
+/// ```text
+/// [nick.alice]
+/// token = alice-s3cret   # then `/nick alice alice-s3cret`
+/// ```
+#[derive(Default)]
+pub struct Nicks {
+    reserved: HashMap<String, String>, // lowercased nick -> token
+    /// Name clients `guest-<n>` right away instead of `client <fd>`, see
+    /// `--guest-nicks`.
+    pub guests: bool,
+}
+
+impl Nicks {
+    pub fn from_config(config: &Config) -> Result<Nicks> {
+        let mut reserved = HashMap::new();
+        for (name, section) in config.sections_with_prefix("nick") {
+            let token = section.get("token").ok_or_else(|| config.error(section.line, "reserved nick is missing `token`"))?;
+            reserved.insert(name.to_lowercase(), token.value.clone());
+        }
+        Ok(Nicks { reserved, guests: false })
+    }
+
+    /// Why nick can't be taken with token, None if it can. taken says whether
+    /// another client of the tenant goes by a name.
+    pub fn refusal(&self, nick: &str, token: Option<&str>, taken: impl Fn(&str) -> bool) -> Option<String> {
+        if nick.to_lowercase().starts_with(GUEST_PREFIX) {
+            return Some(format!("{}<n> nicks are given out by the server", GUEST_PREFIX));
+        }
+        match self.reserved.get(&nick.to_lowercase()) {
+            Some(expected) if token != Some(expected.as_str()) => return Some(format!("{} is reserved, /nick {} <token>", nick, nick)),
+            None if token.is_some() => return Some(format!("{} is not reserved, leave out the token", nick)),
+            _ => {}
+        }
+        taken(nick).then(|| format!("{} is taken", nick))
+    }
+
+    /// A name for a new client, if guests are named.
+    pub fn guest(&self) -> Option<String> {
+        self.guests.then(|| format!("{}{}", GUEST_PREFIX, NEXT_GUEST.fetch_add(1, Ordering::Relaxed)))
+    }
+}
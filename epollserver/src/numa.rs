@@ -0,0 +1,42 @@
+use std::fs;
+use std::io::{Error, Result};
+
+/// Allocate from the node of the cpu running the thread, see set_mempolicy(2).
+const MPOL_LOCAL: libc::c_int = 4;
+
+/// How many memory nodes the machine has, 1 if it can't tell.
+pub fn nodes() -> usize {
+    let count = fs::read_dir("/sys/devices/system/node")
+        .map(|dir| dir.flatten().filter(|e| is_numbered(&e.file_name().to_string_lossy(), "node")).count())
+        .unwrap_or(0);
+    count.max(1)
+}
+
+/// The memory node cpu belongs to, if the kernel says.
+pub fn node_of(cpu: usize) -> Option<usize> {
+    let dir = fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu)).ok()?;
+    dir.flatten().find_map(|e| e.file_name().to_string_lossy().strip_prefix("node")?.parse().ok())
+}
+
+/// The node all of cpus are on, None if they span nodes.
+pub fn node_of_all(cpus: &[usize]) -> Option<usize> {
+    let node = node_of(*cpus.first()?)?;
+    cpus.iter().all(|cpu| node_of(*cpu) == Some(node)).then_some(node)
+}
+
+/// Makes the calling thread allocate from the node it runs on, even when the
+/// process was started with an interleaving policy. Together with pinning, what
+/// an event loop allocates from then on, client buffers and outboxes included,
+/// sits next to the cores using it.
+pub fn allocate_locally() -> Result<()> {
+    let rc = unsafe { libc::syscall(libc::SYS_set_mempolicy, MPOL_LOCAL, std::ptr::null::<libc::c_ulong>(), 0) };
+    if rc < 0 {
+        let e = Error::last_os_error();
+        return Err(Error::new(e.kind(), format!("cannot allocate node locally -- {}", e)));
+    }
+    Ok(())
+}
+
+fn is_numbered(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix).is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
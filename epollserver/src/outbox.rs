@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Result};
+
+use crate::sys::Sys;
+
+/// Queue a chunk goes to. Control chunks (acks, command replies, server notices) are
+/// written before any queued data, so a slow consumer still hears from the server
+/// promptly. Chunks are never split, so lines don't interleave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lane {
+    Control,
+    Data,
+}
+
+/// Bytes a client could not take right away, written once its socket drains.
+pub struct Outbox {
+    current: Vec<u8>, // chunk being written, finished before anything else
+    head: usize, // bytes of current already written
+    control: VecDeque<Vec<u8>>,
+    data: VecDeque<Vec<u8>>,
+    bytes: usize,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Outbox { current: Vec::new(), head: 0, control: VecDeque::new(), data: VecDeque::new(), bytes: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0
+    }
+
+    /// Bytes waiting to be written.
+    pub fn len(&self) -> usize {
+        self.bytes
+    }
+
+    /// Queues data given in parts as one chunk, leaving out the first skip bytes
+    /// that were written already. It is written first if nothing else is queued
+    /// yet, that is how the rest of a partially written chunk stays ahead of later
+    /// control chunks.
+    pub fn push_parts(&mut self, lane: Lane, parts: &[&[u8]], skip: usize) {
+        let len = parts.iter().map(|p| p.len()).sum::<usize>();
+        if len <= skip {
+            return;
+        }
+        let mut chunk = Vec::with_capacity(len - skip);
+        let mut skip = skip;
+        for part in parts {
+            let from = skip.min(part.len());
+            chunk.extend_from_slice(&part[from..]);
+            skip -= from;
+        }
+        self.bytes += chunk.len();
+        if self.head == self.current.len() && self.control.is_empty() && self.data.is_empty() {
+            self.current = chunk;
+            self.head = 0;
+            return;
+        }
+        match lane {
+            Lane::Control => self.control.push_back(chunk),
+            Lane::Data => self.data.push_back(chunk),
+        }
+    }
+
+    /// Throws away queued data chunks, keeping control chunks and the rest of the
+    /// chunk being written.
+    ///
+    /// Returns the number of bytes dropped.
+    pub fn drop_data(&mut self) -> usize {
+        let dropped: usize = self.data.drain(..).map(|c| c.len()).sum();
+        self.bytes -= dropped;
+        dropped
+    }
+
+    /// Writes as much as the socket takes. Every chunk but the last is written
+    /// with `write_more`, so a backlog of short lines leaves in full segments.
+    ///
+    /// Returns the number of bytes written.
+    pub fn flush(&mut self, sys: &dyn Sys, fd: i32) -> Result<usize> {
+        let mut written = 0;
+        loop {
+            if self.head == self.current.len() {
+                match self.control.pop_front().or_else(|| self.data.pop_front()) {
+                    Some(chunk) => {
+                        self.current = chunk;
+                        self.head = 0;
+                    }
+                    None => break,
+                }
+            }
+            let more = !self.control.is_empty() || !self.data.is_empty();
+            let chunk = &self.current[self.head..];
+            let result = if more { sys.write_more(fd, chunk) } else { sys.write(fd, chunk) };
+            match result {
+                Ok(0) => break,
+                Ok(n) => {
+                    written += n;
+                    self.bytes -= n;
+                    self.head += n;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Outbox {
+        Outbox::new()
+    }
+}
@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::logging::warning;
+use crate::sys::Sys;
+use crate::ClientState;
+
+/// How often the kernel send queues of all clients are summed up.
+const QUEUE_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Event loops of `--workers` that are overloaded right now.
+static DEGRADED_LOOPS: AtomicUsize = AtomicUsize::new(0);
+pub static TRANSITIONS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes queued for the clients of every event loop, as of their last samples.
+pub static QUEUED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// True while the server is overloaded, in any of its event loops. Non-essential
+/// work (verbose logging and any optional feature that is expensive per message)
+/// should be skipped.
+pub fn degraded() -> bool {
+    DEGRADED_LOOPS.load(Ordering::Relaxed) > 0
+}
+
+/// Flips the server into degraded mode when the event loop lags or queued bytes
+/// pile up, and back once both drop under half their thresholds. With several
+/// `--workers` each loop has a monitor of its own: lag is that of its loop, the
+/// queued bytes are those of all loops together, and the server stays degraded
+/// while any of them is.
+pub struct OverloadMonitor {
+    max_lag: Duration,
+    max_queued: usize,
+    last_sample: Instant,
+    queued: usize, // this loops share of QUEUED_BYTES
+    degraded: bool,
+}
+
+impl OverloadMonitor {
+    pub fn new(max_lag: Duration, max_queued: usize) -> OverloadMonitor {
+        OverloadMonitor { max_lag, max_queued, last_sample: Instant::now(), queued: 0, degraded: false }
+    }
+
+    /// Called after every batch of events with the time it took to handle it.
+    pub fn update(&mut self, lag: Duration, sys: &dyn Sys, clients: &HashMap<i32, RefCell<ClientState>>) {
+        let now = sys.now();
+        if now.duration_since(self.last_sample) >= QUEUE_SAMPLE_INTERVAL {
+            self.last_sample = now;
+            let queued = clients.keys().map(|cfd| sys.queued_bytes(*cfd)).sum();
+            self.set_queued(queued);
+        }
+        let queued = QUEUED_BYTES.load(Ordering::Relaxed);
+
+        if !self.degraded && (lag > self.max_lag || queued > self.max_queued) {
+            self.degraded = true;
+            if DEGRADED_LOOPS.fetch_add(1, Ordering::Relaxed) == 0 {
+                TRANSITIONS.fetch_add(1, Ordering::Relaxed);
+                warning!(
+                    "overloaded (loop lag {:?}, {} bytes queued), disabling non-essential features",
+                    lag, queued
+                );
+            }
+        } else if self.degraded && lag <= self.max_lag / 2 && queued <= self.max_queued / 2 {
+            self.degraded = false;
+            if DEGRADED_LOOPS.fetch_sub(1, Ordering::Relaxed) == 1 {
+                TRANSITIONS.fetch_add(1, Ordering::Relaxed);
+                warning!("load subsided (loop lag {:?}, {} bytes queued), leaving degraded mode", lag, queued);
+            }
+        }
+    }
+
+    fn set_queued(&mut self, queued: usize) {
+        if queued >= self.queued {
+            QUEUED_BYTES.fetch_add(queued - self.queued, Ordering::Relaxed);
+        } else {
+            QUEUED_BYTES.fetch_sub(self.queued - queued, Ordering::Relaxed);
+        }
+        self.queued = queued;
+    }
+}
+
+impl Drop for OverloadMonitor {
+    fn drop(&mut self) {
+        self.set_queued(0);
+        if self.degraded {
+            DEGRADED_LOOPS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
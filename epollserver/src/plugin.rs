@@ -0,0 +1,239 @@
+use std::io::{Error, Result};
+use std::path::PathBuf;
+#[cfg(feature = "wasm")]
+use std::{cell::RefCell, fs, io::Write, time::SystemTime};
+
+#[cfg(feature = "wasm")]
+use wasmi::{Caller, Engine, Extern, ExternType, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc, Val, ValType};
+
+#[cfg(feature = "wasm")]
+use crate::logging::warning;
+
+/// Instructions a plugin may execute per message before it is aborted.
+#[cfg(feature = "wasm")]
+const FUEL_PER_MESSAGE: u64 = 10_000_000;
+#[cfg(feature = "wasm")]
+const MAX_PLUGIN_MEMORY: usize = 16 << 20;
+#[cfg(feature = "wasm")]
+const WASI: &str = "wasi_snapshot_preview1";
+#[cfg(feature = "wasm")]
+const ERRNO_SUCCESS: i32 = 0;
+#[cfg(feature = "wasm")]
+const ERRNO_BADF: i32 = 8;
+#[cfg(feature = "wasm")]
+const ERRNO_FAULT: i32 = 21;
+#[cfg(feature = "wasm")]
+const ERRNO_NOSYS: i32 = 52;
+
+/// Sandboxed message transformation loaded with `--plugin foo.wasm`.
+///
+/// A plugin is a wasm32 module (plain or built for wasm32-wasip1) exporting:
+///
+/// - `memory`
+/// - `alloc(len: i32) -> i32`, returning space for the host to copy a message into
+/// - `process_message(ptr: i32, len: i32) -> i64`, returning 0 to pass the message
+///   on unchanged, a negative value to drop it, or `(ptr << 32) | len` of a
+///   replacement message in the plugins memory
+/// - optionally `dealloc(ptr: i32, len: i32)`, called once the host copied a message in
+///
+/// WASI is provided with no capabilities: no files, sockets, args or environment.
+/// Writes to stdout/stderr end up on the servers stderr, clocks and randomness work,
+/// everything else fails with ENOSYS. Each call has a fuel budget and memory is capped,
+/// a plugin that traps or runs out of fuel passes the message on unchanged.
+#[cfg(feature = "wasm")]
+pub struct Plugin {
+    path: PathBuf,
+    store: RefCell<Store<StoreLimits>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+    process: TypedFunc<(i32, i32), i64>,
+}
+
+#[cfg(feature = "wasm")]
+impl Plugin {
+    pub fn load(path: PathBuf) -> Result<Plugin> {
+        let plugin_error = |e: &dyn std::fmt::Display| Error::other(format!("{}: {}", path.display(), e));
+
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, fs::read(&path)?).map_err(|e| plugin_error(&e))?;
+
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_PLUGIN_MEMORY).instances(1).build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL_PER_MESSAGE).map_err(|e| plugin_error(&e))?;
+
+        let linker = wasi_linker(&engine, &module).map_err(|e| plugin_error(&e))?;
+        let instance = linker.instantiate_and_start(&mut store, &module).map_err(|e| plugin_error(&e))?;
+
+        let memory = instance.get_memory(&store, "memory")
+            .ok_or_else(|| plugin_error(&"missing `memory` export"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc").map_err(|e| plugin_error(&e))?;
+        let process = instance.get_typed_func::<(i32, i32), i64>(&store, "process_message")
+            .map_err(|e| plugin_error(&e))?;
+        let dealloc = instance.get_typed_func::<(i32, i32), ()>(&store, "dealloc").ok();
+
+        Ok(Plugin { path, store: RefCell::new(store), memory, alloc, dealloc, process })
+    }
+
+    /// Runs a single message (without its newline) through the plugin.
+    ///
+    /// Returns None if the plugin dropped the message.
+    pub fn process_message(&self, message: &[u8]) -> Option<Vec<u8>> {
+        match self.call(message) {
+            Ok(result) => result,
+            Err(e) => {
+                warning!("plugin {} failed: {}", self.path.display(), e);
+                Some(message.to_vec())
+            }
+        }
+    }
+
+    fn call(&self, message: &[u8]) -> std::result::Result<Option<Vec<u8>>, wasmi::Error> {
+        let mut store = self.store.borrow_mut();
+        store.set_fuel(FUEL_PER_MESSAGE)?;
+
+        let len = message.len() as i32;
+        let ptr = self.alloc.call(&mut *store, len)?;
+        self.memory.write(&mut *store, ptr as u32 as usize, message)?;
+        let action = self.process.call(&mut *store, (ptr, len))?;
+
+        let result = match action {
+            0 => Some(message.to_vec()),
+            a if a < 0 => None,
+            a => {
+                // the plugin could claim any length, only what is in its memory is copied
+                let (start, len) = ((a as u64 >> 32) as usize, (a as u64 & 0xffff_ffff) as usize);
+                let out = self.memory.data(&*store).get(start..start.saturating_add(len))
+                    .ok_or_else(|| wasmi::Error::new("returned a message outside its memory"))?;
+                Some(out.to_vec())
+            }
+        };
+
+        if let Some(dealloc) = &self.dealloc {
+            dealloc.call(&mut *store, (ptr, len))?;
+        }
+        Ok(result)
+    }
+}
+
+/// A linker providing the capability-free WASI described on `Plugin`.
+#[cfg(feature = "wasm")]
+fn wasi_linker(engine: &Engine, module: &Module) -> std::result::Result<Linker<StoreLimits>, wasmi::Error> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(WASI, "fd_write", |mut caller: Caller<'_, StoreLimits>, fd: i32, iovs: i32, iovs_len: i32, nwritten: i32| -> i32 {
+        if fd != 1 && fd != 2 {
+            return ERRNO_BADF;
+        }
+        let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+            Some(memory) => memory,
+            None => return ERRNO_FAULT,
+        };
+
+        let mut written = 0u32;
+        for i in 0..iovs_len.max(0) as usize {
+            let mut iov = [0; 8];
+            if memory.read(&caller, iovs as u32 as usize + i * 8, &mut iov).is_err() {
+                return ERRNO_FAULT;
+            }
+            let base = u32::from_le_bytes([iov[0], iov[1], iov[2], iov[3]]) as usize;
+            let len = u32::from_le_bytes([iov[4], iov[5], iov[6], iov[7]]) as usize;
+            let Some(data) = memory.data(&caller).get(base..base.saturating_add(len)) else {
+                return ERRNO_FAULT;
+            };
+            let _ = std::io::stderr().write_all(data);
+            written += data.len() as u32;
+        }
+
+        match memory.write(&mut caller, nwritten as u32 as usize, &written.to_le_bytes()) {
+            Ok(_) => ERRNO_SUCCESS,
+            Err(_) => ERRNO_FAULT,
+        }
+    })?;
+
+    linker.func_wrap(WASI, "proc_exit", |code: i32| -> std::result::Result<(), wasmi::Error> {
+        Err(wasmi::Error::i32_exit(code))
+    })?;
+
+    // no arguments and no environment: report zero entries of zero bytes
+    for sizes_get in ["args_sizes_get", "environ_sizes_get"] {
+        linker.func_wrap(WASI, sizes_get, |mut caller: Caller<'_, StoreLimits>, count: i32, size: i32| -> i32 {
+            let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            let zeroes = memory.write(&mut caller, count as u32 as usize, &[0; 4])
+                .and_then(|_| memory.write(&mut caller, size as u32 as usize, &[0; 4]));
+            if zeroes.is_ok() { ERRNO_SUCCESS } else { ERRNO_FAULT }
+        })?;
+    }
+    for get in ["args_get", "environ_get"] {
+        linker.func_wrap(WASI, get, |_: i32, _: i32| -> i32 { ERRNO_SUCCESS })?;
+    }
+
+    linker.func_wrap(WASI, "clock_time_get", |mut caller: Caller<'_, StoreLimits>, _id: i32, _precision: i64, time: i32| -> i32 {
+        let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+            Some(memory) => memory,
+            None => return ERRNO_FAULT,
+        };
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        match memory.write(&mut caller, time as u32 as usize, &nanos.to_le_bytes()) {
+            Ok(_) => ERRNO_SUCCESS,
+            Err(_) => ERRNO_FAULT,
+        }
+    })?;
+
+    linker.func_wrap(WASI, "random_get", |mut caller: Caller<'_, StoreLimits>, buf: i32, len: i32| -> i32 {
+        let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+            Some(memory) => memory,
+            None => return ERRNO_FAULT,
+        };
+        let mut bytes = vec![0u8; len.max(0) as usize];
+        unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut libc::c_void, bytes.len(), 0); }
+        match memory.write(&mut caller, buf as u32 as usize, &bytes) {
+            Ok(_) => ERRNO_SUCCESS,
+            Err(_) => ERRNO_FAULT,
+        }
+    })?;
+
+    // every other WASI call the module imports fails with ENOSYS
+    let provided = ["fd_write", "proc_exit", "args_sizes_get", "environ_sizes_get", "args_get", "environ_get", "clock_time_get", "random_get"];
+    for import in module.imports() {
+        if import.module() != WASI || provided.contains(&import.name()) {
+            continue;
+        }
+        if let ExternType::Func(ty) = import.ty() {
+            linker.func_new(WASI, import.name(), ty.clone(), |_, _, results: &mut [Val]| {
+                match results {
+                    [] => Ok(()),
+                    [result] if result.ty() == ValType::I32 => {
+                        *result = Val::I32(ERRNO_NOSYS);
+                        Ok(())
+                    }
+                    _ => Err(wasmi::Error::new("unsupported WASI call")),
+                }
+            })?;
+        }
+    }
+
+    Ok(linker)
+}
+
+/// Stand-in when built without the `wasm` feature, `--plugin` then fails at startup.
+#[cfg(not(feature = "wasm"))]
+pub struct Plugin;
+
+#[cfg(not(feature = "wasm"))]
+impl Plugin {
+    pub fn load(path: PathBuf) -> Result<Plugin> {
+        let errmsg = format!("cannot load {}, built without the wasm feature", path.display());
+        Err(Error::new(std::io::ErrorKind::Unsupported, errmsg))
+    }
+
+    pub fn process_message(&self, message: &[u8]) -> Option<Vec<u8>> {
+        Some(message.to_vec())
+    }
+}
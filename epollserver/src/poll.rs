@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::sys::{socket, Sys};
+
+/// Which readiness syscall `--backend` waits with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Epoll,
+    /// Plain poll(2), for systems without epoll or to rule it out when debugging.
+    Poll,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Backend, String> {
+        match s {
+            "epoll" => Ok(Backend::Epoll),
+            "poll" => Ok(Backend::Poll),
+            _ => Err(format!("unknown backend {:?}, expected epoll or poll", s)),
+        }
+    }
+}
+
+/// Real sockets multiplexed with poll(2). Keeps the interest set itself and
+/// hands all of it to the kernel on every wait, so it costs O(clients) per
+/// wakeup where epoll costs O(ready fds).
+#[derive(Default)]
+pub struct Poll {
+    /// Watched fds with whether they want read and write readiness.
+    interest: RefCell<BTreeMap<i32, (bool, bool)>>,
+    fds: RefCell<Vec<libc::pollfd>>,
+}
+
+impl Poll {
+    pub fn new() -> Poll {
+        Poll::default()
+    }
+}
+
+impl Sys for Poll {
+    fn wait(&self, ready: &mut Vec<i32>, timeout_ms: i32) -> Result<()> {
+        let mut fds = self.fds.borrow_mut();
+        fds.clear();
+        for (&fd, &(readable, writable)) in self.interest.borrow().iter() {
+            let mut events = 0;
+            if readable {
+                events |= libc::POLLIN;
+            }
+            if writable {
+                events |= libc::POLLOUT;
+            }
+            fds.push(libc::pollfd { fd, events, revents: 0 });
+        }
+
+        let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        ready.extend(fds.iter().filter(|p| p.revents != 0).map(|p| p.fd));
+        Ok(())
+    }
+
+    fn watch(&self, fd: i32) -> Result<()> {
+        let mut interest = self.interest.borrow_mut();
+        if interest.contains_key(&fd) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("fd {} is already watched", fd)));
+        }
+        interest.insert(fd, (true, false));
+        Ok(())
+    }
+
+    fn unwatch(&self, fd: i32) {
+        self.interest.borrow_mut().remove(&fd);
+    }
+
+    fn set_interest(&self, fd: i32, readable: bool, writable: bool) -> Result<()> {
+        match self.interest.borrow_mut().get_mut(&fd) {
+            Some(wanted) => {
+                *wanted = (readable, writable);
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::NotFound, format!("fd {} is not watched", fd))),
+        }
+    }
+
+    fn accept(&self, listener: i32) -> Result<(i32, SocketAddr)> {
+        socket::accept(listener)
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize> {
+        socket::read(fd, buf)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        socket::write(fd, buf)
+    }
+
+    fn writev(&self, fd: i32, parts: &[&[u8]]) -> Result<usize> {
+        socket::writev(fd, parts)
+    }
+
+    fn write_more(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        socket::write_more(fd, buf)
+    }
+
+    fn close(&self, fd: i32) {
+        // closing does not drop the fd from our set the way it does from epoll's
+        self.interest.borrow_mut().remove(&fd);
+        socket::close(fd)
+    }
+
+    fn peer_addr(&self, fd: i32) -> Result<SocketAddr> {
+        socket::peer_addr(fd)
+    }
+
+    fn queued_bytes(&self, fd: i32) -> usize {
+        socket::queued_bytes(fd)
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,70 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Switches to an unprivileged account once every socket is bound, optionally
+/// locking the process into a chroot first. group defaults to the users primary
+/// group. Files opened later, like the ban list, are then looked up inside the
+/// chroot and need to be writable by that account.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot: Option<&Path>) -> Result<()> {
+    // look names up before chroot hides /etc
+    let (uid, user_gid) = match user {
+        Some(name) => {
+            let (uid, gid) = lookup_user(name)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(name) => Some(lookup_group(name)?),
+        None => user_gid,
+    };
+
+    if let Some(dir) = chroot {
+        let dir = c_string(&dir.to_string_lossy())?;
+        if unsafe { libc::chroot(dir.as_ptr()) } < 0 || unsafe { libc::chdir(c"/".as_ptr()) } < 0 {
+            return Err(os_error("chroot"));
+        }
+    }
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } < 0 || unsafe { libc::setgid(gid) } < 0 {
+            return Err(os_error("setgid"));
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } < 0 {
+            return Err(os_error("setuid"));
+        }
+        // make sure there is no way back
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::other("privileges could be regained after setuid"));
+        }
+    }
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let cname = c_string(name)?;
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(Error::new(ErrorKind::NotFound, format!("no user {}", name)));
+    }
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    let cname = c_string(name)?;
+    let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        return Err(Error::new(ErrorKind::NotFound, format!("no group {}", name)));
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{:?} contains a nul byte", s)))
+}
+
+fn os_error(call: &str) -> Error {
+    Error::other(format!("{} failed -- {}", call, Error::last_os_error()))
+}
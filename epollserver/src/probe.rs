@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+/// Probes sent that a late answer still counts for.
+const OUTSTANDING: usize = 16;
+/// Latencies the quantiles are taken over.
+const SAMPLES: usize = 1024;
+
+/// Measures how long broadcasts take to reach clients: every interval the
+/// server sends `* probe <id>` down the same queue as messages to clients that
+/// asked for probes with `/probe` (or `HELLO caps=probe`), and they answer with
+/// `/pong <id>`. The time in between ends up in metrics as p50 and p99.
+pub struct Probes {
+    interval: Duration,
+    next_due: Instant,
+    next_id: u64,
+    sent: VecDeque<(u64, Instant)>,
+    samples: VecDeque<Duration>,
+}
+
+impl Probes {
+    pub fn new(interval: Duration, now: Instant) -> Probes {
+        Probes { interval, next_due: now + interval, next_id: 1, sent: VecDeque::new(), samples: VecDeque::new() }
+    }
+
+    /// When the next probe should go out.
+    pub fn next_due(&self) -> Instant {
+        self.next_due
+    }
+
+    /// The id the next probe will get.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// The id of a new probe to send, if one is due.
+    pub fn due(&mut self, now: Instant) -> Option<u64> {
+        if now < self.next_due {
+            return None;
+        }
+        self.next_due = now + self.interval;
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.sent.len() == OUTSTANDING {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((id, now));
+        Some(id)
+    }
+
+    /// Records an answer to probe id.
+    ///
+    /// Returns the latency, None for ids not sent or too long ago.
+    pub fn answer(&mut self, id: u64, now: Instant) -> Option<Duration> {
+        let (_, sent) = self.sent.iter().find(|(i, _)| *i == id)?;
+        let latency = now.duration_since(*sent);
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+        Some(latency)
+    }
+
+    /// The latency q (0 to 1) of recent answers are at most.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        let i = ((sorted.len() as f64 * q).ceil() as usize).saturating_sub(1);
+        sorted.get(i).copied()
+    }
+
+    /// p50 and p99 of recent answers in prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP epollbroadcast_probe_latency_seconds Time from sending a probe to a client until it answers.");
+        let _ = writeln!(out, "# TYPE epollbroadcast_probe_latency_seconds summary");
+        for q in [0.5, 0.99] {
+            if let Some(latency) = self.quantile(q) {
+                let _ = writeln!(out, "epollbroadcast_probe_latency_seconds{{quantile=\"{}\"}} {}", q, latency.as_secs_f64());
+            }
+        }
+        let sum: Duration = self.samples.iter().sum();
+        let _ = writeln!(out, "epollbroadcast_probe_latency_seconds_sum {}", sum.as_secs_f64());
+        let _ = writeln!(out, "epollbroadcast_probe_latency_seconds_count {}", self.samples.len());
+        out
+    }
+}
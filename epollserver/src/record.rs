@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::logging::{error, warning};
+use crate::sim::SimNet;
+use crate::sys::Sys;
+use crate::{ClientState, EpollServer};
+
+const MAGIC: &[u8; 8] = b"EPBREC01";
+
+const CONNECT: u8 = 0; // payload is the peer address
+const DATA: u8 = 1;
+const HANGUP: u8 = 2;
+
+/// Wraps a `Sys` and appends everything clients send to a session file:
+///
+/// ```text
+/// "EPBREC01" then records of
+/// micros since start: u64 | kind: u8 | fd: i32 | payload length: u32 | payload
+/// ```
+///
+/// All integers are little endian. The file is flushed whenever the server is
+/// about to wait for events.
+pub struct Recorder {
+    inner: Box<dyn Sys>,
+    start: Instant,
+    out: RefCell<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(inner: Box<dyn Sys>, path: &Path) -> Result<Recorder> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        Ok(Recorder { start: inner.now(), inner, out: RefCell::new(out) })
+    }
+
+    fn record(&self, kind: u8, fd: i32, payload: &[u8]) {
+        let micros = self.inner.now().duration_since(self.start).as_micros() as u64;
+        let mut out = self.out.borrow_mut();
+        let written = out.write_all(&micros.to_le_bytes())
+            .and_then(|_| out.write_all(&[kind]))
+            .and_then(|_| out.write_all(&fd.to_le_bytes()))
+            .and_then(|_| out.write_all(&(payload.len() as u32).to_le_bytes()))
+            .and_then(|_| out.write_all(payload));
+        if let Err(e) = written {
+            error!("failed to record traffic -- {}", e);
+        }
+    }
+}
+
+impl Sys for Recorder {
+    fn wait(&self, ready: &mut Vec<i32>, timeout_ms: i32) -> Result<()> {
+        if let Err(e) = self.out.borrow_mut().flush() {
+            error!("failed to flush recorded traffic -- {}", e);
+        }
+        self.inner.wait(ready, timeout_ms)
+    }
+
+    fn watch(&self, fd: i32) -> Result<()> {
+        self.inner.watch(fd)
+    }
+
+    fn unwatch(&self, fd: i32) {
+        self.inner.unwatch(fd)
+    }
+
+    fn set_interest(&self, fd: i32, readable: bool, writable: bool) -> Result<()> {
+        self.inner.set_interest(fd, readable, writable)
+    }
+
+    fn accept(&self, listener: i32) -> Result<(i32, SocketAddr)> {
+        let (fd, addr) = self.inner.accept(listener)?;
+        self.record(CONNECT, fd, addr.to_string().as_bytes());
+        Ok((fd, addr))
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize> {
+        let result = self.inner.read(fd, buf);
+        match &result {
+            Ok(0) => self.record(HANGUP, fd, &[]),
+            Ok(n) => self.record(DATA, fd, &buf[..*n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => self.record(HANGUP, fd, &[]),
+        }
+        result
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        self.inner.write(fd, buf)
+    }
+
+    fn write_more(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        self.inner.write_more(fd, buf)
+    }
+
+    fn writev(&self, fd: i32, parts: &[&[u8]]) -> Result<usize> {
+        self.inner.writev(fd, parts)
+    }
+
+    fn close(&self, fd: i32) {
+        self.inner.close(fd)
+    }
+
+    fn peer_addr(&self, fd: i32) -> Result<SocketAddr> {
+        self.inner.peer_addr(fd)
+    }
+
+    fn queued_bytes(&self, fd: i32) -> usize {
+        self.inner.queued_bytes(fd)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn event_fd(&self) -> Option<i32> {
+        self.inner.event_fd()
+    }
+}
+
+struct Frame {
+    at: Duration,
+    kind: u8,
+    fd: i32,
+    payload: Vec<u8>,
+}
+
+/// Reads the next frame, None at the end of the session.
+fn read_frame(input: &mut impl Read) -> Result<Option<Frame>> {
+    let mut header = [0; 17];
+    match input.read_exact(&mut header[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    input.read_exact(&mut header[1..])?;
+
+    let micros = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let fd = i32::from_le_bytes(header[9..13].try_into().unwrap());
+    let len = u32::from_le_bytes(header[13..17].try_into().unwrap());
+    let mut payload = vec![0; len as usize];
+    input.read_exact(&mut payload)?;
+
+    Ok(Some(Frame { at: Duration::from_micros(micros), kind: header[8], fd, payload }))
+}
+
+/// Feeds a recorded session through the server on a simulated network.
+///
+/// Frames are delivered `speed` times as fast as they were recorded, a speed of
+/// 0 replays without pausing. The simulated clock always follows the recording.
+pub fn replay(path: &Path, speed: f64, net: Rc<SimNet>, mut epserver: EpollServer) -> Result<()> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a recorded session", path.display())));
+    }
+
+    let mut clients: HashMap<i32, RefCell<ClientState>> = HashMap::new();
+    let mut fds: HashMap<i32, i32> = HashMap::new(); // recorded fd -> simulated fd
+    let started = Instant::now();
+    let (mut frames, mut delivered) = (0, 0);
+    let mut clock = Duration::ZERO;
+
+    while let Some(frame) = read_frame(&mut input)? {
+        if speed > 0.0 {
+            let due = frame.at.div_f64(speed);
+            if let Some(pause) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(pause);
+            }
+        }
+        net.advance(frame.at.saturating_sub(clock));
+        clock = clock.max(frame.at);
+
+        match frame.kind {
+            CONNECT => {
+                let addr = String::from_utf8_lossy(&frame.payload).parse()
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "corrupt peer address in recording"))?;
+                fds.insert(frame.fd, net.connect(addr));
+            }
+            DATA => match fds.get(&frame.fd) {
+                Some(fd) => net.send(*fd, &frame.payload),
+                None => warning!("data for unknown client {} in recording", frame.fd),
+            },
+            HANGUP => {
+                if let Some(fd) = fds.remove(&frame.fd) {
+                    net.close(fd);
+                }
+            }
+            kind => return Err(Error::new(ErrorKind::InvalidData, format!("unknown frame kind {}", kind))),
+        }
+        frames += 1;
+
+        while crate::poll_once(&mut epserver, &mut clients, 0)? > 0 {}
+        delivered += fds.values().map(|fd| net.recv(*fd).len()).sum::<usize>();
+    }
+
+    println!("replayed {} frames from {}, {} bytes delivered to clients", frames, path.display(), delivered);
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Result;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+pub struct Room {
+    pub name: String,
+    pub members: BTreeSet<i32>,
+    /// Members allowed to change the topic. Whoever joins an empty room becomes one.
+    pub ops: BTreeSet<i32>,
+    /// Retained message delivered to everyone who joins.
+    pub topic: Option<String>,
+    /// Members that joined with `Qos::Reliable`.
+    pub reliable: BTreeSet<i32>,
+    /// Created by an operator, kept until they delete it even when empty.
+    pub pinned: bool,
+    /// Invite only, only clients in invited may join.
+    pub locked: bool,
+    pub invited: BTreeSet<i32>,
+    /// Most members the room takes.
+    pub cap: Option<usize>,
+    /// Joining takes `/join <#room> <password>`.
+    pub password: Option<String>,
+    /// Nick of whoever created the room, they may always join and become an op.
+    pub owner: Option<String>,
+    /// If not empty, only these nicks may join.
+    pub allowed: BTreeSet<String>,
+    /// Latest messages, as many as its policy keeps, sent to clients joining.
+    pub recent: VecDeque<Vec<u8>>,
+}
+
+impl Room {
+    fn new(name: &str) -> Room {
+        Room {
+            name: name.to_string(),
+            members: BTreeSet::new(),
+            ops: BTreeSet::new(),
+            topic: None,
+            reliable: BTreeSet::new(),
+            pinned: false,
+            locked: false,
+            invited: BTreeSet::new(),
+            cap: None,
+            password: None,
+            owner: None,
+            allowed: BTreeSet::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Whether nothing keeps the room around anymore.
+    fn is_abandoned(&self) -> bool {
+        self.members.is_empty() && self.topic.is_none() && !self.pinned
+    }
+}
+
+/// How a room member wants messages delivered once its outbox is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Qos {
+    /// Messages are dropped, publishers never wait.
+    BestEffort,
+    /// Messages are queued anyway and publishers stop being read until the outbox
+    /// drains to half of `--max-queue-bytes`.
+    Reliable,
+}
+
+impl Qos {
+    pub fn parse(name: &str) -> Option<Qos> {
+        match name {
+            "best-effort" => Some(Qos::BestEffort),
+            "reliable" => Some(Qos::Reliable),
+            _ => None,
+        }
+    }
+}
+
+/// A number of messages per period, like `5/s`, `100/m` or `1000/h`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Rate, String> {
+        let invalid = || format!("invalid rate {:?}, expected messages per s, m or h like 5/s", s);
+        let (messages, per) = s.split_once('/').ok_or_else(invalid)?;
+        let per = match per {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            _ => return Err(invalid()),
+        };
+        match messages.parse() {
+            Ok(messages) if messages > 0 => Ok(Rate { messages, per }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let per = match self.per.as_secs() {
+            1 => "s",
+            60 => "m",
+            _ => "h",
+        };
+        write!(f, "{}/{}", self.messages, per)
+    }
+}
+
+/// What a publisher has left of a rooms rate, refilling continuously so a full
+/// bucket allows a burst of the whole rate.
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn full(rate: Rate, now: Instant) -> Bucket {
+        Bucket { tokens: rate.messages as f64, last: now }
+    }
+
+    /// Takes one message, returns false if the rate is used up.
+    pub fn take(&mut self, rate: Rate, now: Instant) -> bool {
+        let refill = now.saturating_duration_since(self.last).as_secs_f64() / rate.per.as_secs_f64();
+        self.tokens = (self.tokens + refill * rate.messages as f64).min(rate.messages as f64);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Limits on one room, from a `[room.<name>]` section of the config. They apply
+/// to the room of that name in every tenant.
+#[derive(Clone, Debug, Default)]
+pub struct RoomPolicy {
+    /// How often each member may send to the room.
+    pub rate: Option<Rate>,
+    /// Longest message, not counting the room name in front.
+    pub max_message_bytes: Option<usize>,
+    /// Recent messages kept for clients that join.
+    pub history: usize,
+}
+
+impl RoomPolicy {
+    /// Every `[room.<name>]` section of the config, keyed by `#<name>`.
+    pub fn from_config(config: &Config) -> Result<BTreeMap<String, RoomPolicy>> {
+        let mut policies = BTreeMap::new();
+        for (name, section) in config.sections_with_prefix("room") {
+            let room = format!("#{}", name);
+            if !valid_name(&room) {
+                return Err(config.error(section.line, "room names are up to 32 letters, digits, - or _"));
+            }
+            let mut policy = RoomPolicy::default();
+            if let Some(entry) = section.get("rate") {
+                policy.rate = Some(entry.value.parse().map_err(|e: String| config.error(entry.line, &e))?);
+            }
+            if let Some(entry) = section.get("max_message_bytes") {
+                policy.max_message_bytes = Some(entry.value.parse().map_err(|_| config.error(entry.line, "max_message_bytes must be a number"))?);
+            }
+            if let Some(entry) = section.get("history") {
+                policy.history = entry.value.parse().map_err(|_| config.error(entry.line, "history must be a number"))?;
+            }
+            policies.insert(room, policy);
+        }
+        Ok(policies)
+    }
+}
+
+/// Named subsets of clients. A message starting with `#room ` is only delivered
+/// to that rooms members, everything else goes to everyone. Rooms disappear with
+/// their last member unless they have a topic or an operator created them.
+#[derive(Default)]
+pub struct Rooms {
+    rooms: BTreeMap<String, Room>,
+}
+
+/// Whether name is a valid room name: `#` and 1 to 32 letters, digits, `-` or `_`.
+pub fn valid_name(name: &str) -> bool {
+    match name.strip_prefix('#') {
+        Some(rest) => (1..=32).contains(&rest.len())
+            && rest.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+        None => false,
+    }
+}
+
+impl Rooms {
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Room> {
+        self.rooms.values()
+    }
+
+    /// The room a message is addressed to, if it starts with the name of one.
+    pub fn addressed(&self, message: &[u8]) -> Option<&Room> {
+        let end = message.iter().position(|&b| b == b' ')?;
+        let name = std::str::from_utf8(&message[..end]).ok()?;
+        self.rooms.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Room> {
+        self.rooms.get_mut(name)
+    }
+
+    /// Why fd, known as nick, may not join the room: it is locked, for allowed
+    /// nicks only, wants a password or is full. Members may always join again,
+    /// the owner and invited clients get past everything but the cap.
+    pub fn refusal(&self, name: &str, fd: i32, nick: Option<&str>, password: Option<&str>) -> Option<&'static str> {
+        let room = self.rooms.get(name).filter(|r| !r.members.contains(&fd))?;
+        let owner = nick.is_some() && room.owner.as_deref() == nick;
+        if !owner && !room.invited.contains(&fd) {
+            if room.locked {
+                return Some("is invite only");
+            }
+            if !room.allowed.is_empty() && !nick.is_some_and(|n| room.allowed.contains(n)) {
+                return Some("is for allowed nicks only");
+            }
+            if room.password.is_some() && room.password.as_deref() != password {
+                return Some("needs the right password");
+            }
+        }
+        if room.cap.is_some_and(|cap| room.members.len() >= cap) {
+            return Some("is full");
+        }
+        None
+    }
+
+    /// Creates an empty room that stays until deleted.
+    ///
+    /// Returns false if it existed already, it is kept until deleted from now on.
+    pub fn create(&mut self, name: &str) -> bool {
+        let created = !self.rooms.contains_key(name);
+        self.rooms.entry(name.to_string()).or_insert_with(|| Room::new(name)).pinned = true;
+        created
+    }
+
+    /// Removes a room with everyone in it, returning it.
+    pub fn delete(&mut self, name: &str) -> Option<Room> {
+        self.rooms.remove(name)
+    }
+
+    /// Adds fd, known as nick, to the room, creating it with them as its owner if
+    /// needed. Joining again changes the qos.
+    pub fn join(&mut self, name: &str, fd: i32, nick: Option<&str>, qos: Qos) -> &Room {
+        let room = self.rooms.entry(name.to_string()).or_insert_with(|| Room {
+            owner: nick.map(str::to_string),
+            ..Room::new(name)
+        });
+        if room.members.is_empty() || (nick.is_some() && room.owner.as_deref() == nick) {
+            room.ops.insert(fd);
+        }
+        room.members.insert(fd);
+        match qos {
+            Qos::Reliable => room.reliable.insert(fd),
+            Qos::BestEffort => room.reliable.remove(&fd),
+        };
+        room
+    }
+
+    /// Returns false if fd was not in the room.
+    pub fn part(&mut self, name: &str, fd: i32) -> bool {
+        let room = match self.rooms.get_mut(name) {
+            Some(room) => room,
+            None => return false,
+        };
+        room.ops.remove(&fd);
+        room.reliable.remove(&fd);
+        let was_member = room.members.remove(&fd);
+        if room.is_abandoned() {
+            self.rooms.remove(name);
+        }
+        was_member
+    }
+
+    /// Removes fd from every room and its invites, when it disconnects.
+    pub fn part_all(&mut self, fd: i32) {
+        for room in self.rooms.values_mut() {
+            room.invited.remove(&fd);
+        }
+        let names: Vec<String> = self.rooms.values()
+            .filter(|r| r.members.contains(&fd))
+            .map(|r| r.name.clone())
+            .collect();
+        for name in names {
+            self.part(&name, fd);
+        }
+    }
+
+    /// Keeps message as one of the rooms recent ones, up to keep of them.
+    pub fn remember(&mut self, name: &str, message: &[u8], keep: usize) {
+        let Some(room) = self.rooms.get_mut(name).filter(|_| keep > 0) else { return };
+        while room.recent.len() >= keep {
+            room.recent.pop_front();
+        }
+        room.recent.push_back(message.to_vec());
+    }
+
+    /// Sets or clears the topic, creating the room if needed.
+    pub fn set_topic(&mut self, name: &str, topic: Option<String>) {
+        let room = self.rooms.entry(name.to_string()).or_insert_with(|| Room::new(name));
+        room.topic = topic;
+        if room.is_abandoned() {
+            self.rooms.remove(name);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::rooms::Rooms;
+
+/// What rooms beyond the limit are counted as.
+const OTHER: &str = "other";
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    messages: u64,
+    bytes: u64,
+    drops: u64,
+}
+
+/// Messages, bytes and drops per room, to plan capacity per feed rather than for
+/// the whole server. Clients make rooms up as they go, so only the first `limit`
+/// rooms seen get series of their own, see `--room-metrics`, and the rest add up
+/// under `room="other"`. A room that is gone gives its series up the next time
+/// metrics are rendered, making space for another.
+pub struct RoomStats {
+    limit: usize,
+    counts: RefCell<HashMap<(String, String), Counts>>, // tenant, room
+}
+
+impl RoomStats {
+    pub fn new(limit: usize) -> RoomStats {
+        RoomStats { limit, counts: RefCell::new(HashMap::new()) }
+    }
+
+    fn update(&self, tenant: &str, room: &str, f: impl FnOnce(&mut Counts)) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut counts = self.counts.borrow_mut();
+        let mut key = (tenant.to_string(), room.to_string());
+        let tracked = counts.len() - usize::from(counts.contains_key(&(String::new(), OTHER.to_string())));
+        if !counts.contains_key(&key) && tracked >= self.limit {
+            key = (String::new(), OTHER.to_string());
+        }
+        f(counts.entry(key).or_default());
+    }
+
+    /// Counts messages broadcast to room and the bytes written or queued for its
+    /// members. tenant is empty for the default one.
+    pub(crate) fn sent(&self, tenant: &str, room: &str, messages: usize, bytes: usize) {
+        self.update(tenant, room, |c| {
+            c.messages += messages as u64;
+            c.bytes += bytes as u64;
+        });
+    }
+
+    /// Counts messages to room that were dropped, for breaking its policy or a
+    /// full member outbox.
+    pub(crate) fn dropped(&self, tenant: &str, room: &str, messages: usize) {
+        self.update(tenant, room, |c| c.drops += messages as u64);
+    }
+
+    pub fn reset(&self) {
+        for counts in self.counts.borrow_mut().values_mut() {
+            *counts = Counts::default();
+        }
+    }
+
+    /// Renders the counters, and the members of every room, in prometheus text
+    /// format.
+    pub(crate) fn render_metrics<'a>(&self, namespaces: impl Iterator<Item = (&'a str, &'a RefCell<Rooms>)>) -> String {
+        let mut out = String::new();
+        if self.limit == 0 {
+            return out;
+        }
+        let mut members = Vec::new();
+        for (tenant, rooms) in namespaces {
+            for room in rooms.borrow().iter() {
+                members.push(((tenant.to_string(), room.name.clone()), room.members.len()));
+            }
+        }
+        members.sort();
+        let other = (String::new(), OTHER.to_string());
+        let mut counts = self.counts.borrow_mut();
+        counts.retain(|key, _| *key == other || members.iter().any(|(room, _)| room == key));
+        // quiet rooms get series too while there is space, for their members
+        for (room, _) in &members {
+            let tracked = counts.len() - usize::from(counts.contains_key(&other));
+            if tracked < self.limit && !counts.contains_key(room) {
+                counts.insert(room.clone(), Counts::default());
+            }
+        }
+        let mut keys: Vec<_> = counts.keys().cloned().collect();
+        keys.sort();
+
+        let families = [
+            ("epollbroadcast_room_messages_total", "Messages broadcast per room."),
+            ("epollbroadcast_room_bytes_total", "Bytes written or queued for the members of each room."),
+            ("epollbroadcast_room_drops_total", "Messages to each room dropped by its policy or a full member outbox."),
+        ];
+        for (i, (name, help)) in families.into_iter().enumerate() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for key in &keys {
+                let c = &counts[key];
+                let _ = writeln!(out, "{}{{tenant=\"{}\",room=\"{}\"}} {}", name, key.0, key.1, [c.messages, c.bytes, c.drops][i]);
+            }
+        }
+        let _ = writeln!(out, "# HELP epollbroadcast_room_members Members per room.");
+        let _ = writeln!(out, "# TYPE epollbroadcast_room_members gauge");
+        let (tracked, untracked): (Vec<_>, Vec<_>) = members.into_iter().partition(|(room, _)| counts.contains_key(room));
+        for ((tenant, room), n) in tracked {
+            let _ = writeln!(out, "epollbroadcast_room_members{{tenant=\"{}\",room=\"{}\"}} {}", tenant, room, n);
+        }
+        if !untracked.is_empty() || counts.contains_key(&other) {
+            let n: usize = untracked.iter().map(|(_, n)| n).sum();
+            let _ = writeln!(out, "epollbroadcast_room_members{{tenant=\"\",room=\"{}\"}} {}", OTHER, n);
+        }
+        out
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::Write as _;
+use std::io::{Error, Result};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use regex::bytes::Regex;
+
+use crate::config::Config;
+use crate::Metadata;
+
+/// Where a rule sends a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// The members of a room of the senders tenant.
+    Room(String),
+    /// Clients of the senders tenant whose metadata has key set to value.
+    Tagged(String, String),
+}
+
+enum Action {
+    Drop,
+    Transform(Vec<u8>),
+    Route(Target),
+    Copy(Target),
+    Handoff(PathBuf),
+}
+
+struct Rule {
+    name: String,
+    sender: Option<String>,
+    room: Option<String>, // empty for messages to everyone
+    from: Option<(String, String)>,
+    pattern: Option<Regex>,
+    action: Action,
+    hits: AtomicUsize,
+}
+
+/// What the rules made of a message.
+pub struct Verdict {
+    /// The body, if a rule transformed it.
+    pub body: Option<Vec<u8>>,
+    /// Whether it still goes where it was sent, no rule dropped or routed it away.
+    pub original: bool,
+    /// Where else it goes, in rule order.
+    pub targets: Vec<Target>,
+    /// The rule and helper socket the sender is handed off to, see handoff.rs.
+    pub handoff: Option<(String, PathBuf)>,
+}
+
+/// Ordered routing rules evaluated for every message on its way to broadcast,
+/// configured with one `[rule.<name>]` section per rule:
+///
+/// ```text
+/// [rule.alerts]
+/// sender = monitor
+/// match = ^ALERT
+/// action = copy
+/// to = #oncall
+/// ```
+///
+/// A rule matches when all of its conditions do: `sender` is the senders nick,
+/// `room` the room the message is addressed to (`none` for messages to
+/// everyone), `from = <key>=<value>` the senders metadata and `match` a regex on
+/// the body, without the room name. Its `action` is one of
+///
+/// * `drop` -- nobody gets the message
+/// * `route` -- the message goes `to` a room or to the clients with metadata
+///   `<key>=<value>` instead of where it was sent
+/// * `copy` -- like route, but the message still goes where it was sent too
+/// * `transform` -- replaces what `match` matched with `replacement`
+/// * `handoff` -- nobody gets the message, the sender is passed to the helper
+///   process listening on the unix `socket`, with the message and whatever it
+///   sends after, say a helper serving file transfers
+///
+/// Drop, route and handoff end the evaluation, later rules see transformed bodies.
+pub struct Rules {
+    rules: Vec<Rule>,
+    path: Option<PathBuf>,
+}
+
+impl Rules {
+    pub fn from_config(config: &Config) -> Result<Rules> {
+        let mut rules = Vec::new();
+
+        for (name, section) in config.sections_with_prefix("rule") {
+            let pattern = match section.get("match") {
+                Some(entry) => Some(Regex::new(&entry.value)
+                    .map_err(|e| config.error(entry.line, &format!("invalid regex -- {}", e)))?),
+                None => None,
+            };
+            let from = match section.get("from") {
+                Some(entry) => Some(entry.value.split_once('=')
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| config.error(entry.line, "from must be `<key>=<value>`"))?),
+                None => None,
+            };
+            let room = match section.get("room") {
+                Some(entry) if entry.value == "none" => Some(String::new()),
+                Some(entry) if entry.value.starts_with('#') => Some(entry.value.clone()),
+                Some(entry) => return Err(config.error(entry.line, "room must be `#<name>` or `none`")),
+                None => None,
+            };
+            let target = match section.get("to") {
+                Some(entry) => match entry.value.split_once('=') {
+                    _ if entry.value.starts_with('#') => Some(Target::Room(entry.value.clone())),
+                    Some((key, value)) => Some(Target::Tagged(key.trim().to_string(), value.trim().to_string())),
+                    None => return Err(config.error(entry.line, "to must be `#<room>` or `<key>=<value>`")),
+                },
+                None => None,
+            };
+
+            let action = match section.get("action").map(|e| (e.value.as_str(), e.line)) {
+                Some(("drop", _)) => Action::Drop,
+                Some(("transform", line)) => match (section.get("replacement"), &pattern) {
+                    (Some(entry), Some(_)) => Action::Transform(entry.value.clone().into_bytes()),
+                    _ => return Err(config.error(line, "transform rule needs `match` and `replacement`")),
+                },
+                Some(("route", line)) => Action::Route(target.ok_or_else(|| config.error(line, "route rule is missing `to`"))?),
+                Some(("copy", line)) => Action::Copy(target.ok_or_else(|| config.error(line, "copy rule is missing `to`"))?),
+                Some(("handoff", line)) => match section.get("socket") {
+                    Some(entry) => Action::Handoff(PathBuf::from(&entry.value)),
+                    None => return Err(config.error(line, "handoff rule is missing `socket`")),
+                },
+                Some((_, line)) => return Err(config.error(line, "action must be `route`, `copy`, `drop`, `transform` or `handoff`")),
+                None => return Err(config.error(section.line, "rule is missing `action`")),
+            };
+
+            rules.push(Rule {
+                name: name.to_string(),
+                sender: section.get("sender").map(|e| e.value.clone()),
+                room,
+                from,
+                pattern,
+                action,
+                hits: AtomicUsize::new(0),
+            });
+        }
+
+        Ok(Rules { rules, path: config.path().map(|p| p.to_path_buf()) })
+    }
+
+    /// Reads the rules from the config file again, keeping the old ones on error.
+    pub fn reload(&mut self) -> Result<()> {
+        let path = self.path.clone().ok_or_else(|| Error::other("no --config to reload rules from"))?;
+        *self = Rules::from_config(&Config::load(&path)?)?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Whether any rule hands clients off to a helper.
+    pub fn hands_off(&self) -> bool {
+        self.rules.iter().any(|rule| matches!(rule.action, Action::Handoff(_)))
+    }
+
+    /// Runs a single message body (without its newline or room name) from nick
+    /// with metadata meta, addressed to room if any, through the rules in order.
+    pub fn evaluate(&self, nick: Option<&str>, meta: &Metadata, room: Option<&str>, body: &[u8]) -> Verdict {
+        let mut verdict = Verdict { body: None, original: true, targets: Vec::new(), handoff: None };
+
+        for rule in &self.rules {
+            let text = verdict.body.as_deref().unwrap_or(body);
+            if rule.sender.as_ref().is_some_and(|s| nick != Some(s.as_str()))
+                || rule.room.as_ref().is_some_and(|r| room.unwrap_or("") != r)
+                || rule.from.as_ref().is_some_and(|(key, value)| meta.get(key) != Some(value))
+                || rule.pattern.as_ref().is_some_and(|p| !p.is_match(text))
+            {
+                continue;
+            }
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+
+            match &rule.action {
+                Action::Drop => {
+                    verdict.original = false;
+                    break;
+                }
+                Action::Route(target) => {
+                    verdict.original = false;
+                    verdict.targets.push(target.clone());
+                    break;
+                }
+                Action::Copy(target) => verdict.targets.push(target.clone()),
+                Action::Handoff(socket) => {
+                    verdict.original = false;
+                    verdict.handoff = Some((rule.name.clone(), socket.clone()));
+                    break;
+                }
+                Action::Transform(with) => {
+                    if let Some(pattern) = &rule.pattern {
+                        verdict.body = Some(pattern.replace_all(text, with.as_slice()).into_owned());
+                    }
+                }
+            }
+        }
+
+        verdict
+    }
+
+    /// Per rule hit counters in prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        if self.rules.is_empty() {
+            return out;
+        }
+
+        let _ = writeln!(out, "# HELP epollbroadcast_rule_hits_total Messages matched by each routing rule.");
+        let _ = writeln!(out, "# TYPE epollbroadcast_rule_hits_total counter");
+        for rule in &self.rules {
+            let _ = writeln!(
+                out,
+                "epollbroadcast_rule_hits_total{{rule=\"{}\"}} {}",
+                rule.name,
+                rule.hits.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+
+    pub fn reset_hits(&self) {
+        for rule in &self.rules {
+            rule.hits.store(0, Ordering::Relaxed);
+        }
+    }
+}
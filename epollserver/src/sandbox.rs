@@ -0,0 +1,242 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::logging::warning;
+
+/// What `--sandbox` locks down once the server is set up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sandbox {
+    /// Only the syscalls the event loop needs, everything else fails with EPERM.
+    Seccomp,
+    /// No filesystem access except the files the server writes or rereads.
+    Landlock,
+    All,
+}
+
+impl FromStr for Sandbox {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Sandbox, String> {
+        match s {
+            "seccomp" => Ok(Sandbox::Seccomp),
+            "landlock" => Ok(Sandbox::Landlock),
+            "all" => Ok(Sandbox::All),
+            _ => Err(format!("unknown sandbox {:?}, expected seccomp, landlock or all", s)),
+        }
+    }
+}
+
+/// Files the server still touches after startup.
+#[derive(Default)]
+pub struct Paths {
+    /// Read again later, like a script on reload.
+    pub read: Vec<PathBuf>,
+    /// Created or replaced later, like the ban list. Access is granted to the
+    /// whole directory since they are written through a temporary file.
+    pub write: Vec<PathBuf>,
+}
+
+/// Applies the sandbox to the whole process. Landlock goes first because its
+/// own syscalls are not on the seccomp allowlist, and is skipped with a warning
+/// on kernels without it. Neither can be undone.
+pub fn enter(sandbox: Sandbox, paths: &Paths) -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(os_error("prctl(PR_SET_NO_NEW_PRIVS)"));
+    }
+    if sandbox != Sandbox::Seccomp {
+        match landlock(paths) {
+            Err(e) if e.kind() == ErrorKind::Unsupported => warning!("landlock is not available, filesystem is not restricted"),
+            result => result?,
+        }
+    }
+    if sandbox != Sandbox::Landlock {
+        seccomp()?;
+    }
+    Ok(())
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_MAKE_REG: u64 = 1 << 8;
+/// Every filesystem right of landlock ABI 1, from EXECUTE to MAKE_SYM.
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn landlock(paths: &Paths) -> Result<()> {
+    let abi = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+    };
+    if abi < 1 {
+        return Err(Error::new(ErrorKind::Unsupported, "landlock"));
+    }
+    let attr = RulesetAttr { handled_access_fs: ACCESS_FS_V1 };
+    let ruleset = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, &attr, std::mem::size_of::<RulesetAttr>(), 0)
+    } as i32;
+    if ruleset < 0 {
+        return Err(os_error("landlock_create_ruleset"));
+    }
+
+    let result = (|| {
+        for path in &paths.read {
+            allow(ruleset, path, ACCESS_READ_FILE)?;
+        }
+        for path in &paths.write {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            allow(ruleset, dir, ACCESS_READ_FILE | ACCESS_WRITE_FILE | ACCESS_READ_DIR | ACCESS_REMOVE_FILE | ACCESS_MAKE_REG)?;
+        }
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } < 0 {
+            return Err(os_error("landlock_restrict_self"));
+        }
+        Ok(())
+    })();
+    unsafe { libc::close(ruleset); }
+    result
+}
+
+fn allow(ruleset: i32, path: &Path, access: u64) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{} contains a nul byte", path.display())))?;
+    let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::other(format!("cannot open {} -- {}", path.display(), Error::last_os_error())));
+    }
+    // files only take file rights
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    let is_dir = unsafe { libc::fstat(fd, stat.as_mut_ptr()) } == 0
+        && unsafe { stat.assume_init() }.st_mode & libc::S_IFMT == libc::S_IFDIR;
+    let allowed_access = if is_dir { access } else { access & (ACCESS_READ_FILE | ACCESS_WRITE_FILE) };
+    let rule = PathBeneathAttr { allowed_access, parent_fd: fd };
+    let rc = unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule, 0) };
+    unsafe { libc::close(fd); }
+    if rc < 0 {
+        return Err(os_error("landlock_add_rule"));
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// What the event loop, the admin and metrics endpoints, sinks, the allocator,
+/// following `--tail` files, and the ban list and dump file writes use.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn allowed_syscalls() -> Vec<libc::c_long> {
+    let mut calls = vec![
+        libc::SYS_epoll_pwait,
+        libc::SYS_epoll_ctl,
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_recvfrom,
+        libc::SYS_sendto,
+        libc::SYS_sendmsg,
+        libc::SYS_accept4,
+        libc::SYS_accept,
+        libc::SYS_close,
+        libc::SYS_shutdown,
+        libc::SYS_getpeername,
+        libc::SYS_getsockname,
+        libc::SYS_getsockopt,
+        libc::SYS_ioctl,
+        libc::SYS_fcntl,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_futex,
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_getrandom,
+        libc::SYS_openat,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_unlinkat,
+        libc::SYS_pread64,
+        libc::SYS_inotify_add_watch,
+        libc::SYS_inotify_rm_watch,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    calls.extend([libc::SYS_epoll_wait, libc::SYS_open, libc::SYS_rename, libc::SYS_stat, libc::SYS_poll]);
+    calls
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp() -> Result<()> {
+    use libc::{sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    fn stmt(code: u32, k: u32) -> sock_filter {
+        sock_filter { code: code as u16, jt: 0, jf: 0, k }
+    }
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter { code: code as u16, jt, jf, k }
+    }
+    // offsets into struct seccomp_data
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const RET_ALLOW: u32 = 0x7fff_0000;
+    const RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const RET_ERRNO: u32 = 0x0005_0000;
+
+    let calls = allowed_syscalls();
+    let mut program = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET | BPF_K, RET_KILL_PROCESS),
+        stmt(BPF_LD | BPF_W | BPF_ABS, NR),
+    ];
+    for (i, nr) in calls.iter().enumerate() {
+        // on a match skip the remaining checks and the EPERM to land on ALLOW
+        let to_allow = (calls.len() - i) as u8;
+        program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, to_allow, 0));
+    }
+    program.push(stmt(BPF_RET | BPF_K, RET_ERRNO | libc::EPERM as u32));
+    program.push(stmt(BPF_RET | BPF_K, RET_ALLOW));
+
+    let fprog = sock_fprog { len: program.len() as u16, filter: program.as_mut_ptr() };
+    if unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &fprog as *const sock_fprog) } < 0 {
+        return Err(os_error("prctl(PR_SET_SECCOMP)"));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp() -> Result<()> {
+    Err(Error::other("no seccomp allowlist for this architecture"))
+}
+
+fn os_error(call: &str) -> Error {
+    Error::other(format!("{} failed -- {}", call, Error::last_os_error()))
+}
@@ -0,0 +1,106 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// What `--require-utf8` does with messages that are not valid UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Utf8Policy {
+    /// Drop them and tell the sender.
+    Reject,
+    /// Replace invalid sequences with U+FFFD.
+    Replace,
+}
+
+impl FromStr for Utf8Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Utf8Policy, String> {
+        match s {
+            "reject" => Ok(Utf8Policy::Reject),
+            "replace" => Ok(Utf8Policy::Replace),
+            _ => Err(format!("unknown utf8 policy {:?}, expected reject or replace", s)),
+        }
+    }
+}
+
+/// Checks run on every message before filters see it, off by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sanitizer {
+    pub utf8: Option<Utf8Policy>,
+    /// Remove control characters and ANSI escape sequences, so a message can't
+    /// move the cursor, recolor or retitle the terminals of other clients.
+    pub strip_control: bool,
+}
+
+impl Sanitizer {
+    pub fn is_off(&self) -> bool {
+        self.utf8.is_none() && !self.strip_control
+    }
+
+    /// The message to broadcast instead, or Err with why it was rejected.
+    pub fn apply<'a>(&self, message: &'a [u8]) -> Result<Cow<'a, [u8]>, &'static str> {
+        let mut message = Cow::Borrowed(message);
+        match self.utf8 {
+            Some(Utf8Policy::Reject) if std::str::from_utf8(&message).is_err() => {
+                return Err("message is not valid UTF-8");
+            }
+            Some(Utf8Policy::Replace) => {
+                if let Cow::Owned(fixed) = String::from_utf8_lossy(&message) {
+                    message = Cow::Owned(fixed.into_bytes());
+                }
+            }
+            _ => {}
+        }
+        if self.strip_control && message.iter().any(|&b| is_control(b) || b == 0xc2) {
+            message = Cow::Owned(strip_control(&message));
+        }
+        Ok(message)
+    }
+}
+
+/// C0 controls but tab, and DEL.
+fn is_control(b: u8) -> bool {
+    (b < 0x20 && b != b'\t') || b == 0x7f
+}
+
+/// Drops control characters, C1 controls encoded as UTF-8 and whole escape
+/// sequences: CSI (`ESC [` up to a final byte), OSC and other strings (`ESC ]`
+/// up to BEL or `ESC \`) and two byte escapes.
+fn strip_control(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len());
+    let mut i = 0;
+    while i < message.len() {
+        match message[i] {
+            0x1b => i = skip_escape(message, i + 1),
+            0xc2 if message.get(i + 1).is_some_and(|b| (0x80..0xa0).contains(b)) => i += 2,
+            b if is_control(b) => i += 1,
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Index after the escape sequence whose introducer follows ESC at i.
+fn skip_escape(message: &[u8], i: usize) -> usize {
+    match message.get(i) {
+        Some(b'[') => match message[i + 1..].iter().position(|b| (0x40..0x7f).contains(b)) {
+            Some(end) => i + 1 + end + 1,
+            None => message.len(),
+        },
+        Some(b']' | b'P' | b'X' | b'^' | b'_') => {
+            let mut j = i + 1;
+            while j < message.len() {
+                match message[j] {
+                    0x07 => return j + 1,
+                    0x1b if message.get(j + 1) == Some(&b'\\') => return j + 2,
+                    _ => j += 1,
+                }
+            }
+            j
+        }
+        Some(_) => i + 1,
+        None => i,
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::{Error, Result};
+use std::path::PathBuf;
+#[cfg(feature = "scripting")]
+use std::{cell::RefCell, rc::Rc};
+
+#[cfg(feature = "scripting")]
+use rhai::{Dynamic, Engine, Scope, AST};
+
+#[cfg(feature = "scripting")]
+use crate::logging::warning;
+
+/// Operations a hook may run per call before it is aborted.
+#[cfg(feature = "scripting")]
+const MAX_OPERATIONS: u64 = 1_000_000;
+#[cfg(feature = "scripting")]
+const MAX_CALL_LEVELS: usize = 32;
+#[cfg(feature = "scripting")]
+const MAX_STRING_SIZE: usize = 1 << 20;
+/// Elements per array or map a script may build.
+#[cfg(feature = "scripting")]
+const MAX_ELEMENTS: usize = 10_000;
+
+/// Rhai script providing optional hooks, any of which may be left out:
+///
+/// ```text
+/// fn on_connect(fd, peer) { reply("welcome!"); }
+/// fn on_message(fd, nick, msg) { if msg.contains("spam") { return false; } msg.to_upper() }
+/// fn on_disconnect(fd, nick) { }
+/// fn on_tick() { }   // runs every --tick-ms, even when nothing happens
+/// ```
+///
+/// `on_message` returns the message to broadcast instead, `false` to drop it, or
+/// nothing to pass it on unchanged. `reply(text)` sends a line back to the client
+/// the hook runs for. Each call is limited in operations, recursion and the size
+/// of what it builds, a hook that goes past them fails like one that errors and
+/// the message is passed on unchanged.
+#[cfg(feature = "scripting")]
+pub struct ScriptHooks {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    scope: RefCell<Scope<'static>>,
+    replies: Rc<RefCell<Vec<String>>>,
+}
+
+#[cfg(feature = "scripting")]
+impl ScriptHooks {
+    pub fn load(path: PathBuf) -> Result<ScriptHooks> {
+        let replies = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_ELEMENTS)
+            .set_max_map_size(MAX_ELEMENTS);
+        let sink = replies.clone();
+        engine.register_fn("reply", move |text: &str| sink.borrow_mut().push(text.to_string()));
+
+        let mut hooks = ScriptHooks {
+            path,
+            engine,
+            ast: AST::empty(),
+            scope: RefCell::new(Scope::new()),
+            replies,
+        };
+        hooks.reload()?;
+        Ok(hooks)
+    }
+
+    /// Recompiles the script and reruns its top level, keeping the old one on error.
+    pub fn reload(&mut self) -> Result<()> {
+        let ast = self.engine.compile_file(self.path.clone())
+            .map_err(|e| Error::other(format!("{}: {}", self.path.display(), e)))?;
+
+        let mut scope = Scope::new();
+        self.engine.run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| Error::other(format!("{}: {}", self.path.display(), e)))?;
+
+        self.ast = ast;
+        self.scope = RefCell::new(scope);
+        Ok(())
+    }
+
+    /// Returns replies for the new client.
+    pub fn on_connect(&self, fd: i32, peer: &str) -> Vec<String> {
+        self.call("on_connect", (fd as i64, peer.to_string()));
+        self.take_replies()
+    }
+
+    /// Runs a single message (without its newline) through the script.
+    ///
+    /// Returns the message to broadcast, None if it was dropped, and the replies for the orator.
+    pub fn on_message(&self, fd: i32, nick: Option<&str>, message: &[u8]) -> (Option<Vec<u8>>, Vec<String>) {
+        let text = String::from_utf8_lossy(message).into_owned();
+        let result = self.call("on_message", (fd as i64, nick_arg(nick), text));
+
+        let message = match result {
+            Some(r) if r.is_string() => Some(r.into_string().unwrap_or_default().into_bytes()),
+            Some(r) if r.as_bool() == Ok(false) => None,
+            _ => Some(message.to_vec()),
+        };
+        (message, self.take_replies())
+    }
+
+    pub fn on_disconnect(&self, fd: i32, nick: Option<&str>) {
+        self.call("on_disconnect", (fd as i64, nick_arg(nick)));
+        self.replies.borrow_mut().clear();
+    }
+
+    pub fn on_tick(&self) {
+        self.call("on_tick", ());
+        self.replies.borrow_mut().clear();
+    }
+
+    /// Calls hook if the script defines it, errors are logged and treated like a missing hook.
+    fn call(&self, hook: &str, args: impl rhai::FuncArgs) -> Option<Dynamic> {
+        self.ast.iter_functions().find(|f| f.name == hook)?;
+
+        let mut scope = self.scope.borrow_mut();
+        match self.engine.call_fn::<Dynamic>(&mut scope, &self.ast, hook, args) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                warning!("{} failed in {}: {}", hook, self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn take_replies(&self) -> Vec<String> {
+        std::mem::take(&mut *self.replies.borrow_mut())
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn nick_arg(nick: Option<&str>) -> Dynamic {
+    match nick {
+        Some(nick) => nick.into(),
+        None => Dynamic::UNIT,
+    }
+}
+
+/// Stand-in when built without the `scripting` feature, `--script` then fails at startup.
+#[cfg(not(feature = "scripting"))]
+pub struct ScriptHooks;
+
+#[cfg(not(feature = "scripting"))]
+impl ScriptHooks {
+    pub fn load(path: PathBuf) -> Result<ScriptHooks> {
+        let errmsg = format!("cannot load {}, built without the scripting feature", path.display());
+        Err(Error::new(std::io::ErrorKind::Unsupported, errmsg))
+    }
+
+    pub fn reload(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn on_connect(&self, _fd: i32, _peer: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn on_message(&self, _fd: i32, _nick: Option<&str>, message: &[u8]) -> (Option<Vec<u8>>, Vec<String>) {
+        (Some(message.to_vec()), Vec::new())
+    }
+
+    pub fn on_disconnect(&self, _fd: i32, _nick: Option<&str>) {}
+
+    pub fn on_tick(&self) {}
+}
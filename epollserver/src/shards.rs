@@ -0,0 +1,239 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{Error, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::logging::{debug, warning};
+use crate::{broadcast, drain, Audience, ClientState, EpollServer, Metadata, Origin};
+
+/// Broadcasts the bus holds, shards falling further behind than this lose the
+/// oldest ones.
+pub const BUS_SLOTS: usize = 16384;
+
+/// A broadcast one event loop hands the others, stamped and attributed already.
+struct Forwarded {
+    from: usize,
+    node: Option<usize>,
+    tenant: Option<usize>,
+    scope: Scope,
+    message: Vec<u8>,
+}
+
+/// Who a forwarded broadcast is for, rooms go by name as every loop has its own.
+enum Scope {
+    Everyone,
+    Room(String),
+    Tagged(String, String),
+}
+
+/// A place on the ring and what was last published there, None until the
+/// publisher that claimed it first is done.
+#[derive(Default)]
+struct Slot {
+    seq: u64,
+    entry: Option<Arc<Forwarded>>,
+}
+
+#[derive(Default)]
+struct Stats {
+    clients: AtomicUsize,
+    forwarded: AtomicUsize,
+    received: AtomicUsize,
+    dropped: AtomicUsize,
+    cross_node: AtomicUsize,
+}
+
+/// What every shard publishes to and reads from: a bounded ring whose next
+/// sequence number any of them claims with one atomic add. Each slot has a lock
+/// of its own that only its publisher and the shards reading it ever meet on, so
+/// shards don't queue up behind each other on one channel or mutex, and a
+/// broadcast is stored once however many shards read it.
+struct Bus {
+    ring: Vec<Mutex<Slot>>,
+    /// The next sequence number to publish at.
+    head: AtomicU64,
+    draining: AtomicBool,
+    wakes: Vec<OwnedFd>,
+    stats: Vec<Stats>,
+}
+
+/// One of the event loops of `--workers`, each with its own clients, rooms and
+/// history. Clients stay with the loop that accepted them; broadcasts reach the
+/// clients of the other loops through the bus, with an eventfd wakeup per loop.
+pub struct Shard {
+    pub id: usize,
+    /// The memory node the loop is pinned to, if it spans just one.
+    pub node: Option<usize>,
+    bus: Arc<Bus>,
+    /// The next sequence number this shard reads.
+    cursor: Cell<u64>,
+    drained: Cell<bool>,
+}
+
+/// Creates count shards that forward to each other, to move to one thread each.
+pub fn create(count: usize) -> Result<Vec<Shard>> {
+    let mut wakes = Vec::with_capacity(count);
+    for _ in 0..count {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            let errmsg = format!("eventfd failed -- {}", Error::last_os_error());
+            return Err(Error::other(errmsg));
+        }
+        wakes.push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    let slots = if count > 1 { BUS_SLOTS } else { 0 };
+    let bus = Arc::new(Bus {
+        ring: (0..slots).map(|_| Mutex::default()).collect(),
+        head: AtomicU64::new(0),
+        draining: AtomicBool::new(false),
+        wakes,
+        stats: (0..count).map(|_| Stats::default()).collect(),
+    });
+    Ok((0..count).map(|id| Shard { id, node: None, bus: bus.clone(), cursor: Cell::new(0), drained: Cell::new(false) }).collect())
+}
+
+impl Shard {
+    /// Readable when other shards published something.
+    pub fn fd(&self) -> i32 {
+        self.bus.wakes[self.id].as_raw_fd()
+    }
+
+    /// Publishes what the loop broadcast for every other shard.
+    pub(crate) fn forward(&self, tenant: Option<usize>, audience: &Audience, message: &[u8]) {
+        let scope = match audience {
+            Audience::Everyone => Scope::Everyone,
+            Audience::Room(room) => Scope::Room(room.name.clone()),
+            Audience::Tagged(key, value) => Scope::Tagged(key.to_string(), value.to_string()),
+        };
+        let entry = Arc::new(Forwarded { from: self.id, node: self.node, tenant, scope, message: message.to_vec() });
+        let seq = self.bus.head.fetch_add(1, Ordering::AcqRel);
+        let mut slot = self.bus.ring[seq as usize % BUS_SLOTS].lock().unwrap_or_else(PoisonError::into_inner);
+        // a publisher a whole lap ahead may have been quicker, what it left is newer
+        if slot.entry.is_none() || slot.seq < seq {
+            *slot = Slot { seq, entry: Some(entry) };
+        }
+        drop(slot);
+        self.bus.stats[self.id].forwarded.fetch_add(1, Ordering::Relaxed);
+        self.wake_others();
+    }
+
+    /// Tells every other shard to drain as well.
+    pub(crate) fn drain_others(&self) {
+        self.bus.draining.store(true, Ordering::Release);
+        self.wake_others();
+    }
+
+    fn wake_others(&self) {
+        let one = 1u64.to_ne_bytes();
+        for wake in self.bus.wakes.iter().enumerate().filter(|(id, _)| *id != self.id).map(|(_, wake)| wake) {
+            unsafe { libc::write(wake.as_raw_fd(), one.as_ptr() as *const libc::c_void, one.len()) };
+        }
+    }
+
+    /// Takes what the others published since the last call, skipping ahead past
+    /// whatever was overwritten before this shard got to it.
+    fn take(&self) -> Vec<Arc<Forwarded>> {
+        let mut count = [0u8; 8];
+        unsafe { libc::read(self.fd(), count.as_mut_ptr() as *mut libc::c_void, count.len()) };
+        let head = self.bus.head.load(Ordering::Acquire);
+        let mut cursor = self.cursor.get();
+        let mut taken = Vec::new();
+        while cursor < head {
+            let slot = self.bus.ring[cursor as usize % BUS_SLOTS].lock().unwrap_or_else(PoisonError::into_inner);
+            match &slot.entry {
+                Some(entry) if slot.seq == cursor => {
+                    if entry.from != self.id {
+                        if entry.node.is_some() && self.node.is_some() && entry.node != self.node {
+                            self.bus.stats[self.id].cross_node.fetch_add(1, Ordering::Relaxed);
+                        }
+                        taken.push(entry.clone());
+                    }
+                    cursor += 1;
+                }
+                Some(_) if slot.seq > cursor => {
+                    // lapped, go on with the oldest that can still be there
+                    let oldest = head.saturating_sub(BUS_SLOTS as u64).max(cursor + 1);
+                    warning!("shard {} fell {} broadcasts behind, they are dropped", self.id, oldest - cursor);
+                    self.bus.stats[self.id].dropped.fetch_add((oldest - cursor) as usize, Ordering::Relaxed);
+                    cursor = oldest;
+                }
+                // claimed but not written yet, its publisher wakes us once it is
+                _ => break,
+            }
+        }
+        self.cursor.set(cursor);
+        self.bus.stats[self.id].received.fetch_add(taken.len(), Ordering::Relaxed);
+        taken
+    }
+
+    /// Whether another shard started draining, true only the first time.
+    fn should_drain(&self) -> bool {
+        if self.drained.get() || !self.bus.draining.load(Ordering::Acquire) {
+            return false;
+        }
+        self.drained.set(true);
+        true
+    }
+
+    /// Counts the clients of this shard for the metrics.
+    pub(crate) fn count(&self, clients: usize) {
+        self.bus.stats[self.id].clients.store(clients, Ordering::Relaxed);
+    }
+}
+
+/// Sends the clients of this shard what the others forwarded, when its eventfd
+/// is readable.
+pub(crate) fn deliver(epserver: &mut EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    let Some((forwarded, drain)) = epserver.shard.as_ref().map(|s| (s.take(), s.should_drain())) else { return };
+    let meta = Metadata::new();
+    for forwarded in forwarded {
+        let Forwarded { tenant, scope, message, .. } = &*forwarded;
+        let origin = Origin { fd: -1, tenant: *tenant, meta: &meta, peer: None, label: None, forwarded: true };
+        match scope {
+            Scope::Everyone => {
+                broadcast(origin, message, Audience::Everyone, epserver, clients);
+            }
+            Scope::Room(name) => {
+                let rooms = epserver.rooms_for(*tenant).borrow();
+                match rooms.get(name) {
+                    Some(room) => {
+                        broadcast(origin, message, Audience::Room(room), epserver, clients);
+                    }
+                    None => debug!(Broadcast, "no members of forwarded {} here", name),
+                }
+            }
+            Scope::Tagged(key, value) => {
+                broadcast(origin, message, Audience::Tagged(key, value), epserver, clients);
+            }
+        }
+    }
+    if drain {
+        drain::start(epserver, clients, epserver.drain_timeout);
+    }
+}
+
+/// Clients, forwarded, dropped and cross node broadcasts of every shard, in
+/// prometheus text format.
+pub fn render_metrics(shard: &Shard) -> String {
+    let mut out = String::new();
+    let stats = &shard.bus.stats;
+    let metrics = [
+        ("epollbroadcast_shard_clients", "gauge", "Clients of each event loop as of its last tick.", 0),
+        ("epollbroadcast_shard_forwarded_total", "counter", "Broadcasts an event loop published to the others.", 1),
+        ("epollbroadcast_shard_received_total", "counter", "Broadcasts an event loop got from the others.", 2),
+        ("epollbroadcast_shard_dropped_total", "counter", "Broadcasts an event loop fell too far behind to get.", 3),
+        ("epollbroadcast_shard_cross_node_total", "counter", "Broadcasts an event loop got from one pinned to another memory node.", 4),
+    ];
+    for (name, kind, help, which) in metrics {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (id, stats) in stats.iter().enumerate() {
+            let value = [&stats.clients, &stats.forwarded, &stats.received, &stats.dropped, &stats.cross_node][which].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{{shard=\"{}\"}} {}", name, id, value);
+        }
+    }
+    out
+}
@@ -0,0 +1,55 @@
+use std::io::{Error, Result};
+use std::mem::{size_of, MaybeUninit};
+
+/// Signals delivered through a signalfd, so the event loop sees them like any
+/// other readable fd instead of being interrupted at a random point. The signals
+/// are blocked for the process, install before starting any threads.
+pub struct Signals {
+    fd: i32,
+}
+
+impl Signals {
+    pub fn install(signals: &[i32]) -> Result<Signals> {
+        let fd = unsafe {
+            let mut mask = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(mask.as_mut_ptr());
+            for signal in signals {
+                libc::sigaddset(mask.as_mut_ptr(), *signal);
+            }
+            let mask = mask.assume_init();
+            if libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) != 0 {
+                return Err(Error::last_os_error());
+            }
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd < 0 {
+            let errmsg = format!("signalfd failed -- {}", Error::last_os_error());
+            return Err(Error::other(errmsg));
+        }
+        Ok(Signals { fd })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Returns the signals received since the last call.
+    pub fn read(&self) -> Vec<i32> {
+        let mut received = Vec::new();
+        loop {
+            let mut info = MaybeUninit::<libc::signalfd_siginfo>::uninit();
+            let size = size_of::<libc::signalfd_siginfo>();
+            let n = unsafe { libc::read(self.fd, info.as_mut_ptr() as *mut libc::c_void, size) };
+            if n as usize != size {
+                return received;
+            }
+            received.push(unsafe { info.assume_init() }.ssi_signo as i32);
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
@@ -0,0 +1,332 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::sys::Sys;
+use crate::{ClientState, EpollServer};
+
+/// Fd of the simulated listening socket.
+pub const SIM_LISTENER: i32 = 3;
+/// Default socket buffer size of a simulated connection.
+const DEFAULT_WINDOW: usize = 64 * 1024;
+
+struct Conn {
+    addr: SocketAddr,
+    to_server: VecDeque<u8>,
+    to_client: Vec<u8>,
+    window: usize, // unread bytes the client side buffers before writes block
+    client_closed: bool,
+    server_closed: bool,
+}
+
+struct Net {
+    start: Instant,
+    elapsed: Duration,
+    next_fd: i32,
+    backlog: VecDeque<i32>,
+    conns: BTreeMap<i32, Conn>,
+    watched: BTreeSet<i32>,
+    writers: BTreeSet<i32>, // watched fds that also want to hear about write space
+    paused: BTreeSet<i32>, // watched fds that don't want to hear about data to read
+}
+
+/// Deterministic in-memory network and clock. Fds are handed out in order and never
+/// reused, readiness is reported in fd order and time only moves when told to (or
+/// when the server waits with a timeout and nothing is ready).
+pub struct SimNet {
+    net: RefCell<Net>,
+}
+
+impl Default for SimNet {
+    fn default() -> SimNet {
+        SimNet::new()
+    }
+}
+
+impl SimNet {
+    pub fn new() -> SimNet {
+        SimNet {
+            net: RefCell::new(Net {
+                start: Instant::now(),
+                elapsed: Duration::ZERO,
+                next_fd: SIM_LISTENER + 1,
+                backlog: VecDeque::new(),
+                conns: BTreeMap::new(),
+                watched: BTreeSet::new(),
+                writers: BTreeSet::new(),
+                paused: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// Queues a connection on the listener, returns the fd the server will see.
+    pub fn connect(&self, addr: SocketAddr) -> i32 {
+        let mut net = self.net.borrow_mut();
+        let fd = net.next_fd;
+        net.next_fd += 1;
+        net.conns.insert(fd, Conn {
+            addr,
+            to_server: VecDeque::new(),
+            to_client: Vec::new(),
+            window: DEFAULT_WINDOW,
+            client_closed: false,
+            server_closed: false,
+        });
+        net.backlog.push_back(fd);
+        fd
+    }
+
+    /// Bytes sent by the client side of fd.
+    pub fn send(&self, fd: i32, bytes: &[u8]) {
+        if let Some(conn) = self.net.borrow_mut().conns.get_mut(&fd) {
+            conn.to_server.extend(bytes);
+        }
+    }
+
+    /// Takes everything the server wrote to fd so far.
+    pub fn recv(&self, fd: i32) -> Vec<u8> {
+        match self.net.borrow_mut().conns.get_mut(&fd) {
+            Some(conn) => std::mem::take(&mut conn.to_client),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_window(&self, fd: i32, window: usize) {
+        if let Some(conn) = self.net.borrow_mut().conns.get_mut(&fd) {
+            conn.window = window;
+        }
+    }
+
+    /// Client side hangs up.
+    pub fn close(&self, fd: i32) {
+        if let Some(conn) = self.net.borrow_mut().conns.get_mut(&fd) {
+            conn.client_closed = true;
+        }
+    }
+
+    pub fn server_closed(&self, fd: i32) -> bool {
+        self.net.borrow().conns.get(&fd).is_none_or(|c| c.server_closed)
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.net.borrow_mut().elapsed += by;
+    }
+}
+
+impl Sys for SimNet {
+    fn wait(&self, ready: &mut Vec<i32>, timeout_ms: i32) -> Result<()> {
+        let mut net = self.net.borrow_mut();
+        for &fd in &net.watched {
+            let ready_now = match net.conns.get(&fd) {
+                Some(conn) => {
+                    let writable = net.writers.contains(&fd) && conn.to_client.len() < conn.window;
+                    let readable = !net.paused.contains(&fd) && !conn.to_server.is_empty();
+                    writable || readable || conn.client_closed
+                }
+                None => fd == SIM_LISTENER && !net.backlog.is_empty(),
+            };
+            if ready_now {
+                ready.push(fd);
+            }
+        }
+
+        // nothing will ever become ready on its own, so a timeout simply passes
+        if ready.is_empty() && timeout_ms > 0 {
+            net.elapsed += Duration::from_millis(timeout_ms as u64);
+        }
+        Ok(())
+    }
+
+    fn watch(&self, fd: i32) -> Result<()> {
+        let mut net = self.net.borrow_mut();
+        if fd != SIM_LISTENER && !net.conns.contains_key(&fd) {
+            return Err(Error::new(ErrorKind::Unsupported, "fd is not part of the simulation"));
+        }
+        net.watched.insert(fd);
+        Ok(())
+    }
+
+    fn unwatch(&self, fd: i32) {
+        let mut net = self.net.borrow_mut();
+        net.watched.remove(&fd);
+        net.writers.remove(&fd);
+        net.paused.remove(&fd);
+    }
+
+    fn set_interest(&self, fd: i32, readable: bool, writable: bool) -> Result<()> {
+        let mut net = self.net.borrow_mut();
+        if !net.watched.contains(&fd) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        if writable {
+            net.writers.insert(fd);
+        } else {
+            net.writers.remove(&fd);
+        }
+        if readable {
+            net.paused.remove(&fd);
+        } else {
+            net.paused.insert(fd);
+        }
+        Ok(())
+    }
+
+    fn accept(&self, _listener: i32) -> Result<(i32, SocketAddr)> {
+        let mut net = self.net.borrow_mut();
+        let fd = net.backlog.pop_front().ok_or(Error::from(ErrorKind::WouldBlock))?;
+        Ok((fd, net.conns[&fd].addr))
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize> {
+        let mut net = self.net.borrow_mut();
+        let conn = net.conns.get_mut(&fd).ok_or(Error::from_raw_os_error(libc::EBADF))?;
+        if conn.to_server.is_empty() {
+            return if conn.client_closed { Ok(0) } else { Err(Error::from(ErrorKind::WouldBlock)) };
+        }
+
+        let n = buf.len().min(conn.to_server.len());
+        for (dst, src) in buf.iter_mut().zip(conn.to_server.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        let mut net = self.net.borrow_mut();
+        let conn = net.conns.get_mut(&fd).ok_or(Error::from_raw_os_error(libc::EBADF))?;
+        if conn.client_closed {
+            return Err(Error::from(ErrorKind::BrokenPipe));
+        }
+
+        let n = buf.len().min(conn.window.saturating_sub(conn.to_client.len()));
+        if n == 0 && !buf.is_empty() {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        conn.to_client.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn close(&self, fd: i32) {
+        let mut net = self.net.borrow_mut();
+        net.watched.remove(&fd);
+        net.writers.remove(&fd);
+        net.paused.remove(&fd);
+        if let Some(conn) = net.conns.get_mut(&fd) {
+            conn.server_closed = true;
+        }
+    }
+
+    fn peer_addr(&self, fd: i32) -> Result<SocketAddr> {
+        let net = self.net.borrow();
+        net.conns.get(&fd).map(|c| c.addr).ok_or(Error::from_raw_os_error(libc::ENOTCONN))
+    }
+
+    fn queued_bytes(&self, fd: i32) -> usize {
+        self.net.borrow().conns.get(&fd).map_or(0, |c| c.to_client.len())
+    }
+
+    fn now(&self) -> Instant {
+        let net = self.net.borrow();
+        net.start + net.elapsed
+    }
+}
+
+/// Runs the server against a scenario file instead of real sockets. Each line is a
+/// step, after which the server handles events until nothing is ready:
+///
+/// ```text
+/// connect <name> [ip:port]    open a connection
+/// send <name> <text>          send text and a newline
+/// write <name> <text>         send text as is, `\n` is a newline
+/// close <name>                hang up the client side
+/// window <name> <bytes>       cap what the client takes in per step (slow reader)
+/// advance <ms>                move the clock forward
+/// expect <name> <text>        the next line name received must be text
+/// expect-nothing <name>       name has received nothing unread
+/// expect-closed <name>        the server closed name
+/// ```
+///
+/// Lines starting with `#` are comments. Fails on the first unmet expectation.
+pub fn run_scenario(path: &Path, net: Rc<SimNet>, mut epserver: EpollServer) -> Result<()> {
+    let text = fs::read_to_string(path)?;
+    let mut clients: HashMap<i32, RefCell<ClientState>> = HashMap::new();
+    let mut names: HashMap<String, i32> = HashMap::new();
+    let mut received: HashMap<i32, Vec<u8>> = HashMap::new();
+
+    for (i, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |msg: String| Error::other(format!("{}:{}: {}", path.display(), i + 1, msg));
+
+        let (step, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let (name, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+        let fd = names.get(name).copied();
+        let known = |fd: Option<i32>| fd.ok_or_else(|| fail(format!("unknown client {}", name)));
+
+        match step {
+            "connect" => {
+                let octet = names.len() % 250 + 2;
+                let addr = match arg {
+                    "" => format!("10.0.0.{}:40000", octet).parse(),
+                    addr => addr.parse(),
+                };
+                let addr = addr.map_err(|_| fail(format!("invalid address {}", arg)))?;
+                names.insert(name.to_string(), net.connect(addr));
+            }
+            "send" => net.send(known(fd)?, format!("{}\n", arg).as_bytes()),
+            "write" => net.send(known(fd)?, arg.replace("\\n", "\n").as_bytes()),
+            "close" => net.close(known(fd)?),
+            "window" => {
+                let window = arg.parse().map_err(|_| fail(format!("invalid window {}", arg)))?;
+                net.set_window(known(fd)?, window);
+            }
+            "advance" => {
+                let ms = name.parse().map_err(|_| fail(format!("invalid duration {}", name)))?;
+                net.advance(Duration::from_millis(ms));
+            }
+            "expect" | "expect-nothing" | "expect-closed" => {}
+            _ => return Err(fail(format!("unknown step `{}`", step))),
+        }
+
+        // run the server until it has nothing left to do
+        while crate::poll_once(&mut epserver, &mut clients, 0)? > 0 {}
+
+        for fd in names.values() {
+            received.entry(*fd).or_default().extend(net.recv(*fd));
+        }
+
+        match step {
+            "expect" => {
+                let buf = received.entry(known(fd)?).or_default();
+                let got = match buf.iter().position(|&b| b == b'\n') {
+                    Some(n) => buf.drain(..=n).collect::<Vec<u8>>(),
+                    None => return Err(fail(format!("{} received nothing, expected `{}`", name, arg))),
+                };
+                let got = String::from_utf8_lossy(&got);
+                if got.trim_end_matches('\n') != arg {
+                    return Err(fail(format!("{} received `{}`, expected `{}`", name, got.trim_end(), arg)));
+                }
+            }
+            "expect-nothing" => {
+                let buf = received.entry(known(fd)?).or_default();
+                if !buf.is_empty() {
+                    return Err(fail(format!("{} received `{}`", name, String::from_utf8_lossy(buf).trim_end())));
+                }
+            }
+            "expect-closed" if !net.server_closed(known(fd)?) => {
+                return Err(fail(format!("{} is still connected", name)));
+            }
+            _ => {}
+        }
+    }
+
+    println!("scenario {} passed", path.display());
+    Ok(())
+}
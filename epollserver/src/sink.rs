@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sys::Sys;
+use crate::Metadata;
+
+pub static SINK_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Datagrams kept while a sink socket is full, older ones are dropped first.
+const MAX_PENDING: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Syslog,
+    Gelf,
+}
+
+/// Forwards every broadcast as a UDP datagram to a log collector, given as
+/// `syslog://host[:514]` (RFC 5424) or `gelf://host[:12201]` (uncompressed GELF).
+/// The senders metadata goes along as structured data, or GELF additional fields.
+///
+/// Sends never block: datagrams the socket can't take wait until the event loop
+/// reports it writable again.
+pub struct Sink {
+    format: Format,
+    socket: UdpSocket,
+    hostname: String,
+    pending: RefCell<VecDeque<Vec<u8>>>,
+}
+
+impl Sink {
+    pub fn open(url: &str) -> Result<Sink> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("invalid sink {} -- {}", url, msg));
+        let (format, rest) = match url.split_once("://") {
+            Some(("syslog", rest)) => (Format::Syslog, rest),
+            Some(("gelf", rest)) => (Format::Gelf, rest),
+            _ => return Err(invalid("expected syslog://host:port or gelf://host:port")),
+        };
+        let default_port = match format {
+            Format::Syslog => 514,
+            Format::Gelf => 12201,
+        };
+        let addr = rest.to_socket_addrs()
+            .or_else(|_| (rest, default_port).to_socket_addrs())?
+            .next()
+            .ok_or_else(|| invalid("host not found"))?;
+
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.set_nonblocking(true)?;
+        socket.connect(addr)?;
+        Ok(Sink { format, socket, hostname: hostname(), pending: RefCell::new(VecDeque::new()) })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.socket.as_raw_fd()
+    }
+
+    /// Forwards a single message (without its newline) and its senders metadata.
+    pub fn send(&self, sys: &dyn Sys, message: &[u8], meta: &Metadata) {
+        let datagram = self.encode(message, meta);
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_empty() {
+            match self.socket.send(&datagram) {
+                Ok(_) => return,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    let _ = sys.set_interest(self.fd(), false, true);
+                }
+                Err(_) => {
+                    // nobody listening right now, collectors come and go
+                    SINK_DROPPED.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+        if pending.len() == MAX_PENDING {
+            pending.pop_front();
+            SINK_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        pending.push_back(datagram);
+    }
+
+    /// Sends what waited for the socket, once it is writable.
+    pub fn flush(&self, sys: &dyn Sys) {
+        let mut pending = self.pending.borrow_mut();
+        while let Some(datagram) = pending.front() {
+            match self.socket.send(datagram) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Ok(_) => {}
+                Err(_) => {
+                    SINK_DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            pending.pop_front();
+        }
+        let _ = sys.set_interest(self.fd(), false, false);
+    }
+
+    fn encode(&self, message: &[u8], meta: &Metadata) -> Vec<u8> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.format {
+            Format::Syslog => {
+                // 32473 is the enterprise number reserved for examples, good enough for private ids
+                let data = match meta.is_empty() {
+                    true => "-".to_string(),
+                    false => {
+                        let params: Vec<String> = meta.iter().map(|(k, v)| format!("{}=\"{}\"", k, sd_escape(v))).collect();
+                        format!("[meta@32473 {}]", params.join(" "))
+                    }
+                };
+                // facility user, severity informational, timestamp left to the collector
+                let mut out = format!("<14>1 - {} epollserver {} - {} ", self.hostname, std::process::id(), data).into_bytes();
+                out.extend_from_slice(message);
+                out
+            }
+            Format::Gelf => {
+                // additional fields start with _, and _id is reserved
+                let fields: String = meta.iter().filter(|(k, _)| *k != "id")
+                    .map(|(k, v)| format!(",\"_{}\":\"{}\"", k, json_escape(v)))
+                    .collect();
+                format!(
+                    "{{\"version\":\"1.1\",\"host\":\"{}\",\"short_message\":\"{}\",\"timestamp\":{}.{:03},\"level\":6{}}}",
+                    json_escape(&self.hostname),
+                    json_escape(&String::from_utf8_lossy(message)),
+                    now.as_secs(),
+                    now.subsec_millis(),
+                    fields
+                ).into_bytes()
+            }
+        }
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } < 0 {
+        return "-".to_string();
+    }
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+/// Escapes a structured data parameter value, RFC 5424 section 6.3.3.
+fn sd_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::logging::{info, warning};
+use crate::{notify, peer_name, remove_client, ClientState, EpollServer};
+
+pub static SLOW_CONSUMERS: AtomicUsize = AtomicUsize::new(0);
+
+/// What happens to a client once it is found to be a slow consumer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowPolicy {
+    /// Only log it and mark it in `/who`, the admin `list` and dumps.
+    Warn,
+    /// Also throw away the broadcasts queued for it, keeping server notices.
+    Drop,
+    Disconnect,
+}
+
+impl FromStr for SlowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SlowPolicy, String> {
+        match s {
+            "warn" => Ok(SlowPolicy::Warn),
+            "drop" => Ok(SlowPolicy::Drop),
+            "disconnect" => Ok(SlowPolicy::Disconnect),
+            _ => Err(format!("unknown slow consumer policy {:?}, expected warn, drop or disconnect", s)),
+        }
+    }
+}
+
+/// A client is slow once data has been waiting in its outbox for longer than
+/// threshold without it draining, so a burst it catches up on doesn't count.
+pub struct SlowConsumers {
+    pub threshold: Duration,
+    pub policy: SlowPolicy,
+}
+
+/// Marks clients backed up past the threshold as slow and applies the policy,
+/// run every tick.
+pub fn check(epserver: &EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
+    let Some(slow) = &epserver.slow_consumers else { return };
+    let now = epserver.sys.now();
+    let mut disconnect = Vec::new();
+    for (cfd, client) in clients.iter() {
+        let mut client = client.borrow_mut();
+        let Some(since) = client.backed_up_since else { continue };
+        let backed_up = now.saturating_duration_since(since);
+        if client.slow || backed_up < slow.threshold {
+            continue;
+        }
+        client.slow = true;
+        SLOW_CONSUMERS.fetch_add(1, Ordering::Relaxed);
+        let addr = peer_name(&client);
+        warning!(
+            "slow consumer fd={} nick={} addr={} queued={} backed_up_ms={} policy={:?}",
+            cfd, client.nick.as_deref().unwrap_or("-"), addr, client.outbox.len(), backed_up.as_millis(), slow.policy
+        );
+        match slow.policy {
+            SlowPolicy::Warn => {}
+            SlowPolicy::Drop => {
+                let dropped = client.outbox.drop_data();
+                notify(epserver, &mut client, format!("* too slow, dropped {} bytes of messages\n", dropped).as_bytes());
+            }
+            SlowPolicy::Disconnect => disconnect.push(*cfd),
+        }
+    }
+    for cfd in disconnect {
+        remove_client(epserver, cfd, clients, "slow consumer");
+    }
+}
+
+/// Called once a backed up clients outbox drained.
+pub fn caught_up(client: &mut ClientState, epserver: &EpollServer) {
+    let Some(since) = client.backed_up_since.take() else { return };
+    let residence = epserver.sys.now().saturating_duration_since(since);
+    crate::metrics::QUEUE_RESIDENCE_MS.observe(residence.as_millis() as u64);
+    client.last_residence = Some(residence);
+    if client.slow {
+        client.slow = false;
+        info!("slow consumer fd={} caught up after {}ms", client.fd, residence.as_millis());
+    }
+}
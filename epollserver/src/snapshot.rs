@@ -0,0 +1,213 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::bans::Ban;
+use crate::logging::warning;
+use crate::EpollServer;
+
+/// Version written on the first line, restore refuses newer ones. v2 added
+/// `room` entries, v3 their access rules, v4 `subscription` cursors.
+pub const VERSION: u32 = 4;
+
+const HEADER: &str = "epollbroadcast-snapshot";
+
+/// Writes what operators set up and what clients need after a restart to path:
+/// bans, rooms operators created, locked or capped, the access rules of private
+/// rooms, room topics, durable subscription cursors and the history of every
+/// tenant. Other rooms only live as long as their members'
+/// connections and are left out, so are invites.
+///
+/// One entry per line after the `epollbroadcast-snapshot <version>` header:
+///
+/// ```text
+/// ban <ip> [expiry unix seconds]
+/// room <tenant|-> <#room> [pinned] [locked] [cap=<n>] [key=<password>] [owner=<nick>] [allow=<nick>...]
+/// topic <tenant|-> <#room> <text...>
+/// subscription <tenant|-> <name> <first offset not acked>
+/// history <tenant|-> <next offset>
+/// message <tenant|-> <offset> <text...>
+/// ```
+pub fn save(epserver: &mut EpollServer, path: &Path) -> Result<()> {
+    let mut out = format!("{} {}\n", HEADER, VERSION).into_bytes();
+    for ban in epserver.bans.iter() {
+        out.extend_from_slice(format!("ban {}", ban.ip).as_bytes());
+        if let Some(until) = ban.until {
+            out.extend_from_slice(format!(" {}", until.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())).as_bytes());
+        }
+        out.push(b'\n');
+    }
+    for (tenant, rooms) in epserver.namespaces() {
+        let tenant = if tenant.is_empty() { "-" } else { tenant };
+        for room in rooms.borrow().iter() {
+            let private = room.password.is_some() || !room.allowed.is_empty();
+            if room.pinned || room.locked || room.cap.is_some() || private {
+                let mut entry = format!("room {} {}", tenant, room.name);
+                if room.pinned {
+                    entry.push_str(" pinned");
+                }
+                if room.locked {
+                    entry.push_str(" locked");
+                }
+                if let Some(cap) = room.cap {
+                    entry.push_str(&format!(" cap={}", cap));
+                }
+                if let Some(password) = &room.password {
+                    entry.push_str(&format!(" key={}", password));
+                }
+                if let Some(owner) = room.owner.as_ref().filter(|_| private) {
+                    entry.push_str(&format!(" owner={}", owner));
+                }
+                for nick in &room.allowed {
+                    entry.push_str(&format!(" allow={}", nick));
+                }
+                out.extend_from_slice(format!("{}\n", entry).as_bytes());
+            }
+            if let Some(topic) = &room.topic {
+                out.extend_from_slice(format!("topic {} {} {}\n", tenant, room.name, topic).as_bytes());
+            }
+        }
+    }
+    for (t, name, next) in epserver.subscriptions.borrow().iter() {
+        let tenant = t.map_or("-", |t| epserver.tenants[t].name.as_str());
+        out.extend_from_slice(format!("subscription {} {} {}\n", tenant, name, next).as_bytes());
+    }
+    for t in std::iter::once(None).chain((0..epserver.tenants.len()).map(Some)) {
+        let tenant = t.map_or("-", |t| epserver.tenants[t].name.as_str());
+        let history = epserver.history_for(t).borrow();
+        out.extend_from_slice(format!("history {} {}\n", tenant, history.next_offset()).as_bytes());
+        for (offset, message) in history.messages() {
+            out.extend_from_slice(format!("message {} {} ", tenant, offset).as_bytes());
+            out.extend_from_slice(message);
+            out.push(b'\n');
+        }
+    }
+
+    // write then rename so a crash never leaves a truncated snapshot behind
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, out)?;
+    fs::rename(&tmp, path)
+}
+
+/// Loads a snapshot written by save into a server that has just been set up.
+/// Entries for tenants that are no longer configured are skipped.
+///
+/// Returns the number of entries restored.
+pub fn restore(epserver: &mut EpollServer, path: &Path) -> Result<usize> {
+    let data = fs::read(path)?;
+    let mut lines = data.split(|&b| b == b'\n').enumerate();
+    let header = lines.next().map(|(_, l)| String::from_utf8_lossy(l).into_owned()).unwrap_or_default();
+    match header.strip_prefix(HEADER).map(|v| v.trim().parse::<u32>()) {
+        Some(Ok(version)) if version <= VERSION => {}
+        Some(Ok(version)) => return Err(invalid(1, &format!("snapshot version {} is newer than {}", version, VERSION))),
+        _ => return Err(invalid(1, "not a snapshot")),
+    }
+
+    let mut restored = 0;
+    for (n, line) in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(4, |&b| b == b' ');
+        let kind = fields.next().unwrap_or_default();
+        let mut field = || fields.next().map(|f| String::from_utf8_lossy(f).into_owned());
+        let entry = match kind {
+            b"ban" => restore_ban(epserver, field(), field()),
+            b"room" | b"topic" | b"subscription" | b"history" | b"message" => {
+                let tenant = field().ok_or_else(|| invalid(n + 1, "missing tenant"))?;
+                let t = match tenant.as_str() {
+                    "-" => None,
+                    name => match epserver.tenants.iter().position(|t| t.name == name) {
+                        Some(t) => Some(t),
+                        None => {
+                            warning!("{} line {}: skipping entry for unknown tenant {}", path.display(), n + 1, name);
+                            continue;
+                        }
+                    },
+                };
+                match kind {
+                    b"room" => restore_room(epserver, t, field(), field()),
+                    b"topic" => restore_topic(epserver, t, field(), field()),
+                    b"subscription" => restore_subscription(epserver, t, field(), field()),
+                    b"history" => restore_next(epserver, t, field()),
+                    _ => restore_message(epserver, t, field(), line),
+                }
+            }
+            _ => Err("unknown entry"),
+        };
+        entry.map_err(|e| invalid(n + 1, e))?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+fn restore_ban(epserver: &mut EpollServer, ip: Option<String>, until: Option<String>) -> std::result::Result<(), &'static str> {
+    let ip = ip.and_then(|ip| ip.parse::<IpAddr>().ok()).ok_or("invalid address")?;
+    let until = match until {
+        Some(secs) => Some(UNIX_EPOCH + Duration::from_secs(secs.parse().map_err(|_| "invalid ban expiry")?)),
+        None => None,
+    };
+    epserver.bans.restore(Ban { ip, until }).map_err(|_| "failed to save ban list")
+}
+
+fn restore_room(epserver: &mut EpollServer, t: Option<usize>, room: Option<String>, flags: Option<String>) -> std::result::Result<(), &'static str> {
+    let name = room.filter(|r| crate::rooms::valid_name(r)).ok_or("invalid room name")?;
+    let mut rooms = epserver.rooms_for(t).borrow_mut();
+    // pinned for now so it exists, set as saved below
+    rooms.create(&name);
+    let room = rooms.get_mut(&name).ok_or("invalid room name")?;
+    room.pinned = false;
+    for flag in flags.as_deref().unwrap_or_default().split(' ').filter(|f| !f.is_empty()) {
+        match flag {
+            "pinned" => room.pinned = true,
+            "locked" => room.locked = true,
+            _ => match flag.split_once('=') {
+                Some(("cap", cap)) => room.cap = Some(cap.parse().map_err(|_| "invalid room cap")?),
+                Some(("key", password)) => room.password = Some(password.to_string()),
+                Some(("owner", nick)) => room.owner = Some(nick.to_string()),
+                Some(("allow", nick)) => {
+                    room.allowed.insert(nick.to_string());
+                }
+                _ => return Err("invalid room flag"),
+            },
+        }
+    }
+    Ok(())
+}
+
+fn restore_topic(epserver: &mut EpollServer, t: Option<usize>, room: Option<String>, text: Option<String>) -> std::result::Result<(), &'static str> {
+    let room = room.filter(|r| crate::rooms::valid_name(r)).ok_or("invalid room name")?;
+    epserver.rooms_for(t).borrow_mut().set_topic(&room, Some(text.ok_or("missing topic")?));
+    Ok(())
+}
+
+fn restore_subscription(epserver: &mut EpollServer, t: Option<usize>, name: Option<String>, next: Option<String>) -> std::result::Result<(), &'static str> {
+    let name = name.filter(|n| crate::subscriptions::valid_name(n)).ok_or("invalid subscription name")?;
+    let next = next.and_then(|n| n.parse().ok()).ok_or("invalid offset")?;
+    epserver.subscriptions.borrow_mut().restore(t, &name, next);
+    Ok(())
+}
+
+fn restore_next(epserver: &mut EpollServer, t: Option<usize>, next: Option<String>) -> std::result::Result<(), &'static str> {
+    let next = next.and_then(|n| n.parse().ok()).ok_or("invalid offset")?;
+    epserver.history_for(t).borrow_mut().advance(next);
+    Ok(())
+}
+
+fn restore_message(epserver: &mut EpollServer, t: Option<usize>, offset: Option<String>, line: &[u8]) -> std::result::Result<(), &'static str> {
+    let offset: u64 = offset.and_then(|o| o.parse().ok()).ok_or("invalid offset")?;
+    // the text is taken from the raw line, it need not be utf-8
+    let text = line.splitn(4, |&b| b == b' ').nth(3).unwrap_or_default();
+    let mut history = epserver.history_for(t).borrow_mut();
+    if history.messages().last().is_some_and(|(last, _)| offset <= *last) {
+        return Err("message offsets have to increase");
+    }
+    history.push_at(offset, text);
+    Ok(())
+}
+
+fn invalid(line: usize, e: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, e))
+}
@@ -0,0 +1,237 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::logging::info;
+use crate::{check_message, health, take_messages, welcome, ClientState, EpollServer, BUFFER_SIZE};
+
+pub static SNIFFED_TLS: AtomicUsize = AtomicUsize::new(0);
+pub static SNIFFED_HTTP: AtomicUsize = AtomicUsize::new(0);
+pub static SNIFFED_PROXY: AtomicUsize = AtomicUsize::new(0);
+
+/// How long a client may stay silent before it counts as a line client, one that
+/// only listens. It is only checked on ticks.
+const SILENT: Duration = Duration::from_millis(100);
+
+const PROXY_V1: &[u8] = b"PROXY ";
+const PROXY_V2: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest PROXY v1 header, crlf included.
+const PROXY_V1_MAX: usize = 107;
+const METHODS: [&[u8]; 7] = [b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH "];
+
+/// What a connection speaks, going by its first bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Line,
+    /// A TLS ClientHello.
+    Tls,
+    /// An HTTP request, headers complete.
+    Http,
+    /// A PROXY protocol v1 or v2 header.
+    Proxy,
+}
+
+/// Tells what data, the first bytes a client sent, is.
+///
+/// Returns None while it could still be more than one thing.
+pub fn detect(data: &[u8]) -> Option<Protocol> {
+    let is = |prefix: &[u8]| data.starts_with(prefix);
+    let could_be = |prefix: &[u8]| prefix.starts_with(&data[..data.len().min(prefix.len())]);
+
+    match data {
+        [] | [0x16] => return None,
+        [0x16, 0x03, ..] => return Some(Protocol::Tls),
+        _ => {}
+    }
+    if is(PROXY_V1) || is(PROXY_V2) {
+        return Some(Protocol::Proxy);
+    }
+    if METHODS.iter().any(|m| is(m)) {
+        let first = match data.iter().position(|&b| b == b'\n') {
+            Some(end) => &data[..end],
+            None => return None,
+        };
+        // GET with anything but a request line is a message like any other
+        if !first.trim_ascii_end().split(|&b| b == b' ').next_back().is_some_and(|v| v.starts_with(b"HTTP/")) {
+            return Some(Protocol::Line);
+        }
+        let complete = data.windows(4).any(|w| w == b"\r\n\r\n") || data.windows(2).any(|w| w == b"\n\n");
+        return complete.then_some(Protocol::Http);
+    }
+    if could_be(PROXY_V1) || could_be(PROXY_V2) || METHODS.iter().any(|m| could_be(m)) {
+        return None;
+    }
+    Some(Protocol::Line)
+}
+
+/// What to do with a client that was being sniffed, after it sent more.
+pub enum Sniffed {
+    /// Nothing to tell yet.
+    Wait,
+    /// It speaks the line protocol, what it sent so far are messages.
+    Line,
+    /// It was answered if it could be, and goes.
+    Close(&'static str),
+}
+
+/// Looks at what the client sent so far, on listeners with `sniff = true`, so the
+/// line protocol, TLS, HTTP and PROXY protocol can share one port:
+///
+/// * a PROXY header (v1 or v2) is taken off, on `proxy_protocol` listeners, and
+///   the client address is the one it names from then on
+/// * HTTP requests get the `/healthz` and `/readyz` probes answered, 404 for
+///   anything else and 501 for WebSocket upgrades
+/// * TLS handshakes are closed, the server has no TLS
+///
+/// Clients are told apart before they get anything, presence notices included;
+/// ones that send nothing count as line clients after a short while.
+pub(crate) fn sniff(client: &mut ClientState, epserver: &EpollServer) -> Sniffed {
+    loop {
+        let data = &client.buf[..client.off];
+        match detect(data) {
+            None if client.off < BUFFER_SIZE => return Sniffed::Wait,
+            None | Some(Protocol::Line) => return Sniffed::Line,
+            Some(Protocol::Tls) => {
+                SNIFFED_TLS.fetch_add(1, Ordering::Relaxed);
+                info!("fd {} started a TLS handshake, closed as there is no TLS", client.fd);
+                return Sniffed::Close("tls");
+            }
+            Some(Protocol::Http) => {
+                SNIFFED_HTTP.fetch_add(1, Ordering::Relaxed);
+                let (status, body) = answer(data, epserver);
+                let _ = epserver.sys.write(client.fd, health::response(status, &body).as_bytes());
+                return Sniffed::Close("http");
+            }
+            Some(Protocol::Proxy) => {
+                if !client.proxied {
+                    info!("fd {} sent a PROXY header where none is expected, closed", client.fd);
+                    return Sniffed::Close("proxy");
+                }
+                let (len, source) = match proxy_header(data) {
+                    None if client.off < BUFFER_SIZE => return Sniffed::Wait,
+                    None | Some(Err(_)) => {
+                        info!("fd {} sent an invalid PROXY header, closed", client.fd);
+                        return Sniffed::Close("proxy");
+                    }
+                    Some(Ok(header)) => header,
+                };
+                SNIFFED_PROXY.fetch_add(1, Ordering::Relaxed);
+                client.proxied = false;
+                if let Some(source) = source {
+                    info!("fd {} is proxied for {}", client.fd, source);
+                    if epserver.bans.is_banned(source.ip()) {
+                        info!("refused banned client {}", source);
+                        return Sniffed::Close("banned");
+                    }
+                    client.peer = Some(source);
+                }
+                client.buf.copy_within(len..client.off, 0);
+                client.off -= len;
+                if client.off == 0 {
+                    return Sniffed::Wait;
+                }
+            }
+        }
+    }
+}
+
+/// Lets clients that stayed silent on sniffing listeners in as line clients.
+pub(crate) fn check(epserver: &EpollServer, clients: &HashMap<i32, RefCell<ClientState>>) {
+    let now = epserver.sys.now();
+    for client in clients.values() {
+        let mut client = client.borrow_mut();
+        if client.sniffed || now.saturating_duration_since(client.last_active) < SILENT {
+            continue;
+        }
+        client.sniffed = true;
+        welcome(&mut client, epserver, clients);
+        // bytes that could have been the start of something else are messages now
+        if client.off > 0 && check_message(&mut client, 0, epserver).unwrap_or(false) {
+            take_messages(&mut client, epserver, clients);
+        }
+    }
+}
+
+/// The response to an HTTP request head.
+fn answer(head: &[u8], epserver: &EpollServer) -> (u16, String) {
+    let head = String::from_utf8_lossy(head);
+    let websocket = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("upgrade:") && line.contains("websocket")
+    });
+    if websocket {
+        return (501, "websocket is not supported, connect with the line protocol\n".to_string());
+    }
+    match head.lines().next().and_then(|line| health::request_path(line.trim_end())) {
+        Some(path) => health::probe(path, epserver),
+        None => (404, "not found\n".to_string()),
+    }
+}
+
+/// Parses a PROXY protocol header at the start of data.
+///
+/// Returns its length and the source address it names, if it names one, or None
+/// while it is incomplete.
+fn proxy_header(data: &[u8]) -> Option<Result<(usize, Option<SocketAddr>), ()>> {
+    if data.starts_with(PROXY_V2) {
+        if data.len() < 16 {
+            return None;
+        }
+        let len = 16 + u16::from_be_bytes([data[14], data[15]]) as usize;
+        if data.len() < len {
+            return None;
+        }
+        if data[12] >> 4 != 2 {
+            return Some(Err(()));
+        }
+        // LOCAL connections are the proxy itself, health checks and the like
+        if data[12] & 0x0f == 0 {
+            return Some(Ok((len, None)));
+        }
+        let addresses = &data[16..len];
+        let source = match data[13] >> 4 {
+            1 if addresses.len() >= 12 => {
+                let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+                Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([addresses[8], addresses[9]])))
+            }
+            2 if addresses.len() >= 36 => {
+                let octets: [u8; 16] = addresses[..16].try_into().ok()?;
+                Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), u16::from_be_bytes([addresses[32], addresses[33]])))
+            }
+            _ => None,
+        };
+        return Some(Ok((len, source)));
+    }
+
+    let end = match data.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= PROXY_V1_MAX => end,
+        Some(_) => return Some(Err(())),
+        None if data.len() < PROXY_V1_MAX => return None,
+        None => return Some(Err(())),
+    };
+    let line = String::from_utf8_lossy(&data[..end]);
+    let words: Vec<&str> = line.split(' ').collect();
+    let source = match words[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => match (source.parse::<IpAddr>(), port.parse::<u16>()) {
+            (Ok(ip), Ok(port)) => Some(SocketAddr::new(ip, port)),
+            _ => return Some(Err(())),
+        },
+        _ => return Some(Err(())),
+    };
+    Some(Ok((end + 2, source)))
+}
+
+/// Connections told apart by protocol, in prometheus text format.
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP epollbroadcast_sniffed_total Connections on sniffing listeners that spoke something else than the line protocol.");
+    let _ = writeln!(out, "# TYPE epollbroadcast_sniffed_total counter");
+    for (protocol, count) in [("tls", &SNIFFED_TLS), ("http", &SNIFFED_HTTP), ("proxy", &SNIFFED_PROXY)] {
+        let _ = writeln!(out, "epollbroadcast_sniffed_total{{protocol=\"{}\"}} {}", protocol, count.load(Ordering::Relaxed));
+    }
+    out
+}
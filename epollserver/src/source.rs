@@ -0,0 +1,410 @@
+use std::cell::{Cell, RefCell};
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::logging::{info, warning};
+use crate::rooms;
+use crate::sys::Sys;
+use crate::tail::Tail;
+
+pub static SHED: AtomicUsize = AtomicUsize::new(0);
+/// Sources not read from right now, see Backpressure::Pause.
+pub static PAUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// Wait before reopening after the first failure, doubled for every further one
+/// up to MAX_RETRY.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// What a source reads lines from.
+#[derive(Clone, Debug)]
+pub enum Device {
+    /// A serial port or other tty, put in raw mode at baud, see `--serial`.
+    Serial { path: PathBuf, baud: u32 },
+    /// A named pipe, created if missing, see `--fifo`. It is held open for
+    /// writing as well, so it never reads as closed when a writer quits and the
+    /// next `echo >>` just goes on where the last left off.
+    Fifo(PathBuf),
+    /// A file followed as it grows, see Tail and `--tail`.
+    Tail(PathBuf),
+}
+
+impl Device {
+    /// Parses `--serial <path>@<baud>`.
+    pub fn serial(spec: &str) -> Result<Device> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("invalid serial port {} -- {}", spec, msg));
+        let (path, baud) = spec.rsplit_once('@').ok_or_else(|| invalid("expected <path>@<baud>"))?;
+        let baud = baud.parse().map_err(|_| invalid("baud is not a number"))?;
+        speed(baud).ok_or_else(|| invalid("unsupported baud rate"))?;
+        Ok(Device::Serial { path: PathBuf::from(path), baud })
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Device::Serial { path, .. } | Device::Fifo(path) | Device::Tail(path) => path,
+        }
+    }
+
+    /// The name its lines are sent under.
+    fn label(&self) -> String {
+        match self {
+            Device::Serial { path, .. } | Device::Tail(path) => path.file_name().map_or_else(|| "serial".to_string(), |n| n.to_string_lossy().into_owned()),
+            Device::Fifo(_) => "pipe".to_string(),
+        }
+    }
+
+    /// Opens the device for nonblocking reads.
+    ///
+    /// Returns the fd to read, and what copies into it for tailed files.
+    fn open(&self) -> Result<(i32, Option<Tail>)> {
+        let path = CString::new(self.path().as_os_str().as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let access = match self {
+            Device::Serial { .. } => libc::O_RDONLY | libc::O_NOCTTY,
+            Device::Tail(path) => {
+                let (tail, fd) = Tail::open(path)?;
+                return Ok((fd, Some(tail)));
+            }
+            Device::Fifo(_) => {
+                if unsafe { libc::mkfifo(path.as_ptr(), 0o660) } < 0 && Error::last_os_error().kind() != ErrorKind::AlreadyExists {
+                    return Err(Error::last_os_error());
+                }
+                libc::O_RDWR
+            }
+        };
+        let fd = unsafe { libc::open(path.as_ptr(), access | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let set_up = match self {
+            Device::Serial { baud, .. } => raw_mode(fd, *baud),
+            Device::Fifo(_) => is_fifo(fd),
+            Device::Tail(_) => Ok(()),
+        };
+        if let Err(e) = set_up {
+            unsafe { libc::close(fd); }
+            return Err(e);
+        }
+        Ok((fd, None))
+    }
+}
+
+/// The termios speed for baud.
+fn speed(baud: u32) -> Option<libc::speed_t> {
+    Some(match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        921600 => libc::B921600,
+        _ => return None,
+    })
+}
+
+/// Fails unless fd is a named pipe, rather than reading some other file.
+fn is_fifo(fd: i32) -> Result<()> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a named pipe"));
+    }
+    Ok(())
+}
+
+/// Turns off echo and line editing, so bytes arrive as the device sent them.
+fn raw_mode(fd: i32, baud: u32) -> Result<()> {
+    let speed = speed(baud).ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+    let mut tio: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut tio) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    unsafe {
+        libc::cfmakeraw(&mut tio);
+        libc::cfsetispeed(&mut tio, speed);
+        libc::cfsetospeed(&mut tio, speed);
+    }
+    tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tio) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// What a source does while the server is overloaded, so a feed nobody can keep
+/// up with is not fanned out in full regardless.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backpressure {
+    /// Stop reading it, what it sends waits in the kernel.
+    Pause,
+    /// Drop its lines.
+    Drop,
+    /// Keep one line in n, drop the others.
+    Sample(u64),
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Backpressure, String> {
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            ["pause"] => Ok(Backpressure::Pause),
+            ["drop"] => Ok(Backpressure::Drop),
+            ["sample", n] => match n.parse() {
+                Ok(n) if n > 0 => Ok(Backpressure::Sample(n)),
+                _ => Err(format!("can't keep 1 in {:?} lines", n)),
+            },
+            _ => Err(format!("unknown backpressure {:?}, expected pause, drop or sample <n>", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Opened(i32), // not served yet
+    Serving(i32),
+    Closed { retry_at: Instant, failures: u32 },
+}
+
+/// How the lines of a source go out, see Sources.
+struct Routing {
+    label: String,
+    room: Option<String>,
+    tag: bool,
+    backpressure: Option<Backpressure>,
+}
+
+struct Source {
+    label: String,
+    room: Option<String>,
+    tag: bool,
+    backpressure: Option<Backpressure>,
+    paused: Cell<bool>,
+    device: Device,
+    state: Cell<State>,
+    tail: RefCell<Option<Tail>>,
+}
+
+/// A source fd to be served like a client that only ever sends.
+pub struct Opened {
+    pub fd: i32,
+    pub label: String,
+    /// Where its lines go unless they name a room.
+    pub room: Option<String>,
+    /// Whether its lines go out as `[<label>] <line>`.
+    pub tag: bool,
+    pub backpressure: Option<Backpressure>,
+}
+
+/// Devices whose lines are broadcast as if a client named after them sent them,
+/// for feeds that would otherwise need a shim process to connect for them. A
+/// source that fails, like a serial adapter being unplugged, is reopened until
+/// it is back.
+///
+/// Besides `--serial`, `--fifo` and `--tail`, sources can be given a label of
+/// their own and a room to feed, with one `[source.<label>]` section each:
+///
+/// ```text
+/// [source.gps]
+/// serial = /dev/ttyUSB0@4800   # or fifo = <path>, or tail = <path>
+/// room = #gps
+/// tag = true   # lines go out as `[gps] <line>`, the default
+/// backpressure = sample 100   # or pause or drop, see Backpressure
+/// ```
+///
+/// Every source sets the metadata `source=<label>`, which sinks pass along.
+/// Without `backpressure`, a source is read and fanned out however overloaded
+/// the server is.
+#[derive(Default)]
+pub struct Sources {
+    sources: Vec<Source>,
+}
+
+impl Sources {
+    /// Opens the sources of the `[source.<label>]` sections.
+    pub fn from_config(config: &Config) -> Result<Sources> {
+        let mut sources = Sources::default();
+        for (label, section) in config.sections_with_prefix("source") {
+            let mut devices = Vec::new();
+            if let Some(entry) = section.get("serial") {
+                devices.push(Device::serial(&entry.value).map_err(|e| config.error(entry.line, &e.to_string()))?);
+            }
+            if let Some(entry) = section.get("fifo") {
+                devices.push(Device::Fifo(PathBuf::from(&entry.value)));
+            }
+            if let Some(entry) = section.get("tail") {
+                devices.push(Device::Tail(PathBuf::from(&entry.value)));
+            }
+            if devices.len() != 1 {
+                return Err(config.error(section.line, "source needs one of `serial`, `fifo` or `tail`"));
+            }
+            let room = match section.get("room") {
+                Some(entry) if rooms::valid_name(&entry.value) => Some(entry.value.clone()),
+                Some(entry) => return Err(config.error(entry.line, "room must be a room like `#gps`")),
+                None => None,
+            };
+            let tag = match section.get("tag").map(|e| (e.value.as_str(), e.line)) {
+                None | Some(("true", _)) => true,
+                Some(("false", _)) => false,
+                Some((_, line)) => return Err(config.error(line, "tag must be `true` or `false`")),
+            };
+            let backpressure = match section.get("backpressure") {
+                Some(entry) => Some(entry.value.parse().map_err(|e: String| config.error(entry.line, &e))?),
+                None => None,
+            };
+            let device = devices.remove(0);
+            let routing = Routing { label: label.to_string(), room, tag, backpressure };
+            sources.open(device, routing).map_err(|e| config.error(section.line, &e.to_string()))?;
+        }
+        Ok(sources)
+    }
+
+    /// Adds device under the name it has, without a room or tag.
+    pub fn add(&mut self, device: Device) -> Result<()> {
+        let routing = Routing { label: device.label(), room: None, tag: false, backpressure: None };
+        self.open(device, routing)
+    }
+
+    /// Opens device right away, so a wrong path stops the server starting.
+    fn open(&mut self, device: Device, routing: Routing) -> Result<()> {
+        let (fd, tail) = device.open().map_err(|e| Error::new(e.kind(), format!("cannot open {} -- {}", device.path().display(), e)))?;
+        let Routing { label, room, tag, backpressure } = routing;
+        self.sources.push(Source {
+            label,
+            room,
+            tag,
+            backpressure,
+            paused: Cell::new(false),
+            device,
+            state: Cell::new(State::Opened(fd)),
+            tail: RefCell::new(tail),
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// The paths sources read, for the sandbox to allow. Tailed files get their
+    /// whole directory, where they are rotated to.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.sources.iter().map(|s| match &s.device {
+            Device::Tail(path) => path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")),
+            device => device.path(),
+        })
+    }
+
+    /// Whether fd is what tells a tailed file changed.
+    pub fn owns(&self, fd: i32) -> bool {
+        self.sources.iter().any(|s| s.tail.borrow().as_ref().is_some_and(|t| t.fd() == fd))
+    }
+
+    /// Copies what was appended to the tailed file fd is about.
+    pub fn handle_event(&self, fd: i32) {
+        for source in &self.sources {
+            if let Some(tail) = source.tail.borrow_mut().as_mut().filter(|t| t.fd() == fd) {
+                tail.changed();
+            }
+        }
+    }
+
+    /// Reopens the source whose client fd was removed, if it was one.
+    pub fn closed(&self, sys: &dyn Sys, fd: i32, now: Instant) {
+        if let Some(source) = self.sources.iter().find(|s| matches!(s.state.get(), State::Serving(f) if f == fd)) {
+            if let Some(tail) = source.tail.borrow_mut().take() {
+                sys.unwatch(tail.fd());
+            }
+            if source.paused.replace(false) {
+                PAUSED.fetch_sub(1, Ordering::Relaxed);
+            }
+            info!("source {} closed, reopening in {}s", source.label, RETRY_BACKOFF.as_secs());
+            source.state.set(State::Closed { retry_at: now + RETRY_BACKOFF, failures: 0 });
+        }
+    }
+
+    /// Reopens what is due, goes on copying tailed files that filled their pipe,
+    /// and pauses or resumes reading sources as the server is overloaded or not,
+    /// on every wakeup.
+    ///
+    /// Returns the sources to serve from now on.
+    pub fn poll(&self, sys: &dyn Sys, now: Instant, overloaded: bool) -> Vec<Opened> {
+        let mut opened = Vec::new();
+        for source in &self.sources {
+            let fd = match source.state.get() {
+                State::Opened(fd) => fd,
+                State::Serving(fd) => {
+                    if source.backpressure == Some(Backpressure::Pause) && source.paused.get() != overloaded {
+                        source.paused.set(overloaded);
+                        let _ = sys.set_interest(fd, !overloaded, false);
+                        match overloaded {
+                            true => PAUSED.fetch_add(1, Ordering::Relaxed),
+                            false => PAUSED.fetch_sub(1, Ordering::Relaxed),
+                        };
+                    }
+                    if let Some(tail) = source.tail.borrow_mut().as_mut().filter(|t| t.is_behind()) {
+                        tail.copy();
+                    }
+                    continue;
+                }
+                State::Closed { retry_at, .. } if now < retry_at => continue,
+                State::Closed { failures, .. } => match source.device.open() {
+                    Ok((fd, tail)) => {
+                        info!("reopened source {}", source.label);
+                        *source.tail.borrow_mut() = tail;
+                        fd
+                    }
+                    Err(e) => {
+                        source.failed(now, failures + 1, &e);
+                        continue;
+                    }
+                },
+            };
+            let inotify = source.tail.borrow().as_ref().map(Tail::fd);
+            if let Err(e) = sys.watch(fd).and_then(|_| inotify.map_or(Ok(()), |i| sys.watch(i))) {
+                sys.unwatch(fd);
+                sys.close(fd);
+                source.tail.borrow_mut().take();
+                source.failed(now, 1, &e);
+                continue;
+            }
+            source.state.set(State::Serving(fd));
+            opened.push(Opened { fd, label: source.label.clone(), room: source.room.clone(), tag: source.tag, backpressure: source.backpressure });
+        }
+        opened
+    }
+
+    /// When poll has something to do next, now for sources opened but not
+    /// served yet.
+    pub fn next_due(&self, now: Instant) -> Option<Instant> {
+        self.sources.iter()
+            .filter_map(|s| match s.state.get() {
+                State::Opened(_) => Some(now),
+                State::Closed { retry_at, .. } => Some(retry_at),
+                State::Serving(_) => None,
+            })
+            .min()
+    }
+}
+
+impl Source {
+    fn failed(&self, now: Instant, failures: u32, e: &Error) {
+        if failures == 1 {
+            warning!("cannot reopen source {}, retrying -- {}", self.label, e);
+        }
+        let backoff = RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(failures - 1)).min(MAX_RETRY);
+        self.state.set(State::Closed { retry_at: now + backoff, failures });
+    }
+}
@@ -0,0 +1,149 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::logging::info;
+use crate::{notify, remove_client, send, ClientState, EpollServer};
+
+pub static REDELIVERED: AtomicUsize = AtomicUsize::new(0);
+
+struct Cursor {
+    next: u64, // first offset not acknowledged
+    attached: Option<i32>,
+}
+
+/// Named durable subscriptions of each tenant, for consumers that must not miss
+/// messages across reconnects. A client attaches with `/subscribe <name>` and
+/// acknowledges what it processed with `/ack <offset>`; the cursor keeps where
+/// acknowledgements stopped after the connection is gone, and the next client to
+/// attach gets everything from there on again from history. One that keeps messages
+/// unacknowledged for longer than the ack timeout is disconnected, so it comes
+/// back and gets them redelivered.
+#[derive(Default)]
+pub struct Subscriptions {
+    cursors: BTreeMap<(Option<usize>, String), Cursor>,
+}
+
+/// Subscription names are up to 32 letters, digits, `-`, `_` or `.`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32 && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+impl Subscriptions {
+    /// Attaches fd to the subscription of tenant called name, starting a new one
+    /// at offset next if there is none.
+    ///
+    /// Returns the first offset not acknowledged, or the fd it is attached to already.
+    pub fn attach(&mut self, tenant: Option<usize>, name: &str, fd: i32, next: u64) -> Result<u64, i32> {
+        let cursor = self.cursors.entry((tenant, name.to_string())).or_insert(Cursor { next, attached: None });
+        match cursor.attached {
+            Some(other) if other != fd => Err(other),
+            _ => {
+                cursor.attached = Some(fd);
+                Ok(cursor.next)
+            }
+        }
+    }
+
+    /// Lets another connection attach, keeping the cursor.
+    pub fn detach(&mut self, tenant: Option<usize>, name: &str) {
+        if let Some(cursor) = self.cursors.get_mut(&(tenant, name.to_string())) {
+            cursor.attached = None;
+        }
+    }
+
+    /// Forgets a subscription altogether.
+    pub fn remove(&mut self, tenant: Option<usize>, name: &str) {
+        self.cursors.remove(&(tenant, name.to_string()));
+    }
+
+    /// Moves the cursor past offset, never back.
+    ///
+    /// Returns the first offset not acknowledged.
+    pub fn ack(&mut self, tenant: Option<usize>, name: &str, offset: u64) -> u64 {
+        match self.cursors.get_mut(&(tenant, name.to_string())) {
+            Some(cursor) => {
+                cursor.next = cursor.next.max(offset + 1);
+                cursor.next
+            }
+            None => offset + 1,
+        }
+    }
+
+    /// Sets where a subscription is, as restored from a snapshot.
+    pub fn restore(&mut self, tenant: Option<usize>, name: &str, next: u64) {
+        self.cursors.insert((tenant, name.to_string()), Cursor { next, attached: None });
+    }
+
+    /// Every subscription with its tenant and first offset not acknowledged.
+    pub fn iter(&self) -> impl Iterator<Item = (Option<usize>, &str, u64)> {
+        self.cursors.iter().map(|((t, name), cursor)| (*t, name.as_str(), cursor.next))
+    }
+}
+
+/// Attaches the client to the subscription called name, moving it off any other,
+/// and sends it every retained message it has not acknowledged.
+pub(crate) fn subscribe(client: &mut ClientState, name: &str, epserver: &EpollServer) -> Result<(), String> {
+    let history = epserver.history_for(client.tenant).borrow();
+    let next = epserver.subscriptions.borrow_mut().attach(client.tenant, name, client.fd, history.next_offset())
+        .map_err(|fd| format!("{} is taken by client {}", name, fd))?;
+    if let Some(old) = client.subscription.replace(name.to_string()).filter(|old| old != name) {
+        epserver.subscriptions.borrow_mut().detach(client.tenant, &old);
+    }
+    client.delivered = None;
+    client.unacked_since = None;
+
+    let oldest = history.messages().next().map_or(history.next_offset(), |(o, _)| *o);
+    let mut out = Vec::new();
+    if next < oldest {
+        out.extend_from_slice(format!("* redelivery gap, messages from {} on are gone\n", next).as_bytes());
+    }
+    let mut redelivered = 0;
+    for (at, message) in history.messages().filter(|(o, _)| *o >= next) {
+        out.extend_from_slice(format!("@{} ", at).as_bytes());
+        out.extend_from_slice(message);
+        out.push(b'\n');
+        client.delivered = Some(*at);
+        redelivered += 1;
+    }
+    drop(history);
+    REDELIVERED.fetch_add(redelivered, Ordering::Relaxed);
+    if redelivered > 0 {
+        client.unacked_since = Some(epserver.sys.now());
+    }
+    client.offsets = true;
+    out.extend_from_slice(format!("* subscribed {}, redelivered {} from {}\n", name, redelivered, next).as_bytes());
+    send(epserver, client, &out);
+    Ok(())
+}
+
+/// Records that the client processed everything up to offset.
+pub(crate) fn ack(client: &mut ClientState, offset: u64, epserver: &EpollServer) -> Result<(), String> {
+    let name = client.subscription.as_deref().ok_or("not subscribed")?;
+    let delivered = client.delivered.filter(|d| offset <= *d).ok_or_else(|| format!("{} was not delivered yet", offset))?;
+    let next = epserver.subscriptions.borrow_mut().ack(client.tenant, name, offset);
+    // progress restarts the clock, the rest has as long as the first message had
+    client.unacked_since = (next <= delivered).then(|| epserver.sys.now());
+    Ok(())
+}
+
+/// Disconnects subscribers that kept messages unacknowledged for longer than the
+/// ack timeout, they get them again once they subscribe after reconnecting.
+pub(crate) fn check(epserver: &EpollServer, clients: &mut HashMap<i32, RefCell<ClientState>>) {
+    let now = epserver.sys.now();
+    let overdue: Vec<i32> = clients.values()
+        .map(|c| c.borrow())
+        .filter(|c| c.unacked_since.is_some_and(|since| now.duration_since(since) >= epserver.ack_timeout))
+        .map(|c| c.fd)
+        .collect();
+    for fd in overdue {
+        if let Some(client) = clients.get(&fd) {
+            let mut client = client.borrow_mut();
+            let name = client.subscription.clone().unwrap_or_default();
+            info!("fd {} kept messages of subscription {} unacknowledged for {}s", fd, name, epserver.ack_timeout.as_secs());
+            let notice = format!("* no ack for {}s, reconnect and /subscribe {} to get the rest again\n", epserver.ack_timeout.as_secs(), name);
+            notify(epserver, &mut client, notice.as_bytes());
+        }
+        remove_client(epserver, fd, clients, "ack timeout");
+    }
+}
@@ -0,0 +1,361 @@
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::ManuallyDrop;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream};
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::time::Instant;
+
+/// The syscalls the broadcast core is built on, so the same server logic runs
+/// against real sockets (`Epoll`, `Poll`) or the deterministic in-memory network in sim.rs.
+///
+/// Fds double as event tokens: `wait` reports the fds that became readable.
+pub trait Sys {
+    /// Waits up to timeout_ms (-1 blocks forever) and appends ready fds to ready.
+    fn wait(&self, ready: &mut Vec<i32>, timeout_ms: i32) -> Result<()>;
+
+    /// Starts reporting read readiness of fd.
+    fn watch(&self, fd: i32) -> Result<()>;
+
+    fn unwatch(&self, fd: i32);
+
+    /// Chooses whether wait reports a watched fd when it has data to read, when it
+    /// can take more writes, or both. Hangups are reported either way.
+    fn set_interest(&self, fd: i32, readable: bool, writable: bool) -> Result<()>;
+
+    /// Accepts a nonblocking connection from the listening fd.
+    fn accept(&self, listener: i32) -> Result<(i32, SocketAddr)>;
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize>;
+
+    /// Writes as much of buf as fits right now, WouldBlock if nothing fits.
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize>;
+
+    /// Like write, but hints that more data follows right away, so the kernel
+    /// can put both into one segment instead of sending buf on its own.
+    fn write_more(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        self.write(fd, buf)
+    }
+
+    /// Like write, for data given in parts, written in order as if they were one.
+    fn writev(&self, fd: i32, parts: &[&[u8]]) -> Result<usize> {
+        match parts {
+            [data] => self.write(fd, data),
+            _ => self.write(fd, &parts.concat()),
+        }
+    }
+
+    fn close(&self, fd: i32);
+
+    fn peer_addr(&self, fd: i32) -> Result<SocketAddr>;
+
+    /// Bytes written to fd that the peer has not received yet.
+    fn queued_bytes(&self, fd: i32) -> usize;
+
+    fn now(&self) -> Instant;
+
+    /// An fd that becomes readable whenever wait has something to report, for
+    /// running the server inside another event loop.
+    fn event_fd(&self) -> Option<i32> {
+        None
+    }
+
+    /// Writes all of buf, failing with WouldBlock if the peer can't take it all.
+    fn write_all(&self, fd: i32, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(fd, buf) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(n) => buf = &buf[n..],
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Real sockets multiplexed with epoll.
+pub struct Epoll {
+    epfd: i32,
+    events: RefCell<Vec<libc::epoll_event>>,
+}
+
+impl Epoll {
+    pub fn new(max_events: usize) -> Result<Epoll> {
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epfd < 0 {
+            let errmsg = format!("epoll_create1 failed -- {}", Error::last_os_error());
+            return Err(Error::other(errmsg));
+        }
+
+        let events = vec![libc::epoll_event { events: 0, u64: 0 }; max_events];
+        Ok(Epoll { epfd, events: RefCell::new(events) })
+    }
+}
+
+impl Sys for Epoll {
+    fn wait(&self, ready: &mut Vec<i32>, timeout_ms: i32) -> Result<()> {
+        let mut events = self.events.borrow_mut();
+        let n = unsafe { libc::epoll_wait(self.epfd, events.as_mut_ptr(), events.len() as i32, timeout_ms) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        ready.extend(events[..n as usize].iter().map(|e| e.u64 as i32));
+        Ok(())
+    }
+
+    fn watch(&self, fd: i32) -> Result<()> {
+        let mut e = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: fd as u64
+        };
+
+        if unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, &mut e) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn unwatch(&self, fd: i32) {
+        unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()); }
+    }
+
+    fn set_interest(&self, fd: i32, readable: bool, writable: bool) -> Result<()> {
+        let mut events = 0;
+        if readable {
+            events |= libc::EPOLLIN;
+        }
+        if writable {
+            events |= libc::EPOLLOUT;
+        }
+        let mut e = libc::epoll_event {
+            events: events as u32,
+            u64: fd as u64
+        };
+
+        if unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_MOD, fd, &mut e) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn accept(&self, listener: i32) -> Result<(i32, SocketAddr)> {
+        socket::accept(listener)
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize> {
+        socket::read(fd, buf)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        socket::write(fd, buf)
+    }
+
+    fn writev(&self, fd: i32, parts: &[&[u8]]) -> Result<usize> {
+        socket::writev(fd, parts)
+    }
+
+    fn write_more(&self, fd: i32, buf: &[u8]) -> Result<usize> {
+        socket::write_more(fd, buf)
+    }
+
+    fn close(&self, fd: i32) {
+        socket::close(fd)
+    }
+
+    fn peer_addr(&self, fd: i32) -> Result<SocketAddr> {
+        socket::peer_addr(fd)
+    }
+
+    fn queued_bytes(&self, fd: i32) -> usize {
+        socket::queued_bytes(fd)
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn event_fd(&self) -> Option<i32> {
+        Some(self.epfd)
+    }
+}
+
+/// The socket calls both real backends share, only waiting differs.
+pub(crate) mod socket {
+    use super::*;
+
+    pub(crate) fn accept(listener: i32) -> Result<(i32, SocketAddr)> {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let flags = libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        let fd = unsafe { libc::accept4(listener, &mut storage as *mut _ as *mut libc::sockaddr, &mut len, flags) };
+        if fd < 0 {
+            let e = Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EINVAL) => accept_fallback(listener),
+                _ => Err(e),
+            };
+        }
+
+        match socket_addr(&storage) {
+            Some(addr) => Ok((fd, addr)),
+            None => {
+                unsafe { libc::close(fd); }
+                Err(Error::new(ErrorKind::Unsupported, "client address is not ip"))
+            }
+        }
+    }
+
+    pub(crate) fn read(fd: i32, buf: &mut [u8]) -> Result<usize> {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub(crate) fn write(fd: i32, buf: &[u8]) -> Result<usize> {
+        let n = unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub(crate) fn writev(fd: i32, parts: &[&[u8]]) -> Result<usize> {
+        let iov: Vec<libc::iovec> = parts.iter()
+            .take(libc::UIO_MAXIOV as usize)
+            .map(|p| libc::iovec { iov_base: p.as_ptr() as *mut libc::c_void, iov_len: p.len() })
+            .collect();
+        let n = unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as libc::c_int) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub(crate) fn write_more(fd: i32, buf: &[u8]) -> Result<usize> {
+        let n = unsafe { libc::send(fd, buf.as_ptr() as *const libc::c_void, buf.len(), libc::MSG_MORE) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub(crate) fn close(fd: i32) {
+        unsafe { libc::close(fd); }
+    }
+
+    pub(crate) fn peer_addr(fd: i32) -> Result<SocketAddr> {
+        let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+        stream.peer_addr()
+    }
+
+    pub(crate) fn queued_bytes(fd: i32) -> usize {
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut pending) } < 0 {
+            return 0;
+        }
+        pending.max(0) as usize
+    }
+}
+
+/// Binds a listening socket on addr. IPv6 sockets only take IPv6, so `[::]:port`
+/// and `0.0.0.0:port` can be bound side by side for dual stack.
+pub fn listen(addr: SocketAddr) -> Result<TcpListener> {
+    let v6 = match addr {
+        SocketAddr::V4(_) => return TcpListener::bind(addr),
+        SocketAddr::V6(v6) => v6,
+    };
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // owned from here on, so it is closed on every error below
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    let size = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let mut sin6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sin6.sin6_port = v6.port().to_be();
+    sin6.sin6_addr.s6_addr = v6.ip().octets();
+    sin6.sin6_flowinfo = v6.flowinfo();
+    sin6.sin6_scope_id = v6.scope_id();
+    let failed = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, &on as *const _ as *const libc::c_void, size) < 0
+            || libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &on as *const _ as *const libc::c_void, size) < 0
+            || libc::bind(fd, &sin6 as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t) < 0
+            || libc::listen(fd, 128) < 0
+    };
+    if failed {
+        return Err(Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Starts connecting a nonblocking socket to addr, it is done once writable.
+pub fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    let family = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // owned from here on, so it is closed on every error below
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*v4.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_addr.s6_addr = v6.ip().octets();
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_scope_id = v6.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    if unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len as libc::socklen_t) } < 0 {
+        let e = Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(stream)
+}
+
+/// accept and separate fcntl calls, for kernels without accept4.
+fn accept_fallback(listener: i32) -> Result<(i32, SocketAddr)> {
+    // the listener stays owned by whoever bound it
+    let listener = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(listener) });
+    let (stream, addr) = listener.accept()?;
+    stream.set_nonblocking(true)?;
+    let fd = stream.into_raw_fd();
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+    Ok((fd, addr))
+}
+
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,171 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::logging::{info, warning};
+
+/// Bytes copied from the file per read.
+const CHUNK: usize = 64 * 1024;
+
+/// Follows a file like `tail -F`, see `--tail`. Regular files can't be polled,
+/// so what is appended is copied into a pipe whose other end is read like any
+/// source. The file is watched with inotify, and so is its directory, to notice
+/// it being rotated: a new file under the same path is read from its start, one
+/// truncated in place from its new end. When the pipe is full, copying stops
+/// until the server read what is in it. A new file that can't be watched is
+/// copied from on every tick instead, until watching it works.
+pub(crate) struct Tail {
+    path: PathBuf,
+    inotify: i32,
+    file: File,
+    inode: u64,
+    offset: u64,
+    watch: i32, // on the file, replaced when it is rotated, -1 while that failed
+    pipe: i32, // write end
+    behind: bool, // the pipe was full, there may be more to copy
+}
+
+impl Tail {
+    /// Starts following path from its current end.
+    ///
+    /// Returns it and the end of the pipe to read lines from.
+    pub(crate) fn open(path: &Path) -> Result<(Tail, i32)> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        if !meta.is_file() {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a regular file"));
+        }
+        let inotify = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if inotify < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            let e = Error::last_os_error();
+            unsafe { libc::close(inotify); }
+            return Err(e);
+        }
+        let mut tail = Tail { path: path.to_path_buf(), inotify, file, inode: meta.ino(), offset: meta.len(), watch: -1, pipe: fds[1], behind: false };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let watched = add_watch(inotify, dir, libc::IN_CREATE | libc::IN_MOVED_TO)
+            .and_then(|_| add_watch(inotify, path, FILE_EVENTS));
+        match watched {
+            Ok(watch) => tail.watch = watch,
+            Err(e) => {
+                unsafe { libc::close(fds[0]); }
+                return Err(e); // tail closes the rest
+            }
+        }
+        Ok((tail, fds[0]))
+    }
+
+    /// The inotify fd, readable when the file or its directory changed.
+    pub(crate) fn fd(&self) -> i32 {
+        self.inotify
+    }
+
+    /// Whether copy should be called without inotify saying so, the pipe was
+    /// full or the file is not watched.
+    pub(crate) fn is_behind(&self) -> bool {
+        self.behind || self.watch < 0
+    }
+
+    /// Handles what inotify reported and copies whatever was appended.
+    pub(crate) fn changed(&mut self) {
+        let mut events = [0u8; 4096];
+        while unsafe { libc::read(self.inotify, events.as_mut_ptr() as *mut libc::c_void, events.len()) } > 0 {}
+        self.copy();
+    }
+
+    /// Copies what the file has past offset into the pipe, then switches to the
+    /// file now at path if it was rotated.
+    pub(crate) fn copy(&mut self) {
+        self.behind = false;
+        if self.watch < 0 {
+            self.watch_file();
+        }
+        match fs::metadata(&self.path) {
+            Ok(meta) if meta.ino() == self.inode && meta.len() < self.offset => self.offset = 0, // truncated
+            _ => {}
+        }
+        if !self.drain() {
+            return;
+        }
+        let Ok(meta) = fs::metadata(&self.path) else { return }; // rotated away, not back yet
+        if meta.ino() == self.inode {
+            return;
+        }
+        let Ok(file) = File::open(&self.path) else { return };
+        unsafe { libc::inotify_rm_watch(self.inotify, self.watch); }
+        self.watch_file();
+        self.file = file;
+        self.inode = meta.ino();
+        self.offset = 0;
+        self.drain();
+    }
+
+    /// Watches the file now at path, warning when that fails for the first time.
+    fn watch_file(&mut self) {
+        match add_watch(self.inotify, &self.path, FILE_EVENTS) {
+            Ok(watch) => {
+                if self.watch < 0 {
+                    info!("watching {} again", self.path.display());
+                }
+                self.watch = watch;
+            }
+            Err(e) => {
+                if self.watch >= 0 {
+                    warning!("cannot watch {}, reading it every tick instead -- {}", self.path.display(), e);
+                }
+                self.watch = -1;
+            }
+        }
+    }
+
+    /// Copies the file from offset to its end.
+    ///
+    /// Returns false if the pipe filled up first.
+    fn drain(&mut self) -> bool {
+        let mut buf = vec![0; CHUNK];
+        loop {
+            let n = match self.file.read_at(&mut buf, self.offset) {
+                Ok(0) | Err(_) => return true,
+                Ok(n) => n,
+            };
+            let written = unsafe { libc::write(self.pipe, buf.as_ptr() as *const libc::c_void, n) };
+            if written > 0 {
+                self.offset += written as u64;
+            }
+            if written < n as isize {
+                self.behind = true;
+                return false;
+            }
+        }
+    }
+}
+
+impl Drop for Tail {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.inotify);
+            libc::close(self.pipe);
+        }
+    }
+}
+
+const FILE_EVENTS: u32 = libc::IN_MODIFY | libc::IN_MOVE_SELF | libc::IN_DELETE_SELF;
+
+fn add_watch(inotify: i32, path: &Path, mask: u32) -> Result<i32> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let watch = unsafe { libc::inotify_add_watch(inotify, path.as_ptr(), mask) };
+    if watch < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(watch)
+}
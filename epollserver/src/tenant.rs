@@ -0,0 +1,74 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Write as _;
+use std::io::Result;
+
+use crate::config::Config;
+use crate::history::History;
+use crate::rooms::Rooms;
+
+/// An isolated namespace of clients with its own rooms and history. Clients only
+/// ever see messages, presence notices and `/who` entries of their own tenant.
+/// Configured with one `[tenant.<name>]` section each:
+///
+/// ```text
+/// [tenant.acme]
+/// token = acme-s3cret   # picks this tenant on a listener with `tenant = *`
+/// max_clients = 100     # optional
+/// ```
+///
+/// Listeners put clients into a tenant with `tenant = <name>`, or let `/auth`
+/// choose one by token with `tenant = *`. Everyone else is in the default
+/// tenant, which uses the servers own rooms and history.
+pub struct Tenant {
+    pub name: String,
+    pub token: Option<String>,
+    pub max_clients: Option<usize>,
+    pub rooms: RefCell<Rooms>,
+    pub history: RefCell<History>,
+    /// Messages broadcast within the tenant, for metrics.
+    pub messages: Cell<u64>,
+}
+
+impl Tenant {
+    /// Every tenant in config, each keeping up to history messages starting at
+    /// first_offset.
+    pub fn from_config(config: &Config, history: usize, first_offset: u64) -> Result<Vec<Tenant>> {
+        let mut tenants = Vec::new();
+        for (name, section) in config.sections_with_prefix("tenant") {
+            let max_clients = match section.get("max_clients") {
+                Some(entry) => Some(entry.value.parse().map_err(|_| config.error(entry.line, "max_clients must be a number"))?),
+                None => None,
+            };
+            tenants.push(Tenant {
+                name: name.to_string(),
+                token: section.get("token").map(|e| e.value.clone()),
+                max_clients,
+                rooms: RefCell::new(Rooms::default()),
+                history: RefCell::new(History::new(history, first_offset)),
+                messages: Cell::new(0),
+            });
+        }
+        Ok(tenants)
+    }
+}
+
+/// Per tenant client counts and messages in prometheus text format, counts has
+/// the number of clients of each tenant.
+pub fn render_metrics(tenants: &[Tenant], counts: &[usize]) -> String {
+    let mut out = String::new();
+    if tenants.is_empty() {
+        return out;
+    }
+
+    let _ = writeln!(out, "# HELP epollbroadcast_tenant_clients Connected clients per tenant.");
+    let _ = writeln!(out, "# TYPE epollbroadcast_tenant_clients gauge");
+    for (tenant, count) in tenants.iter().zip(counts) {
+        let _ = writeln!(out, "epollbroadcast_tenant_clients{{tenant=\"{}\"}} {}", tenant.name, count);
+    }
+    let _ = writeln!(out, "# HELP epollbroadcast_tenant_messages_total Messages broadcast per tenant.");
+    let _ = writeln!(out, "# TYPE epollbroadcast_tenant_messages_total counter");
+    for tenant in tenants {
+        let _ = writeln!(out, "epollbroadcast_tenant_messages_total{{tenant=\"{}\"}} {}", tenant.name, tenant.messages.get());
+    }
+    out
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::logging::warning;
+use crate::rooms::{Bucket, Rate};
+use crate::EpollServer;
+
+pub static PAUSES: AtomicUsize = AtomicUsize::new(0);
+pub static PAUSED_MS: AtomicUsize = AtomicUsize::new(0);
+/// Event loops whose listeners are paused right now.
+pub static PAUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// The least time listeners stay paused, so a storm doesn't flip them back and
+/// forth every other wakeup.
+const STORM_PAUSE: Duration = Duration::from_millis(100);
+
+/// Limits how fast the loop accepts connections on all its listeners together,
+/// set with `--max-accept-rate`. Once a storm uses the rate up, the listeners
+/// are not watched for a while and the loop only serves the clients it already
+/// has; connections keep waiting in the kernel backlog meanwhile.
+pub struct AcceptThrottle {
+    rate: Option<Rate>,
+    bucket: Option<Bucket>,
+    paused: Option<(Instant, Instant)>, // since, until
+}
+
+impl AcceptThrottle {
+    pub fn new(rate: Option<Rate>, now: Instant) -> AcceptThrottle {
+        AcceptThrottle { rate, bucket: rate.map(|rate| Bucket::full(rate, now)), paused: None }
+    }
+
+    /// Whether one more connection may be accepted, if not the listeners
+    /// should be paused.
+    pub(crate) fn admit(&mut self, now: Instant) -> bool {
+        match (self.rate, self.bucket.as_mut()) {
+            (Some(rate), Some(bucket)) => bucket.take(rate, now),
+            _ => true,
+        }
+    }
+
+    /// When paused listeners are watched again.
+    pub fn resume_due(&self) -> Option<Instant> {
+        self.paused.map(|(_, until)| until)
+    }
+}
+
+/// Stops watching the listeners until STORM_PAUSE passed, or the rate has a
+/// connection to spare again if that takes longer.
+pub(crate) fn pause(epserver: &mut EpollServer) {
+    let Some(rate) = epserver.throttle.rate else { return };
+    if epserver.throttle.paused.is_some() {
+        return;
+    }
+    let now = epserver.sys.now();
+    let pause = STORM_PAUSE.max(rate.per / rate.messages);
+    warning!("accepting faster than {}, pausing listeners for {}ms", rate, pause.as_millis());
+    for (listener, _) in &epserver.listeners {
+        let _ = epserver.sys.set_interest(*listener, false, false);
+    }
+    epserver.throttle.paused = Some((now, now + pause));
+    PAUSES.fetch_add(1, Ordering::Relaxed);
+    PAUSED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Watches paused listeners again once their pause is over, unless draining
+/// stopped watching them for good.
+pub(crate) fn check(epserver: &mut EpollServer) {
+    let Some((since, until)) = epserver.throttle.paused else { return };
+    let now = epserver.sys.now();
+    if now < until {
+        return;
+    }
+    epserver.throttle.paused = None;
+    PAUSED.fetch_sub(1, Ordering::Relaxed);
+    PAUSED_MS.fetch_add(now.duration_since(since).as_millis() as usize, Ordering::Relaxed);
+    if epserver.draining.is_some() {
+        return;
+    }
+    for (listener, _) in &epserver.listeners {
+        let _ = epserver.sys.set_interest(*listener, true, false);
+    }
+}
@@ -0,0 +1,352 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logging::{info, warning};
+
+pub static SPANS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// How long a trace waits for its message to be flushed to every recipient.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Traces waiting on flushes at once, the oldest is cut short past it.
+const MAX_OPEN: usize = 256;
+/// Request bytes kept for a slow or absent collector, newer spans are dropped past it.
+const MAX_PENDING: usize = 1 << 20;
+
+struct Span {
+    name: &'static str,
+    id: [u8; 8],
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, u64)>,
+}
+
+/// A sampled message between being read and reaching every recipients socket.
+struct Trace {
+    id: [u8; 16],
+    root: [u8; 8],
+    fd: i32,
+    read: SystemTime,
+    fanned_out: SystemTime,
+    spans: Vec<Span>,
+    waiting: BTreeSet<i32>, // recipients it is still queued for
+}
+
+/// The message being relayed right now, if it was sampled.
+struct Relaying {
+    fd: i32,
+    bytes: usize,
+    read: SystemTime,
+    relayed: SystemTime,
+    filtered: Option<SystemTime>,
+}
+
+/// Traces the way of sampled messages through the server and exports them to
+/// an OpenTelemetry collector with OTLP over HTTP (JSON), given as
+/// `--otlp-endpoint http://host[:4318]`. Each trace has a `message` root span
+/// with a child span per stage:
+///
+/// * `receive` -- reading the socket and framing, up to relaying
+/// * `filter` -- sanitizer, dedup, filters, scripts and the plugin
+/// * `fan-out` -- rules, rooms and writing to every recipient
+/// * `flush` -- until the recipients that had to queue it have written it
+///
+/// Spans collect in memory and go out on every tick, without ever blocking the
+/// event loop for longer than a connect attempt. Reconnecting needs `socket` and
+/// `connect`, which `--sandbox seccomp` does not allow, sampled spans are dropped
+/// then.
+pub struct Tracer {
+    addr: SocketAddr,
+    host: String,
+    ratio: f64,
+    seen: Cell<u64>,
+    read_at: Cell<Option<SystemTime>>,
+    relaying: RefCell<Option<Relaying>>,
+    open: RefCell<VecDeque<Trace>>,
+    done: RefCell<Vec<Trace>>,
+    stream: RefCell<Option<TcpStream>>,
+    pending: RefCell<Vec<u8>>,
+    down: Cell<bool>, // warned about the collector being unreachable
+}
+
+impl Tracer {
+    /// Traces about ratio (0 to 1) of all messages for the collector at endpoint.
+    pub fn new(endpoint: &str, ratio: f64) -> Result<Tracer> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("invalid otlp endpoint {} -- {}", endpoint, msg));
+        let host = endpoint.strip_prefix("http://").ok_or_else(|| invalid("expected http://host:port"))?;
+        let host = host.trim_end_matches('/');
+        let addr = host.to_socket_addrs()
+            .or_else(|_| (host, 4318).to_socket_addrs())?
+            .next()
+            .ok_or_else(|| invalid("host not found"))?;
+        let tracer = Tracer {
+            addr,
+            host: host.to_string(),
+            ratio: ratio.clamp(0.0, 1.0),
+            seen: Cell::new(0),
+            read_at: Cell::new(None),
+            relaying: RefCell::new(None),
+            open: RefCell::new(VecDeque::new()),
+            done: RefCell::new(Vec::new()),
+            stream: RefCell::new(None),
+            pending: RefCell::new(Vec::new()),
+            down: Cell::new(false),
+        };
+        tracer.connect();
+        Ok(tracer)
+    }
+
+    /// Notes that a client has just been read from, where its messages start.
+    pub fn read(&self) {
+        self.read_at.set(Some(SystemTime::now()));
+    }
+
+    /// Starts relaying bytes of messages from fd, traced if sampled.
+    ///
+    /// Returns whether it was.
+    pub fn begin(&self, fd: i32, bytes: usize) -> bool {
+        let seen = self.seen.get() + 1;
+        self.seen.set(seen);
+        if (seen as f64 * self.ratio).floor() == ((seen - 1) as f64 * self.ratio).floor() {
+            return false;
+        }
+        let now = SystemTime::now();
+        let read = self.read_at.get().unwrap_or(now);
+        *self.relaying.borrow_mut() = Some(Relaying { fd, bytes, read, relayed: now, filtered: None });
+        true
+    }
+
+    /// Ends the filter stage of the message being relayed, if it is traced.
+    pub fn filtered(&self) {
+        if let Some(relaying) = self.relaying.borrow_mut().as_mut() {
+            relaying.filtered = Some(SystemTime::now());
+        }
+    }
+
+    /// Ends the fan-out of the traced message, which recipients had to queue
+    /// for flushing later.
+    pub fn fanned_out(&self, recipients: usize, bytes: usize, waiting: BTreeSet<i32>) {
+        let Some(relaying) = self.relaying.borrow_mut().take() else {
+            return;
+        };
+        let now = SystemTime::now();
+        let mut trace = Trace {
+            id: random(),
+            root: random(),
+            fd: relaying.fd,
+            read: relaying.read,
+            fanned_out: now,
+            spans: Vec::new(),
+            waiting,
+        };
+        trace.spans.push(Span { name: "receive", id: random(), start: relaying.read, end: relaying.relayed, attributes: vec![("message.bytes", relaying.bytes as u64)] });
+        let fan_out = match relaying.filtered {
+            Some(filtered) => {
+                trace.spans.push(Span { name: "filter", id: random(), start: relaying.relayed, end: filtered, attributes: Vec::new() });
+                filtered
+            }
+            None => relaying.relayed,
+        };
+        let attributes = vec![("recipients", recipients as u64), ("bytes.sent", bytes as u64)];
+        trace.spans.push(Span { name: "fan-out", id: random(), start: fan_out, end: now, attributes });
+
+        if trace.waiting.is_empty() {
+            self.finish(trace, now);
+            return;
+        }
+        let mut open = self.open.borrow_mut();
+        if open.len() == MAX_OPEN {
+            if let Some(oldest) = open.pop_front() {
+                self.finish(oldest, now);
+            }
+        }
+        open.push_back(trace);
+    }
+
+    /// Notes that fd has nothing queued anymore, or is gone.
+    pub fn drained(&self, fd: i32) {
+        let mut open = self.open.borrow_mut();
+        if open.is_empty() {
+            return;
+        }
+        let now = SystemTime::now();
+        for trace in open.iter_mut() {
+            trace.waiting.remove(&fd);
+        }
+        while let Some(i) = open.iter().position(|t| t.waiting.is_empty()) {
+            if let Some(trace) = open.remove(i) {
+                self.finish(trace, now);
+            }
+        }
+    }
+
+    fn finish(&self, mut trace: Trace, end: SystemTime) {
+        let attributes = match trace.waiting.len() {
+            0 => Vec::new(),
+            n => vec![("flush.incomplete", n as u64)],
+        };
+        trace.spans.push(Span { name: "flush", id: random(), start: trace.fanned_out, end, attributes });
+        trace.spans.push(Span { name: "message", id: trace.root, start: trace.read, end, attributes: vec![("client.fd", trace.fd as u64)] });
+        self.done.borrow_mut().push(trace);
+    }
+
+    /// Sends what finished since the last call to the collector, and closes
+    /// traces waiting on flushes for too long.
+    pub fn export(&self) {
+        let now = SystemTime::now();
+        let expired: VecDeque<Trace> = {
+            let mut open = self.open.borrow_mut();
+            let (old, young) = open.drain(..).partition(|t| now.duration_since(t.fanned_out).unwrap_or_default() >= FLUSH_TIMEOUT);
+            *open = young;
+            old
+        };
+        for trace in expired {
+            self.finish(trace, now);
+        }
+
+        let done = self.done.take();
+        if !done.is_empty() {
+            let spans = done.iter().map(|t| t.spans.len()).sum::<usize>();
+            let mut pending = self.pending.borrow_mut();
+            if pending.len() > MAX_PENDING {
+                SPANS_DROPPED.fetch_add(spans, Ordering::Relaxed);
+            } else {
+                let body = encode(&done);
+                let _ = write!(
+                    pending,
+                    "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                    self.host,
+                    body.len()
+                );
+                pending.extend_from_slice(body.as_bytes());
+            }
+        }
+        self.send();
+    }
+
+    fn connect(&self) {
+        match TcpStream::connect_timeout(&self.addr, Duration::from_millis(100)).and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                if self.down.replace(false) {
+                    info!("connected to otlp collector {}", self.addr);
+                }
+                *self.stream.borrow_mut() = Some(stream);
+            }
+            Err(e) => {
+                if !self.down.replace(true) {
+                    warning!("cannot reach otlp collector {} -- {}", self.addr, e);
+                }
+            }
+        }
+    }
+
+    fn send(&self) {
+        if self.pending.borrow().is_empty() {
+            return;
+        }
+        if self.stream.borrow().is_none() {
+            self.connect();
+        }
+        let mut stream = self.stream.borrow_mut();
+        let Some(conn) = stream.as_mut() else {
+            self.lost(&mut self.pending.borrow_mut());
+            return;
+        };
+
+        // responses are of no interest, they only have to be read off the socket
+        let mut scratch = [0; 4096];
+        let mut closed = loop {
+            match conn.read(&mut scratch) {
+                Ok(0) => break true,
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                Err(_) => break true,
+            }
+        };
+        let mut pending = self.pending.borrow_mut();
+        while !closed && !pending.is_empty() {
+            match conn.write(&pending) {
+                Ok(0) => closed = true,
+                Ok(n) => {
+                    pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => closed = true,
+            }
+        }
+        if closed {
+            // a request cut in half would garble the next connection
+            *stream = None;
+            self.lost(&mut pending);
+        }
+    }
+
+    fn lost(&self, pending: &mut Vec<u8>) {
+        if !pending.is_empty() {
+            SPANS_DROPPED.fetch_add(1, Ordering::Relaxed);
+            pending.clear();
+        }
+    }
+}
+
+fn encode(traces: &[Trace]) -> String {
+    let mut out = String::from(concat!(
+        r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"epollbroadcast"}}]},"#,
+        r#""scopeSpans":[{"scope":{"name":"epollbroadcast"},"spans":["#
+    ));
+    let mut first = true;
+    for trace in traces {
+        for span in &trace.spans {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            let root = span.id == trace.root;
+            let _ = write!(out, r#"{{"traceId":"{}","spanId":"{}","#, hex(&trace.id), hex(&span.id));
+            if !root {
+                let _ = write!(out, r#""parentSpanId":"{}","#, hex(&trace.root));
+            }
+            let _ = write!(
+                out,
+                r#""name":"{}","kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+                span.name,
+                if root { 2 } else { 1 },
+                nanos(span.start),
+                nanos(span.end.max(span.start))
+            );
+            for (i, (key, value)) in span.attributes.iter().enumerate() {
+                let sep = if i > 0 { "," } else { "" };
+                let _ = write!(out, r#"{}{{"key":"{}","value":{{"intValue":"{}"}}}}"#, sep, key, value);
+            }
+            out.push_str("]}");
+        }
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+fn nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Random ids from getrandom, or from the clock if it fails.
+fn random<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    let n = unsafe { libc::getrandom(id.as_mut_ptr().cast(), N, 0) };
+    if n != N as isize {
+        let seed = nanos(SystemTime::now()).to_le_bytes();
+        for (i, b) in id.iter_mut().enumerate() {
+            *b = seed[i % seed.len()] ^ (i as u8).wrapping_mul(151);
+        }
+    }
+    id
+}
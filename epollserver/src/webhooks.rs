@@ -0,0 +1,332 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use regex::bytes::Regex;
+
+use crate::config::Config;
+use crate::logging::{info, warning};
+use crate::overload;
+use crate::sink::json_escape;
+use crate::sys::{self, Sys};
+
+pub static WEBHOOK_SENT: AtomicUsize = AtomicUsize::new(0);
+pub static WEBHOOK_RETRIES: AtomicUsize = AtomicUsize::new(0);
+pub static WEBHOOK_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Messages not queued while the server was overloaded.
+pub static WEBHOOK_SKIPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Messages kept per webhook while its endpoint is slow or down, the oldest are
+/// dropped past it.
+const MAX_QUEUED: usize = 10_000;
+/// How long a request may take from connecting to the end of the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry, doubled for every further one up to MAX_RETRY.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// `{"webhook":"<name>","messages":["...",...]}`
+    Json,
+    /// `{"text":"..."}` with one message per line, for Slack incoming webhooks.
+    Slack,
+}
+
+/// A POST on its way.
+struct Request {
+    stream: TcpStream,
+    out: Vec<u8>,
+    response: Vec<u8>,
+    batch: Vec<Vec<u8>>,
+    deadline: Instant,
+}
+
+struct Webhook {
+    name: String,
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    pattern: Option<Regex>,
+    format: Format,
+    batch: usize,
+    retries: u32,
+    queue: RefCell<VecDeque<Vec<u8>>>,
+    request: RefCell<Option<Request>>,
+    failed: RefCell<Option<(Vec<Vec<u8>>, u32)>>, // batch to send again and how often it failed
+    retry_at: Cell<Option<Instant>>,
+}
+
+/// HTTP endpoints broadcast messages are POSTed to as JSON, to bridge into chat
+/// or alerting systems, configured with one `[webhook.<name>]` section each:
+///
+/// ```text
+/// [webhook.alerts]
+/// url = http://hooks.internal:8080/broadcast
+/// match = ^ALERT
+/// format = slack
+/// batch = 20
+/// retries = 3
+/// ```
+///
+/// Messages matching `match` (every message without one) queue up and go out up
+/// to `batch` (50 by default) per request, one request per webhook at a time, so
+/// whatever arrives meanwhile makes up the next. A request that fails or gets no
+/// 2xx answer is retried up to `retries` (3 by default) times with growing pauses,
+/// then its messages are dropped. `format` is `json` (the default) or `slack`.
+///
+/// Connections are nonblocking and handled by the event loop like clients, only
+/// plain http is supported. While the server is overloaded messages are not
+/// queued and no new requests start, those on their way finish.
+#[derive(Default)]
+pub struct Webhooks {
+    webhooks: Vec<Webhook>,
+}
+
+impl Webhooks {
+    pub fn from_config(config: &Config) -> Result<Webhooks> {
+        let mut webhooks = Vec::new();
+
+        for (name, section) in config.sections_with_prefix("webhook") {
+            let url = section.get("url").ok_or_else(|| config.error(section.line, "webhook is missing `url`"))?;
+            let (host, path) = url.value.strip_prefix("http://")
+                .map(|rest| rest.split_once('/').map_or((rest, "/".to_string()), |(host, path)| (host, format!("/{}", path))))
+                .ok_or_else(|| config.error(url.line, "url must be http://host[:port]/path"))?;
+            let addr = host.to_socket_addrs().or_else(|_| (host, 80).to_socket_addrs())
+                .ok()
+                .and_then(|mut a| a.next())
+                .ok_or_else(|| config.error(url.line, &format!("{} does not resolve", host)))?;
+            let pattern = match section.get("match") {
+                Some(entry) => Some(Regex::new(&entry.value)
+                    .map_err(|e| config.error(entry.line, &format!("invalid regex -- {}", e)))?),
+                None => None,
+            };
+            let format = match section.get("format").map(|e| (e.value.as_str(), e.line)) {
+                None | Some(("json", _)) => Format::Json,
+                Some(("slack", _)) => Format::Slack,
+                Some((_, line)) => return Err(config.error(line, "format must be `json` or `slack`")),
+            };
+            let batch = match section.get("batch") {
+                Some(entry) => entry.value.parse().ok().filter(|n| *n > 0)
+                    .ok_or_else(|| config.error(entry.line, "batch must be a positive number"))?,
+                None => 50,
+            };
+            let retries = match section.get("retries") {
+                Some(entry) => entry.value.parse().map_err(|_| config.error(entry.line, "retries must be a number"))?,
+                None => 3,
+            };
+
+            webhooks.push(Webhook {
+                name: name.to_string(),
+                addr,
+                host: host.to_string(),
+                path,
+                pattern,
+                format,
+                batch,
+                retries,
+                queue: RefCell::new(VecDeque::new()),
+                request: RefCell::new(None),
+                failed: RefCell::new(None),
+                retry_at: Cell::new(None),
+            });
+        }
+
+        Ok(Webhooks { webhooks })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty()
+    }
+
+    /// Queues a single broadcast message (without its newline) for every webhook
+    /// it matches, unless the server is overloaded.
+    pub fn offer(&self, message: &[u8]) {
+        for webhook in self.webhooks.iter().filter(|w| w.pattern.as_ref().is_none_or(|p| p.is_match(message))) {
+            if overload::degraded() {
+                WEBHOOK_SKIPPED.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let mut queue = webhook.queue.borrow_mut();
+            if queue.len() == MAX_QUEUED {
+                queue.pop_front();
+                WEBHOOK_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            queue.push_back(message.to_vec());
+        }
+    }
+
+    pub fn owns(&self, fd: i32) -> bool {
+        self.webhooks.iter().any(|w| w.request.borrow().as_ref().is_some_and(|r| r.stream.as_raw_fd() == fd))
+    }
+
+    /// Moves the request on fd along, it became readable or writable.
+    pub fn handle_event(&self, sys: &dyn Sys, fd: i32, now: Instant) {
+        if let Some(webhook) = self.webhooks.iter().find(|w| w.request.borrow().as_ref().is_some_and(|r| r.stream.as_raw_fd() == fd)) {
+            webhook.progress(sys, now);
+        }
+    }
+
+    /// Starts requests that are due and gives up on those taking too long, on every
+    /// wakeup.
+    pub fn poll(&self, sys: &dyn Sys, now: Instant) {
+        for webhook in &self.webhooks {
+            let overdue = webhook.request.borrow().as_ref().is_some_and(|r| now >= r.deadline);
+            if overdue {
+                webhook.finish(sys, now, Err("timed out".to_string()));
+            }
+            if webhook.request.borrow().is_none() && webhook.retry_at.get().is_none_or(|at| now >= at) && !overload::degraded() {
+                webhook.start(sys, now);
+            }
+        }
+    }
+
+    /// When poll has something to do next, a retry or a request timing out.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.webhooks.iter()
+            .flat_map(|w| [w.retry_at.get().filter(|_| !overload::degraded()), w.request.borrow().as_ref().map(|r| r.deadline)])
+            .flatten()
+            .min()
+    }
+}
+
+impl Webhook {
+    fn start(&self, sys: &dyn Sys, now: Instant) {
+        self.retry_at.set(None);
+        let (batch, _) = match self.failed.borrow().clone() {
+            Some(failed) => failed,
+            None => {
+                let mut queue = self.queue.borrow_mut();
+                let n = queue.len().min(self.batch);
+                (queue.drain(..n).collect::<Vec<Vec<u8>>>(), 0)
+            }
+        };
+        if batch.is_empty() {
+            return;
+        }
+
+        let body = self.encode(&batch);
+        let mut out = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        ).into_bytes();
+        out.extend_from_slice(body.as_bytes());
+
+        let stream = match sys::connect(self.addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.failed(batch, now, &e.to_string());
+                return;
+            }
+        };
+        let fd = stream.as_raw_fd();
+        if let Err(e) = sys.watch(fd).and_then(|_| sys.set_interest(fd, true, true)) {
+            self.failed(batch, now, &e.to_string());
+            return;
+        }
+        *self.request.borrow_mut() = Some(Request { stream, out, response: Vec::new(), batch, deadline: now + REQUEST_TIMEOUT });
+    }
+
+    fn progress(&self, sys: &dyn Sys, now: Instant) {
+        let outcome = {
+            let mut request = self.request.borrow_mut();
+            let Some(req) = request.as_mut() else { return };
+            send_and_receive(req, sys)
+        };
+        if let Some(result) = outcome {
+            self.finish(sys, now, result);
+        }
+    }
+
+    /// Closes the request, result is its status or why there is none.
+    fn finish(&self, sys: &dyn Sys, now: Instant, result: std::result::Result<u16, String>) {
+        let Some(req) = self.request.borrow_mut().take() else { return };
+        sys.unwatch(req.stream.as_raw_fd());
+        match result {
+            Ok(status) if (200..300).contains(&status) => {
+                WEBHOOK_SENT.fetch_add(req.batch.len(), Ordering::Relaxed);
+                if self.failed.take().is_some() {
+                    info!("webhook {} delivered again", self.name);
+                }
+            }
+            Ok(status) => self.failed(req.batch, now, &format!("answered {}", status)),
+            Err(why) => self.failed(req.batch, now, &why),
+        }
+    }
+
+    fn failed(&self, batch: Vec<Vec<u8>>, now: Instant, why: &str) {
+        let attempts = self.failed.borrow().as_ref().map_or(0, |(_, n)| *n).saturating_add(1);
+        if attempts > self.retries {
+            warning!("webhook {} dropped {} messages after {} attempts -- {}", self.name, batch.len(), attempts, why);
+            WEBHOOK_DROPPED.fetch_add(batch.len(), Ordering::Relaxed);
+            *self.failed.borrow_mut() = None;
+            return;
+        }
+        if attempts == 1 {
+            warning!("webhook {} failed, retrying -- {}", self.name, why);
+        }
+        WEBHOOK_RETRIES.fetch_add(1, Ordering::Relaxed);
+        self.retry_at.set(Some(now + RETRY_BACKOFF.saturating_mul(2u32.saturating_pow((attempts - 1).min(10))).min(MAX_RETRY)));
+        *self.failed.borrow_mut() = Some((batch, attempts));
+    }
+
+    fn encode(&self, batch: &[Vec<u8>]) -> String {
+        let texts: Vec<String> = batch.iter().map(|m| json_escape(&String::from_utf8_lossy(m))).collect();
+        match self.format {
+            Format::Json => format!(
+                "{{\"webhook\":\"{}\",\"messages\":[{}]}}",
+                json_escape(&self.name),
+                texts.iter().map(|t| format!("\"{}\"", t)).collect::<Vec<String>>().join(",")
+            ),
+            Format::Slack => format!("{{\"text\":\"{}\"}}", texts.join("\\n")),
+        }
+    }
+}
+
+/// Writes what is left of the request and reads what came of the response.
+///
+/// Returns the status once the answer is complete, None while it is not.
+fn send_and_receive(req: &mut Request, sys: &dyn Sys) -> Option<std::result::Result<u16, String>> {
+    while !req.out.is_empty() {
+        match req.stream.write(&req.out) {
+            Ok(n) => {
+                req.out.drain(..n);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+            Err(e) => return Some(Err(e.to_string())),
+        }
+    }
+    let _ = sys.set_interest(req.stream.as_raw_fd(), true, false);
+
+    let mut buf = [0; 4096];
+    loop {
+        match req.stream.read(&mut buf) {
+            // Connection: close, the answer is over once the server hangs up
+            Ok(0) => return Some(status(&req.response)),
+            Ok(n) => {
+                // only the status line matters
+                if req.response.len() < 256 {
+                    req.response.extend_from_slice(&buf[..n]);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+            Err(e) => return Some(Err(e.to_string())),
+        }
+    }
+}
+
+fn status(response: &[u8]) -> std::result::Result<u16, String> {
+    let line = response.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next().and_then(|s| s.parse().ok())) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => Ok(status),
+        _ => Err("no http status in the answer".to_string()),
+    }
+}
@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::{peer_name, ClientState, EpollServer};
+
+/// Clients listed per page by `/who` and the admin `list`.
+pub const PAGE_SIZE: usize = 20;
+
+/// Lists page (counting from 1) of the connected clients in fd order, one line
+/// each: fd, nick, address, rooms, idle seconds and queued bytes, ending in
+/// `slow` for slow consumers and then the clients metadata. borrowed is
+/// used for its own entry, because it can't be borrowed from clients again.
+/// Only clients visible is true for are listed.
+pub fn list(
+    epserver: &EpollServer,
+    clients: &HashMap<i32, RefCell<ClientState>>,
+    borrowed: Option<&ClientState>,
+    page: usize,
+    visible: impl Fn(&ClientState) -> bool,
+) -> String {
+    let mut fds: Vec<i32> = clients.keys().copied()
+        .filter(|fd| match borrowed.filter(|c| c.fd == *fd) {
+            Some(client) => visible(client),
+            None => visible(&clients[fd].borrow()),
+        })
+        .collect();
+    fds.sort_unstable();
+    let pages = fds.len().div_ceil(PAGE_SIZE).max(1);
+
+    let mut out = format!("page {}/{}, {} clients\n", page.min(pages), pages, fds.len());
+    let start = (page.clamp(1, pages) - 1) * PAGE_SIZE;
+    for fd in fds.iter().skip(start).take(PAGE_SIZE) {
+        let line = match borrowed.filter(|c| c.fd == *fd) {
+            Some(client) => entry(client, epserver),
+            None => entry(&clients[fd].borrow(), epserver),
+        };
+        out.push_str(&line);
+    }
+    out
+}
+
+/// Per client gauges, labeled with fd, nick and address so a misbehaving client
+/// can be told apart on a dashboard.
+pub fn render_metrics(clients: &HashMap<i32, RefCell<ClientState>>) -> String {
+    let mut fds: Vec<i32> = clients.keys().copied().collect();
+    fds.sort_unstable();
+    let mut out = String::from("# HELP epollbroadcast_client_queued_bytes Bytes waiting to be written to a client\n# TYPE epollbroadcast_client_queued_bytes gauge\n");
+    for fd in fds {
+        let Ok(client) = clients[&fd].try_borrow() else { continue };
+        let _ = writeln!(
+            out,
+            "epollbroadcast_client_queued_bytes{{fd=\"{}\",nick=\"{}\",peer=\"{}\"}} {}",
+            fd,
+            client.nick.as_deref().unwrap_or("-").replace('\\', "\\\\").replace('"', "\\\""),
+            peer_name(&client),
+            client.outbox.len()
+        );
+    }
+    out
+}
+
+fn entry(client: &ClientState, epserver: &EpollServer) -> String {
+    let addr = peer_name(client);
+    let rooms: Vec<String> = epserver.rooms_for(client.tenant).borrow().iter()
+        .filter(|r| r.members.contains(&client.fd))
+        .map(|r| r.name.clone())
+        .collect();
+    let idle = epserver.sys.now().saturating_duration_since(client.last_active);
+    format!(
+        "{} {} {} rooms={} idle={}s queued={}{}{}\n",
+        client.fd,
+        client.nick.as_deref().unwrap_or("-"),
+        addr,
+        if rooms.is_empty() { "-".to_string() } else { rooms.join(",") },
+        idle.as_secs(),
+        client.outbox.len(),
+        if client.slow { " slow" } else { "" },
+        client.meta.iter().map(|(k, v)| format!(" {}={}", k, v)).collect::<String>()
+    )
+}
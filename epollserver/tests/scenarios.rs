@@ -0,0 +1,44 @@
+//! Runs every scenario in scenarios/ against the simulated network, with the
+//! flags its `# flags:` line asks for.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// The flags on a `# flags: ...` line among the comments at the top of the
+/// scenario, none without one.
+fn flags(scenario: &str) -> Vec<String> {
+    scenario.lines()
+        .take_while(|line| line.starts_with('#'))
+        .find_map(|line| line.strip_prefix("# flags:"))
+        .map(|flags| flags.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+#[test]
+fn scenarios() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths: Vec<_> = fs::read_dir(dir.join("scenarios")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "txt"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenarios found");
+
+    let mut failed = Vec::new();
+    for path in &paths {
+        let relative = path.strip_prefix(dir).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_epollserver"))
+            .current_dir(dir)
+            .arg("--simulate")
+            .arg(relative)
+            .args(flags(&fs::read_to_string(path).unwrap()))
+            .output()
+            .unwrap();
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            failed.push(format!("{}: {}", relative.display(), stderr.lines().last().unwrap_or("failed")));
+        }
+    }
+    assert!(failed.is_empty(), "{} of {} scenarios failed:\n{}", failed.len(), paths.len(), failed.join("\n"));
+}